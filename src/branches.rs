@@ -0,0 +1,35 @@
+use git2::{BranchType, Oid, Repository};
+use glob::Pattern;
+
+use crate::error::Result;
+
+/// The remote-tracking branch of `remote` matching `pattern` whose tip was
+/// committed last. Branches committed at the same time are ordered by name,
+/// the first one wins.
+pub(crate) fn newest(
+    repo: &Repository,
+    remote: &str,
+    pattern: &Pattern,
+) -> Result<Option<(String, Oid)>> {
+    let prefix = format!("{}/", remote);
+    let mut newest: Option<(i64, String, Oid)> = None;
+    for branch in repo.branches(Some(BranchType::Remote))? {
+        let (branch, _) = branch?;
+        let name = match branch.name()?.and_then(|name| name.strip_prefix(&prefix)) {
+            Some(name) if name != "HEAD" && pattern.matches(name) => name.to_owned(),
+            _ => continue,
+        };
+        let commit = branch.get().peel_to_commit()?;
+        let time = commit.committer().when().seconds();
+        let newer = match &newest {
+            Some((newest_time, newest_name, _)) => {
+                time > *newest_time || (time == *newest_time && name < *newest_name)
+            }
+            None => true,
+        };
+        if newer {
+            newest = Some((time, name, commit.id()));
+        }
+    }
+    Ok(newest.map(|(_, name, oid)| (name, oid)))
+}
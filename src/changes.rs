@@ -0,0 +1,27 @@
+use std::path::PathBuf;
+
+use git2::{DiffFindOptions, Oid, Repository};
+
+use crate::error::Result;
+
+/// The paths changed between two commits. Renamed files are reported with
+/// their old and new path, deleted files with the path they had.
+pub(crate) fn changed_paths(repo: &Repository, from: Oid, to: Oid) -> Result<Vec<PathBuf>> {
+    let old = repo.find_commit(from)?.tree()?;
+    let new = repo.find_commit(to)?.tree()?;
+    let mut diff = repo.diff_tree_to_tree(Some(&old), Some(&new), None)?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+
+    let mut paths = Vec::new();
+    for delta in diff.deltas() {
+        for path in [delta.old_file().path(), delta.new_file().path()]
+            .iter()
+            .flatten()
+        {
+            if !paths.iter().any(|known| known == path) {
+                paths.push(path.to_path_buf());
+            }
+        }
+    }
+    Ok(paths)
+}
@@ -0,0 +1,694 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    str::FromStr,
+    sync::Mutex,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{Error, Result},
+    memory,
+    output::{self, Capture, LogFormat},
+    pid_file, shutdown,
+};
+
+/// How long the output of a stopped command is waited for. Processes it
+/// started are left running with `--no-process-group` and may keep the
+/// pipes open.
+const DRAIN: Duration = Duration::from_secs(1);
+
+/// How often a stopping command is checked for having exited.
+const POLL: Duration = Duration::from_millis(50);
+
+/// The pids of the processes started here, which `--init` leaves to be
+/// waited for by whoever started them.
+static STARTED: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// The processes started here. Held while the orphans are reaped, so a
+/// process started meanwhile isn't taken for one before it is registered.
+#[cfg(target_os = "linux")]
+pub(crate) fn started() -> std::sync::MutexGuard<'static, Vec<u32>> {
+    STARTED.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Starts `command`, registered before `init::reap` can see it exit.
+pub(crate) fn spawn(command: &mut Command) -> io::Result<Child> {
+    let mut started = STARTED.lock().unwrap_or_else(|err| err.into_inner());
+    let child = command.spawn()?;
+    started.push(child.id());
+    Ok(child)
+}
+
+/// Forgets `pid` once it was waited for.
+pub(crate) fn forget(pid: u32) {
+    STARTED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .retain(|&started| started != pid);
+}
+
+/// `Command::output` of a process `init::reap` leaves alone.
+pub(crate) fn output(command: &mut Command) -> io::Result<Output> {
+    let child = spawn(
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )?;
+    let pid = child.id();
+    let output = child.wait_with_output();
+    forget(pid);
+    output
+}
+
+/// `Command::status` of a process `init::reap` leaves alone.
+pub(crate) fn status(command: &mut Command) -> io::Result<ExitStatus> {
+    let mut child = spawn(command)?;
+    let status = child.wait();
+    forget(child.id());
+    status
+}
+
+/// The signal of `--stop-signal` asking the command to exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Signal {
+    pub name: &'static str,
+    #[cfg(unix)]
+    number: libc::c_int,
+}
+
+impl Signal {
+    pub const TERM: Signal = Signal {
+        name: "TERM",
+        #[cfg(unix)]
+        number: libc::SIGTERM,
+    };
+
+    /// Names like `kill -l` prints them, with or without `SIG`, given with
+    /// `option`.
+    #[cfg(unix)]
+    pub fn parse(option: &'static str, s: &str) -> Result<Self> {
+        let name = s.to_uppercase();
+        let name = name.strip_prefix("SIG").unwrap_or(&name);
+        SIGNALS
+            .iter()
+            .find(|signal| signal.name == name)
+            .copied()
+            .ok_or_else(|| {
+                Error::InvalidArgument(
+                    option,
+                    format!(
+                        "{}, use one of {}",
+                        s,
+                        SIGNALS
+                            .iter()
+                            .map(|signal| signal.name)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                )
+            })
+    }
+
+    /// How the signal shows in messages, on Windows what is sent instead.
+    pub fn describe(&self) -> String {
+        match cfg!(windows) {
+            true => "CTRL_BREAK".to_owned(),
+            false => format!("SIG{}", self.name),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn parse(option: &'static str, s: &str) -> Result<Self> {
+        let instead = match option {
+            "stop-signal" => ", the command is killed",
+            _ => "",
+        };
+        Err(Error::InvalidArgument(
+            option,
+            format!("{}, there are no signals on this platform{}", s, instead),
+        ))
+    }
+}
+
+#[cfg(unix)]
+const SIGNALS: [Signal; 6] = [
+    Signal {
+        name: "HUP",
+        number: libc::SIGHUP,
+    },
+    Signal {
+        name: "INT",
+        number: libc::SIGINT,
+    },
+    Signal {
+        name: "QUIT",
+        number: libc::SIGQUIT,
+    },
+    Signal::TERM,
+    Signal {
+        name: "USR1",
+        number: libc::SIGUSR1,
+    },
+    Signal {
+        name: "USR2",
+        number: libc::SIGUSR2,
+    },
+];
+
+impl FromStr for Signal {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Signal::parse("stop-signal", s)
+    }
+}
+
+/// The program `name` is started as. Windows only looks for `.exe` files
+/// on the `PATH`, so a name without extension is looked up with `PATHEXT` to
+/// find scripts like `npm.cmd` too, which then run through `cmd.exe`.
+#[cfg(windows)]
+pub(crate) fn program(name: &str) -> std::ffi::OsString {
+    let path = Path::new(name);
+    if path.extension().is_some() || path.components().count() != 1 {
+        return name.into();
+    }
+    let extensions = std::env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".to_owned());
+    let dirs = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&dirs)
+        .flat_map(|dir| {
+            extensions
+                .split(';')
+                .filter(|extension| !extension.is_empty())
+                .map(move |extension| dir.join(format!("{}{}", name, extension.to_lowercase())))
+        })
+        .find(|candidate| candidate.is_file())
+        .map_or_else(|| name.into(), PathBuf::into_os_string)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn program(name: &str) -> std::ffi::OsString {
+    name.into()
+}
+
+/// What `--stdin` gives the command, the build and the hooks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stdin {
+    /// Nothing to read, the right thing for a daemon.
+    Null,
+    /// localdeploy's own, for running it in the foreground.
+    Inherit,
+    /// A pipe which is never written to.
+    Pipe,
+}
+
+impl FromStr for Stdin {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "null" => Ok(Stdin::Null),
+            "inherit" => Ok(Stdin::Inherit),
+            "pipe" => Ok(Stdin::Pipe),
+            _ => Err(Error::InvalidArgument("stdin", s.to_owned())),
+        }
+    }
+}
+
+impl Stdin {
+    pub fn stdio(self) -> Stdio {
+        match self {
+            Stdin::Null => Stdio::null(),
+            Stdin::Inherit => Stdio::inherit(),
+            Stdin::Pipe => Stdio::piped(),
+        }
+    }
+}
+
+/// How the command exited, `code 3` or on unix `signal 9`, as the history
+/// records it.
+pub(crate) fn exit_detail(status: ExitStatus) -> String {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return format!("signal {}", signal);
+    }
+    match status.code() {
+        Some(code) => format!("code {}", code),
+        None => status.to_string(),
+    }
+}
+
+/// `command` as it would be typed into a shell, quoting the words which
+/// need it.
+pub(crate) fn shell_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|word| {
+            let word = word.to_string_lossy();
+            let plain = !word.is_empty()
+                && word
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+            match plain {
+                true => word.into_owned(),
+                false => format!("'{}'", word.replace('\'', "'\\''")),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The command line of `command` and the names of its variables.
+fn describe(command: &Command) -> (String, Vec<String>) {
+    let env = command
+        .get_envs()
+        .map(|(name, _)| name.to_string_lossy().into_owned())
+        .collect();
+    (shell_line(command), env)
+}
+
+/// The running command. Unless `--no-process-group`, it gets a process
+/// group of its own on unix and a job object on Windows, so stopping it also
+/// stops what it started.
+pub(crate) struct Running {
+    child: Child,
+    started: Instant,
+    /// The threads copying stdout and stderr to the log file.
+    readers: Vec<JoinHandle<()>>,
+    /// The process group of the command, which is signalled instead of it.
+    #[cfg(unix)]
+    group: Option<libc::pid_t>,
+    #[cfg(windows)]
+    job: Option<job::Job>,
+    /// The command has a console process group of its own, which is asked
+    /// to stop with CTRL_BREAK.
+    #[cfg(windows)]
+    console_group: bool,
+    /// The master side of the pseudo-terminal of `--pty`, only held so it
+    /// keeps being resized along with localdeploy's terminal.
+    #[cfg(unix)]
+    _pty: Option<std::sync::Arc<std::fs::File>>,
+    /// The `--child-pid-file`, removed once the command stopped.
+    pid_file: Option<PathBuf>,
+    /// The command line and the names of the variables it was given, for
+    /// a crash report.
+    line: String,
+    env: Vec<String>,
+}
+
+impl Running {
+    /// Starts `command`, with its output going to `log` or else to
+    /// localdeploy's. With `pty` it runs in a pseudo-terminal, all of its
+    /// output then comes as one stream.
+    pub fn spawn(
+        command: &mut Command,
+        group: bool,
+        log: Option<&Capture>,
+        pty: bool,
+    ) -> io::Result<Self> {
+        #[cfg(unix)]
+        if pty {
+            return Self::spawn_pty(command, group, log);
+        }
+        #[cfg(not(unix))]
+        if pty {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "pseudo-terminals need unix",
+            ));
+        }
+        #[cfg(unix)]
+        if group {
+            std::os::unix::process::CommandExt::process_group(command, 0);
+        }
+        // Only a group of its own can be sent CTRL_BREAK without localdeploy
+        // getting it too.
+        #[cfg(windows)]
+        if group {
+            std::os::windows::process::CommandExt::creation_flags(
+                command,
+                windows_sys::Win32::System::Threading::CREATE_NEW_PROCESS_GROUP,
+            );
+        }
+        // Only stderr is read for the tail, stdout stays localdeploy's.
+        let piped = |piped: bool| match piped {
+            true => Stdio::piped(),
+            false => Stdio::inherit(),
+        };
+        let (stdout, stderr) = match log {
+            Some(log) => {
+                let read = log.log.is_some() || log.syslog.is_some() || log.ready.is_some();
+                (piped(read), piped(read || log.tail.is_some()))
+            }
+            None => (Stdio::inherit(), Stdio::inherit()),
+        };
+        let mut child = spawn(command.stdout(stdout).stderr(stderr))?;
+        let (line, env) = describe(command);
+        let mut readers = Vec::new();
+        if let Some(log) = log {
+            if let Some(stdout) = child.stdout.take() {
+                readers.push(output::capture(stdout, "stdout", log.clone()));
+            }
+            if let Some(stderr) = child.stderr.take() {
+                readers.push(output::capture(stderr, "stderr", log.clone()));
+            }
+        }
+        #[cfg(unix)]
+        let group = group.then(|| child.id() as libc::pid_t);
+        #[cfg(windows)]
+        let job = match group {
+            true => match job::Job::assign(&child) {
+                Ok(job) => Some(job),
+                Err(err) => {
+                    warn!(
+                        "no job object for the command, what it starts outlives it: {}",
+                        err
+                    );
+                    None
+                }
+            },
+            false => None,
+        };
+        Ok(Self {
+            child,
+            started: Instant::now(),
+            readers,
+            #[cfg(unix)]
+            group,
+            #[cfg(windows)]
+            job,
+            #[cfg(windows)]
+            console_group: group,
+            #[cfg(unix)]
+            _pty: None,
+            pid_file: None,
+            line,
+            env,
+        })
+    }
+
+    /// Starts `command` in a new session with a pseudo-terminal, whose
+    /// output is copied like a pipe's would be. The session is the group
+    /// signalled unless `--no-process-group`.
+    #[cfg(unix)]
+    fn spawn_pty(command: &mut Command, group: bool, log: Option<&Capture>) -> io::Result<Self> {
+        let master = crate::pty::attach(command)?;
+        let spawned = spawn(command);
+        // The command holds the slave side, it has to be closed here for
+        // the master to see the end of the output.
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        let child = spawned?;
+        let (line, env) = describe(command);
+        let capture = log.cloned().unwrap_or(Capture {
+            log: None,
+            format: LogFormat::Plain,
+            label: None,
+            ready: None,
+            tail: None,
+            syslog: None,
+            sha: None,
+        });
+        let reader = output::capture(master.try_clone()?, "pty", capture);
+        Ok(Self {
+            group: group.then(|| child.id() as libc::pid_t),
+            child,
+            started: Instant::now(),
+            readers: vec![reader],
+            _pty: Some(master),
+            pid_file: None,
+            line,
+            env,
+        })
+    }
+
+    /// Writes the pid of the command to `path`, which is removed again when
+    /// it stops.
+    pub fn write_pid_file(&mut self, path: &Path) -> io::Result<()> {
+        pid_file::write(path, &format!("{}\n", self.child.id()))?;
+        self.pid_file = Some(path.to_owned());
+        Ok(())
+    }
+
+    /// How the command exited, if it did.
+    pub fn exited(&mut self) -> Option<ExitStatus> {
+        let status = self.child.try_wait().ok().flatten();
+        if status.is_some() {
+            self.remove_pid_file();
+        }
+        status
+    }
+
+    fn remove_pid_file(&mut self) {
+        if let Some(path) = self.pid_file.take() {
+            pid_file::remove(&path, self.child.id());
+        }
+    }
+
+    /// Waits up to `timeout` for the command to exit, with all of its
+    /// output. Gives up early when localdeploy is asked to stop.
+    pub fn finish(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        while self.started.elapsed() < timeout && !shutdown::requested() {
+            if let Some(status) = self.exited() {
+                self.drain();
+                return Some(status);
+            }
+            thread::sleep(POLL);
+        }
+        None
+    }
+
+    /// The resident memory of the command and what it started, in bytes.
+    pub fn rss(&self) -> Option<u64> {
+        #[cfg(unix)]
+        let group = self.group();
+        #[cfg(not(unix))]
+        let group = None;
+        memory::rss(self.child.id(), group)
+    }
+
+    /// The command line, as a shell would take it.
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// The names of the variables the command was given.
+    pub fn env(&self) -> &[String] {
+        &self.env
+    }
+
+    /// How long the command has been running.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Stops the command, asking it with `signal` first and killing it if
+    /// it is still running after `timeout`. It is always waited for, so no
+    /// zombie is left behind. Windows has no signals, there the command is
+    /// asked with CTRL_BREAK if it has a process group of its own and
+    /// otherwise killed at once.
+    pub fn stop(&mut self, signal: Signal, timeout: Duration) {
+        self.terminate(signal, timeout);
+        self.remove_pid_file();
+    }
+
+    fn terminate(&mut self, signal: Signal, timeout: Duration) {
+        if let Ok(Some(status)) = self.child.try_wait() {
+            if !self.group_alive() {
+                verbose!("the command had exited already ({})", status);
+                self.drain();
+                return;
+            }
+        }
+        let started = Instant::now();
+        if self.send(signal) {
+            while started.elapsed() < timeout {
+                match self.child.try_wait() {
+                    // What the command started has to be gone as well.
+                    Ok(Some(status)) if !self.group_alive() => {
+                        info!(
+                            "the command stopped after {:.1}s on {} ({})",
+                            started.elapsed().as_secs_f64(),
+                            signal.describe(),
+                            status
+                        );
+                        self.drain();
+                        return;
+                    }
+                    Ok(_) => thread::sleep(POLL),
+                    Err(_) => break,
+                }
+            }
+            warn!(
+                "the command didn't stop within {} of {}, killing it",
+                humantime::format_duration(timeout),
+                signal.describe()
+            );
+        }
+        self.kill();
+        match self.child.wait() {
+            Ok(status) => info!(
+                "killed the command after {:.1}s ({})",
+                started.elapsed().as_secs_f64(),
+                status
+            ),
+            Err(err) => warn!("waiting for the killed command failed: {}", err),
+        }
+        self.drain();
+    }
+
+    /// Waits for the rest of the output, so the last lines before the
+    /// command stopped are in the log.
+    pub fn drain(&mut self) {
+        let started = Instant::now();
+        while self.readers.iter().any(|reader| !reader.is_finished()) {
+            if started.elapsed() > DRAIN {
+                verbose!("the pipes of the command are still open, not waiting for them");
+                self.readers.clear();
+                return;
+            }
+            thread::sleep(POLL);
+        }
+        for reader in self.readers.drain(..) {
+            let _ = reader.join();
+        }
+    }
+
+    /// Sends `signal` to the process group, or to the command alone.
+    #[cfg(unix)]
+    pub fn send(&self, signal: Signal) -> bool {
+        let target = match self.group() {
+            Some(group) => -group,
+            // The child isn't waited for yet, so its pid can't have been
+            // reused.
+            None => self.child.id() as libc::pid_t,
+        };
+        unsafe { libc::kill(target, signal.number) == 0 }
+    }
+
+    /// Sends CTRL_BREAK to the console process group of the command, the
+    /// closest Windows has to SIGTERM. Commands which share localdeploy's
+    /// group or run without a console can only be killed.
+    #[cfg(windows)]
+    pub fn send(&self, _signal: Signal) -> bool {
+        use windows_sys::Win32::System::Console::{GenerateConsoleCtrlEvent, CTRL_BREAK_EVENT};
+
+        if !self.console_group {
+            verbose!("the command shares localdeploy's process group, terminating it");
+            return false;
+        }
+        if unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, self.child.id()) } == 0 {
+            verbose!(
+                "sending CTRL_BREAK to the command failed, terminating it: {}",
+                io::Error::last_os_error()
+            );
+            return false;
+        }
+        true
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    pub fn send(&self, _signal: Signal) -> bool {
+        false
+    }
+
+    /// The group to signal. A pid isn't reused while a group of that id
+    /// exists, and localdeploy's own group is never signalled.
+    #[cfg(unix)]
+    fn group(&self) -> Option<libc::pid_t> {
+        self.group
+            .filter(|&group| group > 1 && group != unsafe { libc::getpgrp() })
+    }
+
+    /// Whether something the command started is still running.
+    #[cfg(unix)]
+    fn group_alive(&self) -> bool {
+        self.group()
+            .is_some_and(|group| unsafe { libc::kill(-group, 0) } == 0)
+    }
+
+    /// The job object is closed and so terminated with the command.
+    #[cfg(not(unix))]
+    fn group_alive(&self) -> bool {
+        false
+    }
+
+    fn kill(&mut self) {
+        #[cfg(unix)]
+        if let Some(group) = self.group() {
+            unsafe { libc::kill(-group, libc::SIGKILL) };
+        }
+        #[cfg(windows)]
+        if let Some(job) = &self.job {
+            job.terminate();
+        }
+        let _ = self.child.kill();
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        forget(self.child.id());
+    }
+}
+
+/// The job objects Windows groups processes with. Every process the command
+/// starts joins its job, closing the job kills them all.
+#[cfg(windows)]
+mod job {
+    use std::{io, mem, os::windows::io::AsRawHandle, process::Child, ptr};
+
+    use windows_sys::Win32::{
+        Foundation::{CloseHandle, HANDLE},
+        System::JobObjects::{
+            AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+            SetInformationJobObject, TerminateJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+            JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+        },
+    };
+
+    pub(super) struct Job(HANDLE);
+
+    // A job handle can be used from any thread.
+    unsafe impl Send for Job {}
+
+    impl Job {
+        pub fn assign(child: &Child) -> io::Result<Self> {
+            unsafe {
+                let handle = CreateJobObjectW(ptr::null(), ptr::null());
+                if handle.is_null() {
+                    return Err(io::Error::last_os_error());
+                }
+                let job = Job(handle);
+                let mut limits: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+                limits.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+                if SetInformationJobObject(
+                    handle,
+                    JobObjectExtendedLimitInformation,
+                    &limits as *const _ as *const _,
+                    mem::size_of_val(&limits) as u32,
+                ) == 0
+                    || AssignProcessToJobObject(handle, child.as_raw_handle() as HANDLE) == 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(job)
+            }
+        }
+
+        pub fn terminate(&self) {
+            unsafe { TerminateJobObject(self.0, 1) };
+        }
+    }
+
+    impl Drop for Job {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::{fs, path::PathBuf};
+
+use git2::{Repository, Status, StatusOptions};
+
+use crate::error::{Error, Result};
+
+/// Removes untracked files and directories from the working tree, like
+/// `git clean -fd` (or `git clean -fdx` with `ignored`). Returns the removed
+/// paths relative to the working tree.
+pub(crate) fn clean(repo: &Repository, ignored: bool) -> Result<Vec<PathBuf>> {
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir.canonicalize()?,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut options = StatusOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(false)
+        .include_ignored(ignored)
+        .recurse_ignored_dirs(false);
+
+    let mut removed = Vec::new();
+    for entry in repo.statuses(Some(&mut options))?.iter() {
+        if !entry.status().intersects(Status::WT_NEW | Status::IGNORED) {
+            continue;
+        }
+        let relative = match entry.path() {
+            Some(path) => PathBuf::from(path.trim_end_matches('/')),
+            None => continue,
+        };
+        let path = workdir.join(&relative);
+
+        // Only the parent is resolved, so a symlink pointing outside of the
+        // repo is removed itself instead of its target.
+        let parent = match path.parent() {
+            Some(parent) => parent.canonicalize()?,
+            None => continue,
+        };
+        if !parent.starts_with(&workdir) || relative.starts_with(".git") {
+            return Err(Error::OutsideRepo(path));
+        }
+
+        let metadata = fs::symlink_metadata(&path)?;
+        if metadata.is_dir() {
+            // Like `git clean -fd`, leave nested repositories alone.
+            if path.join(".git").exists() {
+                continue;
+            }
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+        removed.push(relative);
+    }
+    Ok(removed)
+}
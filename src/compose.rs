@@ -0,0 +1,113 @@
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::docker;
+
+/// `--compose`: the services of a compose file run with `docker compose
+/// up -d` in place of the command.
+#[derive(Debug, Clone)]
+pub(crate) struct Compose {
+    /// The compose file relative to the directory of the command, docker
+    /// looks for `compose.yaml` and the like without it.
+    pub file: Option<PathBuf>,
+    /// The `--compose-profile`s.
+    pub profiles: Vec<String>,
+    /// The `--compose-health-service` which has to become healthy.
+    pub health_service: Option<String>,
+    /// Named after the checkout, so the services of every release are the
+    /// same ones.
+    pub project: String,
+}
+
+/// How `docker compose ps` sees the health service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Health {
+    Healthy,
+    Starting,
+    /// Unhealthy or not running, with its state.
+    Failed(String),
+}
+
+impl Compose {
+    pub fn new(
+        file: Option<PathBuf>,
+        profiles: Vec<String>,
+        health_service: Option<String>,
+        checkout: &str,
+    ) -> Self {
+        Self {
+            file,
+            profiles,
+            health_service,
+            // Compose doesn't take the dots docker takes.
+            project: docker::name(checkout).replace('.', "-"),
+        }
+    }
+
+    /// `docker compose` with the file, profiles and project and then `args`.
+    pub fn command(&self, args: &[&str]) -> (String, Vec<String>) {
+        let mut command = vec!["compose".to_owned()];
+        if let Some(file) = &self.file {
+            command.push("-f".to_owned());
+            command.push(file.display().to_string());
+        }
+        for profile in &self.profiles {
+            command.push("--profile".to_owned());
+            command.push(profile.clone());
+        }
+        command.push("-p".to_owned());
+        command.push(self.project.clone());
+        command.extend(args.iter().map(|arg| (*arg).to_owned()));
+        ("docker".to_owned(), command)
+    }
+
+    /// What is run to build a deploy, in order.
+    pub fn build(&self) -> [(&'static str, (String, Vec<String>)); 2] {
+        [
+            ("pull", self.command(&["pull"])),
+            ("build", self.command(&["build"])),
+        ]
+    }
+
+    /// Starts the services of the checkout, replacing those whose
+    /// configuration or image changed.
+    pub fn up(&self) -> (String, Vec<String>) {
+        self.command(&["up", "-d", "--remove-orphans"])
+    }
+
+    /// Lists the containers of `service` for `health`.
+    pub fn ps(&self, service: &str) -> (String, Vec<String>) {
+        self.command(&["ps", "--all", "--format", "json", service])
+    }
+}
+
+/// The health of `service` according to the `output` of `ps`.
+pub(crate) fn health(service: &str, output: &str) -> Result<Health, String> {
+    let containers = containers(output);
+    let container = containers
+        .iter()
+        .find(|container| container["Service"].as_str() == Some(service))
+        .ok_or_else(|| format!("no container of the service {}", service))?;
+    let state = container["State"].as_str().unwrap_or_default();
+    let health = container["Health"].as_str().unwrap_or_default();
+    Ok(match (state, health) {
+        // Without a healthcheck running is as healthy as it gets.
+        ("running", "healthy" | "") => Health::Healthy,
+        ("running", "starting") | ("created", _) | ("restarting", _) => Health::Starting,
+        ("running", health) => Health::Failed(health.to_owned()),
+        (state, _) => Health::Failed(state.to_owned()),
+    })
+}
+
+/// The containers `docker compose ps --format json` lists, older versions
+/// print an array and newer ones an object per line.
+fn containers(output: &str) -> Vec<Value> {
+    match serde_json::from_str(output) {
+        Ok(Value::Array(containers)) => containers,
+        _ => output
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect(),
+    }
+}
@@ -0,0 +1,138 @@
+use std::{fs, path::Path};
+
+use clap::ArgMatches;
+use toml::Value;
+
+use crate::{
+    error::{Error, Result},
+    paths,
+};
+
+/// Command line arguments merged with the optional config file.
+///
+/// Config keys are named after the long command line options, e.g.
+/// `reset-hard = true` or `branch = "main"`. Arguments given on the command
+/// line win over the config file, which in turn wins over clap defaults.
+pub(crate) struct Options<'a> {
+    matches: ArgMatches<'a>,
+    config: toml::value::Table,
+}
+
+impl<'a> Options<'a> {
+    pub fn new(matches: ArgMatches<'a>) -> Result<Self> {
+        let config = match matches.value_of("config") {
+            Some(path) => Options::load(&paths::resolve(path))?,
+            None => toml::value::Table::new(),
+        };
+        Ok(Self { matches, config })
+    }
+
+    fn load(path: &Path) -> Result<toml::value::Table> {
+        let content = fs::read_to_string(path)?;
+        match content.parse::<Value>() {
+            Ok(Value::Table(table)) => Ok(table),
+            Ok(_) => Err(Error::InvalidConfig(format!(
+                "{}: expected a table",
+                path.display()
+            ))),
+            Err(err) => Err(Error::InvalidConfig(format!("{}: {}", path.display(), err))),
+        }
+    }
+
+    /// The arguments of the subcommand `name`, if it was given.
+    pub fn subcommand(&self, name: &str) -> Option<&ArgMatches<'a>> {
+        self.matches.subcommand_matches(name)
+    }
+
+    fn explicit(&self, name: &str) -> bool {
+        self.matches.occurrences_of(name) > 0
+    }
+
+    pub fn value_of(&self, name: &str) -> Option<String> {
+        if self.explicit(name) {
+            return self.matches.value_of(name).map(|v| v.to_owned());
+        }
+        match self.config.get(name) {
+            Some(Value::String(s)) => Some(s.clone()),
+            Some(Value::Array(_)) | Some(Value::Table(_)) | None => {
+                self.matches.value_of(name).map(|v| v.to_owned())
+            }
+            Some(other) => Some(other.to_string()),
+        }
+    }
+
+    /// All values of a repeatable option. In the config file this is an
+    /// array of strings or a single string.
+    pub fn values_of(&self, name: &str) -> Vec<String> {
+        let from_matches = || {
+            self.matches
+                .values_of(name)
+                .map(|values| values.map(|v| v.to_owned()).collect())
+                .unwrap_or_default()
+        };
+        if self.explicit(name) {
+            return from_matches();
+        }
+        match self.config.get(name) {
+            Some(Value::Array(values)) => values
+                .iter()
+                .map(|value| match value {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .collect(),
+            Some(Value::String(s)) => vec![s.clone()],
+            _ => from_matches(),
+        }
+    }
+
+    /// How often a flag was given, e.g. `-vv`. In the config file this is an
+    /// integer or a boolean.
+    pub fn occurrences_of(&self, name: &str) -> u64 {
+        if self.explicit(name) {
+            return self.matches.occurrences_of(name);
+        }
+        match self.config.get(name) {
+            Some(Value::Integer(n)) => (*n).max(0) as u64,
+            Some(Value::Boolean(b)) => *b as u64,
+            _ => 0,
+        }
+    }
+
+    /// The tables of an array of tables like `[[name]]`, which only the
+    /// config file has.
+    pub fn tables(&self, name: &str) -> Result<Vec<toml::value::Table>> {
+        match self.config.get(name) {
+            Some(Value::Array(values)) => values
+                .iter()
+                .map(|value| match value {
+                    Value::Table(table) => Ok(table.clone()),
+                    _ => Err(Error::InvalidConfig(format!("[[{}]] must be tables", name))),
+                })
+                .collect(),
+            Some(_) => Err(Error::InvalidConfig(format!("[[{}]] must be tables", name))),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The table `[name]`, which only the config file has.
+    pub fn table(&self, name: &str) -> Result<Option<toml::value::Table>> {
+        match self.config.get(name) {
+            Some(Value::Table(table)) => Ok(Some(table.clone())),
+            // Given as a list of values instead.
+            Some(Value::Array(_)) | None => Ok(None),
+            Some(_) => Err(Error::InvalidConfig(format!("[{}] must be a table", name))),
+        }
+    }
+
+    pub fn is_present(&self, name: &str) -> bool {
+        if self.explicit(name) {
+            return true;
+        }
+        match self.config.get(name) {
+            Some(Value::Boolean(b)) => *b,
+            Some(_) => true,
+            None => self.matches.is_present(name),
+        }
+    }
+}
@@ -0,0 +1,1024 @@
+use std::{
+    cell::RefCell,
+    env, fs,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use git2::{
+    CertificateCheckStatus, Cred, CredentialType, ErrorClass, ErrorCode, FetchOptions,
+    ProxyOptions, RemoteCallbacks,
+};
+use glob::Pattern;
+use toml::{value::Table, Value};
+use zeroize::Zeroizing;
+
+use crate::{
+    credential::{self, Filled},
+    error::{Error, Result},
+    github_app::{self, GithubApp},
+    known_hosts::{self, Known},
+    log, paths,
+};
+
+/// How to reach and authenticate against the remote. It is owned by value,
+/// so git operations can run on a worker thread.
+#[derive(Debug, Clone)]
+pub(crate) struct Connect {
+    pub username: String,
+    /// The ssh keys to offer, in order.
+    pub keys: Vec<SshKey>,
+    /// The default keys looked for because no key was given, named when
+    /// none of them exists.
+    pub probed: Vec<PathBuf>,
+    pub passphrase: Option<String>,
+    /// Token or password for HTTPS remotes.
+    pub password: Option<String>,
+    /// Installation tokens of a GitHub App for HTTPS remotes, shared by the
+    /// clones on the workers so a token is reused until it expires.
+    pub github_app: Option<Arc<GithubApp>>,
+    /// Credentials of some remotes, which win over the ones above.
+    pub hosts: Vec<HostCredentials>,
+    pub proxy: Option<Proxy>,
+    /// The remote is a local path or `file://` URL, which needs no
+    /// credentials.
+    pub local: bool,
+    /// Ask `git credential` when there is no token or it was rejected.
+    pub credential_helper: bool,
+    /// Only the ssh agent's identities are offered, never key files.
+    pub agent_only: bool,
+    /// Where the host keys of ssh remotes are checked.
+    pub known_hosts: PathBuf,
+    /// Fingerprints of `--host-fingerprint`, one of which the host key must
+    /// have instead of being in `known_hosts`.
+    pub host_fingerprints: Vec<String>,
+    /// Refuse hosts which aren't in `known_hosts` instead of adding them.
+    pub strict_host_keys: bool,
+    /// The port of the remote, which `known_hosts` names hosts with.
+    pub port: Option<u16>,
+    /// What the credential helpers handed out in the current operation.
+    pub filled: RefCell<Option<Filled>>,
+    /// The key offered last in the current operation.
+    pub offered: RefCell<Option<PathBuf>>,
+    /// The credentials handed out last, described for the user.
+    pub used: RefCell<Option<String>>,
+    /// The key a remote accepted last, offered first from then on. It is
+    /// shared by the clones on the workers.
+    pub accepted: Arc<Mutex<Option<PathBuf>>>,
+    /// The password typed for an HTTPS remote without other credentials,
+    /// asked once and shared by the clones on the workers. It is wiped when
+    /// the last one is dropped.
+    pub prompted: Arc<Mutex<Option<Zeroizing<String>>>>,
+}
+
+/// A key pair for ssh remotes. Without the public key libgit2 derives it
+/// from the private one.
+#[derive(Debug, Clone)]
+pub(crate) struct SshKey {
+    pub private_key_path: PathBuf,
+    pub public_key_path: Option<PathBuf>,
+}
+
+/// The keys ssh generates by default, newest type first, which are looked
+/// for without `--private-key`.
+const DEFAULT_KEYS: [&str; 3] = ["~/.ssh/id_ed25519", "~/.ssh/id_ecdsa", "~/.ssh/id_rsa"];
+
+/// How often the credentials callback may be asked in one operation, beyond
+/// once per ssh key.
+const MAX_ATTEMPTS: usize = 3;
+
+fn host_key_error(message: String) -> git2::Error {
+    git2::Error::new(ErrorCode::Certificate, ErrorClass::Ssh, message)
+}
+
+fn offer(offered: &mut Vec<&'static str>, kind: &'static str) {
+    if !offered.contains(&kind) {
+        offered.push(kind);
+    }
+}
+
+/// Credentials for the remotes on `host` or below `url`, from a
+/// `[[credentials]]` table of the config file. What isn't set falls back to
+/// the global options.
+#[derive(Debug, Clone)]
+pub(crate) struct HostCredentials {
+    pub remotes: Remotes,
+    pub username: Option<String>,
+    pub keys: Option<Vec<SshKey>>,
+    pub passphrase: Option<String>,
+    pub password: Option<String>,
+    /// Why the keys or secrets of the table can't be used. Only fetches from
+    /// its remotes fail with it, the others go on.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Remotes {
+    /// The host of the url, `*` and `?` match like in ssh `Host` lines.
+    Host(Pattern),
+    /// Urls starting with this.
+    Url(String),
+}
+
+impl HostCredentials {
+    /// Parses a `[[credentials]]` table, which names the remotes with `host`
+    /// or `url` and otherwise takes the long options of the credentials. A
+    /// malformed table is an error, missing key files or secrets are kept
+    /// for the fetches from its remotes.
+    pub fn from_table(table: &Table) -> Result<Self> {
+        let string = |key: &str| match table.get(key) {
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(_) => Err(format!("{} must be a string", key)),
+            None => Ok(None),
+        };
+        let strings = |key: &str| match table.get(key) {
+            Some(Value::String(value)) => Ok(vec![value.clone()]),
+            Some(Value::Array(values)) => values
+                .iter()
+                .map(|value| match value {
+                    Value::String(value) => Ok(value.clone()),
+                    _ => Err(format!("{} must be strings", key)),
+                })
+                .collect(),
+            Some(_) => Err(format!("{} must be a string or an array of strings", key)),
+            None => Ok(Vec::new()),
+        };
+        let remotes = match (string("host"), string("url")) {
+            (Ok(Some(host)), Ok(None)) => Remotes::Host(Pattern::new(&host).map_err(|err| {
+                Error::InvalidConfig(format!("credentials for {}: {}", host, err))
+            })?),
+            (Ok(None), Ok(Some(url))) => Remotes::Url(url),
+            _ => {
+                return Err(Error::InvalidConfig(
+                    "credentials need either host or url".to_owned(),
+                ))
+            }
+        };
+        let invalid = |message: String| {
+            Error::InvalidConfig(format!("credentials for {}: {}", remotes, message))
+        };
+        if let Some(key) = table.keys().find(|key| {
+            !matches!(
+                key.as_str(),
+                "host"
+                    | "url"
+                    | "username"
+                    | "private-key"
+                    | "public-key"
+                    | "passphrase-env"
+                    | "passphrase-file"
+                    | "token"
+                    | "token-env"
+                    | "token-file"
+            )
+        }) {
+            return Err(invalid(format!("unknown key {}", key)));
+        }
+        let private_keys = strings("private-key").map_err(invalid)?;
+        let keys = match private_keys.is_empty() {
+            true => None,
+            false => Some(
+                ssh_keys(&private_keys, &strings("public-key").map_err(invalid)?)
+                    .map_err(|err| invalid(err.to_string()))?,
+            ),
+        };
+        let mut error = keys
+            .iter()
+            .flatten()
+            .find(|key| !key.private_key_path.exists())
+            .map(|key| format!("no ssh key at {}", key.private_key_path.display()));
+        let passphrase = read_passphrase(
+            string("passphrase-env").map_err(invalid)?,
+            string("passphrase-file").map_err(invalid)?,
+        )
+        .unwrap_or_else(|err| {
+            error.get_or_insert(err.to_string());
+            None
+        });
+        let password = match string("token").map_err(invalid)? {
+            Some(token) => Some(token),
+            None => read_secret(
+                ("token-env", string("token-env").map_err(invalid)?),
+                ("token-file", string("token-file").map_err(invalid)?),
+            )
+            .unwrap_or_else(|err| {
+                error.get_or_insert(err.to_string());
+                None
+            })
+            .map(|token| token.trim_end_matches(['\r', '\n']).to_owned()),
+        };
+        for secret in passphrase.iter().chain(password.iter()) {
+            log::add_secret(secret);
+        }
+        let error = error.map(|message| format!("credentials for {}: {}", remotes, message));
+        if let Some(error) = &error {
+            warn!("{}, fetches from these remotes will fail", error);
+        }
+        Ok(Self {
+            username: string("username").map_err(invalid)?,
+            keys,
+            passphrase,
+            password,
+            error,
+            remotes,
+        })
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        match &self.remotes {
+            Remotes::Host(pattern) => host(url).is_some_and(|host| pattern.matches(host)),
+            Remotes::Url(prefix) => url.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+impl std::fmt::Display for Remotes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Remotes::Host(pattern) => write!(f, "host {}", pattern),
+            Remotes::Url(url) => write!(f, "url {}", url),
+        }
+    }
+}
+
+/// The credentials for one url, the matching `[[credentials]]` over the
+/// global ones.
+struct Credentials<'a> {
+    /// The matching table can't be used.
+    error: Option<&'a str>,
+    username: &'a str,
+    keys: &'a [SshKey],
+    passphrase: Option<&'a str>,
+    password: Option<&'a str>,
+}
+
+/// The proxy HTTP(S) remotes are reached through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Proxy {
+    /// Whatever git's `http.proxy` config and the environment say.
+    Auto,
+    Url(String),
+}
+
+impl Proxy {
+    /// Parses `--proxy`, falling back to `HTTPS_PROXY` and `ALL_PROXY`.
+    pub fn from_option(value: Option<String>) -> Result<Option<Self>> {
+        let (value, source) = match value {
+            Some(value) => (value, "--proxy"),
+            None => match ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+                .iter()
+                .find_map(|name| env::var(name).ok().map(|value| (value, *name)))
+            {
+                Some(found) => found,
+                None => return Ok(None),
+            },
+        };
+        if value == "auto" {
+            return Ok(Some(Proxy::Auto));
+        }
+        // libgit2 only talks to HTTP proxies, so SOCKS would fail on every
+        // fetch.
+        if !(value.starts_with("http://") || value.starts_with("https://")) {
+            return Err(Error::InvalidArgument(
+                "proxy",
+                format!(
+                    "{} (from {}), only http:// and https:// proxies are supported",
+                    value, source
+                ),
+            ));
+        }
+        verbose!("using proxy {} from {}", value, source);
+        Ok(Some(Proxy::Url(value)))
+    }
+
+    /// The host of the proxy, to tell its errors from the remote's.
+    pub fn host(&self) -> Option<&str> {
+        match self {
+            Proxy::Auto => None,
+            Proxy::Url(url) => {
+                let rest = url.split_once("://").map_or(url.as_str(), |(_, rest)| rest);
+                let authority = rest.split('/').next().unwrap_or(rest);
+                let host = authority.rsplit('@').next().unwrap_or(authority);
+                // libgit2 names the host without the port.
+                Some(match host.rsplit_once(':') {
+                    Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+                    _ => host,
+                })
+            }
+        }
+    }
+}
+
+impl Connect {
+    pub fn fetch_options(&self) -> FetchOptions<'_> {
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(self.remote_callbacks());
+        if let Some(proxy) = self.proxy_options() {
+            fetch_options.proxy_options(proxy);
+        }
+        fetch_options
+    }
+
+    pub fn proxy_options(&self) -> Option<ProxyOptions<'_>> {
+        let mut options = ProxyOptions::new();
+        match &self.proxy {
+            Some(Proxy::Auto) => options.auto(),
+            Some(Proxy::Url(url)) => options.url(url),
+            None => return None,
+        };
+        Some(options)
+    }
+
+    /// Points out errors of the proxy, which otherwise look like the remote
+    /// refused the connection.
+    pub fn blame_proxy(&self, err: git2::Error) -> Error {
+        let host = self.proxy.as_ref().and_then(|proxy| proxy.host());
+        let message = err.message().to_lowercase();
+        match (&self.proxy, host) {
+            (Some(Proxy::Url(url)), Some(host))
+                if message.contains("proxy") || message.contains(&host.to_lowercase()) =>
+            {
+                Error::Proxy(redact(url), err)
+            }
+            _ => Error::GitError(err),
+        }
+    }
+
+    /// How to reach the remote at `url`.
+    pub fn with_url(&self, url: &str) -> Connect {
+        let mut connect = self.clone();
+        connect.local = is_local(url);
+        connect.port = port(url);
+        connect
+    }
+
+    pub fn remote_callbacks(&self) -> RemoteCallbacks<'_> {
+        let mut callbacks = RemoteCallbacks::new();
+        if self.local {
+            return callbacks;
+        }
+        callbacks.certificate_check(move |cert, host| {
+            let hostkey = match cert.as_hostkey() {
+                Some(hostkey) => hostkey,
+                // Certificates of HTTPS remotes are checked by libgit2.
+                None => return Ok(CertificateCheckStatus::CertificatePassthrough),
+            };
+            match (hostkey.hostkey_type(), hostkey.hostkey()) {
+                (Some(key_type), Some(key)) => self.check_host_key(host, key_type.name(), key),
+                _ => Err(host_key_error(format!(
+                    "{} sent no host key to check",
+                    host
+                ))),
+            }
+        });
+        let mut attempts = 0;
+        let agent = self.agent_only || env::var_os("SSH_AUTH_SOCK").is_some();
+        let mut pending = None;
+        let mut tried = agent
+            .then(|| "the ssh agent".to_owned())
+            .into_iter()
+            .collect::<Vec<_>>();
+        let mut skipped = Vec::new();
+        // What was handed out, for the error once the remote refused it all.
+        let mut offered = Vec::new();
+        callbacks.credentials(move |url, username_from_url, allowed_types| {
+            let credentials = self.credentials_for(url);
+            if let Some(error) = credentials.error {
+                return Err(git2::Error::new(
+                    ErrorCode::Auth,
+                    ErrorClass::Callback,
+                    error,
+                ));
+            }
+            let username = username(url, username_from_url, credentials.username)?;
+            // libgit2 asks until the callback fails, which must happen even
+            // for a remote refusing everything.
+            attempts += 1;
+            if attempts > MAX_ATTEMPTS + credentials.keys.len() {
+                return Err(git2::Error::new(
+                    ErrorCode::Auth,
+                    ErrorClass::Callback,
+                    format!(
+                        "giving up after {} authentication attempts as {} with {}",
+                        attempts - 1,
+                        username,
+                        offered.join(", ")
+                    ),
+                ));
+            }
+            if !allowed_types.contains(CredentialType::SSH_KEY)
+                && allowed_types.contains(CredentialType::USER_PASS_PLAINTEXT)
+            {
+                offer(&mut offered, "password");
+                if let Some(app) = &self.github_app {
+                    if attempts > 1 {
+                        return Err(git2::Error::new(
+                            ErrorCode::Auth,
+                            ErrorClass::Http,
+                            "the remote rejected the GitHub App installation token",
+                        ));
+                    }
+                    let token = app.token().map_err(|err| {
+                        let code = match err {
+                            Error::GithubApp { rejected: true, .. } => ErrorCode::Auth,
+                            _ => ErrorCode::GenericError,
+                        };
+                        git2::Error::new(code, ErrorClass::Http, err.to_string())
+                    })?;
+                    *self.used.borrow_mut() =
+                        Some("with an installation token of the GitHub App".to_owned());
+                    return Cred::userpass_plaintext(github_app::USERNAME, &token);
+                }
+                return match (credentials.password, attempts) {
+                    (Some(password), 1) => {
+                        *self.used.borrow_mut() =
+                            Some(format!("with the token or password of {}", username));
+                        Cred::userpass_plaintext(username, password)
+                    }
+                    _ if self.credential_helper => {
+                        *self.used.borrow_mut() =
+                            Some("with the git credential helpers".to_owned());
+                        self.helper_credentials(url, username_from_url)
+                    }
+                    (Some(_), _) => Err(git2::Error::new(
+                        ErrorCode::Auth,
+                        ErrorClass::Http,
+                        format!("the remote rejected the token or password for {}", username),
+                    )),
+                    (None, _) => self.prompted_password(url, username, attempts),
+                };
+            }
+            // Without a user in the url libgit2 asks for it before the key.
+            if allowed_types.contains(CredentialType::USERNAME) {
+                return Cred::username(username);
+            }
+            let pending = pending.get_or_insert_with(|| self.key_order(credentials.keys, agent));
+            loop {
+                match pending.next() {
+                    Some(None) => {
+                        offer(&mut offered, "the ssh agent");
+                        *self.offered.borrow_mut() = None;
+                        *self.used.borrow_mut() =
+                            Some(format!("as {} with the ssh agent", username));
+                        return Cred::ssh_key_from_agent(username);
+                    }
+                    Some(Some(key)) => {
+                        if credentials.passphrase.is_none() && is_encrypted(&key.private_key_path) {
+                            skipped.push(key.private_key_path.display().to_string());
+                            continue;
+                        }
+                        tried.push(key.private_key_path.display().to_string());
+                        offer(&mut offered, "ssh keys");
+                        *self.offered.borrow_mut() = Some(key.private_key_path.clone());
+                        *self.used.borrow_mut() = Some(format!(
+                            "as {} with the ssh key {}",
+                            username,
+                            key.private_key_path.display()
+                        ));
+                        return Cred::ssh_key(
+                            username,
+                            key.public_key_path.as_deref(),
+                            &key.private_key_path,
+                            credentials.passphrase,
+                        );
+                    }
+                    None if self.agent_only => {
+                        return Err(git2::Error::new(
+                            ErrorCode::Auth,
+                            ErrorClass::Ssh,
+                            format!("agent had no usable identity for user {}", username),
+                        ))
+                    }
+                    None => return Err(self.keys_rejected(&tried, &skipped)),
+                }
+            }
+        });
+        callbacks
+    }
+
+    /// Checks the host key of `host` against `--host-fingerprint` or the
+    /// known hosts, adding unknown hosts unless `--strict-host-keys`.
+    fn check_host_key(
+        &self,
+        host: &str,
+        key_type: &str,
+        key: &[u8],
+    ) -> std::result::Result<CertificateCheckStatus, git2::Error> {
+        let name = match self.port {
+            Some(port) if port != 22 => format!("[{}]:{}", host, port),
+            _ => host.to_owned(),
+        };
+        let fingerprint = known_hosts::fingerprint(key);
+        if !self.host_fingerprints.is_empty() {
+            return match self.host_fingerprints.contains(&fingerprint) {
+                true => Ok(CertificateCheckStatus::CertificateOk),
+                false => Err(host_key_error(format!(
+                    "host key of {} is {} but --host-fingerprint expects {}",
+                    name,
+                    fingerprint,
+                    self.host_fingerprints.join(" or ")
+                ))),
+            };
+        }
+        let known = known_hosts::check(&self.known_hosts, &name, key_type, key).map_err(|err| {
+            host_key_error(format!("reading {}: {}", self.known_hosts.display(), err))
+        })?;
+        match known {
+            Known::Match => Ok(CertificateCheckStatus::CertificateOk),
+            Known::Mismatch(known) => Err(host_key_error(format!(
+                "host key of {} changed: it is {} but {} has {}, someone may be intercepting the connection",
+                name,
+                fingerprint,
+                self.known_hosts.display(),
+                known.join(", ")
+            ))),
+            Known::Revoked => Err(host_key_error(format!(
+                "host key {} of {} is revoked in {}",
+                fingerprint,
+                name,
+                self.known_hosts.display()
+            ))),
+            Known::Unknown if self.strict_host_keys => Err(host_key_error(format!(
+                "{} is not in {} and --strict-host-keys refuses unknown hosts, its key is {} {}",
+                name,
+                self.known_hosts.display(),
+                key_type,
+                fingerprint
+            ))),
+            Known::Unknown => {
+                known_hosts::add(&self.known_hosts, &name, key_type, key).map_err(|err| {
+                    host_key_error(format!("adding {} to {}: {}", name, self.known_hosts.display(), err))
+                })?;
+                warn!(
+                    "added {} with the {} key {} to {}",
+                    name,
+                    key_type,
+                    fingerprint,
+                    self.known_hosts.display()
+                );
+                Ok(CertificateCheckStatus::CertificateOk)
+            }
+        }
+    }
+
+    /// The credentials for `url`, from the first `[[credentials]]` matching
+    /// it.
+    fn credentials_for(&self, url: &str) -> Credentials<'_> {
+        let host = self.hosts.iter().find(|host| host.matches(url));
+        if let Some(host) = host {
+            debug!("using the credentials for {} with {}", host.remotes, url);
+        }
+        Credentials {
+            error: host.and_then(|host| host.error.as_deref()),
+            username: host
+                .and_then(|host| host.username.as_deref())
+                .unwrap_or(&self.username),
+            keys: host
+                .and_then(|host| host.keys.as_deref())
+                .unwrap_or(&self.keys),
+            passphrase: host
+                .and_then(|host| host.passphrase.as_deref())
+                .or(self.passphrase.as_deref()),
+            password: host
+                .and_then(|host| host.password.as_deref())
+                .or(self.password.as_deref()),
+        }
+    }
+
+    /// Each ssh key is offered once: the agent's first, then the one
+    /// accepted last time, then the others. `None` is the agent.
+    fn key_order<'a>(
+        &self,
+        keys: &'a [SshKey],
+        agent: bool,
+    ) -> std::vec::IntoIter<Option<&'a SshKey>> {
+        let accepted = self
+            .accepted
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone();
+        let mut order = match self.agent_only {
+            true => Vec::new(),
+            false => keys.iter().collect::<Vec<_>>(),
+        };
+        if let Some(index) = order
+            .iter()
+            .position(|key| Some(&key.private_key_path) == accepted.as_ref())
+        {
+            let key = order.remove(index);
+            order.insert(0, key);
+        }
+        agent
+            .then_some(None)
+            .into_iter()
+            .chain(order.into_iter().map(Some))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// The error once every ssh key was offered, naming them.
+    fn keys_rejected(&self, tried: &[String], skipped: &[String]) -> git2::Error {
+        let mut message = if tried.is_empty() && skipped.is_empty() && !self.probed.is_empty() {
+            format!(
+                "no ssh key to offer, none of {} exists, use --private-key or an ssh agent",
+                self.probed
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        } else if tried.is_empty() {
+            "no ssh key to offer, use --private-key or an ssh agent".to_owned()
+        } else {
+            format!(
+                "the remote accepted none of the ssh keys: {}",
+                tried.join(", ")
+            )
+        };
+        if !skipped.is_empty() {
+            message.push_str(&format!(
+                "; {} encrypted, give the passphrase with --passphrase-file, --passphrase-env, --passphrase-keyring or --use-passphrase",
+                skipped.join(", ")
+            ));
+        }
+        git2::Error::new(ErrorCode::Auth, ErrorClass::Ssh, message)
+    }
+
+    /// Asks for the password of an HTTPS remote on the terminal the first
+    /// time and reuses it, unless the remote rejected it.
+    fn prompted_password(
+        &self,
+        url: &str,
+        username: &str,
+        attempts: usize,
+    ) -> std::result::Result<Cred, git2::Error> {
+        let error = |message: String| git2::Error::new(ErrorCode::Auth, ErrorClass::Http, message);
+        let mut prompted = self.prompted.lock().unwrap_or_else(|err| err.into_inner());
+        if attempts > 1 && prompted.take().is_some() {
+            return Err(error(format!(
+                "the remote rejected the password typed for {}",
+                username
+            )));
+        }
+        if prompted.is_none() {
+            let hint = "use --token, --password-stdin or --credential-helper";
+            if !url.starts_with("https://") {
+                return Err(error(format!("the remote needs a password, {}", hint)));
+            }
+            if !std::io::stdin().is_terminal() {
+                return Err(error(format!(
+                    "no credentials configured for https remote {}, {}",
+                    url, hint
+                )));
+            }
+            let password = Zeroizing::new(
+                rpassword::prompt_password_stdout(&format!(
+                    "Password for {}@{}: ",
+                    username,
+                    host(url).unwrap_or(url)
+                ))
+                .map_err(|err| error(format!("reading the password failed: {}", err)))?,
+            );
+            log::add_secret(&password);
+            *prompted = Some(password);
+        }
+        *self.used.borrow_mut() = Some(format!("with the password typed for {}", username));
+        Cred::userpass_plaintext(
+            username,
+            prompted.as_deref().map_or("", |password| password),
+        )
+    }
+
+    /// Credentials from `git credential fill`. Being asked again means the
+    /// remote rejected them, which the helpers are told about.
+    fn helper_credentials(
+        &self,
+        url: &str,
+        username: Option<&str>,
+    ) -> std::result::Result<Cred, git2::Error> {
+        let auth_error =
+            |message: String| git2::Error::new(ErrorCode::Auth, ErrorClass::Http, message);
+        if let Some(rejected) = self.filled.borrow_mut().take() {
+            credential::reject(&rejected);
+            return Err(auth_error(format!(
+                "the remote rejected the credentials of the git credential helper for {}",
+                rejected.url
+            )));
+        }
+        let filled = credential::fill(url, username).map_err(auth_error)?;
+        let cred = Cred::userpass_plaintext(&filled.username, &filled.password);
+        *self.filled.borrow_mut() = Some(filled);
+        cred
+    }
+
+    /// Once an operation succeeded, lets the credential helpers keep what
+    /// they handed out and remembers the ssh key which was accepted.
+    pub fn succeeded(&self) {
+        if let Some(filled) = self.filled.borrow_mut().take() {
+            credential::approve(&filled);
+        }
+        if let Some(key) = self.offered.borrow_mut().take() {
+            *self.accepted.lock().unwrap_or_else(|err| err.into_inner()) = Some(key);
+        }
+    }
+}
+
+/// Makes sure the ssh agent of `--agent-only` can be reached, so a missing
+/// forward shows at startup rather than at the first fetch.
+pub(crate) fn check_agent() -> Result<()> {
+    let socket = env::var_os("SSH_AUTH_SOCK").ok_or_else(|| {
+        Error::InvalidArgument("agent-only", "SSH_AUTH_SOCK is not set".to_owned())
+    })?;
+    #[cfg(unix)]
+    std::os::unix::net::UnixStream::connect(&socket).map_err(|err| {
+        Error::InvalidArgument(
+            "agent-only",
+            format!(
+                "the ssh agent at {} can't be reached: {}",
+                Path::new(&socket).display(),
+                err
+            ),
+        )
+    })?;
+    Ok(())
+}
+
+/// Reads a secret from the environment variable `var` or the file `file`.
+/// The file wins if both are given. The options are named in errors, the
+/// secret never is.
+pub(crate) fn read_secret(
+    (env_option, var): (&'static str, Option<String>),
+    (file_option, file): (&'static str, Option<String>),
+) -> Result<Option<String>> {
+    Ok(Some(match (var, file) {
+        (var, Some(file)) => {
+            if let Some(var) = var {
+                warn!(
+                    "both --{} {} and --{} {} given, using the file",
+                    env_option, var, file_option, file
+                );
+            }
+            fs::read_to_string(paths::resolve(&file))
+                .map_err(|err| Error::InvalidArgument(file_option, format!("{}: {}", file, err)))?
+        }
+        (Some(var), None) => env::var(&var)
+            .map_err(|_| Error::InvalidArgument(env_option, format!("{} is not set", var)))?,
+        (None, None) => return Ok(None),
+    }))
+}
+
+/// Reads the passphrase of the SSH key from `--passphrase-file` or
+/// `--passphrase-env`. The file must not be readable by everyone, a single
+/// trailing newline is dropped.
+pub(crate) fn read_passphrase(var: Option<String>, file: Option<String>) -> Result<Option<String>> {
+    #[cfg(unix)]
+    if let Some(file) = &file {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = fs::metadata(paths::resolve(file))
+            .map_err(|err| Error::InvalidArgument("passphrase-file", format!("{}: {}", file, err)))?
+            .permissions()
+            .mode();
+        if mode & 0o004 != 0 {
+            return Err(Error::InvalidArgument(
+                "passphrase-file",
+                format!("{} is readable by everyone, chmod o-r it", file),
+            ));
+        }
+    }
+    let passphrase = read_secret(("passphrase-env", var), ("passphrase-file", file))?;
+    Ok(passphrase.map(|passphrase| {
+        let passphrase = passphrase.strip_suffix('\n').unwrap_or(&passphrase);
+        passphrase
+            .strip_suffix('\r')
+            .unwrap_or(passphrase)
+            .to_owned()
+    }))
+}
+
+/// Checks that `passphrase` opens the encrypted OpenSSH keys among `keys`,
+/// so a typo shows at startup rather than on every fetch. Other formats are
+/// left to libssh2.
+pub(crate) fn check_passphrase(keys: &[SshKey], passphrase: &str) -> Result<()> {
+    for key in keys {
+        let content = match fs::read_to_string(&key.private_key_path) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        match ssh_key::PrivateKey::from_openssh(&content) {
+            Ok(private) if private.is_encrypted() => {
+                if private.decrypt(passphrase).is_err() {
+                    return Err(Error::WrongPassphrase(key.private_key_path.clone()));
+                }
+            }
+            _ => continue,
+        }
+    }
+    Ok(())
+}
+
+/// Whether the private key at `path` is encrypted, for OpenSSH and PEM
+/// keys. Keys which can't be read count as not encrypted, libgit2 reports
+/// them better.
+fn is_encrypted(path: &Path) -> bool {
+    let key = match fs::read_to_string(path) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    if key.contains("ENCRYPTED") {
+        return true;
+    }
+    // The body of OpenSSH keys starts with the cipher, "none" (base64
+    // `...AAAAABG5vbmU`) for unencrypted ones.
+    let body = key
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect::<String>();
+    body.starts_with("b3BlbnNzaC1rZXktdjEAAAAA")
+        && !body.starts_with("b3BlbnNzaC1rZXktdjEAAAAABG5vbmU")
+}
+
+/// The ssh keys given with `--private-key` and `--public-key`. Public keys
+/// pair up with the private ones in order, the rest are found next to them.
+pub(crate) fn ssh_keys(private_keys: &[String], public_keys: &[String]) -> Result<Vec<SshKey>> {
+    if public_keys.len() > private_keys.len() {
+        return Err(Error::InvalidArgument(
+            "public-key",
+            format!(
+                "{} public keys for {} private keys",
+                public_keys.len(),
+                private_keys.len()
+            ),
+        ));
+    }
+    Ok(private_keys
+        .iter()
+        .enumerate()
+        .map(|(index, private)| {
+            let private_key_path = paths::resolve(private);
+            let public_key_path = match public_keys.get(index) {
+                Some(public) => Some(paths::resolve(public)),
+                None => {
+                    let mut public = private_key_path.clone().into_os_string();
+                    public.push(".pub");
+                    Some(PathBuf::from(public)).filter(|public| public.exists())
+                }
+            };
+            SshKey {
+                private_key_path,
+                public_key_path,
+            }
+        })
+        .collect())
+}
+
+/// The first of the default keys which exists, as ssh picks one, and the
+/// paths looked at.
+pub(crate) fn probe_ssh_keys() -> (Vec<SshKey>, Vec<PathBuf>) {
+    let mut probed = Vec::new();
+    for path in DEFAULT_KEYS {
+        let path = paths::resolve(path);
+        if path.exists() {
+            verbose!("using the ssh key {}", path.display());
+            let mut public = path.clone().into_os_string();
+            public.push(".pub");
+            let public_key_path = Some(PathBuf::from(public)).filter(|public| public.exists());
+            return (
+                vec![SshKey {
+                    private_key_path: path,
+                    public_key_path,
+                }],
+                probed,
+            );
+        }
+        probed.push(path);
+    }
+    (Vec::new(), probed)
+}
+
+/// The host of a url or scp-like `user@host:path`, without user and port.
+fn host(url: &str) -> Option<&str> {
+    let authority = match url.split_once("://") {
+        Some((_, rest)) => rest.split('/').next()?,
+        None => url.split_once(':')?.0,
+    };
+    let host = authority.rsplit('@').next()?;
+    Some(match host.strip_prefix('[') {
+        // IPv6 addresses have colons themselves.
+        Some(host) => host.split(']').next()?,
+        None => host.split(':').next()?,
+    })
+}
+
+/// The user to authenticate as at `url`. `ssh://host/repo.git` has no user,
+/// `--username` fills in.
+fn username<'a>(
+    url: &str,
+    from_url: Option<&'a str>,
+    configured: &'a str,
+) -> std::result::Result<&'a str, git2::Error> {
+    match from_url
+        .filter(|username| !username.is_empty())
+        .unwrap_or(configured)
+    {
+        "" => Err(git2::Error::new(
+            ErrorCode::Auth,
+            ErrorClass::Callback,
+            format!(
+                "no username for {}, put one in the url or use --username",
+                url
+            ),
+        )),
+        username => Ok(username),
+    }
+}
+
+/// The port of an `ssh://` or other url with one.
+fn port(url: &str) -> Option<u16> {
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split('/').next()?;
+    let host = authority.rsplit('@').next()?;
+    let port = match host.strip_prefix('[') {
+        Some(host) => host.split_once("]:")?.1,
+        None => host.split_once(':')?.1,
+    };
+    port.parse().ok()
+}
+
+/// Whether `url` is a local path or `file://` URL rather than one of a
+/// network transport or an scp-like `host:path`.
+pub(crate) fn is_local(url: &str) -> bool {
+    if url.starts_with("file://") {
+        return true;
+    }
+    if url.contains("://") {
+        return false;
+    }
+    match url.split_once(':') {
+        // `C:\repo` is a Windows path, not the host `C`.
+        Some((host, _)) if host.len() == 1 => true,
+        Some((host, _)) => host.contains('/'),
+        None => true,
+    }
+}
+
+/// Makes a local path absolute, so the remote keeps working when
+/// localdeploy is started from another directory. Other URLs are returned
+/// as they are.
+pub(crate) fn absolute_url(url: &str) -> String {
+    if !is_local(url) || url.starts_with("file://") {
+        return url.to_owned();
+    }
+    match fs::canonicalize(url) {
+        // Windows canonicalizes to `\\?\C:\...`, which libgit2 doesn't take.
+        Ok(path) => {
+            let path = path.to_string_lossy();
+            path.strip_prefix(r"\\?\").unwrap_or(&path).to_owned()
+        }
+        Err(_) => url.to_owned(),
+    }
+}
+
+/// Drops the password from a proxy URL before it is shown.
+fn redact(url: &str) -> String {
+    match url.split_once("://") {
+        Some((scheme, rest)) => match rest.split_once('@') {
+            Some((userinfo, host)) if !userinfo.contains('/') => {
+                let user = userinfo.split(':').next().unwrap_or_default();
+                format!("{}://{}@{}", scheme, user, host)
+            }
+            _ => url.to_owned(),
+        },
+        None => url.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::ErrorCode;
+
+    use super::username;
+
+    const URL: &str = "ssh://host/repo.git";
+
+    #[test]
+    fn username_from_url() {
+        assert_eq!(username(URL, Some("deploy"), "git").unwrap(), "deploy");
+    }
+
+    #[test]
+    fn username_falls_back_to_the_option() {
+        assert_eq!(username(URL, None, "git").unwrap(), "git");
+        assert_eq!(username(URL, Some(""), "git").unwrap(), "git");
+    }
+
+    #[test]
+    fn no_username_is_an_auth_error() {
+        for from_url in [None, Some("")] {
+            let err = username(URL, from_url, "").unwrap_err();
+            assert_eq!(err.code(), ErrorCode::Auth);
+            assert!(err.message().contains("--username"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::{Duration, SystemTime},
+};
+
+use crate::log;
+
+/// What a crash report says about a command which exited unexpectedly.
+#[derive(Debug)]
+pub(crate) struct Report<'a> {
+    /// The command or the process of the `--procfile`.
+    pub what: &'a str,
+    pub status: ExitStatus,
+    pub sha: Option<String>,
+    pub branch: &'a str,
+    pub uptime: Duration,
+    /// The command line as a shell would take it.
+    pub command: &'a str,
+    /// Only the names, the values may be secrets.
+    pub env: &'a [String],
+    /// Its last lines, with the stream in front.
+    pub output: Vec<String>,
+}
+
+impl Report<'_> {
+    fn render(&self) -> String {
+        let now = SystemTime::now();
+        let started = now.checked_sub(self.uptime).unwrap_or(now);
+        let mut report = String::new();
+        let _ = writeln!(report, "{} exited unexpectedly", self.what);
+        let _ = writeln!(report, "status: {}", self.status);
+        let _ = writeln!(report, "sha: {}", self.sha.as_deref().unwrap_or("-"));
+        let _ = writeln!(report, "branch: {}", self.branch);
+        let _ = writeln!(
+            report,
+            "started: {}",
+            humantime::format_rfc3339_seconds(started)
+        );
+        let _ = writeln!(report, "exited: {}", humantime::format_rfc3339_seconds(now));
+        let _ = writeln!(
+            report,
+            "uptime: {}",
+            humantime::format_duration(Duration::from_secs(self.uptime.as_secs()))
+        );
+        let _ = writeln!(report, "command: {}", log::redact(self.command));
+        let _ = writeln!(report, "environment: {}", self.env.join(" "));
+        let _ = writeln!(report, "\nlast {} lines:", self.output.len());
+        for line in &self.output {
+            let _ = writeln!(report, "{}", log::redact(line));
+        }
+        report
+    }
+}
+
+/// Writes `report` next to the `log` file as `<name>-crash-<timestamp>.txt`
+/// and removes the reports of the same log beyond `keep`.
+pub(crate) fn write(log: &Path, report: &Report, keep: usize) -> io::Result<PathBuf> {
+    let prefix = prefix(log);
+    // Like the rotated logs, 2024-01-31T12:00:00Z becomes 20240131120000.
+    let timestamp = humantime::format_rfc3339_seconds(SystemTime::now())
+        .to_string()
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>();
+    // Numbered after those of the same second, the oldest of which may be
+    // pruned already.
+    let n = reports(log)
+        .into_iter()
+        .filter(|((written, _), _)| *written == timestamp)
+        .map(|((_, n), _)| n + 1)
+        .max()
+        .unwrap_or(0);
+    let path = match n {
+        0 => log.with_file_name(format!("{}{}.txt", prefix, timestamp)),
+        n => log.with_file_name(format!("{}{}-{}.txt", prefix, timestamp, n)),
+    };
+    fs::write(&path, report.render())?;
+    prune(log, keep);
+    Ok(path)
+}
+
+/// `<name>-crash-` of the log file `<name>.log`.
+fn prefix(log: &Path) -> String {
+    format!(
+        "{}-crash-",
+        log.file_stem().unwrap_or_default().to_string_lossy()
+    )
+}
+
+/// The crash reports of `log` by their timestamp and number, oldest first.
+fn reports(log: &Path) -> Vec<((String, u32), PathBuf)> {
+    let (dir, prefix) = match log.parent() {
+        Some(dir) => (dir, prefix(log)),
+        None => return Vec::new(),
+    };
+    let mut reports = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter_map(|path| {
+                let file = path.file_name()?.to_string_lossy().into_owned();
+                let suffix = file.strip_prefix(&prefix)?.strip_suffix(".txt")?;
+                // Those of a process whose name starts with crash- aren't
+                // numbered like these.
+                let age = match suffix.split_once('-') {
+                    Some((timestamp, n)) => (timestamp.to_owned(), n.parse::<u32>().ok()?),
+                    None => (suffix.to_owned(), 0),
+                };
+                if !age.0.chars().all(|c| c.is_ascii_digit()) {
+                    return None;
+                }
+                Some((age, path))
+            })
+            .collect::<Vec<_>>(),
+        Err(_) => return Vec::new(),
+    };
+    reports.sort();
+    reports
+}
+
+/// Removes the oldest crash reports of `log` beyond `keep`.
+fn prune(log: &Path, keep: usize) {
+    let reports = reports(log);
+    let excess = reports.len().saturating_sub(keep);
+    for (_, path) in reports.into_iter().take(excess) {
+        if let Err(err) = fs::remove_file(&path) {
+            warn!(
+                "removing the crash report {} failed: {}",
+                path.display(),
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use super::{prune, write, Report};
+
+    struct Dir(std::path::PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("localdeploy-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn files(&self) -> Vec<String> {
+            let mut files = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn keeps_the_newest_of_the_same_second() {
+        let dir = Dir::new("crash-prune");
+        let mut names = vec!["app-crash-20240131115959.txt".to_owned()];
+        names.extend((0..12).map(|n| match n {
+            0 => "app-crash-20240131120000.txt".to_owned(),
+            n => format!("app-crash-20240131120000-{}.txt", n),
+        }));
+        names.extend(["app.log", "web-crash-20240131120000.txt"].map(str::to_owned));
+        for name in &names {
+            fs::write(dir.0.join(name), "").unwrap();
+        }
+        prune(&dir.0.join("app.log"), 3);
+        assert_eq!(
+            dir.files(),
+            [
+                "app-crash-20240131120000-10.txt",
+                "app-crash-20240131120000-11.txt",
+                "app-crash-20240131120000-9.txt",
+                "app.log",
+                "web-crash-20240131120000.txt",
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_and_prunes() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let dir = Dir::new("crash-write");
+        let log = dir.0.join("app.log");
+        let report = Report {
+            what: "the command",
+            status: std::process::ExitStatus::from_raw(256),
+            sha: None,
+            branch: "main",
+            uptime: Duration::from_secs(1),
+            command: "sh run.sh",
+            env: &[],
+            output: vec!["stderr: failed".to_owned()],
+        };
+        let written = (0..5)
+            .map(|_| write(&log, &report, 3).unwrap())
+            .collect::<Vec<_>>();
+        let files = dir.files();
+        assert_eq!(files.len(), 3, "{:?}", files);
+        for path in &written[2..] {
+            assert!(path.exists(), "{}", path.display());
+        }
+        let last = written.last().unwrap();
+        let report = fs::read_to_string(last).unwrap();
+        assert!(report.starts_with("the command exited unexpectedly\nstatus: exit status: 1\n"));
+        assert!(report.ends_with("last 1 lines:\nstderr: failed\n"));
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{child, log};
+
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long `git credential` may take. Helpers which want to ask the user
+/// would otherwise hang a headless box.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Credentials handed out by the git credential helpers for `url`.
+#[derive(Clone)]
+pub(crate) struct Filled {
+    pub url: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for Filled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Filled")
+            .field("url", &self.url)
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Asks the configured credential helpers for credentials for `url` with
+/// `git credential fill`, without letting git prompt.
+pub(crate) fn fill(url: &str, username: Option<&str>) -> Result<Filled, String> {
+    let mut input = format!("url={}\n", url);
+    if let Some(username) = username {
+        input.push_str(&format!("username={}\n", username));
+    }
+    let output = run("fill", &input)?;
+    let field = |name: &str| {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            .map(str::to_owned)
+    };
+    match (field("username"), field("password")) {
+        (Some(username), Some(password)) => {
+            log::add_secret(&password);
+            Ok(Filled {
+                url: url.to_owned(),
+                username,
+                password,
+            })
+        }
+        _ => Err("git credential fill returned no username and password".to_owned()),
+    }
+}
+
+/// Tells the helpers the credentials worked, so they can keep them.
+pub(crate) fn approve(filled: &Filled) {
+    if let Err(err) = run("approve", &describe(filled)) {
+        warn!("git credential approve failed: {}", err);
+    }
+}
+
+/// Tells the helpers the credentials were rejected, so they can drop them.
+pub(crate) fn reject(filled: &Filled) {
+    if let Err(err) = run("reject", &describe(filled)) {
+        warn!("git credential reject failed: {}", err);
+    }
+}
+
+fn describe(filled: &Filled) -> String {
+    format!(
+        "url={}\nusername={}\npassword={}\n",
+        filled.url, filled.username, filled.password
+    )
+}
+
+/// Runs `git credential <action>` with `input`, returning its output.
+fn run(action: &str, input: &str) -> Result<String, String> {
+    let mut child = child::spawn(
+        Command::new("git")
+            .args(["credential", action])
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .env("GCM_INTERACTIVE", "never")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )
+    .map_err(|err| format!("failed to run git credential {}: {}", action, err))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // An empty line ends the description.
+        let _ = stdin.write_all(format!("{}\n", input).as_bytes());
+    }
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Ok(status),
+            Ok(None) if started.elapsed() < TIMEOUT => thread::sleep(Duration::from_millis(50)),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                break Err(format!(
+                    "git credential {} timed out after {}s, is a helper waiting for input?",
+                    action,
+                    TIMEOUT.as_secs()
+                ));
+            }
+            Err(err) => break Err(err.to_string()),
+        }
+    };
+    child::forget(child.id());
+    let status = status?;
+    let mut stdout = String::new();
+    let mut stderr = String::new();
+    if let Some(mut out) = child.stdout.take() {
+        let _ = out.read_to_string(&mut stdout);
+    }
+    if let Some(mut err) = child.stderr.take() {
+        let _ = err.read_to_string(&mut stderr);
+    }
+    if status.success() {
+        Ok(stdout)
+    } else {
+        Err(format!(
+            "git credential {} failed: {}",
+            action,
+            stderr.trim()
+        ))
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use git2::{BranchType, Repository, WorktreeAddOptions};
+
+use crate::error::{Error, Result};
+
+/// Where the repository shared by all `--deploy` checkouts lives, relative to
+/// `--path`.
+pub(crate) const SHARED_REPO: &str = ".localdeploy.git";
+
+/// One `--deploy BRANCH:DIR:CMD`, a branch checked out into a directory below
+/// `--path` and the command run there.
+#[derive(Debug, Clone)]
+pub(crate) struct Deploy {
+    pub branch: String,
+    pub dir: PathBuf,
+    pub command: String,
+}
+
+impl FromStr for Deploy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidArgument("deploy", s.to_owned());
+        let mut parts = s.splitn(3, ':');
+        let (branch, dir, command) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(branch), Some(dir), Some(command))
+                if !branch.is_empty() && !dir.is_empty() && !command.trim().is_empty() =>
+            {
+                (branch, Path::new(dir), command)
+            }
+            _ => return Err(invalid()),
+        };
+        // The checkout has to stay below `--path`, next to the shared repo.
+        let normal = dir
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !normal || dir == Path::new(SHARED_REPO) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            branch: branch.to_owned(),
+            dir: dir.to_path_buf(),
+            command: command.to_owned(),
+        })
+    }
+}
+
+/// Opens the checkout of `branch` at `path`, adding it as a linked worktree
+/// of the shared repo on first use. The worktrees share refs and objects, so
+/// one fetch updates all of them.
+pub(crate) fn worktree(
+    repo: &Repository,
+    origin: &str,
+    branch: &str,
+    path: &Path,
+) -> Result<Repository> {
+    if path.exists() {
+        return Ok(Repository::open(path)?);
+    }
+    let local = match repo.find_branch(branch, BranchType::Local) {
+        Ok(local) => local,
+        Err(_) => {
+            let remote_ref = format!("refs/remotes/{}/{}", origin, branch);
+            let commit = repo.find_reference(&remote_ref)?.peel_to_commit()?;
+            repo.branch(branch, &commit, false)?
+        }
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Worktree names can't contain slashes.
+    let name = path
+        .strip_prefix(repo.path().parent().unwrap_or(repo.path()))
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace(['/', '\\'], "-");
+    let mut options = WorktreeAddOptions::new();
+    options.reference(Some(local.get()));
+    let worktree = repo.worktree(&name, path, Some(&options))?;
+    Ok(Repository::open_from_worktree(&worktree)?)
+}
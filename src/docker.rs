@@ -0,0 +1,125 @@
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    child::{self, Running},
+    error::{Error, Result, StepError},
+};
+
+/// The file `docker run` writes the id of the container to, in the git
+/// directory.
+pub(crate) const CIDFILE: &str = "localdeploy-container.cid";
+
+/// `--docker`: the command is a container of an image built from the
+/// checkout.
+#[derive(Debug, Clone)]
+pub(crate) struct Docker {
+    /// The `--docker-image` template the image is tagged with.
+    pub image: String,
+    /// The `--container-name`, the old container is replaced by it.
+    pub container: String,
+    /// The `--docker-run-args` templates, like ports and volumes.
+    pub run_args: Vec<String>,
+}
+
+impl Docker {
+    /// `docker build`, tagging the image built in the directory of the
+    /// command with the `--docker-image`.
+    pub fn build(&self) -> (String, Vec<String>) {
+        let args = ["build", "-t", &self.image, "."];
+        ("docker".to_owned(), args.map(str::to_owned).to_vec())
+    }
+
+    /// The arguments of `docker run` for the image, with the variables
+    /// `names` passed from docker's environment. The container is removed
+    /// once it stops and runs in the foreground, so it is watched and
+    /// stopped like any command.
+    pub fn run(&self, names: Vec<String>, cidfile: &Path) -> Vec<String> {
+        let mut args = vec![
+            "run".to_owned(),
+            "--rm".to_owned(),
+            "--name".to_owned(),
+            self.container.clone(),
+            "--cidfile".to_owned(),
+            cidfile.display().to_string(),
+        ];
+        for name in names {
+            args.push("-e".to_owned());
+            args.push(name);
+        }
+        args.extend(self.run_args.iter().cloned());
+        args.push(self.image.clone());
+        args
+    }
+
+    /// Removes a container left by a start which was killed, or by someone
+    /// else, so the name is free again.
+    pub fn remove(&self, cidfile: &Path) {
+        let removed = child::status(
+            Command::new("docker")
+                .args(["rm", "-f", &self.container])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()),
+        );
+        if let Err(err) = removed {
+            warn!("removing the container {} failed: {}", self.container, err);
+        }
+        let _ = fs::remove_file(cidfile);
+    }
+}
+
+/// The id of the running container, once docker wrote it.
+pub(crate) fn container_id(cidfile: &Path) -> Option<String> {
+    let id = fs::read_to_string(cidfile).ok()?;
+    let id = id.trim();
+    match id.is_empty() {
+        true => None,
+        false => Some(id.to_owned()),
+    }
+}
+
+/// Waits for `docker run` to create the container, failing if it exits
+/// first. Gives up after `timeout` without the id.
+pub(crate) fn wait(
+    running: &mut Running,
+    cidfile: &Path,
+    timeout: Duration,
+) -> Result<Option<String>> {
+    let started = Instant::now();
+    loop {
+        if let Some(id) = container_id(cidfile) {
+            return Ok(Some(id));
+        }
+        if let Some(status) = running.exited() {
+            return Err(Error::Docker("run", StepError::Exit(status)));
+        }
+        if started.elapsed() >= timeout {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// `name` as docker takes it for an image or container, lowercase letters,
+/// digits, `.`, `_` and `-`.
+pub(crate) fn name(name: &str) -> String {
+    let name = name
+        .to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '-',
+        })
+        .collect::<String>();
+    let name = name.trim_matches(|c: char| !c.is_ascii_alphanumeric());
+    match name.is_empty() {
+        true => "localdeploy".to_owned(),
+        false => name.to_owned(),
+    }
+}
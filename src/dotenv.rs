@@ -0,0 +1,146 @@
+use std::{fs, path::Path};
+
+use crate::error::{Error, Result};
+
+/// Reads the `KEY=VALUE` lines of a dotenv file. Blank lines and lines
+/// starting with `#` are skipped, an `export ` before the key is allowed.
+/// Values in double quotes understand `\n`, `\t`, `\"` and `\\`, values in
+/// single quotes are taken as they are, unquoted ones end at a ` #` comment.
+pub(crate) fn load(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| Error::EnvFile(path.to_owned(), 0, err.to_string()))?;
+    let mut variables = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_line(line) {
+            Ok(variable) => variables.push(variable),
+            Err(message) => {
+                return Err(Error::EnvFile(
+                    path.to_owned(),
+                    index + 1,
+                    message.to_owned(),
+                ))
+            }
+        }
+    }
+    Ok(variables)
+}
+
+fn parse_line(line: &str) -> std::result::Result<(String, String), &'static str> {
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, value) = line.split_once('=').ok_or("expected KEY=VALUE")?;
+    let key = key.trim();
+    let valid = key.chars().enumerate().all(|(index, c)| {
+        c == '_' || c.is_ascii_alphabetic() || (index > 0 && (c.is_ascii_digit() || c == '.'))
+    });
+    if key.is_empty() || !valid {
+        return Err("invalid variable name");
+    }
+    let value = value.trim_start();
+    let (value, rest) = match value.chars().next() {
+        Some('"') => double_quoted(&value[1..])?,
+        Some('\'') => match value[1..].split_once('\'') {
+            Some((value, rest)) => (value.to_owned(), rest),
+            None => return Err("unterminated single quote"),
+        },
+        _ => {
+            let end = value.find(" #").unwrap_or(value.len());
+            (value[..end].trim_end().to_owned(), "")
+        }
+    };
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err("unexpected text after the closing quote");
+    }
+    Ok((key.to_owned(), value))
+}
+
+/// The value up to the closing double quote, and what follows it.
+fn double_quoted(value: &str) -> std::result::Result<(String, &str), &'static str> {
+    let mut unquoted = String::new();
+    let mut chars = value.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return Ok((unquoted, &value[index + 1..])),
+            '\\' => match chars.next() {
+                Some((_, 'n')) => unquoted.push('\n'),
+                Some((_, 't')) => unquoted.push('\t'),
+                Some((_, 'r')) => unquoted.push('\r'),
+                Some((_, c @ ('"' | '\\' | '$'))) => unquoted.push(c),
+                Some((_, c)) => {
+                    unquoted.push('\\');
+                    unquoted.push(c);
+                }
+                None => break,
+            },
+            c => unquoted.push(c),
+        }
+    }
+    Err("unterminated double quote")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{load, parse_line};
+    use crate::error::Error;
+
+    fn value(line: &str) -> String {
+        let (key, value) = parse_line(line).unwrap();
+        assert_eq!(key, "KEY");
+        value
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(value("KEY=a b"), "a b");
+        assert_eq!(value("export KEY = a "), "a");
+        assert_eq!(value(r#"KEY="a\n\t\"\\\$ b""#), "a\n\t\"\\$ b");
+        assert_eq!(value(r#"KEY="a\x""#), r"a\x");
+        assert_eq!(value(r"KEY='a\n $b'"), r"a\n $b");
+        assert_eq!(value("KEY=\"a\" # comment"), "a");
+        assert_eq!(value("KEY='a' # comment"), "a");
+        assert_eq!(value("KEY="), "");
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(value("KEY=a#b"), "a#b");
+        assert_eq!(value("KEY=a #b"), "a");
+        assert_eq!(value("KEY=\"a #b\""), "a #b");
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(parse_line("KEY"), Err("expected KEY=VALUE"));
+        assert_eq!(parse_line("1KEY=a"), Err("invalid variable name"));
+        assert_eq!(parse_line("=a"), Err("invalid variable name"));
+        assert_eq!(parse_line("KEY=\"a"), Err("unterminated double quote"));
+        assert_eq!(parse_line("KEY=\"a\\"), Err("unterminated double quote"));
+        assert_eq!(parse_line("KEY='a"), Err("unterminated single quote"));
+        assert_eq!(
+            parse_line("KEY='a' b"),
+            Err("unexpected text after the closing quote")
+        );
+    }
+
+    #[test]
+    fn line_of_the_error() {
+        let path = std::env::temp_dir().join(format!("localdeploy-dotenv-{}", std::process::id()));
+        fs::write(&path, "# comment\nA=1\n\nB=\"2\n").unwrap();
+        let loaded = load(&path);
+        let _ = fs::remove_file(&path);
+        match loaded {
+            Err(Error::EnvFile(file, line, message)) => {
+                assert_eq!(file, path);
+                assert_eq!(line, 4);
+                assert_eq!(message, "unterminated double quote");
+            }
+            loaded => panic!("{:?}", loaded),
+        }
+    }
+}
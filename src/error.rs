@@ -2,17 +2,127 @@ use std::fmt::Display;
 
 use std::env::VarError;
 use std::io::Error as IoError;
+use std::time::Duration;
+
+use git2::{ErrorClass, ErrorCode};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     GitError(git2::Error),
     MissingCommand,
     EnvError(VarError),
     IoError(IoError),
     MissingPath,
+    PathNotEmpty(std::path::PathBuf),
     MissingUrlToRepo,
+    NotFastForward {
+        branch: String,
+        local: git2::Oid,
+        remote: git2::Oid,
+    },
+    DirtyWorkTree(Vec<String>),
+    InvalidConfig(String),
+    /// An `--env-file`, the line or 0 if it can't be read, and what's wrong.
+    EnvFile(std::path::PathBuf, usize, String),
+    /// The `--procfile`, the line or 0 if not about one, and what's wrong.
+    Procfile(std::path::PathBuf, usize, String),
+    /// A process of the `--procfile` which couldn't be started, and why.
+    ProcessStart(String, String),
+    InvalidArgument(&'static str, String),
+    OutsideRepo(std::path::PathBuf),
+    StashConflict(Vec<String>),
+    StashLost(git2::Oid),
+    Submodule(String, git2::Error),
+    NoMatchingTag(String),
+    UnknownCommit(String),
+    Unverified(String, crate::signature::Failure),
+    MissingSigningKeys,
+    NoDefaultBranch(String),
+    NoMatchingBranch(String),
+    MissingCheckoutRef,
+    Timeout(&'static str, Duration),
+    Proxy(String, git2::Error),
+    LfsMissing,
+    Lfs(String, String),
+    Build(StepError),
+    MissingCommandDir(std::path::PathBuf),
+    /// A hook with its command line, and why it failed.
+    Hook(String, StepError),
+    /// A `--command` before the last, its number from 1 and its line.
+    Step(usize, String, StepError),
+    /// `docker build` or `docker run` of `--docker`, and why it failed.
+    Docker(&'static str, StepError),
+    /// A `docker compose` command of `--compose` and why it failed.
+    Compose(&'static str, StepError),
+    /// The command couldn't be started, as a shell would run it, in `dir`.
+    SpawnFailed {
+        cmd: String,
+        dir: std::path::PathBuf,
+        source: IoError,
+    },
+    /// The `--systemd-unit` which failed to restart, and why.
+    Systemd(String, String),
+    /// The new version failed its health check and was rolled back.
+    Unhealthy(git2::Oid, String),
+    NoRollback(Option<git2::Oid>),
+    Corrupted {
+        error: String,
+        dirty: Vec<String>,
+    },
+    Keyring(String),
+    WrongPassphrase(std::path::PathBuf),
+    GithubApp {
+        message: String,
+        /// GitHub refused the app, rather than not being reachable.
+        rejected: bool,
+    },
+    MissingSecret {
+        name: String,
+        url: String,
+    },
+    /// The `--pid-file` of another localdeploy for the repo, and its pid.
+    AlreadyRunning(std::path::PathBuf, u32),
+}
+
+/// Why a build command, hook or health check command failed.
+#[derive(Debug)]
+pub enum StepError {
+    /// It couldn't be started, or its `--env-file` read.
+    Start(String),
+    Exit(std::process::ExitStatus),
+    Timeout(Duration),
+    /// localdeploy was asked to stop while it ran.
+    Stopped,
+}
+
+impl StepError {
+    /// How it failed, as the history records it.
+    pub fn cause(&self) -> &'static str {
+        match self {
+            StepError::Start(_) => "start",
+            StepError::Exit(_) => "exit",
+            StepError::Timeout(_) => "timeout",
+            StepError::Stopped => "stopped",
+        }
+    }
+}
+
+impl Display for StepError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StepError::Start(err) => write!(f, "couldn't start: {}", err),
+            StepError::Exit(status) => write!(f, "failed ({})", status),
+            StepError::Timeout(timeout) => write!(
+                f,
+                "didn't finish within {}",
+                humantime::format_duration(*timeout)
+            ),
+            StepError::Stopped => write!(f, "was stopped"),
+        }
+    }
 }
 
 impl Display for Error {
@@ -23,7 +133,184 @@ impl Display for Error {
             Error::EnvError(err) => write!(f, "Missing env var: {}", err),
             Error::IoError(err) => write!(f, "io error occured: {}", err),
             Error::MissingPath => write!(f, "missing path to repo"),
+            Error::PathNotEmpty(path) => write!(
+                f,
+                "{} already exists and is not empty, use --path to clone elsewhere",
+                path.display()
+            ),
             Error::MissingUrlToRepo => write!(f, "missing url to repo"),
+            Error::NotFastForward {
+                branch,
+                local,
+                remote,
+            } => write!(
+                f,
+                "cannot fast-forward branch {} from {} to {}",
+                branch, local, remote
+            ),
+            Error::DirtyWorkTree(paths) => {
+                write!(
+                    f,
+                    "working tree has {} modified path(s), refusing to update: {}",
+                    paths.len(),
+                    paths
+                        .iter()
+                        .take(10)
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )?;
+                if paths.len() > 10 {
+                    write!(f, ", ...")?;
+                }
+                write!(f, " (use --force-dirty to update anyway)")
+            }
+            Error::InvalidConfig(msg) => write!(f, "invalid config: {}", msg),
+            Error::EnvFile(path, 0, message) => write!(f, "{}: {}", path.display(), message),
+            Error::EnvFile(path, line, message) => {
+                write!(f, "{}:{}: {}", path.display(), line, message)
+            }
+            Error::Procfile(path, 0, message) => write!(f, "{}: {}", path.display(), message),
+            Error::Procfile(path, line, message) => {
+                write!(f, "{}:{}: {}", path.display(), line, message)
+            }
+            Error::ProcessStart(name, err) => write!(f, "the {} process couldn't start: {}", name, err),
+            Error::InvalidArgument(name, value) => {
+                write!(f, "invalid value for --{}: {}", name, value)
+            }
+            Error::StashConflict(paths) => write!(
+                f,
+                "stashed changes conflict with the update in {}, they were kept in the stash",
+                paths.join(", ")
+            ),
+            Error::StashLost(oid) => write!(f, "stashed changes {} were lost", oid),
+            Error::Submodule(path, err) => {
+                write!(f, "submodule {}: {}", path, err.message())
+            }
+            Error::NoMatchingTag(pattern) => write!(f, "no tag matches {}", pattern),
+            Error::UnknownCommit(sha) => {
+                write!(f, "commit {} was not found in the fetched objects", sha)
+            }
+            Error::Unverified(object, failure) => {
+                write!(f, "refusing to deploy {}: {}", object, failure)
+            }
+            Error::MissingSigningKeys => write!(
+                f,
+                "--verify-tag-signatures needs the allowed keys, either as value or with --verify-signatures"
+            ),
+            Error::NoDefaultBranch(remote) => {
+                write!(f, "remote {} has no default branch, use --branch", remote)
+            }
+            Error::NoMatchingBranch(pattern) => write!(f, "no branch matches {}", pattern),
+            Error::Timeout(what, limit) => {
+                write!(f, "{} timed out after {}s", what, limit.as_secs())
+            }
+            Error::Proxy(proxy, err) => write!(f, "proxy {} failed: {}", proxy, err.message()),
+            Error::LfsMissing => write!(
+                f,
+                "--lfs needs git-lfs, install it or make sure it is on PATH"
+            ),
+            Error::Lfs(command, stderr) => write!(f, "{} failed: {}", command, stderr),
+            Error::Build(reason) => write!(f, "the --build-command {}", reason),
+            Error::MissingCommandDir(dir) => {
+                write!(f, "the --command-dir {} doesn't exist", dir.display())
+            }
+            Error::Hook(hook, reason) => write!(f, "the {} {}", hook, reason),
+            Error::Step(number, line, reason) => {
+                write!(f, "step {} of the --command, {}, {}", number, line, reason)
+            }
+            Error::Docker(action, reason) => write!(f, "docker {} {}", action, reason),
+            Error::Compose(action, reason) => write!(f, "docker compose {} {}", action, reason),
+            Error::SpawnFailed { cmd, dir, source } => write!(
+                f,
+                "couldn't start {} in {}: {}",
+                cmd,
+                dir.display(),
+                source
+            ),
+            Error::Systemd(unit, reason) => write!(f, "{} {}", unit, reason),
+            Error::Unhealthy(oid, reason) => write!(f, "{} is unhealthy: {}", oid, reason),
+            Error::NoRollback(Some(oid)) => write!(f, "{} was never deployed here", oid),
+            Error::NoRollback(None) => write!(f, "no earlier deploy to roll back to"),
+            Error::AlreadyRunning(path, pid) => write!(
+                f,
+                "localdeploy already runs for this repo as process {}, see {}",
+                pid,
+                path.display()
+            ),
+            Error::Corrupted { error, dirty } => write!(
+                f,
+                "the repo is corrupted ({}) but has uncommitted changes in {}, not recloning it",
+                error,
+                dirty.join(", ")
+            ),
+            Error::Keyring(err) => write!(f, "keyring: {}", err),
+            Error::GithubApp { message, .. } => write!(f, "GitHub App: {}", message),
+            Error::WrongPassphrase(key) => write!(
+                f,
+                "the passphrase was rejected by the ssh key {}",
+                key.display()
+            ),
+            Error::MissingSecret { name, url } => write!(
+                f,
+                "no {} for {} in the keyring, store it with `localdeploy secret set {}` and the same --path or --new",
+                name, url, name
+            ),
+            Error::MissingCheckoutRef => {
+                write!(f, "--checkout-ref is needed to pick one of the fetched refs")
+            }
+            Error::OutsideRepo(path) => {
+                write!(
+                    f,
+                    "refusing to touch {} outside of the repo",
+                    path.display()
+                )
+            }
+        }
+    }
+}
+
+impl Error {
+    /// Errors talking to the remote, which don't say anything about the
+    /// local repo.
+    pub fn is_network(&self) -> bool {
+        match self {
+            Error::Timeout(..) | Error::Proxy(..) => true,
+            Error::GithubApp { rejected, .. } => !rejected,
+            Error::GitError(err) => {
+                matches!(
+                    err.class(),
+                    ErrorClass::Net | ErrorClass::Http | ErrorClass::Ssh | ErrorClass::Os
+                ) || self.is_auth()
+            }
+            _ => false,
+        }
+    }
+
+    /// Broken objects, packs or index in the local repo, which only a new
+    /// clone fixes.
+    pub fn is_corruption(&self) -> bool {
+        match self {
+            Error::GitError(err) => {
+                matches!(
+                    err.class(),
+                    ErrorClass::Odb | ErrorClass::Zlib | ErrorClass::Index
+                ) && err.code() != ErrorCode::Locked
+            }
+            _ => false,
+        }
+    }
+
+    /// The remote rejected the credentials or its certificate was refused,
+    /// trying again won't help.
+    pub fn is_auth(&self) -> bool {
+        match self {
+            Error::GitError(err) => {
+                matches!(err.code(), ErrorCode::Auth | ErrorCode::Certificate)
+                    || (err.class() == ErrorClass::Ssh
+                        && err.message().to_lowercase().contains("authenticat"))
+            }
+            _ => false,
         }
     }
 }
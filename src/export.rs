@@ -0,0 +1,131 @@
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use git2::{ObjectType, Oid, Repository, Tree};
+
+use crate::{error::Result, sparse::Sparse};
+
+/// Extracts the tree of `commit` into `dir` like `git archive | tar -x`,
+/// without a `.git`. Only paths matching `sparse` are written, if given.
+///
+/// The tree is written next to `dir` first and then renamed into place, so
+/// `dir` never holds a partial export. Submodules are left as empty
+/// directories.
+pub(crate) fn export(
+    repo: &Repository,
+    commit: Oid,
+    dir: &Path,
+    sparse: Option<&Sparse>,
+) -> Result<()> {
+    let parent = match dir.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    fs::create_dir_all(&parent)?;
+    let sibling = |suffix: &str| {
+        let mut name = OsString::from(".");
+        name.push(dir.file_name().unwrap_or_default());
+        name.push(suffix);
+        parent.join(name)
+    };
+    let staging = sibling(".localdeploy-new");
+    let old = sibling(".localdeploy-old");
+    // Leftovers of an interrupted export.
+    for leftover in [&staging, &old] {
+        if leftover.exists() {
+            fs::remove_dir_all(leftover)?;
+        }
+    }
+
+    extract(repo, commit, &staging, sparse)?;
+    if dir.exists() {
+        fs::rename(dir, &old)?;
+    }
+    fs::rename(&staging, dir)?;
+    if old.exists() {
+        fs::remove_dir_all(&old)?;
+    }
+    Ok(())
+}
+
+/// Writes the tree of `commit` into the new directory `dir`.
+pub(crate) fn extract(
+    repo: &Repository,
+    commit: Oid,
+    dir: &Path,
+    sparse: Option<&Sparse>,
+) -> Result<()> {
+    let tree = repo.find_commit(commit)?.tree()?;
+    fs::create_dir(dir)?;
+    write_tree(repo, &tree, dir, Path::new(""), sparse)
+}
+
+fn write_tree(
+    repo: &Repository,
+    tree: &Tree,
+    dir: &Path,
+    prefix: &Path,
+    sparse: Option<&Sparse>,
+) -> Result<()> {
+    for entry in tree.iter() {
+        let name = match entry.name() {
+            // git refuses these as well, they would escape the export.
+            Some(name)
+                if !matches!(name, "." | ".." | "") && !name.eq_ignore_ascii_case(".git") =>
+            {
+                name
+            }
+            _ => continue,
+        };
+        let path = dir.join(name);
+        let relative = prefix.join(name);
+        match entry.kind() {
+            Some(ObjectType::Tree) => {
+                let subtree = repo.find_tree(entry.id())?;
+                fs::create_dir(&path)?;
+                write_tree(repo, &subtree, &path, &relative, sparse)?;
+                // Don't leave directories which only had left out paths.
+                if sparse.is_some() && fs::read_dir(&path)?.next().is_none() {
+                    fs::remove_dir(&path)?;
+                }
+            }
+            Some(ObjectType::Blob) => {
+                if !sparse.is_none_or(|sparse| sparse.matches(&relative)) {
+                    continue;
+                }
+                let blob = repo.find_blob(entry.id())?;
+                write_blob(&path, blob.content(), entry.filemode())?;
+            }
+            // Submodule commits, their content isn't in this repo.
+            _ => {
+                if sparse.is_none_or(|sparse| sparse.matches(&relative)) {
+                    fs::create_dir(&path)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_blob(path: &Path, content: &[u8], mode: i32) -> Result<()> {
+    use std::os::unix::{ffi::OsStrExt, fs::PermissionsExt};
+
+    if mode == 0o120000 {
+        std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(content), path)?;
+        return Ok(());
+    }
+    fs::write(path, content)?;
+    let mode = if mode == 0o100755 { 0o755 } else { 0o644 };
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_blob(path: &Path, content: &[u8], _mode: i32) -> Result<()> {
+    fs::write(path, content)?;
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    log,
+};
+
+/// The username GitHub expects with an installation token.
+pub(crate) const USERNAME: &str = "x-access-token";
+
+/// Tokens are replaced this long before they expire, so a fetch never starts
+/// with one about to run out.
+const REFRESH_BEFORE: Duration = Duration::from_secs(5 * 60);
+
+/// Authenticates as an installation of a GitHub App. Installation tokens
+/// last an hour, they are fetched when needed and kept until shortly before
+/// they expire.
+pub(crate) struct GithubApp {
+    app_id: String,
+    installation_id: String,
+    key: EncodingKey,
+    api: String,
+    token: Mutex<Option<Token>>,
+}
+
+struct Token {
+    value: String,
+    expires: SystemTime,
+}
+
+#[derive(Serialize)]
+struct Claims {
+    iat: u64,
+    exp: u64,
+    iss: String,
+}
+
+#[derive(Deserialize)]
+struct AccessToken {
+    token: String,
+    expires_at: String,
+}
+
+impl std::fmt::Debug for GithubApp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GithubApp")
+            .field("app_id", &self.app_id)
+            .field("installation_id", &self.installation_id)
+            .field("api", &self.api)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GithubApp {
+    /// Reads the app's private key, a PEM file as GitHub hands it out.
+    pub fn new(app_id: String, installation_id: String, key: PathBuf, api: String) -> Result<Self> {
+        let pem = fs::read(&key).map_err(|err| {
+            Error::InvalidArgument("github-app-key", format!("{}: {}", key.display(), err))
+        })?;
+        let key = EncodingKey::from_rsa_pem(&pem).map_err(|err| {
+            Error::InvalidArgument("github-app-key", format!("{}: {}", key.display(), err))
+        })?;
+        Ok(Self {
+            app_id,
+            installation_id,
+            key,
+            api: api.trim_end_matches('/').to_owned(),
+            token: Mutex::new(None),
+        })
+    }
+
+    /// A valid installation token, exchanging a new one if the last is about
+    /// to expire.
+    pub fn token(&self) -> Result<String> {
+        let mut token = self.token.lock().unwrap_or_else(|err| err.into_inner());
+        let fresh = token.as_ref().is_some_and(|token| {
+            token
+                .expires
+                .duration_since(SystemTime::now())
+                .is_ok_and(|left| left > REFRESH_BEFORE)
+        });
+        if !fresh {
+            *token = Some(self.exchange()?);
+        }
+        Ok(token
+            .as_ref()
+            .map(|token| token.value.clone())
+            .unwrap_or_default())
+    }
+
+    /// Signs a JWT as the app and trades it for an installation token.
+    fn exchange(&self) -> Result<Token> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        // GitHub allows ten minutes and some clock drift.
+        let claims = Claims {
+            iat: now.saturating_sub(60),
+            exp: now + 9 * 60,
+            iss: self.app_id.clone(),
+        };
+        let jwt = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &self.key)
+            .map_err(|err| Error::GithubApp {
+                message: format!("signing the JWT failed: {}", err),
+                rejected: true,
+            })?;
+        let url = format!(
+            "{}/app/installations/{}/access_tokens",
+            self.api, self.installation_id
+        );
+        let response = ureq::post(&url)
+            .set("Authorization", &format!("Bearer {}", jwt))
+            .set("Accept", "application/vnd.github+json")
+            .set(
+                "User-Agent",
+                concat!("localdeploy/", env!("CARGO_PKG_VERSION")),
+            )
+            .call();
+        let response = match response {
+            Ok(response) => response,
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                return Err(Error::GithubApp {
+                    message: format!("{} answered {}: {}", url, status, body.trim()),
+                    // A wrong key or id, asking again won't help.
+                    rejected: (400..500).contains(&status) && status != 429,
+                });
+            }
+            Err(err) => {
+                return Err(Error::GithubApp {
+                    message: format!("{}: {}", url, err),
+                    rejected: false,
+                })
+            }
+        };
+        let access: AccessToken = response.into_json().map_err(|err| Error::GithubApp {
+            message: format!("{} sent no token: {}", url, err),
+            rejected: false,
+        })?;
+        let expires =
+            humantime::parse_rfc3339(&access.expires_at).map_err(|err| Error::GithubApp {
+                message: format!("bad expires_at {}: {}", access.expires_at, err),
+                rejected: false,
+            })?;
+        log::add_secret(&access.token);
+        verbose!(
+            "got an installation token of app {} valid until {}",
+            self.app_id,
+            access.expires_at
+        );
+        Ok(Token {
+            value: access.token,
+            expires,
+        })
+    }
+}
@@ -0,0 +1,48 @@
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use regex::Regex;
+
+/// How the new version of the command is checked after a deploy.
+#[derive(Debug, Clone)]
+pub(crate) enum Check {
+    /// The url has to answer with a 2xx status.
+    Url(String),
+    /// The command has to exit with 0.
+    Command((String, Vec<String>)),
+}
+
+/// Asks `url` once, waiting up to `timeout` for the answer. Returns why the
+/// check failed.
+pub(crate) fn get(url: &str, timeout: Duration) -> Result<(), String> {
+    match ureq::get(url).timeout(timeout).call() {
+        Ok(response) if (200..300).contains(&response.status()) => Ok(()),
+        Ok(response) => Err(format!("{} answered {}", url, response.status())),
+        Err(ureq::Error::Status(status, _)) => Err(format!("{} answered {}", url, status)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// When a started command counts as ready.
+#[derive(Debug, Clone)]
+pub(crate) enum Ready {
+    /// Once `host:port` takes connections.
+    Tcp(String),
+    /// Once a line of its output matches.
+    Log(Regex),
+    /// After this long.
+    Delay(Duration),
+}
+
+/// Whether `address` takes connections within `timeout`.
+pub(crate) fn connectable(address: &str, timeout: Duration) -> bool {
+    let addresses = match address.to_socket_addrs() {
+        Ok(addresses) => addresses,
+        Err(_) => return false,
+    };
+    addresses
+        .into_iter()
+        .any(|address| TcpStream::connect_timeout(&address, timeout).is_ok())
+}
@@ -0,0 +1,259 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use git2::{Oid, Repository};
+
+use crate::{
+    error::{Error, Result},
+    log,
+};
+
+/// The history file in the git directory, a line per deploy, restart or
+/// failure of the command. Each line holds:
+///
+/// - the unix time,
+/// - what happened: `deploy`, `rollback`, `restart`, `failed` or `exit`,
+/// - the commit,
+/// - the redacted detail: the remote and `reloaded|restarted` and
+///   `healthy|unhealthy` of a deploy, the reason of a restart, the
+///   `<build|hook|step|run|pull|up|unit> <exit|timeout|start|stopped>` of a
+///   failure or the `<code N|signal N>` of an exit.
+pub(crate) const FILE: &str = "localdeploy-history";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Deploy,
+    Rollback,
+    /// The command was started again by `--restart` or `--max-memory`.
+    Restart,
+    /// The build, a hook or a step of the command failed.
+    Failed,
+    /// The command exited on its own.
+    Exit,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub kind: Kind,
+    pub oid: Oid,
+}
+
+/// Where the deploys since the last regular one rolled back to. The rollback
+/// holds until a commit is fetched which was never deployed, the ones in
+/// `from` are not deployed again.
+#[derive(Debug, Clone)]
+pub(crate) struct Rollback {
+    pub to: Oid,
+    pub from: Vec<Oid>,
+}
+
+fn path(repo: &Repository) -> PathBuf {
+    repo.path().join(FILE)
+}
+
+/// All recorded deploys, oldest first. Lines which can't be parsed are
+/// skipped.
+pub(crate) fn load(repo: &Repository) -> Result<Vec<Entry>> {
+    let content = match fs::read_to_string(path(repo)) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            parts.next()?.parse::<u64>().ok()?;
+            let kind = match parts.next()? {
+                "deploy" => Kind::Deploy,
+                "rollback" => Kind::Rollback,
+                "restart" => Kind::Restart,
+                "failed" => Kind::Failed,
+                "exit" => Kind::Exit,
+                _ => return None,
+            };
+            let oid = Oid::from_str(parts.next()?).ok()?;
+            Some(Entry { kind, oid })
+        })
+        .collect())
+}
+
+/// Appends a deploy of `oid` to the history. `detail` is the remote it was
+/// fetched from, or for restarts why.
+pub(crate) fn record(repo: &Repository, kind: Kind, oid: Oid, detail: Option<&str>) -> Result<()> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default();
+    let kind = match kind {
+        Kind::Deploy => "deploy",
+        Kind::Rollback => "rollback",
+        Kind::Restart => "restart",
+        Kind::Failed => "failed",
+        Kind::Exit => "exit",
+    };
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path(repo))?;
+    match detail {
+        Some(detail) => writeln!(file, "{} {} {} {}", time, kind, oid, log::redact(detail))?,
+        None => writeln!(file, "{} {} {}", time, kind, oid)?,
+    }
+    Ok(())
+}
+
+/// The detail of a deploy: the remote it was fetched from, whether the
+/// command was reloaded or restarted for it and how its health check went.
+pub(crate) fn detail(
+    remote: Option<&str>,
+    action: Option<&str>,
+    health: Option<&str>,
+) -> Option<String> {
+    let parts = remote
+        .into_iter()
+        .chain(action)
+        .chain(health)
+        .collect::<Vec<_>>();
+    (!parts.is_empty()).then(|| parts.join(" "))
+}
+
+/// Replays the history onto a stack of deployed commits. A rollback pops
+/// the stack down to the commit it went back to, so rolling back twice goes
+/// back two deploys.
+fn stack(history: &[Entry]) -> Vec<Oid> {
+    let mut stack = Vec::new();
+    for entry in history {
+        match entry.kind {
+            Kind::Rollback => match stack.iter().rposition(|oid| *oid == entry.oid) {
+                Some(index) => stack.truncate(index + 1),
+                None => stack.push(entry.oid),
+            },
+            // Restarts deploy the same commit again.
+            Kind::Deploy if stack.last() == Some(&entry.oid) => {}
+            Kind::Deploy => stack.push(entry.oid),
+            Kind::Restart | Kind::Failed | Kind::Exit => {}
+        }
+    }
+    stack
+}
+
+/// The commit to roll back to: `to` if given, which must have been deployed
+/// before, otherwise the one deployed before the current one.
+pub(crate) fn rollback_target(history: &[Entry], to: Option<Oid>) -> Result<Oid> {
+    match to {
+        Some(to)
+            if history
+                .iter()
+                .any(|entry| entry.oid == to && entry.kind != Kind::Failed) =>
+        {
+            Ok(to)
+        }
+        Some(to) => Err(Error::NoRollback(Some(to))),
+        None => {
+            let stack = stack(history);
+            match stack.len() {
+                len if len >= 2 => Ok(stack[len - 2]),
+                _ => Err(Error::NoRollback(None)),
+            }
+        }
+    }
+}
+
+/// The rollback in effect, if the history ends with rollbacks.
+pub(crate) fn rollback(history: &[Entry]) -> Option<Rollback> {
+    let start = history
+        .iter()
+        .rposition(|entry| entry.kind == Kind::Deploy)
+        .map_or(0, |index| index + 1);
+    let to = history[start..]
+        .iter()
+        .rfind(|entry| entry.kind == Kind::Rollback)?
+        .oid;
+    let mut from = history
+        .iter()
+        .map(|entry| entry.oid)
+        .filter(|oid| *oid != to)
+        .collect::<Vec<_>>();
+    from.sort();
+    from.dedup();
+    Some(Rollback { to, from })
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::Oid;
+
+    use super::{rollback, rollback_target, Entry, Kind};
+    use crate::error::Error;
+
+    fn oid(byte: u8) -> Oid {
+        Oid::from_bytes(&[byte; 20]).unwrap()
+    }
+
+    fn entry(kind: Kind, byte: u8) -> Entry {
+        Entry {
+            kind,
+            oid: oid(byte),
+        }
+    }
+
+    #[test]
+    fn rolls_back_one_deploy_at_a_time() {
+        let mut history = vec![
+            entry(Kind::Deploy, 1),
+            entry(Kind::Deploy, 2),
+            entry(Kind::Restart, 2),
+            entry(Kind::Deploy, 3),
+        ];
+        assert_eq!(rollback_target(&history, None).unwrap(), oid(2));
+        history.push(entry(Kind::Rollback, 2));
+        assert_eq!(rollback_target(&history, None).unwrap(), oid(1));
+        history.push(entry(Kind::Rollback, 1));
+        assert!(matches!(
+            rollback_target(&history, None),
+            Err(Error::NoRollback(None))
+        ));
+    }
+
+    #[test]
+    fn rolls_back_to_a_deployed_commit() {
+        let history = vec![
+            entry(Kind::Deploy, 1),
+            entry(Kind::Failed, 2),
+            entry(Kind::Deploy, 3),
+        ];
+        assert_eq!(rollback_target(&history, Some(oid(1))).unwrap(), oid(1));
+        for to in [2, 4] {
+            assert!(matches!(
+                rollback_target(&history, Some(oid(to))),
+                Err(Error::NoRollback(Some(rejected))) if rejected == oid(to)
+            ));
+        }
+    }
+
+    #[test]
+    fn holds_back_the_newer_commits() {
+        let mut history = vec![
+            entry(Kind::Deploy, 1),
+            entry(Kind::Deploy, 2),
+            entry(Kind::Deploy, 3),
+        ];
+        assert!(rollback(&history).is_none());
+        history.push(entry(Kind::Rollback, 2));
+        let held = rollback(&history).unwrap();
+        assert_eq!(held.to, oid(2));
+        assert_eq!(held.from, [oid(1), oid(3)]);
+        history.push(entry(Kind::Rollback, 1));
+        let held = rollback(&history).unwrap();
+        assert_eq!(held.to, oid(1));
+        assert_eq!(held.from, [oid(2), oid(3)]);
+        // A new deploy ends the rollback.
+        history.push(entry(Kind::Deploy, 4));
+        assert!(rollback(&history).is_none());
+    }
+}
@@ -0,0 +1,103 @@
+#[cfg(target_os = "linux")]
+use signal_hook::{consts::SIGCHLD, iterator::Signals};
+
+use crate::child;
+
+/// Makes localdeploy the parent of the processes orphaned by the command,
+/// as PID 1 of a container it is already, and reaps them in the background
+/// on each SIGCHLD, during builds, hooks and fetches as well.
+///
+/// SIGTERM and SIGINT aren't passed on as they are, they stop the command
+/// and its process group with `--stop-signal` like for a service manager.
+#[cfg(target_os = "linux")]
+pub(crate) fn adopt() {
+    if std::process::id() != 1 && unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) } != 0 {
+        warn!(
+            "can't adopt the orphaned processes: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    let mut signals = match Signals::new([SIGCHLD]) {
+        Ok(signals) => signals,
+        Err(err) => {
+            warn!("can't reap the orphaned processes: {}", err);
+            return;
+        }
+    };
+    std::thread::spawn(move || {
+        // Those which exited before SIGCHLD was caught as well.
+        reap();
+        for _ in signals.forever() {
+            reap();
+        }
+    });
+}
+
+/// Waits for the orphaned processes which exited, so they don't pile up
+/// as zombies. What localdeploy started itself is left alone, it is waited
+/// for where it was started.
+#[cfg(target_os = "linux")]
+fn reap() {
+    let started = child::started();
+    for pid in children() {
+        if started.contains(&pid) {
+            continue;
+        }
+        let mut status = 0;
+        if unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) } == pid as i32 {
+            debug!("reaped the orphaned process {}", pid);
+        }
+    }
+}
+
+/// The children of every thread of localdeploy, the orphans it adopted
+/// among them.
+#[cfg(target_os = "linux")]
+fn children() -> Vec<u32> {
+    let tasks = match std::fs::read_dir("/proc/self/task") {
+        Ok(tasks) => tasks,
+        Err(_) => return Vec::new(),
+    };
+    tasks
+        .filter_map(|task| task.ok())
+        .filter_map(|task| std::fs::read_to_string(task.path().join("children")).ok())
+        .flat_map(|children| {
+            children
+                .split_whitespace()
+                .filter_map(|pid| pid.parse().ok())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::{
+        process::Command,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{adopt, children};
+    use crate::child;
+
+    #[test]
+    fn reaps_only_orphans() {
+        adopt();
+        for _ in 0..100 {
+            let output =
+                child::output(Command::new("sh").args(["-c", "sleep 0 & exit 3"])).unwrap();
+            assert_eq!(output.status.code(), Some(3));
+        }
+        // The sleeps were orphaned to here and are reaped as well.
+        let started = Instant::now();
+        while !children().is_empty() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "{:?}",
+                children()
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine,
+};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::error::Result;
+
+/// What `known_hosts` says about the key a host presented.
+#[derive(Debug)]
+pub(crate) enum Known {
+    Match,
+    /// The host isn't listed with a key of this type.
+    Unknown,
+    /// The host is listed with other keys of this type, their fingerprints.
+    Mismatch(Vec<String>),
+    Revoked,
+}
+
+/// The `SHA256:...` fingerprint of a key blob, as `ssh-keygen -l` prints it.
+pub(crate) fn fingerprint(key: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(key)))
+}
+
+/// Looks `host`, `[host]:port` for other ports than 22, up in the known hosts
+/// file at `path`. Hashed names and `*`/`?` patterns are understood, lines of
+/// certificate authorities are skipped. A missing file knows no host.
+pub(crate) fn check(path: &Path, host: &str, key_type: &str, key: &[u8]) -> Result<Known> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Known::Unknown),
+        Err(err) => return Err(err.into()),
+    };
+    let host = host.to_lowercase();
+    let mut matched = false;
+    let mut others = Vec::new();
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let (marker, hosts) = match fields.next() {
+            Some(first) if first.starts_with('#') => continue,
+            Some(first) if first.starts_with('@') => (Some(first), fields.next()),
+            first => (None, first),
+        };
+        let (hosts, line_type, line_key) = match (hosts, fields.next(), fields.next()) {
+            (Some(hosts), Some(line_type), Some(line_key)) => (hosts, line_type, line_key),
+            _ => continue,
+        };
+        if marker == Some("@cert-authority") || !matches_hosts(hosts, &host) {
+            continue;
+        }
+        let line_key = match STANDARD.decode(line_key) {
+            Ok(line_key) => line_key,
+            Err(_) => continue,
+        };
+        match marker {
+            Some("@revoked") if line_key == key => return Ok(Known::Revoked),
+            Some(_) => {}
+            None if line_key == key => matched = true,
+            None if line_type == key_type => others.push(fingerprint(&line_key)),
+            None => {}
+        }
+    }
+    Ok(match (matched, others.is_empty()) {
+        (true, _) => Known::Match,
+        (false, true) => Known::Unknown,
+        (false, false) => Known::Mismatch(others),
+    })
+}
+
+/// Appends `host` with its key to the known hosts file at `path`.
+pub(crate) fn add(path: &Path, host: &str, key_type: &str, key: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{} {} {}", host, key_type, STANDARD.encode(key))?;
+    Ok(())
+}
+
+/// Whether the comma separated `patterns` of a line match `host`. A negated
+/// pattern excludes the host even if another one matches it.
+fn matches_hosts(patterns: &str, host: &str) -> bool {
+    if let Some(hashed) = patterns.strip_prefix("|1|") {
+        return matches_hashed(hashed, host);
+    }
+    let mut matched = false;
+    for pattern in patterns.split(',') {
+        let pattern = pattern.to_lowercase();
+        match pattern.strip_prefix('!') {
+            Some(negated) if wildcard(negated.as_bytes(), host.as_bytes()) => return false,
+            Some(_) => {}
+            None => matched |= wildcard(pattern.as_bytes(), host.as_bytes()),
+        }
+    }
+    matched
+}
+
+/// Hashed names are `|1|<salt>|<HMAC-SHA1 of the name>`, both base64.
+fn matches_hashed(hashed: &str, host: &str) -> bool {
+    let (salt, hash) = match hashed.split_once('|') {
+        Some((salt, hash)) => (STANDARD.decode(salt), STANDARD.decode(hash)),
+        None => return false,
+    };
+    match (salt, hash) {
+        (Ok(salt), Ok(hash)) => match Hmac::<Sha1>::new_from_slice(&salt) {
+            Ok(mut mac) => {
+                mac.update(host.as_bytes());
+                mac.verify_slice(&hash).is_ok()
+            }
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+/// ssh's patterns, where `*` matches any run of characters and `?` one.
+fn wildcard(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            wildcard(rest, name) || (!name.is_empty() && wildcard(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name))) => wildcard(rest, name),
+        (Some((expected, rest)), Some((found, name))) if expected == found => wildcard(rest, name),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use hmac::{Hmac, Mac};
+    use sha1::Sha1;
+
+    use super::{check, fingerprint, Known};
+
+    const KEY: &[u8] = b"key";
+    const OTHER: &[u8] = b"other key";
+
+    /// What a known hosts file of `lines` says about `host` presenting `KEY`.
+    fn known(lines: &[String], host: &str) -> Known {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "localdeploy-known-hosts-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, lines.join("\n")).unwrap();
+        let known = check(&path, host, "ssh-ed25519", KEY);
+        let _ = fs::remove_file(&path);
+        known.unwrap()
+    }
+
+    fn line(hosts: &str, key: &[u8]) -> String {
+        format!("{} ssh-ed25519 {}", hosts, STANDARD.encode(key))
+    }
+
+    fn hashed(host: &str) -> String {
+        let salt = b"salt of twenty bytes";
+        let mut mac = Hmac::<Sha1>::new_from_slice(salt).unwrap();
+        mac.update(host.as_bytes());
+        format!(
+            "|1|{}|{}",
+            STANDARD.encode(salt),
+            STANDARD.encode(mac.finalize().into_bytes())
+        )
+    }
+
+    #[test]
+    fn hashed_names() {
+        let lines = [line(&hashed("example.com"), KEY)];
+        assert!(matches!(known(&lines, "example.com"), Known::Match));
+        assert!(matches!(known(&lines, "example.org"), Known::Unknown));
+    }
+
+    #[test]
+    fn ports() {
+        let lines = [line("[example.com]:2222", KEY)];
+        assert!(matches!(known(&lines, "[example.com]:2222"), Known::Match));
+        assert!(matches!(known(&lines, "example.com"), Known::Unknown));
+        let lines = [line("[*.example.com]:*", KEY)];
+        assert!(matches!(
+            known(&lines, "[git.example.com]:2222"),
+            Known::Match
+        ));
+    }
+
+    #[test]
+    fn negated_patterns() {
+        let lines = [line("*.example.com,!secret.example.com", KEY)];
+        assert!(matches!(known(&lines, "git.example.com"), Known::Match));
+        assert!(matches!(
+            known(&lines, "secret.example.com"),
+            Known::Unknown
+        ));
+    }
+
+    #[test]
+    fn revoked() {
+        let lines = [
+            line("example.com", KEY),
+            format!("@revoked {}", line("*", KEY)),
+        ];
+        assert!(matches!(known(&lines, "example.com"), Known::Revoked));
+    }
+
+    #[test]
+    fn cert_authorities_are_skipped() {
+        let lines = [format!("@cert-authority {}", line("example.com", KEY))];
+        assert!(matches!(known(&lines, "example.com"), Known::Unknown));
+    }
+
+    #[test]
+    fn mismatch() {
+        let lines = [
+            line("example.com", OTHER),
+            format!("example.com ssh-rsa {}", STANDARD.encode(b"rsa key")),
+        ];
+        match known(&lines, "example.com") {
+            Known::Mismatch(fingerprints) => assert_eq!(fingerprints, [fingerprint(OTHER)]),
+            known => panic!("{:?}", known),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::{path::Path, process::Command};
+
+use git2::Oid;
+
+use crate::{
+    child,
+    error::{Error, Result},
+};
+
+/// Fails early if `git lfs` can't be run, instead of on the first deploy.
+pub(crate) fn check() -> Result<()> {
+    match child::output(Command::new("git").args(["lfs", "version"])) {
+        Ok(output) if output.status.success() => Ok(()),
+        _ => Err(Error::LfsMissing),
+    }
+}
+
+/// Downloads the LFS objects of `commit` before anything is touched, so a
+/// failing download leaves the running deploy alone.
+pub(crate) fn fetch(workdir: &Path, remote: &str, commit: Oid) -> Result<()> {
+    run(workdir, &["fetch", remote, &commit.to_string()])
+}
+
+/// Replaces the pointer files in the working tree with their content.
+pub(crate) fn pull(workdir: &Path) -> Result<()> {
+    run(workdir, &["pull"])
+}
+
+fn run(workdir: &Path, args: &[&str]) -> Result<()> {
+    let output = child::output(
+        Command::new("git")
+            .arg("lfs")
+            .args(args)
+            .current_dir(workdir),
+    )?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::Lfs(
+            format!("git lfs {}", args.join(" ")),
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ))
+    }
+}
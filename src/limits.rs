@@ -0,0 +1,166 @@
+use std::{fmt, process::Command};
+
+use crate::error::{Error, Result};
+
+/// The niceness and resource limits the command or the build runs with.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Limits {
+    pub nice: Option<i32>,
+    /// The most files it can have open.
+    pub nofile: Option<u64>,
+    /// The most address space in bytes.
+    pub address_space: Option<u64>,
+}
+
+impl fmt::Display for Limits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(nice) = self.nice {
+            parts.push(format!("nice {}", nice));
+        }
+        if let Some(nofile) = self.nofile {
+            parts.push(format!("nofile {}", nofile));
+        }
+        if let Some(address_space) = self.address_space {
+            parts.push(format!("as {}", address_space));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.nice.is_none() && self.nofile.is_none() && self.address_space.is_none()
+    }
+
+    /// The limits of `self`, those of `other` where `self` has none.
+    pub fn or(self, other: Limits) -> Limits {
+        Limits {
+            nice: self.nice.or(other.nice),
+            nofile: self.nofile.or(other.nofile),
+            address_space: self.address_space.or(other.address_space),
+        }
+    }
+
+    /// Fails if the limits, given with the `options` for the niceness, the
+    /// open files and the address space, can't be applied, so that shows at
+    /// startup instead of when the command starts.
+    #[cfg(unix)]
+    pub fn check(&self, [nice, nofile, address_space]: [&'static str; 3]) -> Result<()> {
+        let root = unsafe { libc::geteuid() } == 0;
+        if let Some(value) = self.nice {
+            if !(-20..=19).contains(&value) {
+                return Err(Error::InvalidArgument(
+                    nice,
+                    format!("{}, use -20 to 19", value),
+                ));
+            }
+            if value < 0 && !root {
+                return Err(Error::InvalidArgument(
+                    nice,
+                    format!("{}, a negative niceness needs root", value),
+                ));
+            }
+        }
+        for (option, resource, value) in [
+            (nofile, libc::RLIMIT_NOFILE, self.nofile),
+            (address_space, libc::RLIMIT_AS, self.address_space),
+        ] {
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+            let hard = hard_limit(resource);
+            if !root && hard != libc::RLIM_INFINITY && value as libc::rlim_t > hard {
+                return Err(Error::InvalidArgument(
+                    option,
+                    format!(
+                        "{}, above the hard limit of {}, raising it needs root",
+                        value, hard
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn check(&self, [nice, nofile, address_space]: [&'static str; 3]) -> Result<()> {
+        let option = match (self.nice, self.nofile, self.address_space) {
+            (Some(_), _, _) => nice,
+            (_, Some(_), _) => nofile,
+            (_, _, Some(_)) => address_space,
+            _ => return Ok(()),
+        };
+        Err(Error::InvalidArgument(
+            option,
+            "limits need unix".to_owned(),
+        ))
+    }
+
+    /// Makes `command` take on the limits before it is executed.
+    #[cfg(unix)]
+    pub fn apply(&self, command: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        if self.is_empty() {
+            return;
+        }
+        let limits = *self;
+        // The hard limits are read before, only async-signal-safe calls
+        // between fork and exec.
+        let hard = [hard_limit(libc::RLIMIT_NOFILE), hard_limit(libc::RLIMIT_AS)];
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(nice) = limits.nice {
+                    if libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                for (resource, value, hard) in [
+                    (libc::RLIMIT_NOFILE, limits.nofile, hard[0]),
+                    (libc::RLIMIT_AS, limits.address_space, hard[1]),
+                ] {
+                    if let Some(value) = value {
+                        let value = value as libc::rlim_t;
+                        // Only root gets here with a value above the hard
+                        // limit, see `check`.
+                        let limit = libc::rlimit {
+                            rlim_cur: value,
+                            rlim_max: match hard == libc::RLIM_INFINITY {
+                                true => hard,
+                                false => hard.max(value),
+                            },
+                        };
+                        if libc::setrlimit(resource, &limit) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply(&self, _command: &mut Command) {}
+}
+
+#[cfg(unix)]
+fn hard_limit(resource: LimitResource) -> libc::rlim_t {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    match unsafe { libc::getrlimit(resource, &mut limit) } {
+        0 => limit.rlim_max,
+        _ => libc::RLIM_INFINITY,
+    }
+}
+
+/// glibc has its own type for the resources of `getrlimit`.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type LimitResource = libc::__rlimit_resource_t;
+
+#[cfg(all(unix, not(all(target_os = "linux", target_env = "gnu"))))]
+type LimitResource = libc::c_int;
@@ -1,285 +1,5315 @@
 use std::{
-    env,
-    path::PathBuf,
-    process::{Child, Command, Stdio},
+    cell::RefCell,
+    collections::HashMap,
+    env, fs,
+    io::IsTerminal,
+    path::{Component, Path, PathBuf},
+    process::{Command, ExitStatus},
     str::FromStr,
+    sync::{Arc, Mutex},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use clap::{App, Arg, ArgMatches};
-use error::Error;
-use git2::{Cred, FetchOptions, RemoteCallbacks, Repository};
+use clap::{App, Arg, SubCommand};
+use config::Options;
+use error::{Error, StepError};
+use git2::{
+    build::CheckoutBuilder, BranchType, Commit, Direction, ErrorClass, ErrorCode, FetchOptions,
+    FetchPrune, Oid, Repository, ResetType, Status, StatusOptions, StatusShow,
+};
+use glob::Pattern;
+use regex::Regex;
 use rpassword::prompt_password_stdout;
+use semver::VersionReq;
+
+#[macro_use]
+mod log;
 
+mod branches;
+mod changes;
+mod child;
+mod clean;
+mod compose;
+mod config;
+mod connect;
+mod crash;
+mod credential;
+mod deploys;
+mod docker;
+mod dotenv;
 mod error;
+mod export;
+mod github_app;
+mod health;
+mod history;
+#[cfg(target_os = "linux")]
+mod init;
+mod known_hosts;
+mod lfs;
+mod limits;
+mod memory;
+mod output;
+mod paths;
+mod pid_file;
+mod procfile;
+mod progress;
+#[cfg(unix)]
+mod pty;
+mod refspec;
+mod releases;
+mod secret;
+mod shutdown;
+mod signature;
+mod sparse;
+mod stash;
+mod state;
+mod submodules;
+mod syslog;
+mod systemd;
+mod tags;
+mod template;
+mod timeout;
+mod user;
+mod words;
+
+use crate::{
+    child::{Running, Signal, Stdin},
+    compose::Compose,
+    connect::{Connect, HostCredentials, Proxy},
+    deploys::Deploy,
+    docker::Docker,
+    error::Result,
+    github_app::GithubApp,
+    history::Rollback,
+    limits::Limits,
+    output::{Capture, LogFile, LogFormat, ReadyLine, Rotation, Tail},
+    pid_file::PidFile,
+    progress::Progress,
+    sparse::Sparse,
+    state::{Outcome, State},
+    syslog::Sink,
+    systemd::Unit,
+    user::RunAs,
+};
+
+/// How often the command is checked for having exited between deploys.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A command exiting sooner than this after starting is restarted with a
+/// growing delay, so a crash loop doesn't spin.
+const QUICK_EXIT: Duration = Duration::from_secs(10);
+
+/// How often the memory of the command is measured for `--max-memory`.
+const MEMORY_SAMPLE: Duration = Duration::from_secs(5);
+
+/// How often a started command is checked for being ready.
+const READY_POLL: Duration = Duration::from_millis(100);
 
-use crate::error::Result;
+/// How often starting a command is tried again when it failed for a
+/// reason which passes, and how long to wait before each.
+const SPAWN_RETRIES: u32 = 2;
+const SPAWN_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// How long a command has to keep running after `--reload-signal` for the
+/// reload to count.
+const RELOAD_GRACE: Duration = Duration::from_secs(1);
+
+/// How long to wait between the health checks of a new deploy.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often a mistyped ssh passphrase is asked for again.
+const PASSPHRASE_ATTEMPTS: usize = 3;
 
 pub(crate) struct Main {
     origin: String,
+    /// Remotes tried in order when fetching from `origin` fails.
+    fallbacks: Vec<String>,
+    remote_urls: Vec<(String, String)>,
+    /// The remote the last fetch succeeded from.
+    fetched_from: Option<String>,
     branch: String,
     cmd: String,
     args: Vec<String>,
+    /// The `--command`s before the last, run one after the other before it
+    /// starts.
+    steps: Vec<(String, Vec<String>)>,
+    /// The variables of `--env` and `[env]`, for the command and everything
+    /// run alongside it.
+    env: Vec<(String, String)>,
+    /// Read again for every start, `--env` overrides them.
+    env_files: Vec<PathBuf>,
+    /// The `LOCALDEPLOY_*` variables describing the deploy on disk.
+    deploy_env: Vec<(&'static str, String)>,
+    /// What started the next deploy, for `LOCALDEPLOY_TRIGGER`.
+    trigger: &'static str,
+    /// Run after updating the checkout, before the command is restarted.
+    build: Option<(String, Vec<String>)>,
+    build_timeout: Duration,
+    /// The `--nice` and `--limit-*` of the command, and those of the build.
+    limits: Limits,
+    build_limits: Limits,
+    /// The commit whose build failed, which isn't built again.
+    failed_build: Option<Oid>,
+    pre_deploy_hooks: Vec<(String, Vec<String>)>,
+    post_deploy_hooks: Vec<(String, Vec<String>)>,
+    on_failure_hooks: Vec<(String, Vec<String>)>,
+    /// How long a deploy hook may run before it is killed.
+    hook_timeout: Duration,
+    /// Why the last deploy is degraded.
+    degraded: Option<String>,
+    health_check: Option<health::Check>,
+    /// How long one health check may take.
+    health_timeout: Duration,
+    /// How often a failed health check is tried again.
+    health_retries: u32,
+    ready: Option<health::Ready>,
+    /// How long the command may take to become ready.
+    ready_timeout: Duration,
+    /// Watches the output of the last started command for `--ready-log`.
+    ready_line: Option<Arc<ReadyLine>>,
+    /// The last lines on stderr of the current start of the command.
+    tail: Arc<Tail>,
+    /// With `--overlap` the two ports the versions of the command take
+    /// turns on.
+    ports: Option<(u16, u16)>,
+    /// The port the running command was given.
+    port: Option<u16>,
     repo_path: PathBuf,
-    child: Option<Child>,
+    child: Option<Running>,
     repo: Option<Repository>,
     interval: u64,
-    username: String,
-    public_key_path: PathBuf,
-    private_key_path: PathBuf,
-    passphrase: Option<String>,
+    connect: Connect,
+    git_timeout: Option<Duration>,
+    fetch_retries: u32,
+    fetch_backoff: Duration,
+    /// How long the command may take to exit before it is killed.
+    stop_timeout: Duration,
+    stop_signal: Signal,
+    stdin: Stdin,
+    /// Stop what the command started along with it.
+    process_group: bool,
+    /// The user of `--run-as` the command runs as.
+    run_as: Option<RunAs>,
+    /// Run the command in a pseudo-terminal.
+    pty: bool,
+    /// Syslog or journald of `--log-target`, for localdeploy's messages.
+    syslog: Option<Arc<Sink>>,
+    /// The `--pid-file` of localdeploy, removed when it exits.
+    pid_file: Option<PidFile>,
+    /// Where the pid of the command is kept while it runs.
+    child_pid_file: Option<PathBuf>,
+    /// Reap the orphaned processes, as PID 1 or with `--init`.
+    init: bool,
+    /// How many crash reports are kept in the `--log-dir`, none are written
+    /// with 0.
+    crash_reports: usize,
+    /// With `--docker` the command runs the container of the built image.
+    docker: Option<Docker>,
+    /// The image the running container was started from.
+    image: Option<String>,
+    /// With `--compose` the services run on their own, without a command.
+    compose: Option<Compose>,
+    /// With `--systemd-unit` the unit runs the service and is restarted.
+    unit: Option<Unit>,
+    /// The services of `--compose` or the `--systemd-unit` were started.
+    started: bool,
+    restart: Restart,
+    /// The `--process-restart` policies of the processes of the
+    /// `--procfile`.
+    process_restarts: Vec<(String, Restart)>,
+    /// The `--procfile` relative to the directory of the command, and the
+    /// processes of `--only`.
+    procfile: Option<PathBuf>,
+    only: Vec<String>,
+    /// The first process of the `--procfile`, which runs as the command,
+    /// and its log.
+    process_name: Option<String>,
+    process_log: Option<Capture>,
+    /// The other processes of the `--procfile`.
+    processes: Vec<Process>,
+    /// The `--shell` the command lines of the `--procfile` run with.
+    shell: Option<String>,
+    /// The command exited on its own and this was handled.
+    exit_handled: bool,
+    /// When `--restart` starts the command again.
+    restart_at: Option<Instant>,
+    /// Restarts in a row of a command which exited soon after starting.
+    quick_exits: u32,
+    max_memory: Option<u64>,
+    /// How long the command may use more than `max_memory`.
+    max_memory_for: Duration,
+    memory_sampled: Option<Instant>,
+    /// Since when the command uses more than `max_memory`.
+    memory_over: Option<Instant>,
+    reset_hard: bool,
+    always_restart: bool,
+    on_diverge: OnDiverge,
+    clean: bool,
+    clean_ignored: bool,
+    force_dirty: bool,
+    dirty_untracked: bool,
+    autostash: bool,
+    submodules: bool,
+    recurse_submodules: bool,
+    lfs: bool,
+    sparse: Option<Sparse>,
+    command_dir: Option<PathBuf>,
+    /// Where the output of the command goes, with `--log-dir`.
+    log: Option<Capture>,
+    deploy_dir: Option<PathBuf>,
+    bare: bool,
+    releases: Option<usize>,
+    release_worktrees: bool,
+    /// The new release while it is started and checked, `current` only
+    /// points at it once it is healthy.
+    release: Option<PathBuf>,
+    depth: Option<i32>,
+    tag_pattern: Option<Pattern>,
+    tag_semver: Option<VersionReq>,
+    include_prerelease: bool,
+    commit: Option<String>,
+    branch_pattern: Option<Pattern>,
+    watch_paths: Vec<Pattern>,
+    /// Sent to the command for a deploy instead of restarting it.
+    reload_signal: Option<Signal>,
+    /// The paths a reload picks up, changes to others restart the command.
+    reload_paths: Vec<Pattern>,
+    skip_markers: Vec<String>,
+    skip_advances_checkout: bool,
+    skipped: Option<Oid>,
+    prune: bool,
+    preflight: bool,
+    refspecs: Vec<String>,
+    checkout_ref: Option<String>,
+    deleted_upstream: bool,
+    verify_signatures: Option<PathBuf>,
+    verify_tag_signatures: Option<PathBuf>,
+    deployed: Option<Oid>,
+    /// The checkouts of `--deploy`, which share this repo.
+    deploys: Vec<Main>,
+    rollback: Option<Rollback>,
+    state_file: Option<PathBuf>,
+    reclone_on_corruption: bool,
+    respect_detached: bool,
+    /// Where HEAD was found detached by someone else.
+    detached_at: Option<Oid>,
+    /// The url of the remote when starting, to clone a corrupted repo again.
+    url: Option<String>,
+}
+
+impl Drop for Main {
+    /// Stops the command when localdeploy exits, also after an error.
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            child.stop(self.stop_signal, self.stop_timeout);
+        }
+        self.stop_processes();
+        // A docker killed after the stop timeout leaves its container.
+        if let (Some(docker), Some(cidfile)) = (&self.docker, self.cidfile()) {
+            docker.remove(&cidfile);
+        }
+    }
+}
+
+/// A process of the `--procfile` after the first, which runs as the
+/// command. It is started and stopped along with the command and restarted
+/// on its own by its restart policy.
+struct Process {
+    name: String,
+    cmd: String,
+    args: Vec<String>,
+    restart: Restart,
+    /// Its own log file with `--log-dir`.
+    log: Option<Capture>,
+    child: Option<Running>,
+    exit_handled: bool,
+    restart_at: Option<Instant>,
+    quick_exits: u32,
+    tail: Arc<Tail>,
+}
+
+/// The commit a deploy cycle brings the working tree to.
+#[derive(Debug, Clone)]
+pub(crate) struct Target {
+    oid: Oid,
+    tag: Option<String>,
+    branch: Option<String>,
+}
+
+/// What to do when the fetched branch is not a descendant of the local one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OnDiverge {
+    Fail,
+    Reset,
+    Reclone,
+}
+
+/// `--restart`: when a command which exited on its own is started again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Restart {
+    Always,
+    OnFailure,
+    Never,
+}
+
+impl FromStr for Restart {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => Ok(Restart::Always),
+            "on-failure" => Ok(Restart::OnFailure),
+            "never" => Ok(Restart::Never),
+            _ => Err(Error::InvalidArgument("restart", s.to_owned())),
+        }
+    }
+}
+
+impl FromStr for OnDiverge {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fail" => Ok(OnDiverge::Fail),
+            "reset" => Ok(OnDiverge::Reset),
+            "reclone" => Ok(OnDiverge::Reclone),
+            _ => Err(Error::InvalidArgument("on-diverge", s.to_owned())),
+        }
+    }
 }
 
 impl Main {
-    pub fn new(app: ArgMatches) -> Result<Self> {
-        let origin = app.value_of("origin").unwrap_or("origin").to_owned();
-        let branch = app.value_of("branch").unwrap_or("main").to_owned();
-        let command = match app.value_of("command") {
-            Some(r) => r.to_owned(),
-            None => return Err(Error::MissingCommand),
-        };
-        let repo_path = match app.value_of("path") {
-            Some(path) => PathBuf::from_str(path).unwrap().to_owned(),
-            None => env::current_dir()?.to_owned(),
+    pub fn new(app: Options) -> Result<Self> {
+        let deploys = app
+            .values_of("deploy")
+            .iter()
+            .map(|deploy| deploy.parse())
+            .collect::<Result<Vec<Deploy>>>()?;
+        let branch = app.value_of("branch");
+        let mut commands = app.values_of("command");
+        if commands.is_empty() {
+            commands = app.values_of("commands");
+        }
+        if commands.is_empty()
+            && deploys.is_empty()
+            && !app.is_present("procfile")
+            && !app.is_present("docker")
+            && !app.is_present("compose")
+            && !app.is_present("systemd-unit")
+        {
+            return Err(Error::MissingCommand);
+        }
+        // `--new` without `--path` clones into a directory named after the
+        // repo.
+        let derived = !app.is_present("path") && app.is_present("new");
+        let repo_path = match (app.value_of("path"), app.value_of("new")) {
+            (Some(path), _) => paths::resolve(&path),
+            (None, Some(url)) if derived => {
+                let name = repo_name(&url).ok_or(Error::MissingPath)?;
+                let path = env::current_dir()?.join(name);
+                if path
+                    .read_dir()
+                    .is_ok_and(|mut entries| entries.next().is_some())
+                {
+                    return Err(Error::PathNotEmpty(path));
+                }
+                info!("cloning into {}", path.display());
+                path
+            }
+            (None, _) => env::current_dir()?,
         };
+        let mut _self = Main::configure(&app, branch.clone(), commands, repo_path)?;
+        if let Some(sink) = &_self.syslog {
+            log::set_sink(sink.clone());
+        }
+        // Only localdeploy itself and the command of its own, not those of
+        // the checkouts of `--deploy`.
+        if let Some(path) = app.value_of("pid-file") {
+            _self.pid_file = Some(PidFile::create(&paths::resolve(&path), &_self.repo_path)?);
+        }
+        _self.child_pid_file = app
+            .value_of("child-pid-file")
+            .as_deref()
+            .map(paths::resolve);
+        _self.init = app.is_present("init") || std::process::id() == 1;
+        if _self.init && cfg!(not(target_os = "linux")) {
+            return Err(Error::InvalidArgument(
+                "init",
+                "reaping orphaned processes needs linux".to_owned(),
+            ));
+        }
+        #[cfg(target_os = "linux")]
+        if _self.init {
+            init::adopt();
+            verbose!("reaping orphaned processes as init");
+        }
+        _self.log_paths();
+        if _self.lfs {
+            lfs::check()?;
+        }
+        let new = app.value_of("new").map(|new| connect::absolute_url(&new));
+        _self.connect.local = new.as_deref().is_some_and(connect::is_local);
 
-        let public_key_path = if let Some(path) = app.value_of("public-key") {
-            PathBuf::from_str(path).expect("Parsing PathBuf failed")
+        // The clone needs the agent right away, an existing repo once its
+        // remote is known.
+        if _self.connect.agent_only && new.is_some() && !_self.connect.local {
+            connect::check_agent()?;
+        }
+        _self.read_credentials(&app, new.as_deref(), !deploys.is_empty())?;
+        // With `--deploy` the path holds the checkouts, the repo is next to
+        // them.
+        let shared = !deploys.is_empty();
+        let repo_path = if shared {
+            _self.repo_path.join(deploys::SHARED_REPO)
         } else {
-            PathBuf::from_str(&format!("{}/.ssh/id_rsa.pub", env::var("HOME")?))
-                .expect("Parsing PathBuf failed")
+            _self.repo_path.clone()
         };
-        let private_key_path = if let Some(path) = app.value_of("private-key") {
-            PathBuf::from_str(path).expect("Parsing PathBuf failed")
-        } else {
-            PathBuf::from_str(&format!("{}/.ssh/id_rsa", env::var("HOME")?))
-                .expect("Parsing PathBuf failed")
+        let repo = match (app.is_present("new"), app.is_present("path") || derived) {
+            (true, true) => {
+                let new = match new {
+                    Some(new) => new,
+                    None => return Err(Error::MissingUrlToRepo),
+                };
+                // An exported deploy doesn't need a working tree.
+                let bare = shared || _self.deploy_dir.is_some();
+                _self.new_repo(&new, &repo_path, bare)?
+            }
+            (true, false) => return Err(Error::MissingPath),
+            (false, true) if shared => Repository::open(&repo_path)?,
+            (false, true) if _self.bare => {
+                let repo = Repository::open(&repo_path)?;
+                if !repo.is_bare() {
+                    return Err(Error::InvalidArgument(
+                        "bare",
+                        format!("{} has a working tree", repo_path.display()),
+                    ));
+                }
+                repo
+            }
+            (false, true) => Repository::discover(repo_path)?,
+
+            (false, false) => return Err(Error::MissingPath),
+        };
+        _self.reconcile_sparse(&repo)?;
+        _self.rollback = history::rollback(&history::load(&repo)?);
+        _self.load_state(&repo);
+        _self.add_remotes(&repo)?;
+        _self.url = repo
+            .find_remote(&_self.origin)
+            .ok()
+            .and_then(|remote| remote.url().map(str::to_owned));
+        _self.connect.local = _self.url.as_deref().is_some_and(connect::is_local);
+        if _self.connect.agent_only && !app.is_present("new") && !_self.connect.local {
+            connect::check_agent()?;
+        }
+        _self.repo = Some(repo);
+
+        if shared {
+            _self.add_deploys(&app, deploys)?;
+        } else if branch.is_none() && _self.branch_pattern.is_none() && !_self.detached() {
+            _self.branch = _self.default_branch()?;
+        }
+        Ok(_self)
+    }
+
+    /// Creates the checkout of each `--deploy` after fetching their branches
+    /// into the shared repo.
+    fn add_deploys(&mut self, app: &Options, deploys: Vec<Deploy>) -> Result<()> {
+        if self.on_diverge == OnDiverge::Reclone {
+            return Err(Error::InvalidArgument("on-diverge", "reclone".to_owned()));
+        }
+        self.deploys = deploys
+            .iter()
+            .map(|deploy| {
+                Main::configure(
+                    app,
+                    Some(deploy.branch.clone()),
+                    vec![deploy.command.clone()],
+                    self.repo_path.join(&deploy.dir),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.fetch_git_repo(&self.origin.clone())?;
+
+        let repo = match &self.repo {
+            Some(repo) => repo,
+            None => return Ok(()),
+        };
+        for deploy in &mut self.deploys {
+            deploy.connect.passphrase = self.connect.passphrase.clone();
+            deploy.connect.password = self.connect.password.clone();
+            deploy.connect.github_app = self.connect.github_app.clone();
+            deploy.connect.local = self.connect.local;
+            if let Some(log) = &mut deploy.log {
+                log.label = deploy
+                    .repo_path
+                    .strip_prefix(&self.repo_path)
+                    .ok()
+                    .map(|dir| dir.display().to_string());
+            }
+            let worktree =
+                deploys::worktree(repo, &self.origin, &deploy.branch, &deploy.repo_path)?;
+            deploy.reconcile_sparse(&worktree)?;
+            deploy.rollback = history::rollback(&history::load(&worktree)?);
+            deploy.load_state(&worktree);
+            deploy.repo = Some(worktree);
+        }
+        Ok(())
+    }
+
+    /// Parses the options shared by all deployments, without opening the
+    /// repo.
+    fn configure(
+        app: &Options,
+        branch: Option<String>,
+        mut commands: Vec<String>,
+        repo_path: PathBuf,
+    ) -> Result<Self> {
+        let mut remotes = app.values_of("remote");
+        let origin = match remotes.is_empty() {
+            true => "origin".to_owned(),
+            false => remotes.remove(0),
+        };
+        let remote_urls = app
+            .values_of("remote-url")
+            .into_iter()
+            .map(|value| match value.split_once('=') {
+                Some((name, url)) if !name.is_empty() && !url.is_empty() => {
+                    Ok((name.to_owned(), url.to_owned()))
+                }
+                _ => Err(Error::InvalidArgument("remote-url", value)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let private_keys = app.values_of("private-key");
+        let public_keys = app.values_of("public-key");
+        let (keys, probed) = match private_keys.is_empty() && public_keys.is_empty() {
+            true if !app.is_present("agent-only") => connect::probe_ssh_keys(),
+            true => (Vec::new(), Vec::new()),
+            false => (connect::ssh_keys(&private_keys, &public_keys)?, Vec::new()),
         };
+        let hosts = app
+            .tables("credentials")?
+            .iter()
+            .map(HostCredentials::from_table)
+            .collect::<Result<Vec<_>>>()?;
         let interval = match app.value_of("interval") {
             Some(r) => r.parse::<u64>().unwrap_or(3600),
             None => 3600,
         };
-        let username = app.value_of("username").unwrap_or("").to_owned();
-        let (cmd, args) = Main::parse_cmd_args(command)?;
+        let depth = match app.value_of("depth") {
+            Some(value) => match value.parse::<i32>() {
+                Ok(depth) if depth > 0 => Some(depth),
+                _ => return Err(Error::InvalidArgument("depth", value)),
+            },
+            None => None,
+        };
+        let git_timeout = match app.value_of("git-timeout") {
+            Some(value) => match parse_duration(&value) {
+                Some(timeout) if !timeout.is_zero() => Some(timeout),
+                _ => return Err(Error::InvalidArgument("git-timeout", value)),
+            },
+            None => None,
+        };
+        let fetch_retries = match app.value_of("fetch-retries") {
+            Some(value) => match value.parse::<u32>() {
+                Ok(retries) => retries,
+                Err(_) => return Err(Error::InvalidArgument("fetch-retries", value)),
+            },
+            None => 3,
+        };
+        let releases = match app.value_of("releases") {
+            Some(value) => match value.parse::<usize>() {
+                Ok(keep) if keep > 0 => Some(keep),
+                _ => return Err(Error::InvalidArgument("releases", value)),
+            },
+            None => None,
+        };
+        let fetch_backoff = match app.value_of("fetch-backoff") {
+            Some(value) => match parse_duration(&value) {
+                Some(backoff) => backoff,
+                None => return Err(Error::InvalidArgument("fetch-backoff", value)),
+            },
+            None => Duration::from_secs(1),
+        };
+        let stop_timeout = match app.value_of("stop-timeout") {
+            Some(value) => match parse_duration(&value) {
+                Some(timeout) => timeout,
+                None => return Err(Error::InvalidArgument("stop-timeout", value)),
+            },
+            None => Duration::from_secs(10),
+        };
+        // The config file first, so `--env` overrides it.
+        let mut env: Vec<(String, String)> = Vec::new();
+        let configured = app
+            .table("env")?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, value)| match value {
+                toml::Value::String(value) => (key, value),
+                value => (key, value.to_string()),
+            });
+        let given = app
+            .values_of("env")
+            .into_iter()
+            .map(|value| match value.split_once('=') {
+                Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+                _ => Err(Error::InvalidArgument("env", value)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for (key, value) in configured.chain(given) {
+            env.retain(|(other, _)| *other != key);
+            env.push((key, value));
+        }
+        let env_files = app
+            .values_of("env-file")
+            .iter()
+            .map(|path| paths::resolve(path))
+            .collect::<Vec<_>>();
+        // Broken files are found at startup rather than at the first deploy.
+        for path in &env_files {
+            dotenv::load(path)?;
+        }
+        let shell = match (app.is_present("shell"), app.value_of("shell-path")) {
+            (true, Some(path)) => Some(path),
+            (true, None) if cfg!(windows) => Some("cmd".to_owned()),
+            (true, None) => Some("sh".to_owned()),
+            (false, _) => None,
+        };
+        let shell = shell.as_deref();
+        let process_restarts = app
+            .values_of("process-restart")
+            .into_iter()
+            .map(|value| match value.split_once('=') {
+                Some((name, policy)) if !name.is_empty() => Ok((name.to_owned(), policy.parse()?)),
+                _ => Err(Error::InvalidArgument("process-restart", value)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let build = match app.value_of("build-command") {
+            Some(command) => Some(split_command("build-command", command, shell)?),
+            None => None,
+        };
+        let hooks = |option| {
+            app.values_of(option)
+                .into_iter()
+                .map(|command| split_command(option, command, shell))
+                .collect::<Result<Vec<_>>>()
+        };
+        let pre_deploy_hooks = hooks("pre-deploy-hook")?;
+        let post_deploy_hooks = hooks("post-deploy-hook")?;
+        let on_failure_hooks = hooks("on-failure-hook")?;
+        let build_timeout = match app.value_of("build-timeout") {
+            Some(value) => match parse_duration(&value) {
+                Some(timeout) => timeout,
+                None => return Err(Error::InvalidArgument("build-timeout", value)),
+            },
+            None => Duration::from_secs(10 * 60),
+        };
+        let hook_timeout = match app.value_of("hook-timeout") {
+            Some(value) => match parse_duration(&value) {
+                Some(timeout) => timeout,
+                None => return Err(Error::InvalidArgument("hook-timeout", value)),
+            },
+            None => Duration::from_secs(5 * 60),
+        };
+        let limits = parse_limits(app, ["nice", "limit-nofile", "limit-as"])?;
+        let build_limits =
+            parse_limits(app, ["build-nice", "build-limit-nofile", "build-limit-as"])?.or(limits);
+        limits.check(["nice", "limit-nofile", "limit-as"])?;
+        build_limits.check(["build-nice", "build-limit-nofile", "build-limit-as"])?;
+        let health_check = match (
+            app.value_of("health-check"),
+            app.value_of("health-check-cmd"),
+        ) {
+            (Some(url), _) => Some(health::Check::Url(url)),
+            (None, Some(command)) => Some(health::Check::Command(split_command(
+                "health-check-cmd",
+                command,
+                shell,
+            )?)),
+            (None, None) => None,
+        };
+        let health_timeout = match app.value_of("health-timeout") {
+            Some(value) => match parse_duration(&value) {
+                Some(timeout) if !timeout.is_zero() => timeout,
+                _ => return Err(Error::InvalidArgument("health-timeout", value)),
+            },
+            None => Duration::from_secs(10),
+        };
+        let health_retries = match app.value_of("health-retries") {
+            Some(value) => match value.parse() {
+                Ok(retries) => retries,
+                Err(_) => return Err(Error::InvalidArgument("health-retries", value)),
+            },
+            None => 5,
+        };
+        let ready = match (
+            app.value_of("ready-tcp"),
+            app.value_of("ready-log"),
+            app.value_of("ready-delay"),
+        ) {
+            (Some(address), _, _) => Some(health::Ready::Tcp(address)),
+            (None, Some(pattern), _) => match Regex::new(&pattern) {
+                Ok(pattern) => Some(health::Ready::Log(pattern)),
+                Err(_) => return Err(Error::InvalidArgument("ready-log", pattern)),
+            },
+            (None, None, Some(value)) => match parse_duration(&value) {
+                Some(delay) => Some(health::Ready::Delay(delay)),
+                None => return Err(Error::InvalidArgument("ready-delay", value)),
+            },
+            (None, None, None) => None,
+        };
+        let ready_timeout = match app.value_of("ready-timeout") {
+            Some(value) => match parse_duration(&value) {
+                Some(timeout) => timeout,
+                None => return Err(Error::InvalidArgument("ready-timeout", value)),
+            },
+            None => Duration::from_secs(60),
+        };
+        let port = |option| match app.value_of(option) {
+            Some(value) => match value.parse::<u16>() {
+                Ok(port) => Ok(Some(port)),
+                Err(_) => Err(Error::InvalidArgument(option, value)),
+            },
+            None => Ok(None),
+        };
+        let ports = match (app.is_present("overlap"), port("port-a")?, port("port-b")?) {
+            (false, _, _) => None,
+            (true, _, _) if health_check.is_none() && ready.is_none() => {
+                return Err(Error::InvalidArgument(
+                    "overlap",
+                    "needs --health-check, --health-check-cmd or a --ready-* option".to_owned(),
+                ))
+            }
+            (true, Some(a), Some(b)) if a != b => Some((a, b)),
+            (true, _, _) => {
+                return Err(Error::InvalidArgument(
+                    "overlap",
+                    "needs two different ports with --port-a and --port-b".to_owned(),
+                ))
+            }
+        };
+        let max_memory = match app.value_of("max-memory") {
+            Some(_) if !memory::SUPPORTED => {
+                warn!("--max-memory can't measure memory on this platform, it is ignored");
+                None
+            }
+            Some(value) => match parse_size(&value) {
+                Some(size) if size > 0 => Some(size),
+                _ => return Err(Error::InvalidArgument("max-memory", value)),
+            },
+            None => None,
+        };
+        let max_memory_for = match app.value_of("max-memory-for") {
+            Some(value) => match parse_duration(&value) {
+                Some(duration) => duration,
+                None => return Err(Error::InvalidArgument("max-memory-for", value)),
+            },
+            None => Duration::from_secs(30),
+        };
+        let stdin = match app.value_of("stdin") {
+            Some(value) => value.parse()?,
+            None => Stdin::Null,
+        };
+        let stop_signal = match app.value_of("stop-signal") {
+            Some(value) => value.parse()?,
+            None => Signal::TERM,
+        };
+        let run_as = match app.value_of("run-as") {
+            Some(value) => Some(value.parse::<RunAs>()?),
+            None => None,
+        };
+        let pty = app.is_present("pty");
+        if pty && cfg!(not(unix)) {
+            return Err(Error::InvalidArgument(
+                "pty",
+                "pseudo-terminals need unix".to_owned(),
+            ));
+        }
+        let username = app.value_of("username").unwrap_or_default();
+        for command in &commands {
+            template::check("command", command)?;
+        }
+        for option in [
+            "build-command",
+            "pre-deploy-hook",
+            "post-deploy-hook",
+            "on-failure-hook",
+            "health-check",
+            "health-check-cmd",
+            "ready-tcp",
+            "docker-image",
+            "docker-run-args",
+        ] {
+            for value in app.values_of(option) {
+                template::check(option, &value)?;
+            }
+        }
+        if commands.iter().any(|command| command.trim().is_empty()) {
+            return Err(Error::MissingCommand);
+        }
+        let (cmd, args) = match commands.pop() {
+            Some(command) => split_command("command", command, shell)?,
+            None => (String::new(), Vec::new()),
+        };
+        // The ones before the last run to completion first.
+        let steps = commands
+            .into_iter()
+            .map(|command| split_command("command", command, shell))
+            .collect::<Result<Vec<_>>>()?;
+        let tag_pattern = match app.value_of("tag-pattern") {
+            Some(glob) => match Pattern::new(&glob) {
+                Ok(pattern) => Some(pattern),
+                Err(_) => return Err(Error::InvalidArgument("tag-pattern", glob)),
+            },
+            None => None,
+        };
+        let branch_pattern = match app.value_of("branch-pattern") {
+            Some(glob) => match Pattern::new(&glob) {
+                Ok(pattern) => Some(pattern),
+                Err(_) => return Err(Error::InvalidArgument("branch-pattern", glob)),
+            },
+            None => None,
+        };
+        let mut watch_paths = Vec::new();
+        for glob in app.values_of("watch-path") {
+            match Pattern::new(&glob) {
+                Ok(pattern) => watch_paths.push(pattern),
+                Err(_) => return Err(Error::InvalidArgument("watch-path", glob)),
+            }
+        }
+        let reload_signal = match app.value_of("reload-signal") {
+            Some(value) => Some(Signal::parse("reload-signal", &value)?),
+            None => None,
+        };
+        let mut reload_paths = Vec::new();
+        for glob in app.values_of("reload-path") {
+            match Pattern::new(&glob) {
+                Ok(pattern) => reload_paths.push(pattern),
+                Err(_) => return Err(Error::InvalidArgument("reload-path", glob)),
+            }
+        }
+        let mut skip_markers = app.values_of("skip-marker");
+        if skip_markers.is_empty() {
+            skip_markers = vec!["[skip deploy]".to_owned(), "[deploy skip]".to_owned()];
+        }
+        let tag_semver = if app.is_present("tag-semver") {
+            let req = app.value_of("tag-semver").unwrap_or_else(|| "*".to_owned());
+            match VersionReq::parse(&req) {
+                Ok(req) => Some(req),
+                Err(_) => return Err(Error::InvalidArgument("tag-semver", req)),
+            }
+        } else {
+            None
+        };
+        let commit = match app.value_of("commit") {
+            Some(sha) if sha.len() < 4 || !sha.chars().all(|c| c.is_ascii_hexdigit()) => {
+                return Err(Error::InvalidArgument("commit", sha))
+            }
+            commit => commit,
+        };
+        // Checked once resolved, `~/.ssh/allowed_signers` isn't a file as it
+        // is given.
+        let verify_signatures = match app.value_of("verify-signatures") {
+            Some(path) => match paths::resolve(&path) {
+                resolved if resolved.is_file() => Some(resolved),
+                _ => return Err(Error::InvalidArgument("verify-signatures", path)),
+            },
+            None => None,
+        };
+        let verify_tag_signatures = if app.is_present("verify-tag-signatures") {
+            match app.value_of("verify-tag-signatures") {
+                Some(path) => match paths::resolve(&path) {
+                    resolved if resolved.is_file() => Some(resolved),
+                    _ => return Err(Error::InvalidArgument("verify-tag-signatures", path)),
+                },
+                None => match &verify_signatures {
+                    Some(path) => Some(path.clone()),
+                    None => return Err(Error::MissingSigningKeys),
+                },
+            }
+        } else {
+            None
+        };
+        let refspecs = app.values_of("refspec");
+        for spec in &refspecs {
+            refspec::validate(spec)?;
+        }
+        let checkout_ref = match app.value_of("checkout-ref") {
+            Some(name)
+                if !refspecs
+                    .iter()
+                    .any(|spec| refspec::fetches_into(spec, &name)) =>
+            {
+                return Err(Error::InvalidArgument("checkout-ref", name))
+            }
+            Some(name) => Some(name),
+            // A single refspec fetching one ref is checked out by default.
+            None => match refspecs.as_slice() {
+                [spec] => match refspec::destination(spec) {
+                    Some(name) => Some(name.to_owned()),
+                    None => return Err(Error::MissingCheckoutRef),
+                },
+                [] => None,
+                _ => return Err(Error::MissingCheckoutRef),
+            },
+        };
+        let sparse = match app.values_of("sparse-path") {
+            patterns if patterns.is_empty() => None,
+            patterns => Some(Sparse::new(patterns)?),
+        };
+        let command_dir = match app.value_of("command-dir") {
+            Some(dir)
+                if !Path::new(&dir).components().all(|component| {
+                    matches!(component, Component::Normal(_) | Component::CurDir)
+                }) =>
+            {
+                return Err(Error::InvalidArgument("command-dir", dir))
+            }
+            dir => dir.map(PathBuf::from),
+        };
+        let target = app
+            .value_of("log-target")
+            .unwrap_or_else(|| "file".to_owned());
+        let project = repo_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "localdeploy".to_owned());
+        let docker = match app.is_present("docker") {
+            true if !cmd.is_empty() || build.is_some() || app.is_present("procfile") => {
+                return Err(Error::InvalidArgument(
+                    "docker",
+                    "runs the image in place of --command, --build-command and --procfile"
+                        .to_owned(),
+                ))
+            }
+            true => Some(Docker {
+                image: app
+                    .value_of("docker-image")
+                    .unwrap_or_else(|| format!("{}:{{{{sha}}}}", docker::name(&project))),
+                container: match app.value_of("container-name") {
+                    Some(name) => name,
+                    None => docker::name(&project),
+                },
+                run_args: match app.value_of("docker-run-args") {
+                    Some(line) => words::split(&line).map_err(|reason| {
+                        Error::InvalidArgument("docker-run-args", format!("{}: {}", line, reason))
+                    })?,
+                    None => Vec::new(),
+                },
+            }),
+            false => None,
+        };
+        let compose = match (app.is_present("compose"), app.value_of("compose")) {
+            (true, _) if !cmd.is_empty() || app.is_present("procfile") => {
+                return Err(Error::InvalidArgument(
+                    "compose",
+                    "runs the services in place of --command and --procfile".to_owned(),
+                ))
+            }
+            (true, file) => Some(Compose::new(
+                file.map(PathBuf::from),
+                app.values_of("compose-profile"),
+                app.value_of("compose-health-service"),
+                &project,
+            )),
+            (false, _) => None,
+        };
+        let unit = match app.value_of("systemd-unit") {
+            Some(_) if !cmd.is_empty() || app.is_present("procfile") => {
+                return Err(Error::InvalidArgument(
+                    "systemd-unit",
+                    "restarts the unit in place of --command and --procfile".to_owned(),
+                ))
+            }
+            Some(name) if name.trim().is_empty() => {
+                return Err(Error::InvalidArgument("systemd-unit", name))
+            }
+            Some(name) => Some(Unit {
+                name,
+                user: app.is_present("systemd-user"),
+            }),
+            None => None,
+        };
+        // The image is built in place of the `--build-command` and run as
+        // the command.
+        let (cmd, build) = match &docker {
+            Some(docker) => ("docker".to_owned(), Some(docker.build())),
+            None => (cmd, build),
+        };
+        let syslog = match target.parse()? {
+            syslog::Target::File => None,
+            parsed => match Sink::connect(parsed, &project) {
+                Ok(sink) => Some(Arc::new(sink)),
+                Err(err) => {
+                    warn!("can't reach {}, logging to files: {}", target, err);
+                    None
+                }
+            },
+        };
+        // With `--deploy` the checkouts run the commands.
+        let runs =
+            !cmd.is_empty() || app.is_present("procfile") || compose.is_some() || unit.is_some();
+        let log = match app.value_of("log-dir") {
+            Some(dir) if runs => {
+                let max_size = match app.value_of("log-max-size") {
+                    Some(value) => match parse_size(&value) {
+                        Some(size) if size > 0 => Some(size),
+                        _ => return Err(Error::InvalidArgument("log-max-size", value)),
+                    },
+                    None => None,
+                };
+                let daily = match app.value_of("log-rotate") {
+                    Some(value) if value == "daily" => true,
+                    Some(value) => return Err(Error::InvalidArgument("log-rotate", value)),
+                    None => false,
+                };
+                let keep = match app.value_of("log-keep") {
+                    Some(value) => match value.parse::<usize>() {
+                        Ok(keep) => keep,
+                        Err(_) => return Err(Error::InvalidArgument("log-keep", value)),
+                    },
+                    None => 5,
+                };
+                let rotation = Rotation {
+                    max_size,
+                    daily,
+                    keep,
+                    compress: app.is_present("log-compress"),
+                };
+                let log =
+                    LogFile::open(&paths::resolve(&dir), &repo_path, rotation, run_as.clone())?;
+                info!("the output of the command goes to {}", log.path.display());
+                Some(Capture {
+                    log: Some(Arc::new(log)),
+                    format: match app.value_of("log-format") {
+                        Some(value) => value.parse()?,
+                        None => LogFormat::Plain,
+                    },
+                    label: None,
+                    ready: None,
+                    tail: None,
+                    syslog: syslog.clone(),
+                    sha: None,
+                })
+            }
+            _ if runs && syslog.is_some() => Some(Capture {
+                log: None,
+                format: LogFormat::Plain,
+                label: None,
+                ready: None,
+                tail: None,
+                syslog: syslog.clone(),
+                sha: None,
+            }),
+            _ => None,
+        };
+        let crash_reports = match app.value_of("crash-reports") {
+            Some(value) => match value.parse::<usize>() {
+                Ok(keep) if keep > 0 => keep,
+                _ => return Err(Error::InvalidArgument("crash-reports", value)),
+            },
+            None => 10,
+        };
+        let on_diverge = match app.value_of("on-diverge") {
+            Some(value) => value.parse()?,
+            None => OnDiverge::Fail,
+        };
 
-        let mut _self = Self {
+        Ok(Self {
             child: None,
-            branch,
+            branch: branch.unwrap_or_default(),
             origin,
+            fallbacks: remotes,
+            remote_urls,
+            fetched_from: None,
             cmd,
             args,
+            steps,
+            env,
+            env_files,
+            deploy_env: Vec::new(),
+            trigger: "startup",
+            build,
+            build_timeout,
+            limits,
+            build_limits,
+            failed_build: None,
+            pre_deploy_hooks,
+            post_deploy_hooks,
+            on_failure_hooks,
+            hook_timeout,
+            degraded: None,
+            health_check,
+            health_timeout,
+            health_retries,
+            ready,
+            ready_timeout,
+            ready_line: None,
+            tail: Arc::new(Tail::default()),
+            ports,
+            port: ports.map(|(a, _)| a),
             repo_path,
             repo: None,
             interval,
-            username,
-            public_key_path,
-            private_key_path,
-            passphrase: None,
-        };
+            connect: Connect {
+                username,
+                keys,
+                probed,
+                passphrase: None,
+                password: app.value_of("token"),
+                hosts,
+                local: false,
+                credential_helper: app.is_present("credential-helper"),
+                agent_only: app.is_present("agent-only"),
+                known_hosts: paths::resolve("~/.ssh/known_hosts"),
+                host_fingerprints: app.values_of("host-fingerprint"),
+                strict_host_keys: app.is_present("strict-host-keys"),
+                port: None,
+                filled: RefCell::new(None),
+                offered: RefCell::new(None),
+                used: RefCell::new(None),
+                github_app: None,
+                accepted: Arc::new(Mutex::new(None)),
+                prompted: Arc::new(Mutex::new(None)),
+                proxy: Proxy::from_option(app.value_of("proxy"))?,
+            },
+            git_timeout,
+            fetch_retries,
+            fetch_backoff,
+            stop_timeout,
+            stop_signal,
+            stdin,
+            process_group: !app.is_present("no-process-group"),
+            run_as,
+            pty,
+            syslog,
+            pid_file: None,
+            child_pid_file: None,
+            init: false,
+            crash_reports,
+            docker,
+            image: None,
+            compose,
+            unit,
+            started: false,
+            restart: match app.value_of("restart") {
+                Some(value) => value.parse()?,
+                None => Restart::Never,
+            },
+            process_restarts,
+            procfile: match (app.is_present("procfile"), app.value_of("procfile")) {
+                (true, Some(path)) => Some(PathBuf::from(path)),
+                (true, None) => Some(PathBuf::from("Procfile")),
+                (false, _) => None,
+            },
+            only: app
+                .values_of("only")
+                .iter()
+                .flat_map(|only| only.split(','))
+                .map(|name| name.trim().to_owned())
+                .filter(|name| !name.is_empty())
+                .collect(),
+            process_name: None,
+            process_log: None,
+            processes: Vec::new(),
+            shell: shell.map(str::to_owned),
+            exit_handled: false,
+            restart_at: None,
+            quick_exits: 0,
+            max_memory,
+            max_memory_for,
+            memory_sampled: None,
+            memory_over: None,
+            reset_hard: app.is_present("reset-hard"),
+            always_restart: app.is_present("always-restart"),
+            on_diverge,
+            clean: app.is_present("clean") || app.is_present("clean-ignored"),
+            clean_ignored: app.is_present("clean-ignored"),
+            force_dirty: app.is_present("force-dirty"),
+            dirty_untracked: app.is_present("dirty-untracked"),
+            autostash: app.is_present("autostash"),
+            submodules: !app.is_present("no-submodules"),
+            recurse_submodules: app.is_present("recurse-submodules"),
+            lfs: app.is_present("lfs"),
+            sparse,
+            command_dir,
+            deploy_dir: app.value_of("deploy-dir").as_deref().map(paths::resolve),
+            log,
+            bare: app.is_present("bare"),
+            releases,
+            release_worktrees: app.is_present("release-worktrees"),
+            release: None,
+            depth,
+            tag_pattern,
+            tag_semver,
+            include_prerelease: app.is_present("include-prerelease"),
+            commit,
+            branch_pattern,
+            watch_paths,
+            reload_signal,
+            reload_paths,
+            skip_markers,
+            skip_advances_checkout: app.is_present("skip-advances-checkout"),
+            skipped: None,
+            // A mirror drops branches deleted upstream.
+            prune: app.is_present("prune") || app.is_present("bare"),
+            preflight: !app.is_present("no-preflight"),
+            refspecs,
+            checkout_ref,
+            deleted_upstream: false,
+            verify_signatures,
+            verify_tag_signatures,
+            deployed: None,
+            deploys: Vec::new(),
+            rollback: None,
+            state_file: app.value_of("state-file").as_deref().map(paths::resolve),
+            reclone_on_corruption: app.is_present("reclone-on-corruption"),
+            respect_detached: app.is_present("respect-detached"),
+            detached_at: None,
+            url: None,
+        })
+    }
 
-        if app.is_present("use-passphrase") {
-            _self.passphrase()
+    /// Reads the passphrase and token from wherever the options say, the
+    /// keyring of the repo at `new` or `--path`.
+    fn read_credentials(&mut self, app: &Options, new: Option<&str>, shared: bool) -> Result<()> {
+        self.connect.passphrase = connect::read_passphrase(
+            app.value_of("passphrase-env"),
+            app.value_of("passphrase-file"),
+        )?;
+        // The keyring of the repo's url, both the clone and an existing repo
+        // know it.
+        if app.is_present("passphrase-keyring") || app.is_present("token-keyring") {
+            let url = secret::url(new, &self.repo_path, shared, &self.origin)?;
+            if app.is_present("passphrase-keyring") {
+                self.connect.passphrase = Some(secret::get(&url, "passphrase")?);
+            }
+            if app.is_present("token-keyring") {
+                self.connect.password = Some(secret::get(&url, "token")?);
+            }
         }
-        let repo = match (app.is_present("new"), app.is_present("path")) {
-            (true, true) => {
-                let new = match app.value_of("new") {
-                    Some(new) => new,
-                    None => return Err(Error::MissingUrlToRepo),
+        // Prompting needs someone to answer, under systemd nobody would.
+        if self.connect.passphrase.is_none()
+            && app.is_present("use-passphrase")
+            && !self.connect.local
+            && std::io::stdin().is_terminal()
+        {
+            self.passphrase()?
+        } else if let Some(passphrase) = &self.connect.passphrase {
+            connect::check_passphrase(&self.connect.keys, passphrase)?;
+        }
+        if let Some(token) = connect::read_secret(
+            ("token-env", app.value_of("token-env")),
+            ("token-file", app.value_of("token-file")),
+        )? {
+            self.connect.password = Some(token.trim_end_matches(['\r', '\n']).to_owned());
+        }
+        if let Some(app_id) = app.value_of("github-app") {
+            let github_app = GithubApp::new(
+                app_id,
+                app.value_of("github-installation").unwrap_or_default(),
+                paths::resolve(&app.value_of("github-app-key").unwrap_or_default()),
+                app.value_of("github-api").unwrap_or_default(),
+            )?;
+            // The first token right away, so a wrong app shows at startup.
+            github_app.token()?;
+            self.connect.github_app = Some(Arc::new(github_app));
+        }
+        if app.is_present("password-stdin") {
+            let mut password = String::new();
+            std::io::stdin().read_line(&mut password)?;
+            self.connect.password = Some(password.trim_end_matches(['\r', '\n']).to_owned());
+        }
+        let connect = &self.connect;
+        for secret in connect.passphrase.iter().chain(connect.password.iter()) {
+            log::add_secret(secret);
+        }
+        Ok(())
+    }
+
+    /// `check-auth`: connects to the remote with the configured credentials,
+    /// lists its refs and makes sure the branch to deploy is there. Nothing
+    /// is cloned, fetched or written.
+    fn check_auth(&self, new: Option<&str>, shared: bool) -> Result<()> {
+        let path = match new {
+            Some(_) => None,
+            None if shared => Some(self.repo_path.join(deploys::SHARED_REPO)),
+            None => Some(Repository::discover(&self.repo_path)?.path().to_path_buf()),
+        };
+        let url = match (new, &path) {
+            (Some(new), _) => new.to_owned(),
+            (None, Some(path)) => Repository::open(path)?
+                .find_remote(&self.origin)?
+                .url()
+                .map(str::to_owned)
+                .ok_or(Error::MissingUrlToRepo)?,
+            (None, None) => return Err(Error::MissingPath),
+        };
+        let connect = self.connect.with_url(&url);
+        if connect.agent_only && !connect.local {
+            connect::check_agent()?;
+        }
+        let (origin, detached_url) = (self.origin.clone(), url.clone());
+        let (refs, default_branch, used) =
+            timeout::run("auth check", self.git_timeout, move || {
+                let repo = path.map(Repository::open).transpose()?;
+                let mut remote = match &repo {
+                    Some(repo) => repo.find_remote(&origin)?,
+                    None => git2::Remote::create_detached(detached_url)?,
                 };
-                Main::new_repo(new, _self.fetch_options(), &_self.repo_path)?
-            }
-            (true, false) => return Err(Error::MissingPath),
-            (false, true) => Repository::discover(_self.repo_path.clone())?,
+                let connection = remote
+                    .connect_auth(
+                        Direction::Fetch,
+                        Some(connect.remote_callbacks()),
+                        connect.proxy_options(),
+                    )
+                    .map_err(|err| connect.blame_proxy(err))?;
+                let refs = connection
+                    .list()?
+                    .iter()
+                    .map(|head| (head.name().to_owned(), head.oid()))
+                    .collect::<Vec<_>>();
+                let default_branch = connection.default_branch().ok().and_then(|head| {
+                    head.as_str()
+                        .and_then(|head| head.strip_prefix("refs/heads/"))
+                        .map(str::to_owned)
+                });
+                let used = connect.used.borrow_mut().take();
+                Ok((refs, default_branch, used))
+            })?;
 
-            (false, false) => return Err(Error::MissingPath),
+        info!("connected to {} at {}", self.origin, url);
+        match used {
+            Some(used) => info!("authenticated {}", used),
+            None => info!("the remote needed no credentials"),
+        }
+        let names = refs
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        info!(
+            "the remote lists {} refs: {}{}",
+            names.len(),
+            names
+                .iter()
+                .take(10)
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", "),
+            if names.len() > 10 { ", ..." } else { "" }
+        );
+        let branch = |name: &str| {
+            refs.iter()
+                .find(|(head, _)| head.strip_prefix("refs/heads/") == Some(name))
+                .map(|(_, oid)| *oid)
         };
-        _self.repo = Some(repo);
+        if let Some(pattern) = &self.tag_pattern {
+            match names
+                .iter()
+                .filter_map(|name| name.strip_prefix("refs/tags/"))
+                .find(|tag| pattern.matches(tag.trim_end_matches("^{}")))
+            {
+                Some(tag) => info!("tag {} matches {}", tag, pattern),
+                None => return Err(Error::NoMatchingTag(pattern.to_string())),
+            }
+        } else if let Some(pattern) = &self.branch_pattern {
+            match names
+                .iter()
+                .filter_map(|name| name.strip_prefix("refs/heads/"))
+                .find(|name| pattern.matches(name))
+            {
+                Some(name) => info!("branch {} matches {}", name, pattern),
+                None => return Err(Error::NoMatchingBranch(pattern.to_string())),
+            }
+        } else if self.commit.is_some() || !self.refspecs.is_empty() {
+            info!("not checking the branch, the commit to deploy is only known after fetching");
+        } else {
+            let name = match (self.branch.as_str(), default_branch) {
+                ("", Some(default)) => default,
+                ("", None) => return Err(Error::NoDefaultBranch(self.origin.clone())),
+                (name, _) => name.to_owned(),
+            };
+            match branch(&name) {
+                Some(oid) => info!("branch {} is at {}", name, oid),
+                None => return Err(Error::NoMatchingBranch(name)),
+            }
+        }
+        Ok(())
+    }
 
-        Ok(_self)
+    /// Shows where the path options ended up after `~` and relative paths
+    /// were resolved.
+    fn log_paths(&self) {
+        debug!("repo path {}", self.repo_path.display());
+        for key in &self.connect.keys {
+            match &key.public_key_path {
+                Some(public) => debug!(
+                    "ssh key {} with {}",
+                    key.private_key_path.display(),
+                    public.display()
+                ),
+                None => debug!("ssh key {}", key.private_key_path.display()),
+            }
+        }
+        debug!("known hosts {}", self.connect.known_hosts.display());
+        if let Some(dir) = &self.deploy_dir {
+            debug!("deploy dir {}", dir.display());
+        }
+        if let Some(file) = &self.state_file {
+            debug!("state file {}", file.display());
+        }
     }
 
-    pub fn new_repo<'fo>(
-        new: &str,
-        fetch_options: FetchOptions<'fo>,
-        path: &PathBuf,
-    ) -> Result<Repository> {
-        let mut builder = git2::build::RepoBuilder::new();
-        builder.fetch_options(fetch_options);
-        let _ = std::fs::create_dir_all(path.clone())?;
-        Ok(builder.clone(new, &path)?)
+    pub fn new_repo(&self, new: &str, path: &Path, bare: bool) -> Result<Repository> {
+        std::fs::create_dir_all(path)?;
+        let connect = self.connect.with_url(new);
+        let (url, path) = (new.to_owned(), path.to_path_buf());
+        let (origin, depth) = (self.origin.clone(), self.depth);
+        let patterns = self.sparse_patterns().to_vec();
+        let repo = timeout::run("clone", self.git_timeout, move || {
+            let progress = Progress::new("cloning");
+            let mut builder = git2::build::RepoBuilder::new();
+            builder.bare(bare);
+            let mut callbacks = connect.remote_callbacks();
+            progress.attach(&mut callbacks);
+            let mut fetch_options = git2::FetchOptions::new();
+            fetch_options.remote_callbacks(callbacks);
+            if let Some(proxy) = connect.proxy_options() {
+                fetch_options.proxy_options(proxy);
+            }
+            if let Some(depth) = depth {
+                fetch_options.depth(depth);
+            }
+            let mut checkout = sparse::checkout(&patterns);
+            progress.attach_checkout(&mut checkout);
+            builder.fetch_options(fetch_options);
+            builder.with_checkout(checkout);
+            builder.remote_create(|repo, _name, url| repo.remote(&origin, url));
+            let repo = builder.clone(&url, &path);
+            drop(builder);
+
+            let (objects, bytes) = progress.finish();
+            let repo = repo.map_err(|err| connect.blame_proxy(err))?;
+            connect.succeeded();
+            info!(
+                "cloned {} objects ({}) into {}",
+                objects,
+                progress::bytes(bytes),
+                path.display()
+            );
+            Ok(repo)
+        })?;
+
+        if self.sparse.is_some() {
+            self.reconcile_sparse(&repo)?;
+        }
+        if self.recurse_submodules {
+            submodules::update(&repo, &|| self.fetch_options())?;
+        }
+        Ok(repo)
     }
 
+    /// Deploys once per interval until localdeploy is asked to exit. The
+    /// commands are stopped when `Main` is dropped.
     pub fn run(&mut self) -> Result<()> {
         loop {
-            let _repo = self.fetch_git_repo()?;
-            self.spawn_cmd()?;
-            thread::sleep(Duration::from_secs(self.interval));
+            if shutdown::requested() {
+                info!("shutting down");
+                return Ok(());
+            }
+            match self.fetch_with_retries() {
+                Ok(()) => {}
+                // The deployed command keeps running, the next cycle tries
+                // again.
+                Err(err) if err.is_network() => {
+                    warn!("fetch failed: {}, keeping the current deployment", err);
+                    self.watch_for(Duration::from_secs(self.interval));
+                    continue;
+                }
+                Err(err) if self.reclone_on_corruption && self.is_corrupted(&err) => {
+                    self.recover(&err)?
+                }
+                Err(err) => return Err(err),
+            }
+            if self.deploys.is_empty() {
+                match self.cycle() {
+                    Err(err) if self.reclone_on_corruption && self.is_corrupted(&err) => {
+                        self.recover(&err)?;
+                        self.cycle()?;
+                    }
+                    cycled => cycled?,
+                }
+            }
+            // One failing checkout must not hold back the others.
+            for deploy in &mut self.deploys {
+                if let Err(err) = deploy.cycle() {
+                    warn!("{}: {}", deploy.repo_path.display(), err);
+                }
+            }
+            self.watch_for(Duration::from_secs(self.interval));
+        }
+    }
 
-            if let Some(child) = &mut self.child {
-                let _ = child.kill();
+    /// Waits for the next cycle, meanwhile restarting the commands which
+    /// exited according to `--restart`.
+    fn watch_for(&mut self, duration: Duration) {
+        let started = Instant::now();
+        while !shutdown::requested() {
+            match duration.checked_sub(started.elapsed()) {
+                Some(left) if !left.is_zero() => shutdown::sleep(left.min(WATCH_INTERVAL)),
+                _ => return,
+            }
+            self.watch();
+            self.watch_processes();
+            for deploy in &mut self.deploys {
+                deploy.watch();
+                deploy.watch_processes();
             }
         }
     }
 
-    fn spawn_cmd(&mut self) -> Result<()> {
-        self.child = Some(
-            Command::new(self.cmd.clone())
-                .current_dir(self.repo_path.clone())
-                .stdout(Stdio::piped())
-                .stdin(Stdio::piped())
-                .args(self.args.clone())
-                .spawn()
-                .expect("failed to spawn cmd"),
+    /// Notices the command exiting on its own and starts it again when
+    /// `--restart` says so.
+    fn watch(&mut self) {
+        let child = match &mut self.child {
+            Some(child) => child,
+            None => return,
+        };
+        if let Some(restart_at) = self.restart_at {
+            if Instant::now() >= restart_at {
+                self.restart_at = None;
+                info!("restarting the command");
+                self.restart_cmd("exited");
+            }
+            return;
+        }
+        if self.exit_handled {
+            return;
+        }
+        let status = match child.exited() {
+            Some(status) => status,
+            None => return self.check_memory(),
+        };
+        self.exit_handled = true;
+        // The last lines it wrote may still be in the pipe.
+        child.drain();
+        let uptime = child.uptime();
+        let (line, env) = (child.line().to_owned(), child.env().to_vec());
+        let what = match &self.process_name {
+            Some(name) => format!("the {} process", name),
+            None => "the command".to_owned(),
+        };
+        let report = crash::Report {
+            what: &what,
+            status,
+            sha: self.deploy_sha(),
+            branch: &self.branch,
+            uptime,
+            command: &line,
+            env: &env,
+            output: self.tail.output(),
+        };
+        let report =
+            self.write_crash_report(self.process_log.as_ref().or(self.log.as_ref()), &report);
+        self.report_exit(
+            &what,
+            self.process_name.as_deref(),
+            status,
+            &self.tail.lines(),
+            report.as_deref(),
         );
-        Ok(())
+        let restart = restarts(self.restart_policy(self.process_name.as_deref()), status);
+        if restart {
+            let delay = restart_delay(&mut self.quick_exits, uptime);
+            if !delay.is_zero() {
+                info!(
+                    "{} exited soon after starting, restarting it in {}",
+                    what,
+                    humantime::format_duration(delay)
+                );
+            }
+            self.restart_at = Some(Instant::now() + delay);
+        }
+        self.record_exit(&status.to_string(), restart);
     }
 
-    fn fetch_git_repo(&mut self) -> Result<()> {
-        let mut fo = self.fetch_options();
+    /// Notices the processes of the `--procfile` after the first exiting on
+    /// their own and starts them again by their restart policy.
+    fn watch_processes(&mut self) {
+        let mut processes = std::mem::take(&mut self.processes);
+        for process in &mut processes {
+            if let Some(restart_at) = process.restart_at {
+                if Instant::now() >= restart_at {
+                    process.restart_at = None;
+                    info!("restarting the {} process", process.name);
+                    if let Err(err) = self.spawn_process(process) {
+                        warn!("restarting failed: {}", err);
+                    }
+                }
+                continue;
+            }
+            let child = match &mut process.child {
+                Some(child) if !process.exit_handled => child,
+                _ => continue,
+            };
+            let status = match child.exited() {
+                Some(status) => status,
+                None => continue,
+            };
+            process.exit_handled = true;
+            child.drain();
+            let uptime = child.uptime();
+            let what = format!("the {} process", process.name);
+            let report = crash::Report {
+                what: &what,
+                status,
+                sha: self.deploy_sha(),
+                branch: &self.branch,
+                uptime,
+                command: child.line(),
+                env: child.env(),
+                output: process.tail.output(),
+            };
+            let report = self.write_crash_report(process.log.as_ref(), &report);
+            self.report_exit(
+                &what,
+                Some(&process.name),
+                status,
+                &process.tail.lines(),
+                report.as_deref(),
+            );
+            if restarts(process.restart, status) {
+                let delay = restart_delay(&mut process.quick_exits, uptime);
+                if !delay.is_zero() {
+                    info!(
+                        "{} exited soon after starting, restarting it in {}",
+                        what,
+                        humantime::format_duration(delay)
+                    );
+                }
+                process.restart_at = Some(Instant::now() + delay);
+            }
+        }
+        self.processes = processes;
+    }
 
-        if let Some(repo) = &self.repo {
-            repo.find_remote(&self.origin)?
-                .fetch(&[self.branch.clone()], Some(&mut fo), None)?;
+    /// With `--log-dir` writes the crash `report` of a command which
+    /// failed next to its `log` file, keeping `--crash-reports` of them.
+    fn write_crash_report(&self, log: Option<&Capture>, report: &crash::Report) -> Option<PathBuf> {
+        let log = log.and_then(|log| log.log.as_ref())?;
+        if report.status.success() {
+            return None;
+        }
+        match crash::write(&log.path, report, self.crash_reports) {
+            Ok(path) => Some(path),
+            Err(err) => {
+                warn!("writing the crash report failed: {}", err);
+                None
+            }
         }
-        Ok(())
     }
 
-    fn fetch_options(&self) -> FetchOptions<'_> {
-        let mut callbacks = RemoteCallbacks::new();
-        callbacks.credentials(move |_url, username_from_url, _allowed_types| {
-            let username = if let Some(u) = username_from_url {
-                u
-            } else {
-                &self.username
+    /// Logs how `what`, the command or the `process` of the `--procfile`,
+    /// exited, records it in the history and runs the `--on-failure-hook`s
+    /// when it failed, with the crash `report` if one was written.
+    fn report_exit(
+        &self,
+        what: &str,
+        process: Option<&str>,
+        status: ExitStatus,
+        stderr: &[String],
+        report: Option<&Path>,
+    ) {
+        match status.success() {
+            true => info!("{} exited on its own ({})", what, status),
+            false => {
+                warn!("{} exited unexpectedly ({})", what, status);
+                if !stderr.is_empty() {
+                    warn!("its last lines on stderr:\n{}", stderr.join("\n"));
+                }
+                if let Some(report) = report {
+                    warn!("wrote a crash report to {}", report.display());
+                }
+            }
+        }
+        if let (Some(repo), Some(deployed)) = (&self.repo, self.deployed) {
+            let detail = match process {
+                Some(process) => format!("{} {}", process, child::exit_detail(status)),
+                None => child::exit_detail(status),
             };
-            let mut cred = Cred::ssh_key_from_agent(username);
-            if cred.is_err() {
-                cred = Cred::ssh_key(
-                    username_from_url.unwrap(),
-                    Some(&self.public_key_path),
-                    &self.private_key_path,
-                    self.passphrase.as_deref(),
-                );
+            if let Err(err) = history::record(repo, history::Kind::Exit, deployed, Some(&detail)) {
+                warn!("failed to record the exit: {}", err);
             }
-            cred
-        });
+        }
+        if !status.success() {
+            let mut error = format!("{} exited unexpectedly ({})", what, status);
+            let report = report.map(|report| report.display().to_string());
+            if let Some(report) = &report {
+                error.push_str(&format!(", see {}", report));
+            }
+            let stderr = log::redact(&stderr.join("\n"));
+            for hook in &self.on_failure_hooks {
+                let env = [
+                    ("LOCALDEPLOY_ERROR", error.as_str()),
+                    ("LOCALDEPLOY_STDERR", stderr.as_str()),
+                    (
+                        "LOCALDEPLOY_CRASH_REPORT",
+                        report.as_deref().unwrap_or_default(),
+                    ),
+                ];
+                if let Err(err) = self.run_hook("--on-failure-hook", hook, &env) {
+                    warn!("{}", err);
+                }
+            }
+        }
+    }
 
-        let mut fetch_options = git2::FetchOptions::new();
-        fetch_options.remote_callbacks(callbacks);
-        fetch_options
+    /// The `--restart` policy, or the `--process-restart` of `process`.
+    fn restart_policy(&self, process: Option<&str>) -> Restart {
+        self.process_restarts
+            .iter()
+            .find(|(name, _)| Some(name.as_str()) == process)
+            .map_or(self.restart, |(_, restart)| *restart)
     }
 
-    fn passphrase(&mut self) {
-        self.passphrase = Some(prompt_password_stdout("SSH Passphrase: ").unwrap_or("".to_owned()));
+    /// Restarts the command when it used more than `--max-memory` for
+    /// `--max-memory-for`.
+    fn check_memory(&mut self) {
+        let (max, child) = match (self.max_memory, &self.child) {
+            (Some(max), Some(child)) => (max, child),
+            _ => return,
+        };
+        if self
+            .memory_sampled
+            .is_some_and(|sampled| sampled.elapsed() < MEMORY_SAMPLE)
+        {
+            return;
+        }
+        self.memory_sampled = Some(Instant::now());
+        let rss = match child.rss() {
+            Some(rss) if rss > max => rss,
+            _ => {
+                self.memory_over = None;
+                return;
+            }
+        };
+        let over = *self.memory_over.get_or_insert_with(Instant::now);
+        debug!(
+            "the command uses {}, more than --max-memory {}",
+            progress::bytes(rss as usize),
+            progress::bytes(max as usize)
+        );
+        if over.elapsed() < self.max_memory_for {
+            return;
+        }
+        warn!(
+            "the command used more than --max-memory {} for {}, {} now, restarting it",
+            progress::bytes(max as usize),
+            humantime::format_duration(Duration::from_secs(over.elapsed().as_secs())),
+            progress::bytes(rss as usize)
+        );
+        if let Some(child) = &mut self.child {
+            child.stop(self.stop_signal, self.stop_timeout);
+        }
+        self.restart_cmd("memory");
     }
 
-    fn parse_cmd_args(command: String) -> Result<(String, Vec<String>)> {
-        let mut args = command
-            .trim()
-            .split(" ")
-            .map(|s| s.to_owned())
-            .collect::<Vec<String>>();
+    /// Starts the command again without a deploy, recording `reason` in the
+    /// history.
+    fn restart_cmd(&mut self, reason: &str) {
+        if let Err(err) = self.spawn_cmd() {
+            warn!("restarting the command failed: {}", err);
+            return;
+        }
+        if let (Some(repo), Some(deployed)) = (&self.repo, self.deployed) {
+            if let Err(err) = history::record(repo, history::Kind::Restart, deployed, Some(reason))
+            {
+                warn!("failed to record the restart: {}", err);
+            }
+        }
+    }
 
-        if args.len() <= 1 {
-            return Err(Error::MissingCommand);
+    /// Adds how the command exited to the state of the deploy.
+    fn record_exit(&self, status: &str, restarted: bool) {
+        let path = match &self.repo {
+            Some(repo) => state::path(repo, self.state_file.as_deref()),
+            None => return,
+        };
+        if let Some(mut state) = state::load(&path) {
+            state.exit = Some(status.to_owned());
+            if restarted {
+                state.restarts += 1;
+            }
+            if let Err(err) = state::save(&path, &state) {
+                warn!("failed to write {}: {}", path.display(), err);
+            }
         }
-        let cmd = args.remove(0);
-        Ok((cmd.to_owned(), args))
     }
-}
 
-fn main() -> Result<()> {
-    let app = App::new("localdeploy")
-        .version(env!("CARGO_PKG_VERSION"))
-        .author("Ceeox <me@ceox.dev>")
-        .arg(
-            Arg::with_name("new")
+    /// Handles a HEAD which was detached by hand while following a branch.
+    /// It is re-attached by deploying the branch again, or with
+    /// `--respect-detached` left alone. Returns whether to go on updating.
+    fn check_detached_head(&mut self) -> bool {
+        let head = match &self.repo {
+            Some(repo)
+                if !repo.is_bare()
+                    && !self.detached()
+                    && self.rollback.is_none()
+                    && repo.head_detached().unwrap_or(false) =>
+            {
+                repo.head().ok().and_then(|head| head.target())
+            }
+            _ => {
+                self.detached_at = None;
+                return true;
+            }
+        };
+        let first = self.detached_at != head || head.is_none();
+        self.detached_at = head;
+        let head = head.map(|oid| oid.to_string()).unwrap_or_default();
+        if self.respect_detached {
+            if first {
+                warn!(
+                    "HEAD is detached at {}, not updating (--respect-detached)",
+                    head
+                );
+            }
+            return false;
+        }
+        info!(
+            "HEAD is detached at {}, re-attaching branch {}",
+            head, self.branch
+        );
+        // Deploying again checks out the branch and restarts the command
+        // on what it points to.
+        self.deployed = None;
+        true
+    }
+
+    /// Deploys the fetched target if it changed since the last cycle.
+    fn cycle(&mut self) -> Result<()> {
+        if !self.check_detached_head() {
+            // The command still runs on what was checked out.
+            if !self.running() {
+                match self.start() {
+                    Err(err @ (Error::SpawnFailed { .. } | Error::ProcessStart(..))) => {
+                        warn!("starting the command failed: {}", err)
+                    }
+                    started => started?,
+                }
+            }
+            return Ok(());
+        }
+        let target = self.target()?;
+        let target = self.hold_rollback(target);
+        let tip = target.as_ref().map(|target| target.oid);
+
+        // A failed build is tried again with the next commit.
+        let failed = self.running() && tip.is_some() && self.failed_build == tip;
+        if !failed && (self.always_restart || !self.running() || self.deployed != tip) {
+            match self.verify(target.as_ref()) {
+                Ok(()) => {
+                    let deployed = match self.skip_marker(tip) {
+                        Some(marker) => self.skip(tip, &marker),
+                        None => {
+                            let deployed = self.deploy(target);
+                            if let Err(err) = &deployed {
+                                let error = log::redact(&err.to_string());
+                                for hook in &self.on_failure_hooks {
+                                    let env = [("LOCALDEPLOY_ERROR", error.as_str())];
+                                    if let Err(err) = self.run_hook("--on-failure-hook", hook, &env)
+                                    {
+                                        warn!("{}", err);
+                                    }
+                                }
+                            }
+                            if let Some(tip) = tip {
+                                let error = deployed.as_ref().err().map(|err| err.to_string());
+                                self.save_state(tip, error);
+                                if let Err(err) = &deployed {
+                                    self.record_failure(tip, err);
+                                }
+                            }
+                            deployed
+                        }
+                    };
+                    match deployed {
+                        // Nothing was deployed, so the next cycle tries again.
+                        Err(err @ Error::Lfs(..)) => warn!("not deploying: {}", err),
+                        // The previous version keeps running.
+                        Err(
+                            err @ (Error::Build(..)
+                            | Error::Hook(..)
+                            | Error::MissingCommandDir(..)
+                            | Error::Step(..)
+                            | Error::Procfile(..)
+                            | Error::ProcessStart(..)
+                            | Error::SpawnFailed { .. }
+                            | Error::Docker(..)
+                            | Error::Compose(..)
+                            | Error::Systemd(..)),
+                        ) => {
+                            warn!("not deploying: {}", err)
+                        }
+                        // Deploys the rollback unless a release was switched
+                        // back already.
+                        Err(Error::Unhealthy(..)) if self.deployed.is_none() => self.cycle()?,
+                        Err(Error::Unhealthy(..)) => {}
+                        deployed => deployed?,
+                    }
+                }
+                Err(err) => warn!("not deploying: {}", err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Updates the working tree to `target`, builds it and only then stops
+    /// the command and starts it again. With `--watch-path` the command keeps
+    /// running if none of the watched paths changed.
+    fn deploy(&mut self, target: Option<Target>) -> Result<()> {
+        let tip = target.as_ref().map(|target| target.oid);
+        let tag = target.as_ref().and_then(|target| target.tag.clone());
+        let watched = self.changed_watch_paths(tip);
+        let restart = self.always_restart
+            || !self.running()
+            || watched.as_ref().is_none_or(|paths| !paths.is_empty());
+        // After a restart of localdeploy the commit from the state file is
+        // still checked out, only the command has to be started.
+        let unchanged = !self.running() && tip.is_some() && self.deployed == tip;
+        if let (true, Some(tip)) = (unchanged, tip) {
+            info!("{} is already deployed, starting the command", tip);
+        }
+        let reload = match restart && !unchanged && self.deployed != tip {
+            true => self.reload_signal.filter(|_| self.can_reload(tip)),
+            false => None,
+        };
+        let exported = unchanged
+            && self
+                .deploy_dir
+                .as_ref()
+                .is_some_and(|dir| match self.releases {
+                    Some(_) => dir.join(releases::CURRENT).exists(),
+                    None => dir.exists(),
+                });
+
+        // What a failing health check goes back to.
+        let previous = self.deployed;
+        let previous_release = match (&self.deploy_dir, self.releases) {
+            (Some(dir), Some(_)) => fs::canonicalize(dir.join(releases::CURRENT)).ok(),
+            _ => None,
+        };
+        let rolling_back = self
+            .rollback
+            .as_ref()
+            .is_some_and(|rollback| Some(rollback.to) == tip);
+        self.degraded = None;
+        if !unchanged {
+            for hook in &self.pre_deploy_hooks {
+                self.run_hook("--pre-deploy-hook", hook, &[])?;
+            }
+        }
+        if let (true, Some(repo), Some(tip)) = (self.lfs, &self.repo, tip) {
+            lfs::fetch(repo.workdir().unwrap_or(&self.repo_path), &self.origin, tip)?;
+        }
+        // Written before stopping the command, so a failed release leaves it
+        // running.
+        let release = match (
+            restart && !exported,
+            &self.deploy_dir,
+            self.releases,
+            &self.repo,
+            tip,
+        ) {
+            (true, Some(dir), Some(_), Some(repo), Some(tip)) => Some(releases::create(
+                repo,
+                tip,
+                dir,
+                self.sparse.as_ref(),
+                self.release_worktrees,
+            )?),
+            _ => None,
+        };
+        if let Some(target) = target {
+            if let Some(tag) = &target.tag {
+                info!("deploying tag {} ({})", tag, target.oid);
+            }
+            if let Some(branch) = target.branch {
+                if self.branch.is_empty() {
+                    info!("deploying branch {}", branch);
+                } else if branch != self.branch {
+                    info!("switching from branch {} to {}", self.branch, branch);
+                }
+                self.branch = branch;
+            }
+        }
+        match &watched {
+            Some(paths) if !paths.is_empty() => {
+                info!("watched path(s) changed: {}", paths.join(", "))
+            }
+            Some(_) if !restart => info!("no watched path changed, not restarting"),
+            _ => {}
+        }
+        self.update_working_tree(tip)?;
+        if let Some(tip) = tip {
+            self.deploy_env = self.deploy_variables(tip, tag, previous);
+        }
+        self.trigger = "interval";
+        if let (None, true, Some(dir), Some(repo), Some(tip)) = (
+            &release,
+            restart && !exported,
+            &self.deploy_dir,
+            &self.repo,
+            tip,
+        ) {
+            export::export(repo, tip, dir, self.sparse.as_ref())?;
+            info!("exported {} to {}", tip, dir.display());
+        }
+        // The command keeps running on the old version until the new one is
+        // in place and built.
+        if restart {
+            let built = self
+                .check_command_dir(release.as_deref())
+                .and_then(|()| self.run_build(release.as_deref()))
+                .and_then(|()| self.read_procfile(&self.command_dir(release.as_deref())))
+                .map(|_| ());
+            if let Err(err) = built {
+                self.failed_build = tip;
+                if let (Some(release), Some(repo)) = (&release, &self.repo) {
+                    if let Err(err) = releases::discard(repo, release) {
+                        warn!("failed to remove {}: {}", release.display(), err);
+                    }
+                }
+                return Err(err);
+            }
+            self.failed_build = None;
+        }
+        self.release = release.clone();
+        let reloaded = match reload {
+            Some(signal) => self.reload(signal)?,
+            None => false,
+        };
+        // With `--overlap` the old version runs until the new one is
+        // healthy.
+        let mut old = None;
+        if restart && !reloaded {
+            match (self.ports, self.port, self.child.take()) {
+                (Some((a, b)), Some(port), Some(child)) => {
+                    old = Some((child, port));
+                    self.port = Some(if port == a { b } else { a });
+                }
+                (_, _, Some(mut child)) => child.stop(self.stop_signal, self.stop_timeout),
+                (_, _, None) => {}
+            }
+            self.stop_processes();
+            // A new version gets a clean slate of restarts.
+            self.quick_exits = 0;
+            if let Err(err) = self.start() {
+                // `up -d` may have replaced some of the services already, a
+                // unit failing to start is down.
+                return match (&err, tip, previous) {
+                    (Error::Compose(..) | Error::Systemd(..), Some(tip), Some(previous))
+                        if previous != tip && !rolling_back =>
+                    {
+                        self.roll_back_unhealthy(
+                            tip,
+                            previous,
+                            previous_release,
+                            release,
+                            old,
+                            err.to_string(),
+                        )
+                    }
+                    // Nothing to go back to, the new release is the one
+                    // restarted.
+                    _ => self.activate_release().and(Err(err)),
+                };
+            }
+            if let (Some(_), Some(port)) = (&old, self.port) {
+                info!("started the new version on port {}", port);
+            }
+        }
+        let waits = self.ready.is_some()
+            || self.health_check.is_some()
+            || self
+                .compose
+                .as_ref()
+                .is_some_and(|compose| compose.health_service.is_some());
+        let health = match restart && !unchanged {
+            true if waits => Some(
+                self.wait_ready()
+                    .and_then(|()| self.check_health())
+                    .and_then(|()| self.wait_service_healthy()),
+            ),
+            _ => None,
+        };
+        if let (Some(Err(reason)), Some(tip)) = (&health, tip) {
+            let (reason, tip) = (reason.clone(), tip);
+            if let Some(previous) = previous.filter(|previous| *previous != tip && !rolling_back) {
+                return self.roll_back_unhealthy(
+                    tip,
+                    previous,
+                    previous_release,
+                    release,
+                    old,
+                    reason,
+                );
+            }
+            warn!(
+                "{} is unhealthy: {}, there is nothing to roll back to",
+                tip, reason
+            );
+        }
+        self.activate_release()?;
+        if let Some((mut old, port)) = old {
+            info!("stopping the old version on port {}", port);
+            old.stop(self.stop_signal, self.stop_timeout);
+        }
+        // The old release is only removed once its command stopped and the
+        // new one is healthy.
+        if let (Some(_), Some(dir), Some(keep), Some(repo)) =
+            (&release, &self.deploy_dir, self.releases, &self.repo)
+        {
+            for removed in releases::prune(repo, dir, keep)? {
+                verbose!("removed old release {}", removed.display());
+            }
+        }
+        if let (Some(repo), Some(tip)) = (&self.repo, tip) {
+            // The rollback recorded itself already.
+            if !rolling_back {
+                let outcome = health.as_ref().map(|health| match health {
+                    Ok(()) => "healthy",
+                    Err(_) => "unhealthy",
+                });
+                let action = match (reloaded, restart) {
+                    (true, _) => Some("reloaded"),
+                    (false, true) => Some("restarted"),
+                    (false, false) => None,
+                };
+                history::record(
+                    repo,
+                    history::Kind::Deploy,
+                    tip,
+                    history::detail(self.fetched_from.as_deref(), action, outcome).as_deref(),
+                )?;
+            }
+        }
+        if !unchanged {
+            let mut failed = self
+                .post_deploy_hooks
+                .iter()
+                .filter_map(|hook| self.run_hook("--post-deploy-hook", hook, &[]).err())
+                .map(|err| err.to_string())
+                .collect::<Vec<_>>();
+            for err in &failed {
+                warn!("the deploy is degraded: {}", err);
+            }
+            // An unhealthy deploy without one to roll back to stays, degraded.
+            if let (Some(Err(reason)), Some(tip)) = (health, tip) {
+                failed.insert(0, Error::Unhealthy(tip, reason).to_string());
+            }
+            self.degraded = (!failed.is_empty()).then(|| failed.join(", "));
+        }
+        self.deployed = tip;
+        Ok(())
+    }
+
+    /// The `LOCALDEPLOY_*` variables of deploying `tip` after `previous`.
+    fn deploy_variables(
+        &self,
+        tip: Oid,
+        tag: Option<String>,
+        previous: Option<Oid>,
+    ) -> Vec<(&'static str, String)> {
+        let sha = tip.to_string();
+        let mut variables = vec![
+            ("LOCALDEPLOY_SHORT_SHA", sha[..7].to_owned()),
+            ("LOCALDEPLOY_SHA", sha),
+            (
+                "LOCALDEPLOY_DEPLOY_TIME",
+                humantime::format_rfc3339_seconds(std::time::SystemTime::now()).to_string(),
+            ),
+            ("LOCALDEPLOY_TRIGGER", self.trigger.to_owned()),
+        ];
+        if let Some(tag) = tag {
+            variables.push(("LOCALDEPLOY_TAG", tag));
+        }
+        if let Some(previous) = previous.filter(|previous| *previous != tip) {
+            variables.push(("LOCALDEPLOY_PREVIOUS_SHA", previous.to_string()));
+        }
+        variables
+    }
+
+    /// Waits for the started command to become ready. Returns why it didn't.
+    fn wait_ready(&mut self) -> std::result::Result<(), String> {
+        let ready = match &self.ready {
+            Some(ready) => ready.clone(),
+            None => return Ok(()),
+        };
+        let started = Instant::now();
+        loop {
+            if shutdown::requested() {
+                return Ok(());
+            }
+            if let Some(status) = self.child.as_mut().and_then(Running::exited) {
+                return Err(format!(
+                    "the command exited before it was ready ({})",
+                    status
+                ));
+            }
+            let ready = match &ready {
+                health::Ready::Tcp(address) => {
+                    let address = self.render(address, &self.command_dir(None));
+                    health::connectable(&address, Duration::from_secs(1))
+                }
+                health::Ready::Log(_) => self.ready_line.as_ref().is_some_and(|line| line.seen()),
+                health::Ready::Delay(delay) => started.elapsed() >= *delay,
+            };
+            if ready {
+                info!(
+                    "the command is ready after {:.1}s",
+                    started.elapsed().as_secs_f64()
+                );
+                return Ok(());
+            }
+            if started.elapsed() >= self.ready_timeout {
+                return Err(format!(
+                    "the command wasn't ready within {}",
+                    humantime::format_duration(self.ready_timeout)
+                ));
+            }
+            thread::sleep(READY_POLL);
+        }
+    }
+
+    /// Checks the new version of the command until it is healthy or the
+    /// retries run out. Returns why the last check failed.
+    fn check_health(&mut self) -> std::result::Result<(), String> {
+        let check = match &self.health_check {
+            Some(check) => check.clone(),
+            None => return Ok(()),
+        };
+        let mut failed = String::new();
+        for attempt in 0..=self.health_retries {
+            if attempt > 0 {
+                shutdown::sleep(HEALTH_INTERVAL);
+            }
+            // Stopping localdeploy is no reason to roll back.
+            if shutdown::requested() {
+                return Ok(());
+            }
+            if let Some(status) = self.child.as_mut().and_then(Running::exited) {
+                return Err(format!("the command exited ({})", status));
+            }
+            let checked = match &check {
+                health::Check::Url(url) => health::get(
+                    &self.render(url, &self.command_dir(None)),
+                    self.health_timeout,
+                ),
+                health::Check::Command(command) => self
+                    .run_step(
+                        command,
+                        &self.command_dir(None),
+                        self.health_timeout,
+                        &[],
+                        &|_| {},
+                        self.log.as_ref(),
+                    )
+                    .map_err(|reason| format!("the --health-check-cmd {}", reason)),
+            };
+            match checked {
+                Ok(()) => {
+                    info!("the command is healthy");
+                    return Ok(());
+                }
+                Err(reason) => {
+                    verbose!(
+                        "health check {}/{} failed: {}",
+                        attempt + 1,
+                        self.health_retries + 1,
+                        reason
+                    );
+                    failed = reason;
+                }
+            }
+        }
+        Err(failed)
+    }
+
+    /// Points `current` at the new release once its command started and
+    /// was checked.
+    fn activate_release(&mut self) -> Result<()> {
+        if let (Some(release), Some(dir)) = (self.release.take(), &self.deploy_dir) {
+            releases::activate(dir, &release)?;
+            info!("released {}", release.display());
+        }
+        Ok(())
+    }
+
+    /// Goes back to `previous` after `tip` failed its health check. With
+    /// releases `current` still points at the previous one, which is started
+    /// again at once, otherwise the rollback is deployed by the cycle. The
+    /// `old` command of `--overlap` keeps running.
+    fn roll_back_unhealthy(
+        &mut self,
+        tip: Oid,
+        previous: Oid,
+        previous_release: Option<PathBuf>,
+        release: Option<PathBuf>,
+        old: Option<(Running, u16)>,
+        reason: String,
+    ) -> Result<()> {
+        warn!(
+            "{} is unhealthy: {}, rolling back to {}",
+            tip, reason, previous
+        );
+        if let Some(child) = &mut self.child {
+            child.stop(self.stop_signal, self.stop_timeout);
+        }
+        self.child = None;
+        self.started = false;
+        self.stop_processes();
+        self.release = None;
+        let kept = old.is_some();
+        if let Some((old, port)) = old {
+            info!("the old version keeps running on port {}", port);
+            self.child = Some(old);
+            self.port = Some(port);
+            self.exit_handled = false;
+        }
+        let repo = match &self.repo {
+            Some(repo) => repo,
+            None => return Err(Error::Unhealthy(tip, reason)),
+        };
+        history::record(
+            repo,
+            history::Kind::Deploy,
+            tip,
+            history::detail(self.fetched_from.as_deref(), None, Some("unhealthy")).as_deref(),
+        )?;
+        history::record(repo, history::Kind::Rollback, previous, Some("unhealthy"))?;
+        self.trigger = "rollback";
+        self.deploy_env = self.deploy_variables(previous, None, None);
+        self.rollback = history::rollback(&history::load(repo)?);
+        self.deployed = None;
+        if let Some(previous_release) = previous_release {
+            info!("going back to {}", previous_release.display());
+            if let Some(release) = release {
+                releases::discard(repo, &release)?;
+            }
+            if !kept {
+                self.start()?;
+            }
+            self.deployed = Some(previous);
+        }
+        Err(Error::Unhealthy(tip, reason))
+    }
+
+    /// Takes the last deployed commit from the state file, so an unchanged
+    /// commit isn't deployed again after a restart.
+    fn load_state(&mut self, repo: &Repository) {
+        let path = state::path(repo, self.state_file.as_deref());
+        self.deployed = state::load(&path).and_then(|state| state.deployed());
+        if let Some(deployed) = self.deployed {
+            verbose!("last deployed {} according to {}", deployed, path.display());
+        }
+    }
+
+    /// Records a deploy which failed in its build or a hook in the history,
+    /// with how it failed.
+    fn record_failure(&self, tip: Oid, err: &Error) {
+        let detail = match err {
+            Error::Build(err) => format!("build {}", err.cause()),
+            Error::Hook(_, err) => format!("hook {}", err.cause()),
+            Error::Step(_, _, err) => format!("step {}", err.cause()),
+            Error::Docker(action, err) => format!("{} {}", action, err.cause()),
+            Error::Compose(action, err) => format!("{} {}", action, err.cause()),
+            Error::Systemd(..) => "unit start".to_owned(),
+            _ => return,
+        };
+        if let Some(repo) = &self.repo {
+            if let Err(err) = history::record(repo, history::Kind::Failed, tip, Some(&detail)) {
+                warn!("failed to record the failed deploy: {}", err);
+            }
+        }
+    }
+
+    fn save_state(&self, tip: Oid, error: Option<String>) {
+        if let Some(repo) = &self.repo {
+            let path = state::path(repo, self.state_file.as_deref());
+            let mut state = State::new(tip, &self.branch, error);
+            state.port = self.port;
+            if let Some(cidfile) = self.cidfile() {
+                state.container = docker::container_id(&cidfile);
+                state.image = self.image.clone();
+            }
+            if let (Outcome::Ok, Some(degraded)) = (state.result, &self.degraded) {
+                state.result = Outcome::Degraded;
+                state.error = Some(log::redact(degraded));
+            }
+            if let Err(err) = state::save(&path, &state) {
+                warn!("failed to write {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    /// Records a rollback to `to`, or to the previous deploy, which the next
+    /// cycle deploys.
+    pub fn roll_back(&mut self, to: Option<&str>) -> Result<()> {
+        if !self.deploys.is_empty() {
+            return Err(Error::InvalidArgument(
+                "deploy",
+                "rollback needs a single deployment".to_owned(),
+            ));
+        }
+        let repo = match &self.repo {
+            Some(repo) => repo,
+            None => return Ok(()),
+        };
+        let to = match to {
+            Some(rev) => Some(repo.revparse_single(rev)?.peel_to_commit()?.id()),
+            None => None,
+        };
+        let target = history::rollback_target(&history::load(repo)?, to)?;
+        history::record(repo, history::Kind::Rollback, target, None)?;
+        self.trigger = "manual";
+        self.rollback = history::rollback(&history::load(repo)?);
+        info!("rolling back to {}", target);
+        Ok(())
+    }
+
+    /// Replaces `target` with the commit rolled back to while a rollback is
+    /// in effect. A commit which was never deployed ends the rollback.
+    fn hold_rollback(&mut self, target: Option<Target>) -> Option<Target> {
+        let rollback = match &self.rollback {
+            Some(rollback) => rollback,
+            None => return target,
+        };
+        match target {
+            Some(target) if target.oid != rollback.to && !rollback.from.contains(&target.oid) => {
+                info!(
+                    "{} was never deployed, ending the rollback to {}",
+                    target.oid, rollback.to
+                );
+                self.rollback = None;
+                Some(target)
+            }
+            target => Some(Target {
+                oid: rollback.to,
+                tag: None,
+                branch: target.and_then(|target| target.branch),
+            }),
+        }
+    }
+
+    /// The skip marker in the message of the commit about to be deployed.
+    /// Only commits replacing an earlier deploy are skipped, on startup the
+    /// command is always started.
+    fn skip_marker(&self, tip: Option<Oid>) -> Option<String> {
+        let (repo, tip) = match (&self.repo, tip) {
+            (Some(repo), Some(tip)) if self.running() => (repo, tip),
+            _ => return None,
+        };
+        let commit = repo.find_commit(tip).ok()?;
+        let message = commit.message()?;
+        self.skip_markers
+            .iter()
+            .find(|marker| message.contains(marker.as_str()))
+            .cloned()
+    }
+
+    /// Leaves the command running although a new commit was fetched, and
+    /// moves the checkout along with `--skip-advances-checkout`.
+    fn skip(&mut self, tip: Option<Oid>, marker: &str) -> Result<()> {
+        if self.skipped == tip {
+            return Ok(());
+        }
+        let oid = tip.map(|oid| oid.to_string()).unwrap_or_default();
+        if self.skip_advances_checkout {
+            info!(
+                "skipping deploy of {}: its message contains {}, updating the checkout without restarting",
+                oid, marker
+            );
+            self.update_working_tree(tip)?;
+            self.deployed = tip;
+        } else {
+            info!(
+                "skipping deploy of {}: its message contains {}, keeping the checkout",
+                oid, marker
+            );
+        }
+        self.skipped = tip;
+        Ok(())
+    }
+
+    /// Whether deploying `tip` can reload the running command with
+    /// `--reload-signal`. A new release has a directory of its own the command
+    /// isn't running in, and changes outside the `--reload-path`s need a
+    /// cold start.
+    fn can_reload(&self, tip: Option<Oid>) -> bool {
+        if self.child.is_none() || !self.processes.is_empty() {
+            return false;
+        }
+        if self.releases.is_some() && self.deploy_dir.is_some() {
+            info!("restarting the command into the new release instead of reloading it");
+            return false;
+        }
+        if self.reload_paths.is_empty() {
+            return true;
+        }
+        let changed = match (&self.repo, self.deployed, tip) {
+            (Some(repo), Some(deployed), Some(tip)) => changes::changed_paths(repo, deployed, tip),
+            _ => return false,
+        };
+        let changed = match changed {
+            Ok(changed) => changed,
+            Err(err) => {
+                debug!("can't tell what changed, restarting the command: {}", err);
+                return false;
+            }
+        };
+        match changed.iter().find(|path| {
+            !self
+                .reload_paths
+                .iter()
+                .any(|pattern| pattern.matches_path(path))
+        }) {
+            Some(path) => {
+                info!(
+                    "{} changed outside the --reload-path, restarting the command",
+                    path.display()
+                );
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Sends `signal` to the running command for it to pick up the deploy,
+    /// after the steps of the `--command`. Returns whether it is still running
+    /// after a moment, otherwise it has to be started again.
+    fn reload(&mut self, signal: Signal) -> Result<bool> {
+        self.run_steps()?;
+        let child = match &mut self.child {
+            Some(child) => child,
+            None => return Ok(false),
+        };
+        if !child.send(signal) {
+            warn!(
+                "sending SIG{} to the command failed, restarting it",
+                signal.name
+            );
+            return Ok(false);
+        }
+        shutdown::sleep(RELOAD_GRACE);
+        match child.exited() {
+            Some(status) => {
+                warn!(
+                    "the command exited on SIG{} ({}), starting it again",
+                    signal.name, status
+                );
+                Ok(false)
+            }
+            None => {
+                info!("reloaded the command with SIG{}", signal.name);
+                Ok(true)
+            }
+        }
+    }
+
+    /// The paths matching `--watch-path` which changed since the last deploy.
+    /// `None` if there are no filters or nothing to compare against, so the
+    /// command is always restarted.
+    fn changed_watch_paths(&self, tip: Option<Oid>) -> Option<Vec<String>> {
+        let (repo, deployed, tip) = match (&self.repo, self.deployed, tip) {
+            (Some(repo), Some(deployed), Some(tip)) if !self.watch_paths.is_empty() => {
+                (repo, deployed, tip)
+            }
+            _ => return None,
+        };
+        let changed = match changes::changed_paths(repo, deployed, tip) {
+            Ok(changed) => changed,
+            Err(err) => {
+                debug!("can't diff {} against {}: {}", deployed, tip, err);
+                return None;
+            }
+        };
+        Some(
+            changed
+                .iter()
+                .filter(|path| {
+                    self.watch_paths
+                        .iter()
+                        .any(|pattern| pattern.matches_path(path))
+                })
+                .map(|path| path.display().to_string())
+                .collect(),
+        )
+    }
+
+    /// Checks the signatures of the commit and tag about to be deployed with
+    /// `--verify-signatures` and `--verify-tag-signatures`.
+    fn verify(&self, target: Option<&Target>) -> Result<()> {
+        let (repo, target) = match (&self.repo, target) {
+            (Some(repo), Some(target)) => (repo, target),
+            _ => return Ok(()),
+        };
+        if let (Some(allowed), Some(tag)) = (&self.verify_tag_signatures, &target.tag) {
+            signature::verify_tag(repo, tag, allowed)?;
+        }
+        if let Some(allowed) = &self.verify_signatures {
+            signature::verify_commit(repo, target.oid, allowed)?;
+        }
+        Ok(())
+    }
+
+    /// Where the command runs, in `release` or the new release being
+    /// checked instead of the current one if given.
+    fn command_dir(&self, release: Option<&Path>) -> PathBuf {
+        let root = match (
+            release.or(self.release.as_deref()),
+            &self.deploy_dir,
+            self.releases,
+        ) {
+            (Some(release), _, _) => release.to_owned(),
+            (None, Some(dir), Some(_)) => dir.join(releases::CURRENT),
+            (None, Some(dir), None) => dir.clone(),
+            (None, None, _) => self.repo_path.clone(),
+        };
+        match &self.command_dir {
+            Some(dir) => root.join(dir),
+            None => root,
+        }
+    }
+
+    /// Fails if the `--command-dir` isn't in the checkout, the service may
+    /// have moved.
+    fn check_command_dir(&self, release: Option<&Path>) -> Result<()> {
+        let dir = self.command_dir(release);
+        match self.command_dir.is_some() && !dir.is_dir() {
+            true => Err(Error::MissingCommandDir(dir)),
+            false => Ok(()),
+        }
+    }
+
+    /// `program` in `dir` with the environment of the command. The
+    /// `--env-file`s are read again, so edits apply from the next start.
+    fn command(&self, program: &str, args: &[String], dir: &Path) -> Result<Command> {
+        let mut command = Command::new(child::program(&self.render(program, dir)));
+        for path in &self.env_files {
+            command.envs(dotenv::load(path)?);
+        }
+        command
+            .current_dir(dir)
+            .env("LOCALDEPLOY_BRANCH", &self.branch)
+            .envs(self.deploy_env.iter().map(|(key, value)| (key, value)))
+            .envs(self.env.iter().map(|(key, value)| (key, value)))
+            .args(args.iter().map(|arg| self.render(arg, dir)));
+        if let Some(port) = self.port {
+            command.env("LOCALDEPLOY_PORT", port.to_string());
+        }
+        Ok(command)
+    }
+
+    /// `template` with the variables of the current deploy, for a program
+    /// running in `dir`.
+    fn render(&self, template: &str, dir: &Path) -> String {
+        let deployed = |key| {
+            self.deploy_env
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| value.clone())
+        };
+        template::render(template, |name| match name {
+            "sha" => deployed("LOCALDEPLOY_SHA"),
+            "short_sha" => deployed("LOCALDEPLOY_SHORT_SHA"),
+            "tag" => deployed("LOCALDEPLOY_TAG"),
+            "branch" => Some(self.branch.clone()),
+            "deploy_dir" => Some(dir.display().to_string()),
+            "port" => self.port.map(|port| port.to_string()),
+            _ => None,
+        })
+    }
+
+    /// Runs `step` in `dir` until it exits or `timeout` passes, with its
+    /// output going to `log` or localdeploy's. `prepare` adjusts the command
+    /// before it starts. Returns why it failed.
+    fn run_step(
+        &self,
+        (program, args): &(String, Vec<String>),
+        dir: &Path,
+        timeout: Duration,
+        env: &[(&str, &str)],
+        prepare: &dyn Fn(&mut Command),
+        log: Option<&Capture>,
+    ) -> std::result::Result<(), StepError> {
+        let mut command = self
+            .command(program, args, dir)
+            .map_err(|err| StepError::Start(err.to_string()))?;
+        command.stdin(self.stdin.stdio()).envs(env.iter().copied());
+        prepare(&mut command);
+        let mut running = Running::spawn(&mut command, self.process_group, log, false)
+            .map_err(|err| StepError::Start(err.to_string()))?;
+        match running.finish(timeout) {
+            Some(status) if status.success() => Ok(()),
+            Some(status) => Err(StepError::Exit(status)),
+            None => {
+                running.stop(self.stop_signal, self.stop_timeout);
+                match shutdown::requested() {
+                    true => Err(StepError::Stopped),
+                    false => Err(StepError::Timeout(timeout)),
+                }
+            }
+        }
+    }
+
+    /// Runs the `--build-command` where the command will run, with
+    /// `--compose` pulls and builds the images of the services after it.
+    fn run_build(&self, release: Option<&Path>) -> Result<()> {
+        let build = match &self.build {
+            Some(build) => build,
+            None => return self.run_compose_build(release),
+        };
+        let dir = self.command_dir(release);
+        info!("building in {}", dir.display());
+        let started = Instant::now();
+        if !self.build_limits.is_empty() {
+            debug!("building with {}", self.build_limits);
+        }
+        self.run_step(
+            build,
+            &dir,
+            self.build_timeout,
+            &[],
+            &|command| self.build_limits.apply(command),
+            self.log.as_ref(),
+        )
+        .map_err(|reason| match self.docker {
+            Some(_) => Error::Docker("build", reason),
+            None => Error::Build(reason),
+        })?;
+        info!(
+            "the build finished after {:.1}s",
+            started.elapsed().as_secs_f64()
+        );
+        self.run_compose_build(release)
+    }
+
+    /// Pulls and builds the images of the services of `--compose`.
+    fn run_compose_build(&self, release: Option<&Path>) -> Result<()> {
+        let compose = match &self.compose {
+            Some(compose) => compose,
+            None => return Ok(()),
+        };
+        let dir = self.command_dir(release);
+        for (action, step) in compose.build() {
+            info!("running docker compose {} in {}", action, dir.display());
+            self.run_step(
+                &step,
+                &dir,
+                self.build_timeout,
+                &[],
+                &|command| self.build_limits.apply(command),
+                self.log.as_ref(),
+            )
+            .map_err(|reason| Error::Compose(action, reason))?;
+        }
+        Ok(())
+    }
+
+    /// Starts the services of `--compose` in the background.
+    fn compose_up(&mut self, compose: &Compose) -> Result<()> {
+        self.started = false;
+        info!("starting the services with docker compose up");
+        self.run_step(
+            &compose.up(),
+            &self.command_dir(None),
+            self.hook_timeout,
+            &[],
+            &|command| self.as_command(command),
+            self.log.as_ref(),
+        )
+        .map_err(|reason| Error::Compose("up", reason))?;
+        self.started = true;
+        Ok(())
+    }
+
+    /// Restarts the `--systemd-unit` and waits up to `--ready-timeout` for
+    /// it to become active.
+    fn restart_unit(&mut self, unit: &Unit) -> Result<()> {
+        self.started = false;
+        let failed = |reason: String| Error::Systemd(unit.name.clone(), reason);
+        info!("restarting {}", unit.name);
+        let dir = self.command_dir(None);
+        self.run_step(
+            &unit.restart(),
+            &dir,
+            self.hook_timeout,
+            &[],
+            &|_| {},
+            self.log.as_ref(),
+        )
+        .map_err(|reason| failed(format!("systemctl restart {}", reason)))?;
+        let (program, args) = unit.is_active();
+        let started = Instant::now();
+        loop {
+            let output = self
+                .command(&program, &args, &dir)
+                .and_then(|mut command| Ok(child::output(&mut command)?))
+                .map_err(|err| failed(format!("systemctl is-active failed: {}", err)))?;
+            match systemd::state(&String::from_utf8_lossy(&output.stdout)) {
+                systemd::State::Active => break,
+                systemd::State::Down(state) => return Err(failed(format!("is {}", state))),
+                systemd::State::Activating if started.elapsed() >= self.ready_timeout => {
+                    return Err(failed(format!(
+                        "wasn't active within {}",
+                        humantime::format_duration(self.ready_timeout)
+                    )))
+                }
+                systemd::State::Activating => thread::sleep(READY_POLL),
+            }
+        }
+        info!(
+            "{} is active after {:.1}s",
+            unit.name,
+            started.elapsed().as_secs_f64()
+        );
+        self.started = true;
+        Ok(())
+    }
+
+    /// Waits up to `--ready-timeout` for the `--compose-health-service` to
+    /// become healthy. Returns why it didn't.
+    fn wait_service_healthy(&self) -> std::result::Result<(), String> {
+        let (compose, service) = match &self.compose {
+            Some(compose) => match &compose.health_service {
+                Some(service) => (compose, service),
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        let dir = self.command_dir(None);
+        let (program, args) = compose.ps(service);
+        let started = Instant::now();
+        loop {
+            if shutdown::requested() {
+                return Ok(());
+            }
+            let output = self
+                .command(&program, &args, &dir)
+                .and_then(|mut command| Ok(child::output(&mut command)?))
+                .map_err(|err| format!("docker compose ps failed: {}", err))?;
+            if !output.status.success() {
+                return Err(format!(
+                    "docker compose ps failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            match compose::health(service, &String::from_utf8_lossy(&output.stdout))? {
+                compose::Health::Healthy => {
+                    info!(
+                        "the service {} is healthy after {:.1}s",
+                        service,
+                        started.elapsed().as_secs_f64()
+                    );
+                    return Ok(());
+                }
+                compose::Health::Failed(state) => {
+                    return Err(format!("the service {} is {}", service, state))
+                }
+                compose::Health::Starting if started.elapsed() >= self.ready_timeout => {
+                    return Err(format!(
+                        "the service {} wasn't healthy within {}",
+                        service,
+                        humantime::format_duration(self.ready_timeout)
+                    ))
+                }
+                compose::Health::Starting => shutdown::sleep(HEALTH_INTERVAL),
+            }
+        }
+    }
+
+    /// Runs `hook` given with `option` to completion in the directory of
+    /// the command.
+    fn run_hook(
+        &self,
+        option: &str,
+        hook: &(String, Vec<String>),
+        env: &[(&str, &str)],
+    ) -> Result<()> {
+        let dir = self.command_dir(None);
+        // Before the first deploy there is no directory of the command yet.
+        let dir = match dir.is_dir() {
+            true => dir,
+            false => self.repo_path.clone(),
+        };
+        let line = command_line(hook);
+        verbose!("running the {} {}", option, line);
+        self.run_step(
+            hook,
+            &dir,
+            self.hook_timeout,
+            env,
+            &|_| {},
+            self.log.as_ref(),
+        )
+        .map_err(|reason| Error::Hook(format!("{} {}", option, line), reason))
+    }
+
+    /// Runs the `--command`s before the last one after the other, each has
+    /// to succeed before the next starts. With `--log-dir` each writes to a
+    /// log file of its own.
+    fn run_steps(&self) -> Result<()> {
+        let dir = self.command_dir(None);
+        for (index, step) in self.steps.iter().enumerate() {
+            let line = command_line(step);
+            verbose!("running step {} {}", index + 1, line);
+            let log = self.process_capture(&format!("step-{}", index + 1))?;
+            self.run_step(
+                step,
+                &dir,
+                self.hook_timeout,
+                &[],
+                &|command| self.as_command(command),
+                log.as_ref(),
+            )
+            .map_err(|reason| Error::Step(index + 1, line, reason))?;
+        }
+        Ok(())
+    }
+
+    /// Applies the `--nice`, `--limit-*` and `--run-as` of the command to
+    /// `command`. The niceness goes first, a negative one needs root.
+    fn as_command(&self, command: &mut Command) {
+        self.limits.apply(command);
+        if let Some(run_as) = &self.run_as {
+            run_as.apply(command);
+        }
+    }
+
+    /// The processes of the `--procfile` in `dir`, if there is one.
+    fn read_procfile(&self, dir: &Path) -> Result<Option<Vec<(String, String)>>> {
+        match &self.procfile {
+            Some(path) => procfile::load(&dir.join(path), &self.only).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Starts the command, with `--procfile` all of its processes. The
+    /// Procfile is read again for each start, it may change with a deploy.
+    /// When a process can't start, those started before are stopped again.
+    fn start(&mut self) -> Result<()> {
+        if let Some(compose) = self.compose.clone() {
+            return self.compose_up(&compose);
+        }
+        if let Some(unit) = self.unit.clone() {
+            return self.restart_unit(&unit);
+        }
+        let mut listed = match self.read_procfile(&self.command_dir(None))? {
+            Some(listed) => listed.into_iter(),
+            None => {
+                self.run_steps()?;
+                return self.spawn_cmd();
+            }
+        };
+        let shell = self.shell.as_deref();
+        let (name, line) = listed.next().unwrap_or_default();
+        template::check("procfile", &line)?;
+        let (cmd, args) = split_command("procfile", line, shell)?;
+        self.cmd = cmd;
+        self.args = args;
+        let mut processes = Vec::new();
+        for (name, line) in listed {
+            template::check("procfile", &line)?;
+            let (cmd, args) = split_command("procfile", line, shell)?;
+            processes.push(Process {
+                log: self.process_capture(&name)?,
+                restart: self.restart_policy(Some(&name)),
+                name,
+                cmd,
+                args,
+                child: None,
+                exit_handled: false,
+                restart_at: None,
+                quick_exits: 0,
+                tail: Arc::new(Tail::default()),
+            });
+        }
+        self.process_log = self.process_capture(&name)?;
+        self.process_name = Some(name);
+        self.processes = processes;
+        self.spawn_cmd()?;
+        let mut processes = std::mem::take(&mut self.processes);
+        let started = processes
+            .iter_mut()
+            .try_for_each(|process| self.spawn_process(process));
+        self.processes = processes;
+        if let Err(err) = started {
+            self.stop_processes();
+            if let Some(mut child) = self.child.take() {
+                child.stop(self.stop_signal, self.stop_timeout);
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// With `--log-dir` the log file of the process `name`, next to the
+    /// one of localdeploy's steps.
+    fn process_capture(&self, name: &str) -> Result<Option<Capture>> {
+        match &self.log {
+            Some(log) => Ok(Some(Capture {
+                log: match &log.log {
+                    Some(file) => Some(Arc::new(file.sibling(name)?)),
+                    None => None,
+                },
+                ..log.clone()
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the command runs, or the services of `--compose` were
+    /// started.
+    fn running(&self) -> bool {
+        self.child.is_some() || self.started
+    }
+
+    /// Where `docker run` writes the id of the container of `--docker`.
+    fn cidfile(&self) -> Option<PathBuf> {
+        match (&self.docker, &self.repo) {
+            (Some(_), Some(repo)) => Some(repo.path().join(docker::CIDFILE)),
+            _ => None,
+        }
+    }
+
+    /// The commit being deployed, or the deployed one.
+    fn deploy_sha(&self) -> Option<String> {
+        self.deploy_env
+            .iter()
+            .find(|(name, _)| *name == "LOCALDEPLOY_SHA")
+            .map(|(_, sha)| sha.clone())
+            .or_else(|| self.deployed.map(|oid| oid.to_string()))
+    }
+
+    fn spawn_process(&self, process: &mut Process) -> Result<()> {
+        process.exit_handled = false;
+        process.restart_at = None;
+        process.tail = Arc::new(Tail::default());
+        let capture = match &process.log {
+            Some(log) => Capture {
+                tail: Some(process.tail.clone()),
+                sha: self.deploy_sha(),
+                ..log.clone()
+            },
+            None => Capture {
+                log: None,
+                format: LogFormat::Plain,
+                label: None,
+                ready: None,
+                tail: Some(process.tail.clone()),
+                syslog: None,
+                sha: None,
+            },
+        };
+        let mut command = self.command(&process.cmd, &process.args, &self.command_dir(None))?;
+        self.as_command(&mut command);
+        let running = self
+            .spawn(command.stdin(self.stdin.stdio()), &capture)
+            .map_err(|err| Error::ProcessStart(process.name.clone(), err.to_string()))?;
+        verbose!("started the {} process", process.name);
+        process.child = Some(running);
+        Ok(())
+    }
+
+    /// Starts `command`, trying again a few times when it failed for a
+    /// reason which passes, like a binary the build is still writing.
+    fn spawn(&self, command: &mut Command, capture: &Capture) -> Result<Running> {
+        let mut attempt = 0;
+        loop {
+            match Running::spawn(command, self.process_group, Some(capture), self.pty) {
+                Ok(running) => return Ok(running),
+                Err(err) if attempt < SPAWN_RETRIES && is_transient(&err) => {
+                    attempt += 1;
+                    warn!(
+                        "starting {} failed: {}, retrying in {} ({}/{})",
+                        command.get_program().to_string_lossy(),
+                        err,
+                        humantime::format_duration(SPAWN_RETRY_DELAY),
+                        attempt,
+                        SPAWN_RETRIES
+                    );
+                    shutdown::sleep(SPAWN_RETRY_DELAY);
+                }
+                Err(source) => {
+                    return Err(Error::SpawnFailed {
+                        cmd: child::shell_line(command),
+                        dir: command
+                            .get_current_dir()
+                            .map(Path::to_owned)
+                            .unwrap_or_default(),
+                        source,
+                    })
+                }
+            }
+        }
+    }
+
+    /// Stops the processes of the `--procfile` after the first.
+    fn stop_processes(&mut self) {
+        for process in &mut self.processes {
+            process.restart_at = None;
+            if let Some(mut child) = process.child.take() {
+                child.stop(self.stop_signal, self.stop_timeout);
+            }
+        }
+    }
+
+    fn spawn_cmd(&mut self) -> Result<()> {
+        self.exit_handled = false;
+        self.restart_at = None;
+        self.memory_sampled = None;
+        self.memory_over = None;
+        if !self.env.is_empty() {
+            let names = self
+                .env
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .collect::<Vec<_>>();
+            debug!("starting the command with {}", names.join(", "));
+        }
+        if !self.limits.is_empty() {
+            debug!("starting the command with {}", self.limits);
+        }
+        // Only lines of this start of the command count for `--ready-log`.
+        self.ready_line = match &self.ready {
+            Some(health::Ready::Log(pattern)) => Some(Arc::new(ReadyLine::new(pattern.clone()))),
+            _ => None,
+        };
+        self.tail = Arc::new(Tail::default());
+        let capture = match self.process_log.as_ref().or(self.log.as_ref()) {
+            Some(log) => Capture {
+                ready: self.ready_line.clone(),
+                tail: Some(self.tail.clone()),
+                sha: self.deploy_sha(),
+                ..log.clone()
+            },
+            None => Capture {
+                log: None,
+                format: LogFormat::Plain,
+                label: None,
+                ready: self.ready_line.clone(),
+                tail: Some(self.tail.clone()),
+                syslog: None,
+                sha: None,
+            },
+        };
+        let dir = self.command_dir(None);
+        let mut command = self.command(&self.cmd, &self.args, &dir)?;
+        let cidfile = self.cidfile();
+        if let (Some(docker), Some(cidfile)) = (&self.docker, &cidfile) {
+            docker.remove(cidfile);
+            // The container gets the variables of the command, docker
+            // passes them on from its own environment.
+            let names = command
+                .get_envs()
+                .map(|(name, _)| name.to_string_lossy().into_owned())
+                .collect();
+            let args = docker.run(names, cidfile);
+            command.args(args.iter().map(|arg| self.render(arg, &dir)));
+        }
+        self.image = None;
+        self.as_command(&mut command);
+        let running = self.spawn(command.stdin(self.stdin.stdio()), &capture);
+        let mut running = match (running, &self.process_name) {
+            (Err(err), Some(name)) => {
+                return Err(Error::ProcessStart(name.clone(), err.to_string()))
+            }
+            (running, _) => running?,
+        };
+        if let (Some(docker), Some(cidfile)) = (&self.docker, &cidfile) {
+            let waited = docker::wait(&mut running, cidfile, self.ready_timeout)?;
+            self.image = Some(self.render(&docker.image, &dir));
+            match waited {
+                Some(id) => info!(
+                    "started the container {} ({})",
+                    docker.container,
+                    id.get(..12).unwrap_or(&id)
+                ),
+                None => warn!(
+                    "docker didn't create the container {} within {}",
+                    docker.container,
+                    humantime::format_duration(self.ready_timeout)
+                ),
+            }
+        }
+        if let Some(path) = &self.child_pid_file {
+            if let Err(err) = running.write_pid_file(path) {
+                warn!("writing the pid file {} failed: {}", path.display(), err);
+            }
+        }
+        self.child = Some(running);
+        Ok(())
+    }
+
+    /// Fetches from `origin`, falling back to the `--remote`s after it when
+    /// the network fails.
+    fn fetch_with_retries(&mut self) -> Result<()> {
+        let remotes = std::iter::once(self.origin.clone())
+            .chain(self.fallbacks.clone())
+            .collect::<Vec<_>>();
+        let mut failed: Option<(String, Error)> = None;
+        for remote in remotes {
+            if let Some((from, err)) = failed.take() {
+                warn!("fetch from {} failed: {}, trying {}", from, err, remote);
+            }
+            match self.fetch_remote_with_retries(&remote) {
+                Ok(()) => {
+                    self.fetched_from = Some(remote);
+                    return Ok(());
+                }
+                Err(err) if err.is_network() => failed = Some((remote, err)),
+                Err(err) => return Err(err),
+            }
+        }
+        match failed {
+            Some((_, err)) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Fetches `remote`, retrying network errors `--fetch-retries` times
+    /// with a doubling backoff. Rejected credentials are not retried.
+    fn fetch_remote_with_retries(&mut self, remote: &str) -> Result<()> {
+        let mut backoff = self.fetch_backoff;
+        let mut attempt = 0;
+        loop {
+            match self.fetch_git_repo(remote) {
+                Err(err) if err.is_network() && !err.is_auth() && attempt < self.fetch_retries => {
+                    attempt += 1;
+                    warn!(
+                        "fetch failed: {}, retrying in {} ({}/{})",
+                        err,
+                        humantime::format_duration(backoff),
+                        attempt,
+                        self.fetch_retries
+                    );
+                    // The command is still looked after while waiting.
+                    self.watch_for(backoff);
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn fetch_git_repo(&mut self, remote: &str) -> Result<()> {
+        let refspec = self.refspecs();
+        if let (true, Some(repo)) = (self.preflight, &self.repo) {
+            if !self.remote_changed(repo, &refspec, remote)? {
+                debug!("no ref changed on {}, not fetching", remote);
+                return Ok(());
+            }
+        }
+
+        // The deployed branch keeps its remote-tracking ref, even when it was
+        // deleted upstream.
+        let deployed_ref = format!("refs/remotes/{}/{}", self.origin, self.branch);
+        let mut deleted_upstream = false;
+        if let Some(repo) = &self.repo {
+            let kept = match (self.prune, self.detached(), self.branch.is_empty()) {
+                (true, false, false) => repo.refname_to_id(&deployed_ref).ok(),
+                _ => None,
+            };
+            let mut pruned = self.fetch(repo, refspec, remote)?;
+            if let (Some(oid), Err(_)) = (kept, repo.refname_to_id(&deployed_ref)) {
+                repo.reference(
+                    &deployed_ref,
+                    oid,
+                    false,
+                    "localdeploy: keep deployed branch",
+                )?;
+                pruned.retain(|name| *name != deployed_ref);
+                deleted_upstream = true;
+            }
+            if !pruned.is_empty() {
+                verbose!(
+                    "pruned {} stale ref(s): {}",
+                    pruned.len(),
+                    pruned.join(", ")
+                );
+            }
+        }
+        if deleted_upstream && !self.deleted_upstream {
+            warn!(
+                "branch {} was deleted on {}, keeping the deployed commit",
+                self.branch, self.origin
+            );
+        }
+        self.deleted_upstream = deleted_upstream;
+        Ok(())
+    }
+
+    /// Fetches `refspec` from the remote, within `--git-timeout`. Returns the
+    /// refs removed with `--prune`.
+    ///
+    /// The refspecs name the refs of `origin`, so other remotes update them
+    /// as well.
+    fn fetch(&self, repo: &Repository, refspec: Vec<String>, remote: &str) -> Result<Vec<String>> {
+        let path = repo.path().to_path_buf();
+        let connect = self.connect_for(repo, remote);
+        let (origin, depth, prune) = (remote.to_owned(), self.depth, self.prune);
+        timeout::run("fetch", self.git_timeout, move || {
+            let repo = Repository::open(path)?;
+            let progress = Progress::new("fetching");
+            let pruned = RefCell::new(Vec::new());
+            let mut fo = git2::FetchOptions::new();
+            let mut callbacks = connect.remote_callbacks();
+            progress.attach(&mut callbacks);
+            if prune {
+                fo.prune(FetchPrune::On);
+                callbacks.update_tips(|name, _, new| {
+                    if new.is_zero() {
+                        pruned.borrow_mut().push(name.to_owned());
+                    }
+                    true
+                });
+            }
+            fo.remote_callbacks(callbacks);
+            if let Some(proxy) = connect.proxy_options() {
+                fo.proxy_options(proxy);
+            }
+            if let Some(depth) = depth {
+                fo.depth(depth);
+            }
+            let fetched = repo
+                .find_remote(&origin)?
+                .fetch(&refspec, Some(&mut fo), None);
+            drop(fo);
+
+            let (objects, bytes) = progress.finish();
+            fetched.map_err(|err| connect.blame_proxy(err))?;
+            connect.succeeded();
+            if objects > 0 {
+                info!("fetched {} objects ({})", objects, progress::bytes(bytes));
+            }
+            Ok(pruned.take())
+        })
+    }
+
+    /// The refs advertised by the remote, listed within `--git-timeout`.
+    fn list_remote(&self, repo: &Repository, remote: &str) -> Result<Vec<(String, Oid)>> {
+        let path = repo.path().to_path_buf();
+        let connect = self.connect_for(repo, remote);
+        let origin = remote.to_owned();
+        timeout::run("ref listing", self.git_timeout, move || {
+            let repo = Repository::open(path)?;
+            let mut remote = repo.find_remote(&origin)?;
+            let connection = remote
+                .connect_auth(
+                    Direction::Fetch,
+                    Some(connect.remote_callbacks()),
+                    connect.proxy_options(),
+                )
+                .map_err(|err| connect.blame_proxy(err))?;
+            connect.succeeded();
+            Ok(connection
+                .list()?
+                .iter()
+                .map(|head| (head.name().to_owned(), head.oid()))
+                .collect())
+        })
+    }
+
+    /// How to reach `remote`, which may need other credentials than
+    /// `origin`.
+    fn connect_for(&self, repo: &Repository, remote: &str) -> Connect {
+        match repo
+            .find_remote(remote)
+            .ok()
+            .and_then(|remote| remote.url().map(str::to_owned))
+        {
+            Some(url) => self.connect.with_url(&url),
+            None => self.connect.clone(),
+        }
+    }
+
+    /// Adds the remotes of `--remote-url` or points them at the given url,
+    /// and makes sure all `--remote`s exist.
+    fn add_remotes(&self, repo: &Repository) -> Result<()> {
+        for (name, url) in &self.remote_urls {
+            match repo.find_remote(name) {
+                Ok(remote) if remote.url() == Some(url.as_str()) => {}
+                Ok(_) => {
+                    verbose!("setting the url of remote {} to {}", name, url);
+                    repo.remote_set_url(name, url)?
+                }
+                Err(_) => {
+                    verbose!("adding remote {} at {}", name, url);
+                    repo.remote(name, url)?;
+                }
+            }
+        }
+        for name in &self.fallbacks {
+            repo.find_remote(name)?;
+        }
+        Ok(())
+    }
+
+    /// What is fetched, depending on what is deployed.
+    fn refspecs(&self) -> Vec<String> {
+        if !self.deploys.is_empty() {
+            self.deploys
+                .iter()
+                .map(|deploy| {
+                    format!(
+                        "+refs/heads/{}:refs/remotes/{}/{}",
+                        deploy.branch, self.origin, deploy.branch
+                    )
+                })
+                .collect()
+        } else if !self.refspecs.is_empty() {
+            self.refspecs.clone()
+        } else if self.bare {
+            // Mirrors all branches and tags, the branches as remote-tracking
+            // refs like in every other mode.
+            vec![
+                format!("+refs/heads/*:refs/remotes/{}/*", self.origin),
+                "+refs/tags/*:refs/tags/*".to_owned(),
+            ]
+        } else if self.follows_tags() {
+            vec!["+refs/tags/*:refs/tags/*".to_owned()]
+        } else if self.commit.is_some() || self.branch_pattern.is_some() {
+            // The pinned commit may be on any branch.
+            vec![format!("+refs/heads/*:refs/remotes/{}/*", self.origin)]
+        } else {
+            vec![format!(
+                "+refs/heads/{}:refs/remotes/{}/{}",
+                self.branch, self.origin, self.branch
+            )]
+        }
+    }
+
+    /// Lists the refs on the remote and compares them with the local refs
+    /// `refspecs` fetch into, which is much cheaper than a fetch.
+    fn remote_changed(&self, repo: &Repository, refspecs: &[String], remote: &str) -> Result<bool> {
+        let mut advertised = HashMap::new();
+        for (head, oid) in self.list_remote(repo, remote)? {
+            // Peeled tags are advertised as `<tag>^{}` next to the tag itself.
+            if head.ends_with("^{}") {
+                continue;
+            }
+            for spec in refspecs {
+                if let Some(name) = refspec::transform(spec, &head) {
+                    advertised.insert(name, oid);
+                }
+            }
+        }
+        if advertised
+            .iter()
+            .any(|(name, oid)| repo.refname_to_id(name).ok() != Some(*oid))
+        {
+            return Ok(true);
+        }
+        if !self.prune {
+            return Ok(false);
+        }
+        // Refs deleted upstream are only removed with `--prune`.
+        let kept = format!("refs/remotes/{}/{}", self.origin, self.branch);
+        for spec in refspecs {
+            for reference in refspec::local_refs(repo, spec)? {
+                let stale = !advertised.contains_key(&reference);
+                if stale && !(self.deleted_upstream && reference == kept) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn follows_tags(&self) -> bool {
+        self.tag_pattern.is_some() || self.tag_semver.is_some()
+    }
+
+    /// Whether the deployed commit is checked out without a local branch.
+    fn detached(&self) -> bool {
+        self.follows_tags() || self.commit.is_some() || self.checkout_ref.is_some()
+    }
+
+    /// The fetched commit that should be deployed: the selected tag in tag
+    /// mode, or the remote-tracking ref of the deployed branch.
+    fn target(&self) -> Result<Option<Target>> {
+        let repo = match &self.repo {
+            Some(repo) => repo,
+            None => return Ok(None),
+        };
+        if let Some(sha) = &self.commit {
+            let commit = repo
+                .revparse_single(sha)
+                .and_then(|object| object.peel_to_commit())
+                .map_err(|_| Error::UnknownCommit(sha.clone()))?;
+            return Ok(Some(Target {
+                oid: commit.id(),
+                tag: None,
+                branch: None,
+            }));
+        }
+        if let Some(name) = &self.checkout_ref {
+            let commit = repo.find_reference(name)?.peel_to_commit()?;
+            return Ok(Some(Target {
+                oid: commit.id(),
+                tag: None,
+                branch: None,
+            }));
+        }
+        let tag = match (&self.tag_semver, &self.tag_pattern) {
+            (Some(req), pattern) => Some((
+                tags::highest_semver(repo, pattern.as_ref(), req, self.include_prerelease)?,
+                req.to_string(),
+            )),
+            (None, Some(pattern)) => Some((tags::newest(repo, pattern)?, pattern.to_string())),
+            (None, None) => None,
+        };
+        if let Some((tag, wanted)) = tag {
+            return match tag {
+                Some(tag) => Ok(Some(Target {
+                    oid: tag.commit,
+                    tag: Some(tag.name),
+                    branch: None,
+                })),
+                None => Err(Error::NoMatchingTag(wanted)),
+            };
+        }
+        if let Some(pattern) = &self.branch_pattern {
+            return match branches::newest(repo, &self.origin, pattern)? {
+                Some((branch, oid)) => Ok(Some(Target {
+                    oid,
+                    tag: None,
+                    branch: Some(branch),
+                })),
+                None => Err(Error::NoMatchingBranch(pattern.to_string())),
+            };
+        }
+        let remote_ref = format!("refs/remotes/{}/{}", self.origin, self.branch);
+        Ok(Some(Target {
+            oid: repo.refname_to_id(&remote_ref)?,
+            tag: None,
+            branch: Some(self.branch.clone()),
+        }))
+    }
+
+    /// Brings the working tree to the fetched commit, so the command always
+    /// runs against the fetched code.
+    fn update_working_tree(&mut self, target: Option<Oid>) -> Result<()> {
+        // Bare repos only feed `--deploy-dir`.
+        if self.repo.as_ref().is_some_and(|repo| repo.is_bare()) {
+            return Ok(());
+        }
+        if let (true, Some(repo)) = (self.clean, &self.repo) {
+            let removed = clean::clean(repo, self.clean_ignored)?;
+            if !removed.is_empty() {
+                let paths = removed
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>();
+                info!(
+                    "removed {} untracked path(s): {}",
+                    removed.len(),
+                    paths.join(", ")
+                );
+            }
+        }
+
+        let stash = match (self.autostash, &mut self.repo) {
+            (true, Some(repo)) => stash::save(repo, self.dirty_untracked)?,
+            _ => None,
+        };
+        let result = match (self.detached() || self.rollback.is_some(), target) {
+            (true, Some(oid)) => self.checkout_detached(oid),
+            _ => self.move_to_remote(),
+        };
+        if let (Some(stash), Some(repo)) = (stash, &mut self.repo) {
+            stash::pop(repo, stash)?;
+        }
+        result?;
+
+        if let (Some(sparse), Some(repo)) = (&self.sparse, &self.repo) {
+            sparse::apply(repo, Some(sparse))?;
+        }
+        if let (true, Some(repo)) = (self.submodules, &self.repo) {
+            submodules::update(repo, &|| self.fetch_options())?;
+        }
+        if let (true, Some(repo)) = (self.lfs, &self.repo) {
+            lfs::pull(repo.workdir().unwrap_or(&self.repo_path))?;
+        }
+        Ok(())
+    }
+
+    /// Moves the local branch to the remote one, handling diverged branches
+    /// according to `--on-diverge`.
+    fn move_to_remote(&mut self) -> Result<()> {
+        let (local, remote) = match self.fast_forward()? {
+            Some(diverged) => diverged,
+            None => return Ok(()),
+        };
+        match self.on_diverge {
+            OnDiverge::Fail => {
+                info!(
+                    "branch {} diverged from {}/{} (local {}, remote {}), failing",
+                    self.branch, self.origin, self.branch, local, remote
+                );
+                Err(Error::NotFastForward {
+                    branch: self.branch.clone(),
+                    local,
+                    remote,
+                })
+            }
+            OnDiverge::Reset => {
+                info!(
+                    "branch {} diverged from {}/{} (local {}, remote {}), resetting",
+                    self.branch, self.origin, self.branch, local, remote
+                );
+                if let Some(repo) = &self.repo {
+                    Main::reset_hard(
+                        repo,
+                        &self.branch,
+                        &repo.find_commit(remote)?,
+                        self.sparse_patterns(),
+                    )?;
+                }
+                Ok(())
+            }
+            OnDiverge::Reclone => {
+                info!(
+                    "branch {} diverged from {}/{} (local {}, remote {}), recloning",
+                    self.branch, self.origin, self.branch, local, remote
+                );
+                self.reclone()?;
+                match self.fast_forward()? {
+                    Some((local, remote)) => Err(Error::NotFastForward {
+                        branch: self.branch.clone(),
+                        local,
+                        remote,
+                    }),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Fast-forwards the local branch to the fetched remote-tracking ref and
+    /// checks it out. Returns the local and remote commit if the branches
+    /// diverged and no fast-forward was possible.
+    fn fast_forward(&self) -> Result<Option<(Oid, Oid)>> {
+        let repo = match &self.repo {
+            Some(repo) => repo,
+            None => return Ok(None),
+        };
+        let remote_ref = format!("refs/remotes/{}/{}", self.origin, self.branch);
+        let local_ref = format!("refs/heads/{}", self.branch);
+        let target = repo.find_reference(&remote_ref)?.peel_to_commit()?;
+
+        if self.reset_hard {
+            Main::reset_hard(repo, &self.branch, &target, self.sparse_patterns())?;
+            return Ok(None);
+        }
+
+        let on_branch = repo
+            .head()
+            .map(|head| head.name() == Some(local_ref.as_str()))
+            .unwrap_or(false);
+        match repo.find_reference(&local_ref) {
+            Ok(mut reference) => {
+                let local = reference.peel_to_commit()?.id();
+                // The history of a shallow repo ends too early to tell
+                // whether the branches diverged.
+                if local != target.id()
+                    && !repo.is_shallow()
+                    && !repo.graph_descendant_of(target.id(), local)?
+                {
+                    return Ok(Some((local, target.id())));
+                }
+                if local != target.id() || !on_branch {
+                    self.ensure_clean(repo)?;
+                    // Check out first so a conflicting working tree leaves the
+                    // branch where it was.
+                    repo.checkout_tree(target.as_object(), Some(self.checkout().safe()))?;
+                    reference.set_target(target.id(), "localdeploy: fast-forward")?;
+                }
+            }
+            Err(err) if err.code() == ErrorCode::NotFound => {
+                self.ensure_clean(repo)?;
+                repo.checkout_tree(target.as_object(), Some(self.checkout().safe()))?;
+                repo.branch(&self.branch, &target, false)?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+        repo.set_head(&local_ref)?;
+        Ok(None)
+    }
+
+    /// Checks out `oid` with a detached HEAD.
+    fn checkout_detached(&self, oid: Oid) -> Result<()> {
+        let repo = match &self.repo {
+            Some(repo) => repo,
+            None => return Ok(()),
+        };
+        let commit = repo.find_commit(oid)?;
+        let mut checkout = self.checkout();
+        if self.reset_hard {
+            checkout.force();
+        } else {
+            self.ensure_clean(repo)?;
+            checkout.safe();
+        }
+        repo.checkout_tree(commit.as_object(), Some(&mut checkout))?;
+        repo.set_head_detached(oid)?;
+        Ok(())
+    }
+
+    /// Deletes the local repository and clones it again from the url of the
+    /// configured remote.
+    fn reclone(&mut self) -> Result<()> {
+        let (url, workdir) = match &self.repo {
+            Some(repo) => {
+                let remote = repo.find_remote(&self.origin)?;
+                let url = remote.url().ok_or(Error::MissingUrlToRepo)?.to_owned();
+                let workdir = repo.workdir().unwrap_or_else(|| repo.path()).to_path_buf();
+                (url, workdir)
+            }
+            None => return Ok(()),
+        };
+        self.repo = None;
+        std::fs::remove_dir_all(&workdir)?;
+        let repo = self.new_repo(&url, &workdir, false)?;
+        self.repo = Some(repo);
+        Ok(())
+    }
+
+    /// Whether `err` came from a corrupted repo. Missing objects often
+    /// surface as other errors, like refs which can't be peeled, so git
+    /// errors also check that the objects of each ref can be read.
+    fn is_corrupted(&self, err: &Error) -> bool {
+        if err.is_corruption() {
+            return true;
+        }
+        let repo = match (err, &self.repo) {
+            (Error::GitError(_), Some(repo)) => repo,
+            _ => return false,
+        };
+        let references = match repo.references() {
+            Ok(references) => references,
+            Err(err) => return Error::from(err).is_corruption(),
+        };
+        references
+            .flatten()
+            .filter_map(|reference| reference.target())
+            .any(|oid| {
+                repo.find_object(oid, None)
+                    .and_then(|object| object.peel_to_tree())
+                    .is_err_and(|err| {
+                        err.class() == ErrorClass::Odb || err.class() == ErrorClass::Zlib
+                    })
+            })
+    }
+
+    /// Moves the corrupted repo aside and clones it again from the url it had
+    /// when starting. Refuses if tracked files were modified, they would be
+    /// left behind in the broken repo.
+    fn recover(&mut self, err: &Error) -> Result<()> {
+        let (url, repo) = match (&self.url, &self.repo) {
+            (Some(url), Some(repo)) => (url.clone(), repo),
+            _ => return Err(Error::MissingUrlToRepo),
+        };
+        let mut options = StatusOptions::new();
+        options
+            .show(StatusShow::Workdir)
+            .include_untracked(false)
+            .include_ignored(false);
+        // Comparing the working tree with the index needs no objects, if
+        // even that fails the index is lost anyway.
+        if let Ok(statuses) = repo.statuses(Some(&mut options)) {
+            let index = repo.index().ok();
+            let dirty = statuses
+                .iter()
+                .filter(|entry| entry.status() != Status::CURRENT)
+                .filter_map(|entry| entry.path().map(|path| path.to_owned()))
+                .filter(|path| {
+                    !index
+                        .as_ref()
+                        .is_some_and(|index| sparse::is_skipped(index, path))
+                })
+                .collect::<Vec<_>>();
+            if !dirty.is_empty() {
+                return Err(Error::Corrupted {
+                    error: err.to_string(),
+                    dirty,
+                });
+            }
+        }
+
+        let bare = repo.is_bare();
+        let workdir = repo.workdir().unwrap_or_else(|| repo.path()).to_path_buf();
+        let git_dir = repo.path().strip_prefix(&workdir).map(Path::to_path_buf);
+        let aside = workdir.with_file_name(format!(
+            "{}.corrupt-{}",
+            workdir.file_name().unwrap_or_default().to_string_lossy(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or_default()
+        ));
+        warn!(
+            "the repo is corrupted: {}, moving it to {} and cloning it again",
+            err,
+            aside.display()
+        );
+        self.repo = None;
+        std::fs::rename(&workdir, &aside)?;
+        let repo = self.new_repo(&url, &workdir, bare)?;
+        // Keep the deploy history for rollbacks.
+        if let Ok(git_dir) = git_dir {
+            let _ = std::fs::copy(
+                aside.join(git_dir).join(history::FILE),
+                repo.path().join(history::FILE),
+            );
+        }
+        self.repo = Some(repo);
+        self.deployed = None;
+        Ok(())
+    }
+
+    /// Points the local branch at `target` and forces the index and working
+    /// tree to match it, like `git reset --hard <remote>/<branch>`.
+    fn reset_hard(
+        repo: &Repository,
+        branch: &str,
+        target: &Commit,
+        patterns: &[String],
+    ) -> Result<()> {
+        let local_ref = format!("refs/heads/{}", branch);
+        if repo.find_branch(branch, BranchType::Local).is_err() {
+            repo.branch(branch, target, false)?;
+        }
+        // `reset` moves whatever HEAD points to, so attach it to the branch
+        // first.
+        repo.set_head(&local_ref)?;
+
+        // A forced checkout removes files which were deleted upstream and
+        // replaces files which turned into directories (and vice versa), even
+        // when they are in the way as untracked files.
+        let mut checkout = sparse::checkout(patterns);
+        checkout.force().remove_untracked(false);
+        repo.reset(target.as_object(), ResetType::Hard, Some(&mut checkout))?;
+        Ok(())
+    }
+
+    fn sparse_patterns(&self) -> &[String] {
+        self.sparse.as_ref().map_or(&[], |sparse| sparse.patterns())
+    }
+
+    /// A checkout limited to `--sparse-path`.
+    fn checkout(&self) -> CheckoutBuilder<'static> {
+        sparse::checkout(self.sparse_patterns())
+    }
+
+    /// Applies `--sparse-path` to an existing checkout. When it was dropped
+    /// since the last run, the whole tree is checked out again.
+    fn reconcile_sparse(&self, repo: &Repository) -> Result<()> {
+        let mut config = repo.config()?;
+        let was_sparse = config.get_bool("localdeploy.sparse").unwrap_or(false);
+        match &self.sparse {
+            Some(sparse) => {
+                sparse::apply(repo, Some(sparse))?;
+                config.set_bool("localdeploy.sparse", true)?;
+            }
+            None if was_sparse => {
+                info!("--sparse-path was removed, checking out the whole tree");
+                sparse::apply(repo, None)?;
+                config.remove("localdeploy.sparse")?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Refuses to update a working tree with local modifications, unless
+    /// `--force-dirty` was given. Untracked files only count with
+    /// `--dirty-untracked`.
+    fn ensure_clean(&self, repo: &Repository) -> Result<()> {
+        if self.force_dirty {
+            return Ok(());
+        }
+        let mut options = StatusOptions::new();
+        options
+            .include_untracked(self.dirty_untracked)
+            .recurse_untracked_dirs(false)
+            .include_ignored(false);
+        let index = repo.index()?;
+        let dirty = repo
+            .statuses(Some(&mut options))?
+            .iter()
+            .filter(|entry| entry.status() != Status::CURRENT)
+            .filter_map(|entry| entry.path().map(|path| path.to_owned()))
+            .filter(|path| !sparse::is_skipped(&index, path))
+            .collect::<Vec<_>>();
+        if !dirty.is_empty() {
+            return Err(Error::DirtyWorkTree(dirty));
+        }
+        Ok(())
+    }
+
+    /// Asks the remote for its default branch. The result is remembered in
+    /// the repo config, so a failed detection later on falls back to it.
+    fn default_branch(&self) -> Result<String> {
+        let repo = match &self.repo {
+            Some(repo) => repo,
+            None => return Err(Error::MissingPath),
+        };
+        let path = repo.path().to_path_buf();
+        let connect = self.connect.clone();
+        let origin = self.origin.clone();
+        let detected = timeout::run("default branch detection", self.git_timeout, move || {
+            let repo = Repository::open(path)?;
+            let mut remote = repo.find_remote(&origin)?;
+            let connection = remote
+                .connect_auth(
+                    Direction::Fetch,
+                    Some(connect.remote_callbacks()),
+                    connect.proxy_options(),
+                )
+                .map_err(|err| connect.blame_proxy(err))?;
+            connect.succeeded();
+            let head = connection.default_branch()?;
+            match head
+                .as_str()
+                .and_then(|head| head.strip_prefix("refs/heads/"))
+            {
+                Some(branch) => Ok(branch.to_owned()),
+                None => Err(Error::NoDefaultBranch(origin.clone())),
+            }
+        });
+
+        let mut config = repo.config()?;
+        match detected {
+            Ok(branch) => {
+                info!("using the default branch {} of {}", branch, self.origin);
+                config.set_str("localdeploy.branch", &branch)?;
+                Ok(branch)
+            }
+            Err(err) => match config.get_string("localdeploy.branch") {
+                Ok(branch) => {
+                    warn!(
+                        "could not detect the default branch of {} ({}), using {} as before",
+                        self.origin, err, branch
+                    );
+                    Ok(branch)
+                }
+                Err(_) => Err(err),
+            },
+        }
+    }
+
+    fn fetch_options(&self) -> FetchOptions<'_> {
+        self.connect.fetch_options()
+    }
+
+    /// Prompts for the passphrase of the ssh key, again if the key rejects
+    /// it.
+    fn passphrase(&mut self) -> Result<()> {
+        for attempt in 1..=PASSPHRASE_ATTEMPTS {
+            let passphrase = prompt_password_stdout("SSH Passphrase: ")?;
+            match connect::check_passphrase(&self.connect.keys, &passphrase) {
+                Ok(()) => {
+                    self.connect.passphrase = Some(passphrase);
+                    return Ok(());
+                }
+                Err(err) if attempt < PASSPHRASE_ATTEMPTS => warn!("{}, try again", err),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits the command line given with `option` into the program and its
+/// arguments, quotes and backslashes work as in a shell. With `--shell` it
+/// is passed to the `shell` as it is instead.
+fn split_command(
+    option: &'static str,
+    command: String,
+    shell: Option<&str>,
+) -> Result<(String, Vec<String>)> {
+    if let Some(shell) = shell {
+        if command.trim().is_empty() {
+            return Err(Error::InvalidArgument(option, command));
+        }
+        // cmd.exe has its own syntax, other shells take -c.
+        let is_cmd = Path::new(shell)
+            .file_stem()
+            .is_some_and(|stem| stem.eq_ignore_ascii_case("cmd"));
+        let flag = if is_cmd { "/C" } else { "-c" };
+        return Ok((shell.to_owned(), vec![flag.to_owned(), command]));
+    }
+    let mut words = match words::split(&command) {
+        Ok(words) => words.into_iter(),
+        Err(reason) => {
+            return Err(Error::InvalidArgument(
+                option,
+                format!("{}: {}", command, reason),
+            ))
+        }
+    };
+    match words.next() {
+        Some(program) => Ok((program, words.collect())),
+        None => Err(Error::InvalidArgument(option, command)),
+    }
+}
+
+/// The program and arguments of `command` as one line, for messages.
+fn command_line((program, args): &(String, Vec<String>)) -> String {
+    std::iter::once(program)
+        .chain(args)
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Starting a command failed for a reason which may pass on its own, like a
+/// binary which is still open for writing.
+fn is_transient(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::ExecutableFileBusy
+            | std::io::ErrorKind::ResourceBusy
+            | std::io::ErrorKind::WouldBlock
+            | std::io::ErrorKind::Interrupted
+    )
+}
+
+/// The directory name for cloning `url`: its last path segment without
+/// `.git`, for both `https://host/org/app.git` and `git@host:org/app.git`.
+fn repo_name(url: &str) -> Option<&str> {
+    let path = url.trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    // Only the part after the scheme or scp-like host can name the repo.
+    let path = match path.split_once("://") {
+        Some((_, rest)) => rest.split_once('/')?.1,
+        None => path.rsplit_once(':').map_or(path, |(_, path)| path),
+    };
+    match path.rsplit(['/', '\\']).next() {
+        Some(name) if !name.is_empty() && name != "." && name != ".." => Some(name),
+        _ => None,
+    }
+}
+
+/// Parses sizes like `4096`, `512k`, `10M` or `1G`, plain numbers are
+/// bytes and the units are powers of 1024.
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, ""),
+    };
+    let unit = match unit.trim().to_ascii_lowercase().trim_end_matches('b') {
+        "" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// Whether an exit with `status` is restarted by `policy`.
+fn restarts(policy: Restart, status: ExitStatus) -> bool {
+    match policy {
+        Restart::Always => true,
+        Restart::OnFailure => !status.success(),
+        Restart::Never => false,
+    }
+}
+
+/// How long to wait before restarting a command which ran for `uptime`.
+/// Each exit soon after starting in a row doubles it, counted in
+/// `quick_exits`.
+fn restart_delay(quick_exits: &mut u32, uptime: Duration) -> Duration {
+    *quick_exits = match uptime < QUICK_EXIT {
+        true => *quick_exits + 1,
+        false => 0,
+    };
+    match *quick_exits {
+        0 => Duration::ZERO,
+        n => Duration::from_secs(1 << n.min(6)),
+    }
+}
+
+/// The niceness and limits given with the `options` for them.
+fn parse_limits(app: &Options, [nice, nofile, address_space]: [&'static str; 3]) -> Result<Limits> {
+    let nice = match app.value_of(nice) {
+        Some(value) => match value.parse() {
+            Ok(nice) => Some(nice),
+            Err(_) => return Err(Error::InvalidArgument(nice, value)),
+        },
+        None => None,
+    };
+    let nofile = match app.value_of(nofile) {
+        Some(value) => match value.parse() {
+            Ok(nofile) if nofile > 0 => Some(nofile),
+            _ => return Err(Error::InvalidArgument(nofile, value)),
+        },
+        None => None,
+    };
+    let address_space = match app.value_of(address_space) {
+        Some(value) => match parse_size(&value) {
+            Some(size) if size > 0 => Some(size),
+            _ => return Err(Error::InvalidArgument(address_space, value)),
+        },
+        None => None,
+    };
+    Ok(Limits {
+        nice,
+        nofile,
+        address_space,
+    })
+}
+
+/// Parses durations like `90`, `120s`, `5m` or `1h 30m`, plain numbers are
+/// seconds.
+fn parse_duration(value: &str) -> Option<Duration> {
+    match value.parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => humantime::parse_duration(value).ok(),
+    }
+}
+
+fn main() {
+    if let Err(err) = start() {
+        eprintln!("Error: {}", log::redact(&err.to_string()));
+        std::process::exit(1);
+    }
+}
+
+/// `secret set <name>`: prompts for the secret and stores it in the keyring
+/// under the url of the repo given with `--new` or `--path`.
+fn set_secret(options: &Options, name: &str) -> Result<()> {
+    let new = options
+        .value_of("new")
+        .map(|new| connect::absolute_url(&new));
+    let path = match options.value_of("path") {
+        Some(path) => paths::resolve(&path),
+        None => env::current_dir()?,
+    };
+    let remote = options
+        .values_of("remote")
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| "origin".to_owned());
+    let url = secret::url(new.as_deref(), &path, options.is_present("deploy"), &remote)?;
+    let value = prompt_password_stdout(&format!("{} for {}: ", name, url))?;
+    secret::set(&url, name, value.trim_end_matches(['\r', '\n']))?;
+    info!("stored the {} for {} in the keyring", name, url);
+    Ok(())
+}
+
+/// `check-auth`: tries the credentials against the remote of `--new` or
+/// `--path` without deploying.
+fn check_auth(options: &Options) -> Result<()> {
+    let new = options
+        .value_of("new")
+        .map(|new| connect::absolute_url(&new));
+    let path = match options.value_of("path") {
+        Some(path) => paths::resolve(&path),
+        None => env::current_dir()?,
+    };
+    let shared = options.is_present("deploy");
+    let mut main = Main::configure(options, options.value_of("branch"), Vec::new(), path)?;
+    main.connect.local = new.as_deref().is_some_and(connect::is_local);
+    main.read_credentials(options, new.as_deref(), shared)?;
+    main.check_auth(new.as_deref(), shared)
+}
+
+/// What `--help` says about the variables the command gets and the ones
+/// its command line can use.
+const ENVIRONMENT: &str = "\
+ENVIRONMENT:
+    The command, the --build-command, the hooks and the health check get:
+    LOCALDEPLOY_BRANCH          the deployed branch
+    LOCALDEPLOY_SHA             the deployed commit
+    LOCALDEPLOY_SHORT_SHA       its first 7 characters
+    LOCALDEPLOY_TAG             the deployed tag, with --tag-pattern or --tag-semver
+    LOCALDEPLOY_PREVIOUS_SHA    the commit deployed before
+    LOCALDEPLOY_DEPLOY_TIME     when the deploy started, like 2024-01-31T12:00:00Z
+    LOCALDEPLOY_TRIGGER         startup, interval, manual for rollback, or rollback
+                                after a failed --health-check
+    LOCALDEPLOY_PORT            the port with --overlap
+
+TEMPLATES:
+    {{sha}}, {{short_sha}}, {{branch}}, {{tag}}, {{deploy_dir}} and {{port}} in the
+    command, the --build-command, the hooks and the health check are replaced
+    for every deploy, {{{{ is a literal {{";
+
+fn start() -> Result<()> {
+    let app = App::new("localdeploy")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author("Ceeox <me@ceox.dev>")
+        .after_help(ENVIRONMENT)
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .conflicts_with("verbose")
+                .help("Only print warnings and errors"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .help("Print more about what is going on, repeat for debug output"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .takes_value(true)
+                .value_name("CONFIG")
+                .help("Path to a TOML config file; keys are named like the long options"),
+        )
+        .arg(
+            Arg::with_name("new")
                 .short("n")
                 .long("new")
                 .takes_value(true)
-                .value_name("REPO_URL")
+                .value_name("REPO_URL")
+                .help(
+                    "Url to the new git repo. Ensure a path to where the repo should to cloned to.",
+                ),
+        )
+        .arg(
+            Arg::with_name("branch")
+                .short("b")
+                .long("branch")
+                .takes_value(true)
+                .value_name("BRANCH")
+                .help("Branch to deploy, defaults to the default branch of the remote"),
+        )
+        .arg(
+            Arg::with_name("remote")
+                .short("r")
+                .long("remote")
+                .takes_value(true)
+                .value_name("REMOTE")
+                .default_value("origin")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Provides a default origin to fetch repo from, further ones are tried in order when fetching fails"),
+        )
+        .arg(
+            Arg::with_name("remote-url")
+                .long("remote-url")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAME=URL")
+                .help("Adds the remote NAME at URL or changes its url, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("public-key")
+                .long("public-key")
+                .takes_value(true)
+                .value_name("PUBLIC_KEY")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Path to the public ssh key of the --private-key at the same position, defaults to its .pub file"),
+        )
+        .arg(
+            Arg::with_name("private-key")
+                .long("private-key")
+                .takes_value(true)
+                .value_name("PRIVATE_KEY")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Path to the private ssh key, can be repeated to try several keys in order; defaults to the first of ~/.ssh/id_ed25519, id_ecdsa and id_rsa which exists"),
+        )
+        .arg(
+            Arg::with_name("path")
+                .short("p")
+                .long("path")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("File path to the existing repo"),
+        )
+        .arg(
+            Arg::with_name("command")
+                .short("c")
+                .long("command")
+                .takes_value(true)
+                .value_name("CMD")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Command to run the project, quotes and backslashes work as in a shell. Repeated, the ones before the last run one after the other at each start and have to succeed"),
+        )
+        .arg(
+            Arg::with_name("shell")
+                .long("shell")
+                .help("Run the command, the --build-command, the hooks and --health-check-cmd with sh -c, cmd /C on Windows"),
+        )
+        .arg(
+            Arg::with_name("shell-path")
+                .long("shell-path")
+                .takes_value(true)
+                .value_name("PATH")
+                .requires("shell")
+                .help("The shell of --shell, like /bin/bash or powershell"),
+        )
+        .arg(
+            Arg::with_name("env")
+                .long("env")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("KEY=VALUE")
+                .help("Set KEY to VALUE in the environment of the command, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("env-file")
+                .long("env-file")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATH")
+                .help("Read KEY=VALUE lines for the environment of the command from PATH at every start, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("deploy")
+                .long("deploy")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("BRANCH:DIR:CMD")
+                .conflicts_with_all(&[
+                    "command",
+                    "branch",
+                    "branch-pattern",
+                    "tag-pattern",
+                    "tag-semver",
+                    "commit",
+                    "refspec",
+                ])
+                .help("Check out BRANCH into DIR below --path and run CMD there, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .short("i")
+                .long("interval")
+                .takes_value(true)
+                .value_name("INTERVAL")
+                .default_value("3600")
+                .help("Interval between each git fetch in sec"),
+        )
+        .arg(
+            Arg::with_name("username")
+                .short("u")
+                .long("username")
+                .takes_value(true)
+                .value_name("USERNAME")
+                .default_value("git")
+                .help("Username for git auth"),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .takes_value(true)
+                .value_name("TOKEN")
+                .help("Token or password for HTTPS remotes, used with --username"),
+        )
+        .arg(
+            Arg::with_name("password-stdin")
+                .long("password-stdin")
+                .conflicts_with("token")
+                .help("Read the token or password for HTTPS remotes from the first line of stdin"),
+        )
+        .arg(
+            Arg::with_name("token-env")
+                .long("token-env")
+                .takes_value(true)
+                .value_name("VAR")
+                .conflicts_with_all(&["token", "password-stdin"])
+                .help("Read the token or password for HTTPS remotes from the environment variable VAR"),
+        )
+        .arg(
+            Arg::with_name("token-file")
+                .long("token-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with_all(&["token", "password-stdin"])
+                .help("Read the token or password for HTTPS remotes from FILE, wins over --token-env"),
+        )
+        .arg(
+            Arg::with_name("host-fingerprint")
+                .long("host-fingerprint")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("SHA256:...")
+                .help("Only accept an ssh host key with this fingerprint instead of checking ~/.ssh/known_hosts, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("strict-host-keys")
+                .long("strict-host-keys")
+                .help("Refuse ssh hosts which aren't in ~/.ssh/known_hosts instead of adding them"),
+        )
+        .arg(
+            Arg::with_name("agent-only")
+                .long("agent-only")
+                .conflicts_with_all(&[
+                    "public-key",
+                    "use-passphrase",
+                    "passphrase-env",
+                    "passphrase-file",
+                    "passphrase-keyring",
+                ])
+                .help("Only authenticate ssh remotes with the identities of the ssh agent, never with key files"),
+        )
+        .arg(
+            Arg::with_name("passphrase-env")
+                .long("passphrase-env")
+                .takes_value(true)
+                .value_name("VAR")
+                .help("Read the passphrase of the ssh key from the environment variable VAR"),
+        )
+        .arg(
+            Arg::with_name("passphrase-file")
+                .long("passphrase-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Read the passphrase of the ssh key from FILE, which must not be readable by everyone"),
+        )
+        .arg(
+            Arg::with_name("passphrase-keyring")
+                .long("passphrase-keyring")
+                .conflicts_with_all(&["passphrase-env", "passphrase-file"])
+                .help("Read the passphrase of the ssh key from the system keyring, see `secret set`"),
+        )
+        .arg(
+            Arg::with_name("token-keyring")
+                .long("token-keyring")
+                .conflicts_with_all(&["token", "password-stdin", "token-env", "token-file"])
+                .help("Read the https token from the system keyring, see `secret set`"),
+        )
+        .arg(
+            Arg::with_name("github-app")
+                .long("github-app")
+                .takes_value(true)
+                .value_name("APP_ID")
+                .requires_all(&["github-installation", "github-app-key"])
+                .conflicts_with_all(&["token", "password-stdin", "token-env", "token-file", "token-keyring"])
+                .help("Authenticate HTTPS remotes with installation tokens of this GitHub App"),
+        )
+        .arg(
+            Arg::with_name("github-installation")
+                .long("github-installation")
+                .takes_value(true)
+                .value_name("ID")
+                .requires("github-app")
+                .help("The installation of --github-app to get tokens for"),
+        )
+        .arg(
+            Arg::with_name("github-app-key")
+                .long("github-app-key")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("github-app")
+                .help("The private key of --github-app, as PEM file"),
+        )
+        .arg(
+            Arg::with_name("github-api")
+                .long("github-api")
+                .takes_value(true)
+                .value_name("URL")
+                .default_value("https://api.github.com")
+                .help("The API of GitHub or GitHub Enterprise to get --github-app tokens from"),
+        )
+        .arg(
+            Arg::with_name("credential-helper")
+                .long("credential-helper")
+                .help("Ask git's credential helpers for HTTPS credentials when no token is given or it was rejected"),
+        )
+        .arg(
+            Arg::with_name("use-passphrase")
+                .short("s")
+                .long("use-passphrase")
+                .help("Give a hint if the ssh private is protected by a passphrase"),
+        )
+        .arg(
+            Arg::with_name("reset-hard")
+                .long("reset-hard")
+                .help("Discard local changes and reset to the fetched branch on every update"),
+        )
+        .arg(
+            Arg::with_name("always-restart")
+                .long("always-restart")
+                .help("Restart the command every interval, even when nothing changed"),
+        )
+        .arg(
+            Arg::with_name("clean")
+                .long("clean")
+                .help("Remove untracked files and directories before each update"),
+        )
+        .arg(
+            Arg::with_name("clean-ignored")
+                .long("clean-ignored")
+                .help("Like --clean, but also remove ignored files"),
+        )
+        .arg(
+            Arg::with_name("force-dirty")
+                .long("force-dirty")
+                .help("Update the working tree even if it has local modifications"),
+        )
+        .arg(
+            Arg::with_name("dirty-untracked")
+                .long("dirty-untracked")
+                .help("Treat untracked files as local modifications"),
+        )
+        .arg(
+            Arg::with_name("autostash")
+                .long("autostash")
+                .help("Stash local modifications before updating and reapply them afterwards"),
+        )
+        .arg(
+            Arg::with_name("branch-pattern")
+                .long("branch-pattern")
+                .takes_value(true)
+                .value_name("GLOB")
+                .conflicts_with_all(&["branch", "tag-pattern", "tag-semver", "commit"])
+                .help("Deploy whichever branch matching GLOB was committed to last"),
+        )
+        .arg(
+            Arg::with_name("watch-path")
+                .long("watch-path")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("GLOB")
+                .help("Only restart the command if a changed path matches GLOB, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("reload-signal")
+                .long("reload-signal")
+                .takes_value(true)
+                .value_name("SIGNAL")
+                .help("Send SIGNAL, like HUP, to the running command for a deploy instead of restarting it. It is restarted if it exits on it"),
+        )
+        .arg(
+            Arg::with_name("reload-path")
+                .long("reload-path")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("GLOB")
+                .help("Only reload the command with --reload-signal if all changed paths match GLOB, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("skip-marker")
+                .long("skip-marker")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("MARKER")
+                .help("Don't restart for commits whose message contains MARKER, defaults to [skip deploy] and [deploy skip]"),
+        )
+        .arg(
+            Arg::with_name("skip-advances-checkout")
+                .long("skip-advances-checkout")
+                .help("Still update the checkout for skipped commits, without restarting"),
+        )
+        .arg(
+            Arg::with_name("tag-pattern")
+                .long("tag-pattern")
+                .takes_value(true)
+                .value_name("GLOB")
+                .conflicts_with("branch")
+                .help("Deploy the newest tag matching GLOB instead of a branch"),
+        )
+        .arg(
+            Arg::with_name("tag-semver")
+                .long("tag-semver")
+                .takes_value(true)
+                .min_values(0)
+                .value_name("REQ")
+                .conflicts_with("branch")
+                .help("Deploy the highest semver tag, optionally matching a requirement like ^1.4"),
+        )
+        .arg(
+            Arg::with_name("include-prerelease")
+                .long("include-prerelease")
+                .requires("tag-semver")
+                .help("Consider pre-release versions with --tag-semver"),
+        )
+        .arg(
+            Arg::with_name("commit")
+                .long("commit")
+                .takes_value(true)
+                .value_name("SHA")
+                .conflicts_with_all(&["branch", "tag-pattern", "tag-semver"])
+                .help("Pin the deployment to this commit"),
+        )
+        .arg(
+            Arg::with_name("verify-signatures")
+                .long("verify-signatures")
+                .takes_value(true)
+                .value_name("KEYS")
                 .help(
-                    "Url to the new git repo. Ensure a path to where the repo should to cloned to.",
+                    "Only deploy commits signed by a key in KEYS (GPG keys or ssh allowed signers)",
                 ),
         )
         .arg(
-            Arg::with_name("branch")
-                .short("b")
-                .long("branch")
+            Arg::with_name("verify-tag-signatures")
+                .long("verify-tag-signatures")
                 .takes_value(true)
-                .value_name("BRANCH")
-                .default_value("main")
-                .help("Provides a default branch to fetch repo from"),
+                .min_values(0)
+                .value_name("KEYS")
+                .help("Only deploy signed annotated tags, KEYS defaults to --verify-signatures"),
         )
         .arg(
-            Arg::with_name("remote")
-                .short("r")
-                .long("remote")
+            Arg::with_name("proxy")
+                .long("proxy")
                 .takes_value(true)
-                .value_name("REMOTE")
-                .default_value("origin")
-                .help("Provides a default origin to fetch repo from"),
+                .value_name("URL")
+                .help("HTTP(S) proxy for http remotes or auto to use git's config, defaults to HTTPS_PROXY and ALL_PROXY"),
         )
         .arg(
-            Arg::with_name("public-key")
-                .long("public-key")
+            Arg::with_name("git-timeout")
+                .long("git-timeout")
                 .takes_value(true)
-                .value_name("PUBLIC_KEY")
-                .default_value("~/.ssh/id_rsa.pub")
-                .help("Path to the public ssl key"),
+                .value_name("DURATION")
+                .help("Give up on clones and fetches taking longer than DURATION, e.g. 120s or 5m"),
         )
         .arg(
-            Arg::with_name("private-key")
-                .long("private-key")
+            Arg::with_name("fetch-retries")
+                .long("fetch-retries")
                 .takes_value(true)
-                .value_name("PRIVATE_KEY")
-                .default_value("~/.ssh/id_rsa")
-                .help("Path to the private ssl key"),
+                .value_name("COUNT")
+                .default_value("3")
+                .help("How often a fetch failing because of the network is retried"),
         )
         .arg(
-            Arg::with_name("path")
-                .short("p")
-                .long("path")
+            Arg::with_name("fetch-backoff")
+                .long("fetch-backoff")
                 .takes_value(true)
-                .value_name("PATH")
-                .help("File path to the existing repo"),
+                .value_name("DURATION")
+                .default_value("1s")
+                .help("How long to wait before the first retry, doubled for each further one"),
         )
         .arg(
-            Arg::with_name("command")
-                .short("c")
-                .long("command")
+            Arg::with_name("stop-timeout")
+                .long("stop-timeout")
+                .takes_value(true)
+                .value_name("DURATION")
+                .default_value("10s")
+                .help("How long the command may take to exit after the --stop-signal before it is killed"),
+        )
+        .arg(
+            Arg::with_name("build-command")
+                .long("build-command")
                 .takes_value(true)
                 .value_name("CMD")
-                .help("Command to run the project"),
+                .help("Run CMD in the new checkout before restarting the command, a failing build keeps the old version running"),
         )
         .arg(
-            Arg::with_name("interval")
-                .short("i")
-                .long("interval")
+            Arg::with_name("build-timeout")
+                .long("build-timeout")
                 .takes_value(true)
-                .value_name("INTERVAL")
-                .default_value("3600")
-                .help("Interval between each git fetch in sec"),
+                .value_name("DURATION")
+                .requires("build-command")
+                .help("How long the --build-command may run before it is killed and counts as failed [default: 10m]"),
         )
         .arg(
-            Arg::with_name("username")
-                .short("u")
-                .long("username")
+            Arg::with_name("nice")
+                .long("nice")
                 .takes_value(true)
-                .value_name("USERNAME")
-                .default_value("git")
-                .help("Username for git auth"),
+                .allow_hyphen_values(true)
+                .value_name("N")
+                .help("Run the command with the niceness N, -20 to 19, negative needs root"),
         )
         .arg(
-            Arg::with_name("use-passphrase")
-                .short("s")
-                .long("use-passphrase")
-                .help("Give a hint if the ssh private is protected by a passphrase"),
+            Arg::with_name("limit-nofile")
+                .long("limit-nofile")
+                .takes_value(true)
+                .value_name("N")
+                .help("Let the command open at most N files"),
+        )
+        .arg(
+            Arg::with_name("limit-as")
+                .long("limit-as")
+                .takes_value(true)
+                .value_name("SIZE")
+                .help("Limit the address space of the command to SIZE, like 512M or 2G"),
+        )
+        .arg(
+            Arg::with_name("build-nice")
+                .long("build-nice")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .value_name("N")
+                .help("Run the --build-command with the niceness N [default: --nice]"),
+        )
+        .arg(
+            Arg::with_name("build-limit-nofile")
+                .long("build-limit-nofile")
+                .takes_value(true)
+                .value_name("N")
+                .help("Let the --build-command open at most N files [default: --limit-nofile]"),
+        )
+        .arg(
+            Arg::with_name("build-limit-as")
+                .long("build-limit-as")
+                .takes_value(true)
+                .value_name("SIZE")
+                .help("Limit the address space of the --build-command to SIZE [default: --limit-as]"),
+        )
+        .arg(
+            Arg::with_name("pre-deploy-hook")
+                .long("pre-deploy-hook")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("CMD")
+                .help("Run CMD before updating the checkout, a failing hook aborts the deploy, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("post-deploy-hook")
+                .long("post-deploy-hook")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("CMD")
+                .help("Run CMD after a deploy, a failing hook marks the deploy degraded, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("on-failure-hook")
+                .long("on-failure-hook")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("CMD")
+                .help("Run CMD after a failed deploy or when the command exits with an error, with the error in LOCALDEPLOY_ERROR, the last lines on stderr of the command in LOCALDEPLOY_STDERR and the path of its crash report in LOCALDEPLOY_CRASH_REPORT, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("hook-timeout")
+                .long("hook-timeout")
+                .takes_value(true)
+                .value_name("DURATION")
+                .help("How long a hook may run before it is killed and counts as failed [default: 5m]"),
+        )
+        .arg(
+            Arg::with_name("health-check")
+                .long("health-check")
+                .takes_value(true)
+                .value_name("URL")
+                .conflicts_with("health-check-cmd")
+                .help("After a deploy, wait for URL to answer 2xx and roll back to the previous deploy if it doesn't"),
+        )
+        .arg(
+            Arg::with_name("health-check-cmd")
+                .long("health-check-cmd")
+                .takes_value(true)
+                .value_name("CMD")
+                .help("After a deploy, wait for CMD to exit 0 and roll back to the previous deploy if it doesn't"),
+        )
+        .arg(
+            Arg::with_name("health-timeout")
+                .long("health-timeout")
+                .takes_value(true)
+                .value_name("DURATION")
+                .help("How long one health check may take [default: 10s]"),
+        )
+        .arg(
+            Arg::with_name("health-retries")
+                .long("health-retries")
+                .takes_value(true)
+                .value_name("N")
+                .help("How often a failed health check is tried again before rolling back [default: 5]"),
+        )
+        .arg(
+            Arg::with_name("overlap")
+                .long("overlap")
+                .help("Start the new version on the other of --port-a and --port-b and stop the old one once it is healthy"),
+        )
+        .arg(
+            Arg::with_name("port-a")
+                .long("port-a")
+                .takes_value(true)
+                .value_name("PORT")
+                .requires("overlap")
+                .help("The first port of --overlap, {{port}} in the command and health check becomes it"),
+        )
+        .arg(
+            Arg::with_name("port-b")
+                .long("port-b")
+                .takes_value(true)
+                .value_name("PORT")
+                .requires("overlap")
+                .help("The second port of --overlap"),
+        )
+        .arg(
+            Arg::with_name("ready-tcp")
+                .long("ready-tcp")
+                .takes_value(true)
+                .value_name("HOST:PORT")
+                .conflicts_with_all(&["ready-log", "ready-delay"])
+                .help("A deploy succeeds once the command takes connections on HOST:PORT"),
+        )
+        .arg(
+            Arg::with_name("ready-log")
+                .long("ready-log")
+                .takes_value(true)
+                .value_name("REGEX")
+                .conflicts_with("ready-delay")
+                .help("A deploy succeeds once a line of the command's output matches REGEX"),
+        )
+        .arg(
+            Arg::with_name("ready-delay")
+                .long("ready-delay")
+                .takes_value(true)
+                .value_name("DURATION")
+                .help("A deploy succeeds once the command ran this long"),
+        )
+        .arg(
+            Arg::with_name("ready-timeout")
+                .long("ready-timeout")
+                .takes_value(true)
+                .value_name("DURATION")
+                .help("How long the command may take to become ready before the deploy fails [default: 60s]"),
+        )
+        .arg(
+            Arg::with_name("stop-signal")
+                .long("stop-signal")
+                .takes_value(true)
+                .value_name("SIGNAL")
+                .help("Signal asking the command to exit, like TERM, INT, QUIT or USR2 [default: TERM]"),
+        )
+        .arg(
+            Arg::with_name("stdin")
+                .long("stdin")
+                .takes_value(true)
+                .value_name("MODE")
+                .possible_values(&["null", "inherit", "pipe"])
+                .help("What the command, the build and the hooks read from: nothing, localdeploy's stdin or a pipe which stays empty [default: null]"),
+        )
+        .arg(
+            Arg::with_name("run-as")
+                .long("run-as")
+                .takes_value(true)
+                .value_name("USER[:GROUP]")
+                .help("Run the command as USER, with its groups or GROUP, needs root and unix"),
+        )
+        .arg(
+            Arg::with_name("pty")
+                .long("pty")
+                .help("Run the command in a pseudo-terminal, so it prints colors and progress bars as on a terminal, needs unix"),
+        )
+        .arg(
+            Arg::with_name("init")
+                .long("init")
+                .help("Reap the orphaned processes the command leaves behind, as when running as PID 1 of a container, needs linux"),
+        )
+        .arg(
+            Arg::with_name("pid-file")
+                .long("pid-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Write the pid of localdeploy to PATH, refusing to start while another one runs with it"),
+        )
+        .arg(
+            Arg::with_name("child-pid-file")
+                .long("child-pid-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Write the pid of the command to PATH whenever it starts, removed when it stops"),
+        )
+        .arg(
+            Arg::with_name("log-dir")
+                .long("log-dir")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Append the output of the command to DIR/<checkout>.log instead of printing it"),
+        )
+        .arg(
+            Arg::with_name("log-target")
+                .long("log-target")
+                .takes_value(true)
+                .value_name("TARGET")
+                .possible_values(&["file", "syslog", "journald"])
+                .help("Send the output of the command and localdeploy's messages to syslog or journald, or files as with --log-dir when it can't be reached [default: file]"),
+        )
+        .arg(
+            Arg::with_name("log-max-size")
+                .long("log-max-size")
+                .takes_value(true)
+                .value_name("SIZE")
+                .requires("log-dir")
+                .help("Rotate the log file before it grows beyond SIZE, e.g. 10M"),
+        )
+        .arg(
+            Arg::with_name("log-rotate")
+                .long("log-rotate")
+                .takes_value(true)
+                .value_name("WHEN")
+                .possible_values(&["daily"])
+                .requires("log-dir")
+                .help("Rotate the log file on the first line of each day (UTC)"),
+        )
+        .arg(
+            Arg::with_name("log-keep")
+                .long("log-keep")
+                .takes_value(true)
+                .value_name("N")
+                .requires("log-dir")
+                .help("How many rotated log files to keep [default: 5]"),
+        )
+        .arg(
+            Arg::with_name("crash-reports")
+                .long("crash-reports")
+                .takes_value(true)
+                .value_name("N")
+                .requires("log-dir")
+                .help("How many crash reports to keep in the --log-dir, with the exit status, the deployed commit, the command and its last 200 lines of output of each time the command failed [default: 10]"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["plain", "prefixed", "json"])
+                .requires("log-dir")
+                .help("plain lines, prefixed with the time, stream and --deploy checkout, or json objects [default: plain]"),
+        )
+        .arg(
+            Arg::with_name("log-compress")
+                .long("log-compress")
+                .requires("log-dir")
+                .help("Compress rotated log files with gzip"),
+        )
+        .arg(
+            Arg::with_name("no-process-group")
+                .long("no-process-group")
+                .help("Only stop the command itself, not the processes it started"),
+        )
+        .arg(
+            Arg::with_name("depth")
+                .long("depth")
+                .takes_value(true)
+                .value_name("DEPTH")
+                .help(
+                    "Only clone and fetch the last DEPTH commits, force-pushes can't be detected",
+                ),
+        )
+        .arg(
+            Arg::with_name("refspec")
+                .long("refspec")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("REFSPEC")
+                .conflicts_with_all(&["branch", "branch-pattern", "tag-pattern", "tag-semver", "commit"])
+                .help("Fetch REFSPEC instead of the branch, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("checkout-ref")
+                .long("checkout-ref")
+                .takes_value(true)
+                .value_name("REF")
+                .requires("refspec")
+                .help("The fetched ref to deploy, defaults to the destination of a single --refspec"),
+        )
+        .arg(
+            Arg::with_name("prune")
+                .long("prune")
+                .help("Remove remote-tracking refs of branches deleted upstream when fetching"),
+        )
+        .arg(
+            Arg::with_name("no-preflight")
+                .long("no-preflight")
+                .help("Always fetch instead of listing the remote refs first"),
+        )
+        .arg(
+            Arg::with_name("recurse-submodules")
+                .long("recurse-submodules")
+                .help("Clone submodules together with the repo when using --new"),
+        )
+        .arg(
+            Arg::with_name("no-submodules")
+                .long("no-submodules")
+                .help("Don't initialize and update submodules after updating"),
+        )
+        .arg(
+            Arg::with_name("sparse-path")
+                .long("sparse-path")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATTERN")
+                // Stashing would bring back the left out paths.
+                .conflicts_with("autostash")
+                .help("Only check out paths matching PATTERN, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("command-dir")
+                .long("command-dir")
+                .visible_alias("workdir")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Run the command, the --build-command and the hooks in DIR relative to the repo root or --deploy-dir"),
+        )
+        .arg(
+            Arg::with_name("deploy-dir")
+                .long("deploy-dir")
+                .takes_value(true)
+                .value_name("DIR")
+                .conflicts_with_all(&["deploy", "lfs"])
+                .help("Export the deployed commit without .git into DIR and run the command there"),
+        )
+        .arg(
+            Arg::with_name("releases")
+                .long("releases")
+                .takes_value(true)
+                .value_name("N")
+                .requires("deploy-dir")
+                .help("Extract each deploy into DIR/releases, point DIR/current at it and keep the last N"),
+        )
+        .arg(
+            Arg::with_name("release-worktrees")
+                .long("release-worktrees")
+                .requires("releases")
+                .help("Add each release as a linked worktree of the repo instead of extracting it"),
+        )
+        .arg(
+            Arg::with_name("state-file")
+                .long("state-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with("deploy")
+                .help("Where to remember the last deploy [default: localdeploy-state.json in the git directory]"),
+        )
+        .arg(
+            Arg::with_name("reclone-on-corruption")
+                .long("reclone-on-corruption")
+                .conflicts_with("deploy")
+                .help("Move a corrupted repo aside and clone it again, unless tracked files were modified"),
+        )
+        .arg(
+            Arg::with_name("respect-detached")
+                .long("respect-detached")
+                .help("Don't update while HEAD was detached by hand, instead of re-attaching the branch"),
+        )
+        .arg(
+            Arg::with_name("bare")
+                .long("bare")
+                .requires("deploy-dir")
+                .help("Keep only a bare mirror of all branches and tags at --path, never a working tree"),
+        )
+        .arg(
+            Arg::with_name("lfs")
+                .long("lfs")
+                .help("Download Git LFS files with git lfs after updating, needs git-lfs on PATH"),
+        )
+        .arg(
+            Arg::with_name("procfile")
+                .long("procfile")
+                .takes_value(true)
+                .min_values(0)
+                .value_name("PATH")
+                .conflicts_with_all(&["command", "overlap"])
+                .help("Run the name: command processes of the Procfile at PATH in the directory of the command, the first one is health checked [default: Procfile]"),
+        )
+        .arg(
+            Arg::with_name("only")
+                .long("only")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAMES")
+                .requires("procfile")
+                .help("Only run the comma separated processes of the --procfile"),
+        )
+        .arg(
+            Arg::with_name("process-restart")
+                .long("process-restart")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAME=POLICY")
+                .requires("procfile")
+                .help("The --restart policy of the process NAME of the --procfile, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("docker")
+                .long("docker")
+                .conflicts_with_all(&["command", "procfile", "build-command", "overlap", "deploy"])
+                .help("Build an image of the checkout with docker build and run it as a container in place of the command, the old container keeps running when the build fails"),
+        )
+        .arg(
+            Arg::with_name("compose")
+                .long("compose")
+                .takes_value(true)
+                .min_values(0)
+                .value_name("FILE")
+                .conflicts_with_all(&["command", "procfile", "docker", "overlap", "deploy"])
+                .help("Run the services of the compose FILE in the directory of the command with docker compose up -d in place of the command, after pulling and building their images [default: the compose file docker finds]"),
+        )
+        .arg(
+            Arg::with_name("compose-profile")
+                .long("compose-profile")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PROFILE")
+                .requires("compose")
+                .help("Enable the PROFILE of the compose file, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("compose-health-service")
+                .long("compose-health-service")
+                .takes_value(true)
+                .value_name("SERVICE")
+                .requires("compose")
+                .help("The service of --compose which has to be healthy according to docker compose ps within --ready-timeout, otherwise the deploy is rolled back"),
+        )
+        .arg(
+            Arg::with_name("systemd-unit")
+                .long("systemd-unit")
+                .takes_value(true)
+                .value_name("NAME")
+                .conflicts_with_all(&["command", "procfile", "docker", "compose", "overlap", "deploy"])
+                .help("Restart the systemd unit NAME with systemctl after a deploy in place of running a command, a unit which isn't active within --ready-timeout fails the deploy"),
+        )
+        .arg(
+            Arg::with_name("systemd-user")
+                .long("systemd-user")
+                .requires("systemd-unit")
+                .help("The --systemd-unit is one of the user's systemd manager"),
+        )
+        .arg(
+            Arg::with_name("docker-image")
+                .long("docker-image")
+                .takes_value(true)
+                .value_name("TEMPLATE")
+                .requires("docker")
+                .help("The name and tag of the image of --docker, can use the variables of --command [default: <checkout>:{{sha}}]"),
+        )
+        .arg(
+            Arg::with_name("docker-run-args")
+                .long("docker-run-args")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .value_name("ARGS")
+                .requires("docker")
+                .help("Arguments of docker run like ports, volumes and env, split like the command"),
+        )
+        .arg(
+            Arg::with_name("container-name")
+                .long("container-name")
+                .takes_value(true)
+                .value_name("NAME")
+                .requires("docker")
+                .help("The name of the container of --docker, which replaces any container of that name [default: <checkout>]"),
+        )
+        .arg(
+            Arg::with_name("restart")
+                .long("restart")
+                .takes_value(true)
+                .value_name("POLICY")
+                .possible_values(&["always", "on-failure", "never"])
+                .default_value("never")
+                .help("Start the command again when it exits on its own, always or only on failure"),
+        )
+        .arg(
+            Arg::with_name("max-memory")
+                .long("max-memory")
+                .takes_value(true)
+                .value_name("SIZE")
+                .help("Restart the command when it and what it started use more than SIZE of resident memory, e.g. 512M (Linux only)"),
+        )
+        .arg(
+            Arg::with_name("max-memory-for")
+                .long("max-memory-for")
+                .takes_value(true)
+                .value_name("DURATION")
+                .requires("max-memory")
+                .help("How long the command may stay above --max-memory before it is restarted [default: 30s]"),
+        )
+        .arg(
+            Arg::with_name("on-diverge")
+                .long("on-diverge")
+                .takes_value(true)
+                .value_name("ACTION")
+                .possible_values(&["fail", "reset", "reclone"])
+                .default_value("fail")
+                .help("What to do when the remote branch was force-pushed"),
+        )
+        .subcommand(
+            SubCommand::with_name("rollback")
+                .about("Deploy the previous deploy again, or the one given with --to, then keep running")
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .value_name("COMMIT")
+                        .help("Roll back to this earlier deployed commit"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check-auth")
+                .about("Connect to the remote with the configured credentials and check the branch exists, without deploying"),
+        )
+        .subcommand(
+            SubCommand::with_name("secret")
+                .about("Manage the secrets kept in the system keyring")
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Prompt for a secret and store it in the keyring under the repo's url")
+                        .arg(
+                            Arg::with_name("name")
+                                .required(true)
+                                .possible_values(&secret::NAMES)
+                                .help("The secret to store, read back with --passphrase-keyring or --token-keyring"),
+                        ),
+                ),
         )
         .get_matches();
 
-    let mut main = Main::new(app)?;
+    let options = Options::new(app)?;
+    log::set_level(
+        match (
+            options.is_present("quiet"),
+            options.occurrences_of("verbose"),
+        ) {
+            (true, _) => log::Level::Quiet,
+            (false, 0) => log::Level::Normal,
+            (false, 1) => log::Level::Verbose,
+            (false, _) => log::Level::Debug,
+        },
+    );
+    if options.subcommand("check-auth").is_some() {
+        return check_auth(&options);
+    }
+    if let Some(set) = options
+        .subcommand("secret")
+        .and_then(|secret| secret.subcommand_matches("set"))
+    {
+        return set_secret(&options, set.value_of("name").unwrap_or_default());
+    }
+    let rollback = options
+        .subcommand("rollback")
+        .map(|rollback| rollback.value_of("to").map(str::to_owned));
+    let mut main = Main::new(options)?;
+    if let Some(to) = rollback {
+        main.roll_back(to.as_deref())?;
+    }
+    shutdown::listen()?;
     main.run()?;
 
     Ok(())
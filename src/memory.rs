@@ -0,0 +1,55 @@
+/// Whether the memory of the command can be measured on this platform.
+pub(crate) const SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// The resident memory of the process `pid` in bytes, with `group` that of
+/// all processes in the process group. `None` if it can't be measured.
+#[cfg(target_os = "linux")]
+pub(crate) fn rss(pid: u32, group: Option<i32>) -> Option<u64> {
+    let group = match group {
+        Some(group) => group,
+        None => return process_rss(&pid.to_string()),
+    };
+    let mut total = None;
+    for entry in std::fs::read_dir("/proc").ok()?.flatten() {
+        let name = entry.file_name();
+        let pid = match name.to_str() {
+            Some(pid) if pid.bytes().all(|c| c.is_ascii_digit()) => pid,
+            _ => continue,
+        };
+        // The command name in parentheses may contain anything, the fields
+        // after it are state, parent and process group.
+        let stat = match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        let pgrp = stat
+            .rsplit_once(')')
+            .and_then(|(_, fields)| fields.split_whitespace().nth(2))
+            .and_then(|pgrp| pgrp.parse::<i32>().ok());
+        if pgrp == Some(group) {
+            if let Some(rss) = process_rss(pid) {
+                total = Some(total.unwrap_or(0) + rss);
+            }
+        }
+    }
+    total
+}
+
+#[cfg(target_os = "linux")]
+fn process_rss(pid: &str) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn rss(_pid: u32, _group: Option<i32>) -> Option<u64> {
+    None
+}
@@ -0,0 +1,704 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use regex::Regex;
+
+use crate::{
+    error::{Error, Result},
+    syslog::{Fields, Priority, Sink},
+    user::RunAs,
+};
+
+/// How many of the last lines on stderr are kept for when the command exits.
+const TAIL_LINES: usize = 20;
+
+/// How many of the last lines of all output are kept for a crash report.
+const OUTPUT_LINES: usize = 200;
+
+/// How the lines of `--log-format` are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LogFormat {
+    /// As the command printed them.
+    Plain,
+    /// After the time, the stream and the checkout of `--deploy`.
+    Prefixed,
+    /// One JSON object per line, for log shippers.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "plain" => Ok(LogFormat::Plain),
+            "prefixed" => Ok(LogFormat::Prefixed),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(Error::InvalidArgument("log-format", s.to_owned())),
+        }
+    }
+}
+
+/// Where and how the output of one command is written.
+#[derive(Debug, Clone)]
+pub(crate) struct Capture {
+    /// The log file, or localdeploy's own output without `--log-dir`.
+    pub log: Option<Arc<LogFile>>,
+    pub format: LogFormat,
+    /// The checkout of `--deploy` the command runs in, named in the lines.
+    pub label: Option<String>,
+    pub ready: Option<Arc<ReadyLine>>,
+    pub tail: Option<Arc<Tail>>,
+    /// Syslog or journald of `--log-target`, the log file is only written
+    /// when it can't be reached.
+    pub syslog: Option<Arc<Sink>>,
+    /// The commit deployed when the command started, for journald.
+    pub sha: Option<String>,
+}
+
+/// The last lines the command wrote to stderr, to report them when it
+/// exits unexpectedly, and the last of all its output for a crash report.
+#[derive(Debug, Default)]
+pub(crate) struct Tail {
+    lines: Mutex<VecDeque<String>>,
+    output: Mutex<VecDeque<String>>,
+}
+
+impl Tail {
+    pub fn lines(&self) -> Vec<String> {
+        let lines = self.lines.lock().unwrap_or_else(|err| err.into_inner());
+        lines.iter().cloned().collect()
+    }
+
+    /// The last lines of all streams, each with its stream in front.
+    pub fn output(&self) -> Vec<String> {
+        let output = self.output.lock().unwrap_or_else(|err| err.into_inner());
+        output.iter().cloned().collect()
+    }
+
+    fn push(&self, line: &[u8]) {
+        let mut lines = self.lines.lock().unwrap_or_else(|err| err.into_inner());
+        if lines.len() == TAIL_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(String::from_utf8_lossy(line).trim_end().to_owned());
+    }
+
+    fn record(&self, stream: &str, line: &[u8]) {
+        let mut output = self.output.lock().unwrap_or_else(|err| err.into_inner());
+        if output.len() == OUTPUT_LINES {
+            output.pop_front();
+        }
+        let line = String::from_utf8_lossy(line);
+        output.push_back(format!("{}: {}", stream, line.trim_end()));
+    }
+}
+
+/// Watches the output of a command for the line of `--ready-log`.
+#[derive(Debug)]
+pub(crate) struct ReadyLine {
+    pattern: Regex,
+    seen: AtomicBool,
+}
+
+impl ReadyLine {
+    pub fn new(pattern: Regex) -> Self {
+        Self {
+            pattern,
+            seen: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether a line matched yet.
+    pub fn seen(&self) -> bool {
+        self.seen.load(Ordering::Relaxed)
+    }
+
+    fn check(&self, line: &[u8]) {
+        if !self.seen() && self.pattern.is_match(&String::from_utf8_lossy(line)) {
+            self.seen.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Capture {
+    /// `line` of `stream` as it goes to the log file. Output which isn't
+    /// UTF-8 is kept as is, only JSON replaces invalid bytes.
+    fn format(&self, stream: &str, line: &[u8]) -> Vec<u8> {
+        let time = || humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+        match self.format {
+            LogFormat::Plain => line.to_vec(),
+            LogFormat::Prefixed => {
+                let mut prefix = format!("{} {} ", time(), stream);
+                if let Some(label) = &self.label {
+                    prefix.push_str(&format!("[{}] ", label));
+                }
+                let mut formatted = prefix.into_bytes();
+                formatted.extend_from_slice(line);
+                formatted
+            }
+            LogFormat::Json => {
+                let line = line.strip_suffix(b"\n").unwrap_or(line);
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                let mut object = serde_json::json!({
+                    "time": time(),
+                    "stream": stream,
+                    "line": String::from_utf8_lossy(line),
+                });
+                if let Some(label) = &self.label {
+                    object["checkout"] = label.as_str().into();
+                }
+                object.to_string().into_bytes()
+            }
+        }
+    }
+}
+
+/// When the log file is replaced by a fresh one.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Rotation {
+    /// Once it would grow beyond this many bytes.
+    pub max_size: Option<u64>,
+    /// On the first line of a new day, in UTC.
+    pub daily: bool,
+    /// How many rotated files are kept.
+    pub keep: usize,
+    /// Rotated files are compressed with gzip.
+    pub compress: bool,
+}
+
+/// The log file of `--log-dir` the command's stdout and stderr are appended
+/// to. Lines are written whole, so the two streams don't tear each other's.
+#[derive(Debug)]
+pub(crate) struct LogFile {
+    pub path: PathBuf,
+    rotation: Rotation,
+    /// The `--run-as` user the files are handed to.
+    owner: Option<RunAs>,
+    open: Mutex<Open>,
+    /// Compresses and prunes the last rotated file, after the rotations
+    /// before it.
+    tidying: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Debug)]
+struct Open {
+    file: File,
+    size: u64,
+    /// The day the file was started, in days since the epoch.
+    day: u64,
+}
+
+impl LogFile {
+    /// Opens `<dir>/<name>.log`, named after the checkout at `repo_path`.
+    pub fn open(
+        dir: &Path,
+        repo_path: &Path,
+        rotation: Rotation,
+        owner: Option<RunAs>,
+    ) -> Result<Self> {
+        let name = repo_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "localdeploy".to_owned());
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.log", name));
+        let open = Open::new(&path, owner.as_ref())?;
+        Ok(Self {
+            path,
+            rotation,
+            owner,
+            open: Mutex::new(open),
+            tidying: Mutex::new(None),
+        })
+    }
+
+    /// Opens `<dir>/<name>-<process>.log` next to this one, for a process
+    /// of the `--procfile`.
+    pub fn sibling(&self, process: &str) -> Result<Self> {
+        let mut name = self.path.file_stem().unwrap_or_default().to_owned();
+        name.push(format!("-{}.log", process));
+        let path = self.path.with_file_name(name);
+        let open = Open::new(&path, self.owner.as_ref())?;
+        Ok(Self {
+            path,
+            rotation: self.rotation.clone(),
+            owner: self.owner.clone(),
+            open: Mutex::new(open),
+            tidying: Mutex::new(None),
+        })
+    }
+
+    fn write_line(&self, line: &[u8]) -> io::Result<()> {
+        let mut open = self.open.lock().unwrap_or_else(|err| err.into_inner());
+        let length = line.len() as u64 + u64::from(!line.ends_with(b"\n"));
+        if self.due(&open, length) {
+            // Rotating under the lock, no line can go to the old file.
+            self.rotate(&mut open)?;
+        }
+        open.file.write_all(line)?;
+        if !line.ends_with(b"\n") {
+            open.file.write_all(b"\n")?;
+        }
+        open.size += length;
+        Ok(())
+    }
+
+    fn due(&self, open: &Open, length: u64) -> bool {
+        if open.size == 0 {
+            return false;
+        }
+        let full = self
+            .rotation
+            .max_size
+            .is_some_and(|max| open.size + length > max);
+        full || (self.rotation.daily && today() != open.day)
+    }
+
+    /// Renames the file to `<name>.log.<timestamp>` and starts a new one.
+    /// Compressing it and removing the rotated files beyond `keep` happens
+    /// in the background, the command's output isn't held up meanwhile.
+    fn rotate(&self, open: &mut Open) -> io::Result<()> {
+        open.file.flush()?;
+        // Like the releases, 2024-01-31T12:00:00Z becomes 20240131120000.
+        let timestamp = humantime::format_rfc3339_seconds(SystemTime::now())
+            .to_string()
+            .chars()
+            .filter(char::is_ascii_digit)
+            .collect::<String>();
+        // Rotations within one second are numbered after the earlier ones,
+        // the oldest of which may be pruned already.
+        let n = rotated(&self.path)
+            .into_iter()
+            .filter(|((rotated, _), _)| *rotated == timestamp)
+            .map(|((_, n), _)| n + 1)
+            .max()
+            .unwrap_or(0);
+        let mut rotated = self.path.clone().into_os_string();
+        match n {
+            0 => rotated.push(format!(".{}", timestamp)),
+            n => rotated.push(format!(".{}-{}", timestamp, n)),
+        }
+        let rotated = PathBuf::from(rotated);
+        fs::rename(&self.path, &rotated)?;
+        *open = Open::new(&self.path, self.owner.as_ref())?;
+
+        let mut tidying = self.tidying.lock().unwrap_or_else(|err| err.into_inner());
+        let previous = tidying.take();
+        let (path, rotation, owner) =
+            (self.path.clone(), self.rotation.clone(), self.owner.clone());
+        *tidying = Some(thread::spawn(move || {
+            // One at a time, so a file being compressed isn't pruned.
+            if let Some(previous) = previous {
+                let _ = previous.join();
+            }
+            if rotation.compress {
+                if let Err(err) = compress(&rotated, owner.as_ref()) {
+                    warn!("compressing {} failed: {}", rotated.display(), err);
+                }
+            }
+            prune(&path, rotation.keep);
+        }));
+        Ok(())
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        let tidying = self
+            .tidying
+            .get_mut()
+            .unwrap_or_else(|err| err.into_inner());
+        if let Some(tidying) = tidying.take() {
+            let _ = tidying.join();
+        }
+    }
+}
+
+/// The rotated files of the log file at `path`, compressed or not, by their
+/// timestamp and the number of the rotation within the second, oldest first.
+fn rotated(path: &Path) -> Vec<((String, u32), PathBuf)> {
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, format!("{}.", name.to_string_lossy())),
+        _ => return Vec::new(),
+    };
+    let mut rotated = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter_map(|path| {
+                let file = path.file_name()?.to_string_lossy().into_owned();
+                let suffix = file.strip_prefix(&name)?;
+                let suffix = suffix.strip_suffix(".gz").unwrap_or(suffix);
+                let age = match suffix.split_once('-') {
+                    Some((timestamp, n)) => (timestamp.to_owned(), n.parse().unwrap_or(0)),
+                    None => (suffix.to_owned(), 0u32),
+                };
+                Some((age, path))
+            })
+            .collect::<Vec<_>>(),
+        Err(_) => return Vec::new(),
+    };
+    rotated.sort();
+    rotated
+}
+
+/// Removes the rotated files of the log file at `path` beyond `keep`.
+fn prune(path: &Path, keep: usize) {
+    let rotated = rotated(path);
+    let excess = rotated.len().saturating_sub(keep);
+    for (_, old) in &rotated[..excess] {
+        match fs::remove_file(old) {
+            Ok(()) => verbose!("removed old log {}", old.display()),
+            Err(err) => warn!("removing old log {} failed: {}", old.display(), err),
+        }
+    }
+}
+
+impl Open {
+    fn new(path: &Path, owner: Option<&RunAs>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        if let Some(owner) = owner {
+            owner.chown(path)?;
+        }
+        let metadata = file.metadata()?;
+        // A file left from an earlier run belongs to the day it was written.
+        let day = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or_else(today, |since| since.as_secs() / 86400);
+        Ok(Self {
+            file,
+            size: metadata.len(),
+            day,
+        })
+    }
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() / 86400)
+        .unwrap_or_default()
+}
+
+/// `path` with `.extension` appended.
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.to_path_buf().into_os_string();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+/// Replaces `path` by `path.gz`.
+fn compress(path: &Path, owner: Option<&RunAs>) -> io::Result<()> {
+    let gz = with_extension(path, "gz");
+    let mut encoder = GzEncoder::new(File::create(&gz)?, Compression::default());
+    if let Some(owner) = owner {
+        owner.chown(&gz)?;
+    }
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+/// Copies the lines of `stream`, stdout, stderr or the pseudo-terminal of
+/// `--pty` as `name`, to the log file or localdeploy's own output until the
+/// command and whatever it started close it. A last line without newline is
+/// kept too.
+pub(crate) fn capture<R>(stream: R, name: &'static str, capture: Capture) -> JoinHandle<()>
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut line = Vec::new();
+        let mut failed = false;
+        let mut unreachable = false;
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => return,
+                Ok(_) => {
+                    if let Some(ready) = &capture.ready {
+                        ready.check(&line);
+                    }
+                    if let Some(tail) = &capture.tail {
+                        tail.record(name, &line);
+                        // A pseudo-terminal has stdout and stderr in one.
+                        if let "stderr" | "pty" = name {
+                            tail.push(&line);
+                        }
+                    }
+                    if let Some(sink) = &capture.syslog {
+                        let priority = match name {
+                            "stderr" => Priority::Warning,
+                            _ => Priority::Info,
+                        };
+                        let fields = Fields {
+                            stream: name,
+                            sha: capture.sha.as_deref(),
+                            checkout: capture.label.as_deref(),
+                        };
+                        match sink.line(priority, &fields, &line) {
+                            Ok(()) => {
+                                unreachable = false;
+                                continue;
+                            }
+                            Err(err) if !unreachable => {
+                                warn!(
+                                    "sending to {} failed, logging to files: {}",
+                                    sink.name(),
+                                    err
+                                );
+                                unreachable = true;
+                            }
+                            Err(_) => {}
+                        }
+                    }
+                    let log = match &capture.log {
+                        Some(log) => log,
+                        None => {
+                            let _ = match name {
+                                "stderr" => io::stderr().write_all(&line),
+                                _ => io::stdout().write_all(&line),
+                            };
+                            continue;
+                        }
+                    };
+                    if let Err(err) = log.write_line(&capture.format(name, &line)) {
+                        // Reading on keeps the command from blocking.
+                        if !failed {
+                            warn!("writing to {} failed: {}", log.path.display(), err);
+                        }
+                        failed = true;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Read,
+        path::{Path, PathBuf},
+        sync::Arc,
+        thread,
+    };
+
+    use flate2::read::GzDecoder;
+
+    use super::{prune, LogFile, Rotation};
+
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("localdeploy-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        /// The files and what they hold, uncompressed, oldest rotation first
+        /// and the log file last.
+        fn logs(&self) -> Vec<(String, String)> {
+            let mut logs = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| {
+                    let path = entry.unwrap().path();
+                    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                    let mut content = String::new();
+                    match name.ends_with(".gz") {
+                        true => GzDecoder::new(fs::File::open(&path).unwrap())
+                            .read_to_string(&mut content)
+                            .unwrap(),
+                        false => fs::File::open(&path)
+                            .unwrap()
+                            .read_to_string(&mut content)
+                            .unwrap(),
+                    };
+                    (name, content)
+                })
+                .collect::<Vec<_>>();
+            logs.sort_by_key(|(name, _)| {
+                let age = name.strip_prefix("app.log").unwrap_or(name);
+                let age = age.trim_start_matches('.');
+                let age = age.strip_suffix(".gz").unwrap_or(age);
+                match age.split_once('-') {
+                    Some((timestamp, n)) => {
+                        (age.is_empty(), timestamp.to_owned(), n.parse().unwrap())
+                    }
+                    None => (age.is_empty(), age.to_owned(), 0u32),
+                }
+            });
+            logs
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn open(dir: &Dir, rotation: Rotation) -> LogFile {
+        LogFile::open(&dir.0, Path::new("app"), rotation, None).unwrap()
+    }
+
+    fn contents(logs: &[(String, String)]) -> Vec<&str> {
+        logs.iter().map(|(_, content)| content.as_str()).collect()
+    }
+
+    #[test]
+    fn rotates_at_the_size_limit() {
+        let dir = Dir::new("log-size");
+        let log = open(
+            &dir,
+            Rotation {
+                max_size: Some(10),
+                keep: 5,
+                ..Rotation::default()
+            },
+        );
+        for line in ["a", "b", "c", "dddddddddddddddd", "e"] {
+            log.write_line(line.as_bytes()).unwrap();
+        }
+        drop(log);
+        // A line longer than the limit gets a file of its own.
+        assert_eq!(
+            contents(&dir.logs()),
+            ["a\nb\nc\n", "dddddddddddddddd\n", "e\n"]
+        );
+    }
+
+    #[test]
+    fn rotates_on_a_new_day() {
+        let dir = Dir::new("log-daily");
+        let log = open(
+            &dir,
+            Rotation {
+                daily: true,
+                keep: 5,
+                compress: true,
+                ..Rotation::default()
+            },
+        );
+        log.write_line(b"yesterday\n").unwrap();
+        log.open.lock().unwrap().day -= 1;
+        log.write_line(b"today\n").unwrap();
+        log.write_line(b"still today\n").unwrap();
+        drop(log);
+        let logs = dir.logs();
+        assert!(logs[0].0.ends_with(".gz"), "{:?}", logs);
+        assert_eq!(contents(&logs), ["yesterday\n", "today\nstill today\n"]);
+    }
+
+    #[test]
+    fn prunes_beyond_keep() {
+        let dir = Dir::new("log-prune");
+        for name in [
+            "app.log",
+            "app.log.20240131115959.gz",
+            "app.log.20240131120000",
+            "app.log.20240131120000-1.gz",
+            "app.log.20240131120000-2",
+            "app.log.20240131120000-10.gz",
+            "app-web.log.20240131120000",
+        ] {
+            fs::write(dir.0.join(name), "").unwrap();
+        }
+        prune(&dir.0.join("app.log"), 2);
+        let mut left = fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                "app-web.log.20240131120000",
+                "app.log",
+                "app.log.20240131120000-10.gz",
+                "app.log.20240131120000-2",
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_newest_of_the_same_second() {
+        let dir = Dir::new("log-second");
+        let log = open(
+            &dir,
+            Rotation {
+                max_size: Some(1),
+                keep: 2,
+                compress: true,
+                ..Rotation::default()
+            },
+        );
+        for n in 0..6 {
+            log.write_line(n.to_string().as_bytes()).unwrap();
+        }
+        drop(log);
+        assert_eq!(contents(&dir.logs()), ["3\n", "4\n", "5\n"]);
+    }
+
+    #[test]
+    fn loses_no_lines_while_rotating() {
+        let dir = Dir::new("log-lines");
+        let log = Arc::new(open(
+            &dir,
+            Rotation {
+                max_size: Some(1000),
+                keep: 1000,
+                compress: true,
+                ..Rotation::default()
+            },
+        ));
+        let writers = (0..4)
+            .map(|writer| {
+                let log = log.clone();
+                thread::spawn(move || {
+                    for n in 0..500 {
+                        log.write_line(format!("{} {}\n", writer, n).as_bytes())
+                            .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        drop(log);
+        let logs = dir.logs();
+        assert!(logs.len() > 10, "{}", logs.len());
+        let mut lines = logs
+            .iter()
+            .flat_map(|(_, content)| content.lines())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2000);
+        lines.sort();
+        lines.dedup();
+        assert_eq!(lines.len(), 2000);
+    }
+}
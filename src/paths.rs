@@ -0,0 +1,160 @@
+use std::{env, ffi::OsString, path::PathBuf};
+
+/// Resolves a path given as an option: a leading `~` or `~user` becomes the
+/// home directory and relative paths are made absolute against the current
+/// directory. The path doesn't need to exist.
+pub(crate) fn resolve(path: &str) -> PathBuf {
+    resolve_with(path, &|name| env::var_os(name))
+}
+
+/// `resolve` with the environment variables looked up by `var`.
+fn resolve_with(path: &str, var: &dyn Fn(&str) -> Option<OsString>) -> PathBuf {
+    let path = match path.strip_prefix('~') {
+        Some(rest) => {
+            let (user, rest) = match rest.find(std::path::is_separator) {
+                Some(index) => (&rest[..index], &rest[index + 1..]),
+                None => (rest, ""),
+            };
+            match home(Some(user).filter(|user| !user.is_empty()), var) {
+                // Joined part by part, so the separators are the platform's.
+                Some(home) => rest
+                    .split(std::path::is_separator)
+                    .filter(|part| !part.is_empty())
+                    .fold(home, |path, part| path.join(part)),
+                None => {
+                    warn!("no home directory to expand {} with, set HOME", path);
+                    return PathBuf::from(path);
+                }
+            }
+        }
+        None => PathBuf::from(path),
+    };
+    if path.is_absolute() {
+        return path;
+    }
+    match env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => path,
+    }
+}
+
+/// The home directory of `user`, or of the current user. Windows rarely
+/// sets `HOME`, there it is `USERPROFILE` or `HOMEDRIVE` and `HOMEPATH`.
+fn home(user: Option<&str>, var: &dyn Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    if let Some(user) = user {
+        return user_home(user);
+    }
+    let var = |name: &str| var(name).filter(|value| !value.is_empty());
+    if let Some(home) = var("HOME") {
+        return Some(PathBuf::from(home));
+    }
+    if cfg!(windows) {
+        if let Some(profile) = var("USERPROFILE") {
+            return Some(PathBuf::from(profile));
+        }
+        if let (Some(drive), Some(path)) = (var("HOMEDRIVE"), var("HOMEPATH")) {
+            let mut home = drive;
+            home.push(path);
+            return Some(PathBuf::from(home));
+        }
+    }
+    None
+}
+
+/// The home directory of `user` in the user database, which includes NSS
+/// and LDAP users `/etc/passwd` doesn't list.
+#[cfg(unix)]
+fn user_home(user: &str) -> Option<PathBuf> {
+    crate::user::passwd(user)?.3.map(PathBuf::from)
+}
+
+#[cfg(not(unix))]
+fn user_home(_user: &str) -> Option<PathBuf> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, ffi::OsString, path::PathBuf};
+
+    use super::resolve_with;
+
+    /// An environment with only `vars`.
+    fn env<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<OsString> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| OsString::from(value))
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn home() {
+        let env = env(&[("HOME", "/home/deploy")]);
+        assert_eq!(resolve_with("~", &env), PathBuf::from("/home/deploy"));
+        assert_eq!(
+            resolve_with("~/.ssh/id_ed25519", &env),
+            PathBuf::from("/home/deploy/.ssh/id_ed25519")
+        );
+        assert_eq!(resolve_with("/srv/app", &env), PathBuf::from("/srv/app"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn no_home() {
+        assert_eq!(resolve_with("~/x", &env(&[])), PathBuf::from("~/x"));
+        assert_eq!(
+            resolve_with("~/x", &env(&[("HOME", "")])),
+            PathBuf::from("~/x")
+        );
+    }
+
+    #[test]
+    fn relative() {
+        let dir = env::current_dir().unwrap();
+        assert_eq!(resolve_with("app", &env(&[])), dir.join("app"));
+        assert_eq!(
+            resolve_with("keys/deploy", &env(&[])),
+            dir.join("keys").join("deploy")
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_home() {
+        assert_eq!(
+            resolve_with(
+                r"~\.ssh\id_ed25519",
+                &env(&[("USERPROFILE", r"C:\Users\deploy")])
+            ),
+            PathBuf::from(r"C:\Users\deploy\.ssh\id_ed25519")
+        );
+        assert_eq!(
+            resolve_with(
+                "~/x",
+                &env(&[("HOMEDRIVE", "D:"), ("HOMEPATH", r"\Users\deploy")])
+            ),
+            PathBuf::from(r"D:\Users\deploy\x")
+        );
+        // HOME wins, then USERPROFILE.
+        assert_eq!(
+            resolve_with(
+                "~",
+                &env(&[
+                    ("USERPROFILE", r"C:\Users\deploy"),
+                    ("HOMEDRIVE", "D:"),
+                    ("HOMEPATH", r"\Users\other")
+                ])
+            ),
+            PathBuf::from(r"C:\Users\deploy")
+        );
+        assert_eq!(
+            resolve_with(
+                "~",
+                &env(&[("HOME", r"E:\home"), ("USERPROFILE", r"C:\Users\deploy")])
+            ),
+            PathBuf::from(r"E:\home")
+        );
+    }
+}
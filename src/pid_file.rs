@@ -0,0 +1,100 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::error::{Error, Result};
+
+/// The `--pid-file` of localdeploy, the pid on the first line and the repo
+/// on the second. It is removed when localdeploy exits.
+#[derive(Debug)]
+pub(crate) struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the pid file, failing if another localdeploy for `repo_path`
+    /// still runs with it. One left by a localdeploy which crashed, or by a
+    /// process which isn't localdeploy, is replaced.
+    pub fn create(path: &Path, repo_path: &Path) -> Result<Self> {
+        if let Ok(content) = fs::read_to_string(path) {
+            let mut lines = content.lines();
+            let pid = lines.next().and_then(|pid| pid.trim().parse::<u32>().ok());
+            let repo = lines.next().map(Path::new);
+            match pid {
+                Some(pid) if is_localdeploy(pid) && repo.is_none_or(|repo| repo == repo_path) => {
+                    return Err(Error::AlreadyRunning(path.to_owned(), pid));
+                }
+                Some(pid) => warn!(
+                    "removing the stale pid file {} of process {}",
+                    path.display(),
+                    pid
+                ),
+                None => warn!("removing the unreadable pid file {}", path.display()),
+            }
+        }
+        let pid = std::process::id();
+        write(path, &format!("{}\n{}\n", pid, repo_path.display()))?;
+        verbose!("wrote pid {} to {}", pid, path.display());
+        Ok(Self {
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        remove(&self.path, std::process::id());
+    }
+}
+
+/// Writes `content` to a temporary file and renames it over `path`, so it
+/// is never read half written.
+pub(crate) fn write(path: &Path, content: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, content)?;
+    fs::rename(&temporary, path)
+}
+
+/// Removes the pid file at `path` if it still has `pid`, it may have been
+/// written for a newer process since.
+pub(crate) fn remove(path: &Path, pid: u32) {
+    let current = fs::read_to_string(path)
+        .ok()
+        .and_then(|content| content.lines().next()?.trim().parse::<u32>().ok());
+    if current == Some(pid) {
+        if let Err(err) = fs::remove_file(path) {
+            warn!("removing the pid file {} failed: {}", path.display(), err);
+        }
+    }
+}
+
+/// Whether `pid` is a running localdeploy. Without `/proc` any running
+/// process counts.
+#[cfg(unix)]
+fn is_localdeploy(pid: u32) -> bool {
+    if pid == std::process::id() {
+        return false;
+    }
+    let alive = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0
+        || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    if !alive {
+        return false;
+    }
+    match (
+        fs::read_to_string(format!("/proc/{}/comm", pid)),
+        fs::read_to_string("/proc/self/comm"),
+    ) {
+        (Ok(comm), Ok(own)) => comm == own,
+        _ => true,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_localdeploy(_pid: u32) -> bool {
+    false
+}
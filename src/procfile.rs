@@ -0,0 +1,49 @@
+use std::{fs, path::Path};
+
+use crate::error::{Error, Result};
+
+/// Reads the `name: command` lines of a Procfile, in the order they are
+/// listed. Blank lines and lines starting with `#` are skipped, names are
+/// letters, digits, `_` and `-`. With `only` just the processes named in it
+/// are kept, all of which have to be listed.
+pub(crate) fn load(path: &Path, only: &[String]) -> Result<Vec<(String, String)>> {
+    let error = |line, message: String| Error::Procfile(path.to_owned(), line, message);
+    let content = fs::read_to_string(path).map_err(|err| error(0, err.to_string()))?;
+    let mut processes: Vec<(String, String)> = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, command) = line
+            .split_once(':')
+            .ok_or_else(|| error(index + 1, "expected name: command".to_owned()))?;
+        let (name, command) = (name.trim(), command.trim());
+        let valid = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if name.is_empty() || !valid {
+            return Err(error(index + 1, format!("invalid process name {}", name)));
+        }
+        if command.is_empty() {
+            return Err(error(index + 1, format!("{} has no command", name)));
+        }
+        if processes.iter().any(|(listed, _)| listed == name) {
+            return Err(error(index + 1, format!("{} is listed twice", name)));
+        }
+        processes.push((name.to_owned(), command.to_owned()));
+    }
+    if let Some(missing) = only
+        .iter()
+        .find(|name| !processes.iter().any(|(listed, _)| listed == *name))
+    {
+        return Err(error(0, format!("no process {} for --only", missing)));
+    }
+    if !only.is_empty() {
+        processes.retain(|(name, _)| only.contains(name));
+    }
+    if processes.is_empty() {
+        return Err(error(0, "no processes".to_owned()));
+    }
+    Ok(processes)
+}
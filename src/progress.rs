@@ -0,0 +1,110 @@
+use std::{
+    cell::Cell,
+    io::{IsTerminal, Write},
+    time::{Duration, Instant},
+};
+
+use git2::{build::CheckoutBuilder, Progress as Stats, RemoteCallbacks};
+
+/// How often the progress line is redrawn.
+const THROTTLE: Duration = Duration::from_millis(500);
+
+/// Reports the progress of a clone or fetch. On a terminal a single line is
+/// redrawn at most twice a second, otherwise only the result is logged.
+pub(crate) struct Progress {
+    what: &'static str,
+    interactive: bool,
+    drawn: Cell<Option<Instant>>,
+    objects: Cell<usize>,
+    bytes: Cell<usize>,
+}
+
+impl Progress {
+    pub fn new(what: &'static str) -> Self {
+        Self {
+            what,
+            interactive: crate::log::enabled(crate::log::Level::Normal)
+                && std::io::stdout().is_terminal(),
+            drawn: Cell::new(None),
+            objects: Cell::new(0),
+            bytes: Cell::new(0),
+        }
+    }
+
+    /// Reports downloaded objects and messages of the remote.
+    pub fn attach<'a>(&'a self, callbacks: &mut RemoteCallbacks<'a>) {
+        callbacks.transfer_progress(move |stats| {
+            self.transfer(&stats);
+            true
+        });
+        callbacks.sideband_progress(move |data| {
+            for line in String::from_utf8_lossy(data).split(['\r', '\n']) {
+                if !line.trim().is_empty() {
+                    debug!("remote: {}", line.trim());
+                }
+            }
+            true
+        });
+    }
+
+    /// Reports the files written by the checkout of a clone.
+    pub fn attach_checkout<'a>(&'a self, checkout: &mut CheckoutBuilder<'a>) {
+        checkout.progress(move |_, current, total| {
+            self.draw(|| format!("checking out files {}/{}", current, total));
+        });
+    }
+
+    fn transfer(&self, stats: &Stats) {
+        self.objects.set(stats.received_objects());
+        self.bytes.set(stats.received_bytes());
+        self.draw(|| {
+            let total = stats.total_objects();
+            if stats.received_objects() < total {
+                format!(
+                    "receiving objects {}/{} ({})",
+                    stats.received_objects(),
+                    total,
+                    bytes(stats.received_bytes())
+                )
+            } else {
+                format!("indexing objects {}/{}", stats.indexed_objects(), total)
+            }
+        });
+    }
+
+    fn draw(&self, line: impl FnOnce() -> String) {
+        if !self.interactive || self.drawn.get().is_some_and(|at| at.elapsed() < THROTTLE) {
+            return;
+        }
+        let mut stdout = std::io::stdout();
+        let _ = write!(stdout, "\r\x1b[K{}: {}", self.what, line());
+        let _ = stdout.flush();
+        self.drawn.set(Some(Instant::now()));
+    }
+
+    /// Clears the progress line and returns the number of received objects
+    /// and bytes.
+    pub fn finish(&self) -> (usize, usize) {
+        if self.drawn.get().is_some() {
+            print!("\r\x1b[K");
+            let _ = std::io::stdout().flush();
+        }
+        (self.objects.get(), self.bytes.get())
+    }
+}
+
+/// Formats a byte count for humans.
+pub(crate) fn bytes(count: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = count as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", count)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    fs::File,
+    io,
+    os::unix::{
+        io::{AsRawFd, FromRawFd},
+        process::CommandExt,
+    },
+    process::{Command, Stdio},
+    sync::{Arc, Mutex, Once, Weak},
+    thread,
+};
+
+use signal_hook::{consts::SIGWINCH, iterator::Signals};
+
+/// The pseudo-terminals of the running commands, resized along with
+/// localdeploy's terminal.
+static MASTERS: Mutex<Vec<Weak<File>>> = Mutex::new(Vec::new());
+
+/// Gives `command` a pseudo-terminal of its own for stdin, stdout and
+/// stderr, which becomes the controlling terminal of a new session. Returns
+/// the master side, which the output is read from. The session is a process
+/// group of its own, so the command must not get one with `process_group`.
+pub(crate) fn attach(command: &mut Command) -> io::Result<Arc<File>> {
+    let (mut master, mut slave) = (0, 0);
+    let size = terminal_size().unwrap_or(libc::winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    });
+    if unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            &size,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+    // `openpty` leaves both inherited by whatever is started next.
+    for fd in [master.as_raw_fd(), slave.as_raw_fd()] {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    // Lines end in `\n` in the log as they would have without a terminal.
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(slave.as_raw_fd(), &mut termios) } == 0 {
+        termios.c_oflag &= !libc::ONLCR;
+        unsafe { libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) };
+    }
+    command
+        .stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave));
+    // Stdin is the slave by now, only async-signal-safe calls.
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let master = Arc::new(master);
+    if terminal_size().is_some() {
+        forward_resizes(&master);
+    }
+    Ok(master)
+}
+
+/// The size of localdeploy's terminal, if its stdout is one.
+fn terminal_size() -> Option<libc::winsize> {
+    let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+    match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } {
+        0 => Some(size),
+        _ => None,
+    }
+}
+
+/// Passes the size of localdeploy's terminal on to `master` whenever it
+/// changes. The command then gets a SIGWINCH of its own.
+fn forward_resizes(master: &Arc<File>) {
+    static LISTEN: Once = Once::new();
+
+    MASTERS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .push(Arc::downgrade(master));
+    LISTEN.call_once(|| {
+        let mut signals = match Signals::new([SIGWINCH]) {
+            Ok(signals) => signals,
+            Err(err) => {
+                warn!("not passing on resizes of the terminal: {}", err);
+                return;
+            }
+        };
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                let size = match terminal_size() {
+                    Some(size) => size,
+                    None => continue,
+                };
+                let mut masters = MASTERS.lock().unwrap_or_else(|err| err.into_inner());
+                // The commands which stopped are forgotten.
+                masters.retain(|master| match master.upgrade() {
+                    Some(master) => {
+                        unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &size) };
+                        true
+                    }
+                    None => false,
+                });
+            }
+        });
+    });
+}
@@ -0,0 +1,123 @@
+use git2::{Reference, Repository};
+use glob::Pattern;
+
+use crate::error::{Error, Result};
+
+/// Checks that `spec` is a fetch refspec like `+refs/deploy/*:refs/deploy/*`
+/// before it is handed to libgit2, which only complains during the fetch.
+pub(crate) fn validate(spec: &str) -> Result<()> {
+    let invalid = || Error::InvalidArgument("refspec", spec.to_owned());
+    let (src, dst) = spec
+        .strip_prefix('+')
+        .unwrap_or(spec)
+        .split_once(':')
+        .ok_or_else(invalid)?;
+    if src.matches('*').count() != dst.matches('*').count() || src.matches('*').count() > 1 {
+        return Err(invalid());
+    }
+    let valid =
+        |name: &str| name.starts_with("refs/") && Reference::is_valid_name(&name.replace('*', "x"));
+    if valid(src) && valid(dst) {
+        Ok(())
+    } else {
+        Err(invalid())
+    }
+}
+
+/// The local ref `spec` fetches into, if it names a single ref.
+pub(crate) fn destination(spec: &str) -> Option<&str> {
+    let (_, dst) = spec.split_once(':')?;
+    if dst.contains('*') {
+        None
+    } else {
+        Some(dst)
+    }
+}
+
+/// Whether `name` is written by the fetch refspec `spec`.
+pub(crate) fn fetches_into(spec: &str, name: &str) -> bool {
+    match spec.split_once(':') {
+        Some((_, dst)) => Pattern::new(dst)
+            .map(|pattern| pattern.matches(name))
+            .unwrap_or(false),
+        None => false,
+    }
+}
+
+/// The local ref `spec` fetches the remote ref `name` into.
+pub(crate) fn transform(spec: &str, name: &str) -> Option<String> {
+    let (src, dst) = spec.strip_prefix('+').unwrap_or(spec).split_once(':')?;
+    match src.split_once('*') {
+        Some((prefix, suffix)) => {
+            let matched = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            Some(dst.replacen('*', matched, 1))
+        }
+        None if name == src => Some(dst.to_owned()),
+        None => None,
+    }
+}
+
+/// The names of the local refs `spec` fetches into.
+pub(crate) fn local_refs(repo: &Repository, spec: &str) -> Result<Vec<String>> {
+    let dst = match spec.split_once(':') {
+        Some((_, dst)) => dst,
+        None => return Ok(Vec::new()),
+    };
+    let mut names = Vec::new();
+    for reference in repo.references_glob(dst)? {
+        if let Some(name) = reference?.name() {
+            names.push(name.to_owned());
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{transform, validate};
+    use crate::error::Error;
+
+    fn invalid(spec: &str) -> bool {
+        matches!(validate(spec), Err(Error::InvalidArgument("refspec", value)) if value == spec)
+    }
+
+    #[test]
+    fn valid() {
+        validate("+refs/a/*:refs/b/*").unwrap();
+        validate("refs/heads/main:refs/deploy/main").unwrap();
+    }
+
+    #[test]
+    fn without_a_colon() {
+        assert!(invalid("refs/a/*"));
+        assert!(invalid("+refs/heads/main"));
+    }
+
+    #[test]
+    fn unequal_stars() {
+        assert!(invalid("refs/a/*:refs/b/main"));
+        assert!(invalid("refs/a/main:refs/b/*"));
+        assert!(invalid("refs/a/*/*:refs/b/*/*"));
+    }
+
+    #[test]
+    fn outside_refs() {
+        assert!(invalid("heads/*:refs/b/*"));
+        assert!(invalid("refs/a/*:b/*"));
+    }
+
+    #[test]
+    fn transforms_with_a_suffix() {
+        let spec = "+refs/heads/*/deploy:refs/deploy/*";
+        assert_eq!(
+            transform(spec, "refs/heads/web/deploy").as_deref(),
+            Some("refs/deploy/web")
+        );
+        assert_eq!(transform(spec, "refs/heads/web/main"), None);
+        assert_eq!(transform(spec, "refs/tags/web/deploy"), None);
+        assert_eq!(
+            transform("refs/heads/main:refs/deploy/main", "refs/heads/main").as_deref(),
+            Some("refs/deploy/main")
+        );
+    }
+}
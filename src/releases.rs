@@ -0,0 +1,162 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use git2::{Oid, Repository, WorktreeAddOptions};
+
+use crate::{error::Result, export, sparse, sparse::Sparse};
+
+/// The directory below `--deploy-dir` holding one directory per release.
+pub(crate) const RELEASES: &str = "releases";
+/// The symlink below `--deploy-dir` pointing at the live release.
+pub(crate) const CURRENT: &str = "current";
+
+/// Extracts `commit` into a new `releases/<timestamp>-<shortsha>` below
+/// `dir` and returns its path. The release is only renamed to its final
+/// name once it was written completely.
+///
+/// With `worktree` the release is a linked worktree of `repo` with a
+/// detached HEAD instead, which is removed again if creating it fails.
+pub(crate) fn create(
+    repo: &Repository,
+    commit: Oid,
+    dir: &Path,
+    sparse: Option<&Sparse>,
+    worktree: bool,
+) -> Result<PathBuf> {
+    let releases = dir.join(RELEASES);
+    fs::create_dir_all(&releases)?;
+    // 2024-01-31T12:00:00Z becomes 20240131120000, which sorts by age.
+    let timestamp = humantime::format_rfc3339_seconds(SystemTime::now())
+        .to_string()
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>();
+    let name = format!("{}-{:.7}", timestamp, commit.to_string());
+    let release = releases.join(&name);
+    let staging = releases.join(format!(".{}.localdeploy-new", name));
+    for leftover in [&staging, &release] {
+        if leftover.exists() {
+            fs::remove_dir_all(leftover)?;
+        }
+    }
+    if worktree {
+        let release = fs::canonicalize(&releases)?.join(&name);
+        prune_worktrees(repo)?;
+        let result = add_worktree(repo, commit, &name, &release, sparse);
+        if result.is_err() {
+            let _ = fs::remove_dir_all(&release);
+            let _ = prune_worktrees(repo);
+        }
+        return result.map(|_| release);
+    }
+    export::extract(repo, commit, &staging, sparse)?;
+    fs::rename(&staging, &release)?;
+    Ok(release)
+}
+
+/// The prefix of the worktree names of releases, to tell them apart from
+/// other worktrees of the repo.
+const WORKTREE_PREFIX: &str = "release-";
+
+fn add_worktree(
+    repo: &Repository,
+    commit: Oid,
+    name: &str,
+    path: &Path,
+    sparse: Option<&Sparse>,
+) -> Result<()> {
+    // libgit2 can't add detached worktrees, so check out a throwaway branch
+    // and detach from it.
+    let name = format!("{}{}", WORKTREE_PREFIX, name);
+    let branch = repo.branch(&name, &repo.find_commit(commit)?, true)?;
+    let mut options = WorktreeAddOptions::new();
+    options.reference(Some(branch.get()));
+    let added = repo
+        .worktree(&name, path, Some(&options))
+        .and_then(|worktree| Repository::open_from_worktree(&worktree));
+    let branch_name = branch.get().name().unwrap_or_default().to_owned();
+    let detached = added.and_then(|release| {
+        release.set_head_detached(commit)?;
+        Ok(release)
+    });
+    repo.find_reference(&branch_name)?.delete()?;
+    let release = detached?;
+    if sparse.is_some() {
+        sparse::apply(&release, sparse)?;
+    }
+    Ok(())
+}
+
+/// Forgets the release worktrees whose directory is gone.
+fn prune_worktrees(repo: &Repository) -> Result<()> {
+    for name in repo.worktrees()?.iter().flatten() {
+        if !name.starts_with(WORKTREE_PREFIX) {
+            continue;
+        }
+        let worktree = repo.find_worktree(name)?;
+        if worktree.validate().is_err() {
+            worktree.prune(None)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes a release which never went live, like one whose build failed.
+pub(crate) fn discard(repo: &Repository, release: &Path) -> Result<()> {
+    fs::remove_dir_all(release)?;
+    prune_worktrees(repo)
+}
+
+/// Points the `current` symlink below `dir` at `release`. The new link is
+/// renamed over the old one, so `current` always points at a release.
+#[cfg(unix)]
+pub(crate) fn activate(dir: &Path, release: &Path) -> Result<()> {
+    let name = release.file_name().unwrap_or_default();
+    let target = Path::new(RELEASES).join(name);
+    let staging = dir.join(".current.localdeploy-new");
+    if fs::symlink_metadata(&staging).is_ok() {
+        fs::remove_file(&staging)?;
+    }
+    std::os::unix::fs::symlink(&target, &staging)?;
+    fs::rename(&staging, dir.join(CURRENT))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn activate(_dir: &Path, _release: &Path) -> Result<()> {
+    Err(crate::error::Error::InvalidArgument(
+        "releases",
+        "symlinks are only supported on unix".to_owned(),
+    ))
+}
+
+/// Removes all but the newest `keep` releases below `dir`, never the one
+/// `current` points at. Returns the removed releases.
+pub(crate) fn prune(repo: &Repository, dir: &Path, keep: usize) -> Result<Vec<PathBuf>> {
+    let releases = dir.join(RELEASES);
+    let current = fs::canonicalize(dir.join(CURRENT)).ok();
+    let mut all = fs::read_dir(&releases)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    // Unfinished releases start with a dot.
+    all.retain(|path| {
+        path.is_dir()
+            && path
+                .file_name()
+                .is_some_and(|name| !name.to_string_lossy().starts_with('.'))
+    });
+    all.sort();
+    let mut removed = Vec::new();
+    for release in all.iter().rev().skip(keep) {
+        if fs::canonicalize(release).ok() == current {
+            continue;
+        }
+        fs::remove_dir_all(release)?;
+        removed.push(release.clone());
+    }
+    prune_worktrees(repo)?;
+    Ok(removed)
+}
@@ -0,0 +1,59 @@
+use std::path::Path;
+
+use git2::Repository;
+use keyring::Entry;
+
+use crate::{
+    deploys,
+    error::{Error, Result},
+};
+
+/// The secrets `secret set` can store.
+pub(crate) const NAMES: [&str; 2] = ["passphrase", "token"];
+
+/// The keyring service of the repo at `url`, so several repos deployed by
+/// the same user don't share their secrets.
+fn service(url: &str) -> String {
+    format!("localdeploy:{}", url)
+}
+
+fn entry(url: &str, name: &str) -> Result<Entry> {
+    Entry::new(&service(url), name).map_err(|err| Error::Keyring(err.to_string()))
+}
+
+/// The url the secrets of a deploy are stored under: the `--new` url, or
+/// the url of `remote` in the repo at `path`.
+pub(crate) fn url(new: Option<&str>, path: &Path, shared: bool, remote: &str) -> Result<String> {
+    if let Some(new) = new {
+        return Ok(new.to_owned());
+    }
+    let repo = if shared {
+        Repository::open(path.join(deploys::SHARED_REPO))?
+    } else {
+        Repository::discover(path)?
+    };
+    let remote = repo.find_remote(remote)?;
+    match remote.url() {
+        Some(url) => Ok(url.to_owned()),
+        None => Err(Error::MissingUrlToRepo),
+    }
+}
+
+/// Reads the secret `name` of `url` from the keyring without prompting.
+pub(crate) fn get(url: &str, name: &str) -> Result<String> {
+    match entry(url, name)?.get_password() {
+        Ok(secret) => Ok(secret),
+        Err(keyring::Error::NoEntry) => Err(Error::MissingSecret {
+            name: name.to_owned(),
+            url: url.to_owned(),
+        }),
+        Err(err) => Err(Error::Keyring(err.to_string())),
+    }
+}
+
+/// Stores `secret` as `name` of `url` in the keyring, replacing an older one.
+pub(crate) fn set(url: &str, name: &str, secret: &str) -> Result<()> {
+    entry(url, name)?
+        .set_password(secret)
+        .map_err(|err| Error::Keyring(err.to_string()))
+}
@@ -0,0 +1,44 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+use crate::error::Result;
+
+/// Set by SIGTERM and SIGINT, localdeploy then stops the commands and exits.
+static REQUESTED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+/// Catches SIGTERM and SIGINT. A second one exits right away, for when
+/// stopping the commands hangs.
+pub(crate) fn listen() -> Result<()> {
+    let requested = REQUESTED.get_or_init(|| Arc::new(AtomicBool::new(false)));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, requested.clone())?;
+        signal_hook::flag::register(signal, requested.clone())?;
+    }
+    Ok(())
+}
+
+/// Whether localdeploy was asked to exit.
+pub(crate) fn requested() -> bool {
+    REQUESTED
+        .get()
+        .is_some_and(|requested| requested.load(Ordering::Relaxed))
+}
+
+/// Sleeps for `duration` or until localdeploy is asked to exit.
+pub(crate) fn sleep(duration: Duration) {
+    let started = Instant::now();
+    while !requested() {
+        match duration.checked_sub(started.elapsed()) {
+            Some(left) if !left.is_zero() => thread::sleep(left.min(Duration::from_millis(100))),
+            _ => return,
+        }
+    }
+}
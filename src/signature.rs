@@ -0,0 +1,284 @@
+use std::{
+    fmt::Display,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use git2::{ErrorCode, Oid, Repository};
+
+use crate::{
+    child,
+    error::{Error, Result},
+};
+
+/// Why an object could not be verified.
+#[derive(Debug)]
+pub(crate) enum Failure {
+    Unsigned,
+    Lightweight,
+    UnknownSigner,
+    Invalid(String),
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Unsigned => write!(f, "it is not signed"),
+            Failure::Lightweight => write!(f, "it is a lightweight tag, which can't be signed"),
+            Failure::UnknownSigner => write!(f, "it is signed by an unknown key"),
+            Failure::Invalid(reason) => write!(f, "the signature is invalid: {}", reason),
+        }
+    }
+}
+
+/// Verifies the signature of a commit against the allowed keys.
+pub(crate) fn verify_commit(repo: &Repository, oid: Oid, allowed: &Path) -> Result<()> {
+    let (signature, data) = match repo.extract_signature(&oid, None) {
+        Ok(extracted) => extracted,
+        Err(err) if err.code() == ErrorCode::NotFound => {
+            return Err(Error::Unverified(
+                format!("commit {}", oid),
+                Failure::Unsigned,
+            ))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    verify(&signature, &data, allowed)
+        .map_err(|failure| Error::Unverified(format!("commit {}", oid), failure))
+}
+
+/// Verifies the signature of an annotated tag against the allowed keys.
+pub(crate) fn verify_tag(repo: &Repository, name: &str, allowed: &Path) -> Result<()> {
+    let object = format!("tag {}", name);
+    let oid = repo.refname_to_id(&format!("refs/tags/{}", name))?;
+    if repo.find_tag(oid).is_err() {
+        return Err(Error::Unverified(object, Failure::Lightweight));
+    }
+    let odb = repo.odb()?;
+    let raw = odb.read(oid)?;
+    let (data, signature) = match split_tag(raw.data()) {
+        Some(split) => split,
+        None => return Err(Error::Unverified(object, Failure::Unsigned)),
+    };
+    verify(signature, data, allowed).map_err(|failure| Error::Unverified(object, failure))
+}
+
+/// Splits a raw tag object into the signed part and the signature appended
+/// to its message.
+fn split_tag(raw: &[u8]) -> Option<(&[u8], &[u8])> {
+    const MARKERS: [&[u8]; 3] = [
+        b"-----BEGIN PGP SIGNATURE-----",
+        b"-----BEGIN SSH SIGNATURE-----",
+        b"-----BEGIN SIGNED MESSAGE-----",
+    ];
+    let start = (0..raw.len()).rev().find(|&i| {
+        (i == 0 || raw[i - 1] == b'\n') && MARKERS.iter().any(|marker| raw[i..].starts_with(marker))
+    })?;
+    Some((&raw[..start], &raw[start..]))
+}
+
+/// Verifies a detached signature over `data`.
+///
+/// SSH signatures are checked against `allowed` as an `allowed_signers` file
+/// with `ssh-keygen`, everything else with `gpg` against the keys in
+/// `allowed`, which may be an exported keyring or armored public keys.
+pub(crate) fn verify(
+    signature: &[u8],
+    data: &[u8],
+    allowed: &Path,
+) -> std::result::Result<(), Failure> {
+    let dir = TempDir::new().map_err(|err| Failure::Invalid(err.to_string()))?;
+    let signature_path = dir.path.join("signature");
+    let data_path = dir.path.join("data");
+    fs::write(&signature_path, signature).map_err(|err| Failure::Invalid(err.to_string()))?;
+    fs::write(&data_path, data).map_err(|err| Failure::Invalid(err.to_string()))?;
+
+    if signature.starts_with(b"-----BEGIN SSH SIGNATURE-----") {
+        verify_ssh(&signature_path, data, allowed)
+    } else {
+        verify_gpg(&dir.path, &signature_path, &data_path, allowed)
+    }
+}
+
+fn verify_ssh(signature: &Path, data: &[u8], allowed: &Path) -> std::result::Result<(), Failure> {
+    let principals = child::output(
+        Command::new("ssh-keygen")
+            .args(["-Y", "find-principals", "-f"])
+            .arg(allowed)
+            .arg("-s")
+            .arg(signature),
+    )
+    .map_err(|err| Failure::Invalid(format!("failed to run ssh-keygen: {}", err)))?;
+    let stdout = String::from_utf8_lossy(&principals.stdout);
+    let principal = match stdout.lines().next() {
+        Some(principal) if principals.status.success() => principal.to_owned(),
+        _ => return Err(Failure::UnknownSigner),
+    };
+
+    let mut verify = child::spawn(
+        Command::new("ssh-keygen")
+            .args(["-Y", "verify", "-n", "git", "-f"])
+            .arg(allowed)
+            .arg("-I")
+            .arg(&principal)
+            .arg("-s")
+            .arg(signature)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped()),
+    )
+    .map_err(|err| Failure::Invalid(format!("failed to run ssh-keygen: {}", err)))?;
+    if let Some(mut stdin) = verify.stdin.take() {
+        let _ = stdin.write_all(data);
+    }
+    let pid = verify.id();
+    let output = verify.wait_with_output();
+    child::forget(pid);
+    let output = output.map_err(|err| Failure::Invalid(err.to_string()))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Failure::Invalid(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ))
+    }
+}
+
+fn verify_gpg(
+    home: &Path,
+    signature: &Path,
+    data: &Path,
+    allowed: &Path,
+) -> std::result::Result<(), Failure> {
+    // Import the allowed keys into a throwaway home, so only they are
+    // trusted and the user's keyring is left alone.
+    let import = child::output(
+        Command::new("gpg")
+            .arg("--homedir")
+            .arg(home)
+            .args(["--batch", "--quiet", "--import"])
+            .arg(allowed),
+    )
+    .map_err(|err| Failure::Invalid(format!("failed to run gpg: {}", err)))?;
+    if !import.status.success() {
+        return Err(Failure::Invalid(format!(
+            "failed to import {}: {}",
+            allowed.display(),
+            String::from_utf8_lossy(&import.stderr).trim()
+        )));
+    }
+
+    let output = child::output(
+        Command::new("gpg")
+            .arg("--homedir")
+            .arg(home)
+            .args(["--batch", "--status-fd", "1", "--verify"])
+            .arg(signature)
+            .arg(data),
+    )
+    .map_err(|err| Failure::Invalid(format!("failed to run gpg: {}", err)))?;
+    let status = String::from_utf8_lossy(&output.stdout);
+    let has = |keyword: &str| {
+        status
+            .lines()
+            .any(|line| line.split_whitespace().nth(1) == Some(keyword))
+    };
+    if output.status.success() && has("VALIDSIG") {
+        Ok(())
+    } else if has("NO_PUBKEY") {
+        Err(Failure::UnknownSigner)
+    } else if has("BADSIG") {
+        Err(Failure::Invalid("bad signature".to_owned()))
+    } else {
+        Err(Failure::Invalid(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ))
+    }
+}
+
+/// A private temporary directory which is removed when dropped.
+struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    fn new() -> std::io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "localdeploy-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&path)?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_tag;
+
+    const TAG: &str = "object 0123456789012345678901234567890123456789\n\
+                       type commit\n\
+                       tag v1\n\
+                       tagger test <test@example.com> 0 +0000\n\
+                       \n\
+                       Release v1\n";
+
+    fn split(raw: &str) -> Option<(&str, &str)> {
+        split_tag(raw.as_bytes()).map(|(data, signature)| {
+            (
+                std::str::from_utf8(data).unwrap(),
+                std::str::from_utf8(signature).unwrap(),
+            )
+        })
+    }
+
+    #[test]
+    fn pgp() {
+        let signature = "-----BEGIN PGP SIGNATURE-----\n\nabc\n-----END PGP SIGNATURE-----\n";
+        let raw = format!("{}{}", TAG, signature);
+        assert_eq!(split(&raw), Some((TAG, signature)));
+    }
+
+    #[test]
+    fn ssh() {
+        let signature = "-----BEGIN SSH SIGNATURE-----\nabc\n-----END SSH SIGNATURE-----\n";
+        let raw = format!("{}{}", TAG, signature);
+        assert_eq!(split(&raw), Some((TAG, signature)));
+    }
+
+    #[test]
+    fn unsigned() {
+        assert_eq!(split(TAG), None);
+        // Only at the start of a line.
+        assert_eq!(
+            split(&format!("{}see -----BEGIN PGP SIGNATURE-----\n", TAG)),
+            None
+        );
+    }
+
+    #[test]
+    fn marker_in_the_message() {
+        let message = format!(
+            "{}\n-----BEGIN PGP SIGNATURE-----\nquoted in the message\n\n",
+            TAG
+        );
+        let signature = "-----BEGIN SSH SIGNATURE-----\nabc\n-----END SSH SIGNATURE-----\n";
+        let raw = format!("{}{}", message, signature);
+        // The signature is the last block, the one quoted stays signed.
+        assert_eq!(split(&raw), Some((message.as_str(), signature)));
+    }
+}
@@ -0,0 +1,158 @@
+use std::{collections::HashSet, path::Path};
+
+use git2::{
+    build::CheckoutBuilder, Index, IndexEntry, IndexEntryExtendedFlag, IndexTime, ObjectType, Oid,
+    Pathspec, PathspecFlags, Repository, TreeWalkMode, TreeWalkResult,
+};
+
+use crate::error::{Error, Result};
+
+/// The paths of `--sparse-path`, matched like git pathspecs: `services/api`
+/// includes everything below it, `*.toml` matches in every directory.
+pub(crate) struct Sparse {
+    patterns: Vec<String>,
+    pathspec: Pathspec,
+}
+
+impl Sparse {
+    pub fn new(patterns: Vec<String>) -> Result<Self> {
+        let pathspec = Pathspec::new(patterns.iter())
+            .map_err(|_| Error::InvalidArgument("sparse-path", patterns.join(", ")))?;
+        Ok(Self { patterns, pathspec })
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        self.pathspec.matches_path(path, PathspecFlags::DEFAULT)
+    }
+}
+
+/// A checkout which only writes the paths matching `patterns`, or everything
+/// if there are none.
+pub(crate) fn checkout(patterns: &[String]) -> CheckoutBuilder<'static> {
+    let mut checkout = CheckoutBuilder::new();
+    for pattern in patterns {
+        checkout.path(pattern.as_str());
+    }
+    checkout
+}
+
+/// Brings the working tree in line with `sparse` after a checkout, or with
+/// the whole tree if `sparse` is `None`.
+///
+/// libgit2 doesn't know sparse checkouts, so paths outside the patterns are
+/// marked skip-worktree in the index like git does, which keeps them from
+/// showing up as deleted. Files which left the patterns are removed unless
+/// they were modified, files which joined them are checked out.
+pub(crate) fn apply(repo: &Repository, sparse: Option<&Sparse>) -> Result<()> {
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir.to_path_buf(),
+        None => return Ok(()),
+    };
+    let tree = match repo.head().and_then(|head| head.peel_to_tree()) {
+        Ok(tree) => tree,
+        Err(_) => return Ok(()),
+    };
+    let mut files = Vec::new();
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if entry.kind() == Some(ObjectType::Blob) {
+            if let Some(name) = entry.name() {
+                files.push((format!("{}{}", root, name), entry.id(), entry.filemode()));
+            }
+        }
+        TreeWalkResult::Ok
+    })?;
+
+    let mut index = repo.index()?;
+    let mut missing = Vec::new();
+    let mut in_tree = HashSet::new();
+    for (path, id, mode) in files {
+        let skipped = is_skipped(&index, &path);
+        let file = workdir.join(&path);
+        if sparse.is_none_or(|sparse| sparse.matches(Path::new(&path))) {
+            if skipped {
+                index.add(&entry(&path, id, mode, false))?;
+                missing.push(path);
+            }
+            continue;
+        }
+        if file.symlink_metadata().is_ok() {
+            let checked_out = index.get_path(Path::new(&path), 0).map(|entry| entry.id);
+            if file.is_file() && Some(Oid::hash_file(ObjectType::Blob, &file)?) != checked_out {
+                warn!(
+                    "not removing {} outside of --sparse-path, it was modified",
+                    path
+                );
+                continue;
+            }
+            std::fs::remove_file(&file)?;
+            remove_empty_parents(&workdir, &file);
+        }
+        index.add(&entry(&path, id, mode, true))?;
+        in_tree.insert(path);
+    }
+    // Left out paths which were deleted upstream.
+    let deleted = index
+        .iter()
+        .filter(|entry| entry.flags_extended & IndexEntryExtendedFlag::SKIP_WORKTREE.bits() != 0)
+        .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+        .filter(|path| !in_tree.contains(path))
+        .collect::<Vec<_>>();
+    for path in deleted {
+        index.remove_path(Path::new(&path))?;
+    }
+    index.write()?;
+
+    if !missing.is_empty() {
+        let mut checkout = CheckoutBuilder::new();
+        checkout.safe().recreate_missing(true);
+        for path in &missing {
+            checkout.path(path.as_str());
+        }
+        repo.checkout_index(Some(&mut index), Some(&mut checkout))?;
+    }
+    Ok(())
+}
+
+/// Whether `path` is left out of the working tree. libgit2 reports those
+/// paths as deleted, git doesn't.
+pub(crate) fn is_skipped(index: &Index, path: &str) -> bool {
+    index.get_path(Path::new(path), 0).is_some_and(|entry| {
+        entry.flags_extended & IndexEntryExtendedFlag::SKIP_WORKTREE.bits() != 0
+    })
+}
+
+fn entry(path: &str, id: Oid, mode: i32, skip_worktree: bool) -> IndexEntry {
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode: mode as u32,
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id,
+        flags: 0,
+        flags_extended: if skip_worktree {
+            IndexEntryExtendedFlag::SKIP_WORKTREE.bits()
+        } else {
+            0
+        },
+        path: path.as_bytes().to_vec(),
+    }
+}
+
+/// Removes the directories left empty by removing `file`.
+fn remove_empty_parents(workdir: &Path, file: &Path) {
+    let mut dir = file.parent();
+    while let Some(parent) = dir {
+        if parent == workdir || std::fs::remove_dir(parent).is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+}
@@ -0,0 +1,66 @@
+use git2::{ErrorCode, Oid, Repository, Signature, StashFlags};
+
+use crate::error::{Error, Result};
+
+/// Stashes uncommitted changes, returning the stash commit if there was
+/// anything to stash.
+pub(crate) fn save(repo: &mut Repository, include_untracked: bool) -> Result<Option<Oid>> {
+    let signature = match repo.signature() {
+        Ok(signature) => signature,
+        Err(_) => Signature::now("localdeploy", "localdeploy@localhost")?,
+    };
+    let flags = if include_untracked {
+        StashFlags::INCLUDE_UNTRACKED
+    } else {
+        StashFlags::DEFAULT
+    };
+    match repo.stash_save(&signature, "localdeploy autostash", Some(flags)) {
+        Ok(oid) => Ok(Some(oid)),
+        Err(err) if err.code() == ErrorCode::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Reapplies and drops the stash created by [`save`]. If the changes conflict
+/// with the updated working tree, the stash is kept and the conflicting paths
+/// are reported.
+pub(crate) fn pop(repo: &mut Repository, stash: Oid) -> Result<()> {
+    let mut index = None;
+    repo.stash_foreach(|i, _message, oid| {
+        if *oid == stash {
+            index = Some(i);
+        }
+        index.is_none()
+    })?;
+    let index = match index {
+        Some(index) => index,
+        None => return Err(Error::StashLost(stash)),
+    };
+
+    // Applying a conflicting stash writes conflict markers into the working
+    // tree and drops the stash, so check for conflicts up front.
+    let conflicts = conflicts(repo, stash)?;
+    if !conflicts.is_empty() {
+        return Err(Error::StashConflict(conflicts));
+    }
+    repo.stash_pop(index, None)?;
+    Ok(())
+}
+
+/// The paths which conflict when merging the stashed changes onto HEAD.
+fn conflicts(repo: &Repository, stash: Oid) -> Result<Vec<String>> {
+    let stash = repo.find_commit(stash)?;
+    let base = stash.parent(0)?.tree()?;
+    let head = repo.head()?.peel_to_tree()?;
+    let index = repo.merge_trees(&base, &head, &stash.tree()?, None)?;
+
+    let mut paths = Vec::new();
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+        let entry = conflict.our.or(conflict.their).or(conflict.ancestor);
+        if let Some(entry) = entry {
+            paths.push(String::from_utf8_lossy(&entry.path).into_owned());
+        }
+    }
+    Ok(paths)
+}
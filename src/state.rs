@@ -0,0 +1,119 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use git2::{Oid, Repository};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Result, log};
+
+/// The state file in the git directory unless `--state-file` is given.
+const FILE: &str = "localdeploy-state.json";
+
+/// What was deployed last, kept across restarts of localdeploy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct State {
+    pub oid: String,
+    pub branch: String,
+    /// Unix time of the deploy.
+    pub time: u64,
+    pub result: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// How the command exited on its own last, like `exit status: 1`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit: Option<String>,
+    /// How often `--restart` started the command again since the deploy.
+    #[serde(default)]
+    pub restarts: u32,
+    /// The port of the running command with `--overlap`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// The id of the running container and its image with `--docker`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Outcome {
+    Ok,
+    /// Deployed, but a `--post-deploy-hook` failed or it is unhealthy with
+    /// nothing to roll back to.
+    Degraded,
+    Failed,
+}
+
+impl State {
+    pub fn new(oid: Oid, branch: &str, error: Option<String>) -> Self {
+        Self {
+            oid: oid.to_string(),
+            branch: branch.to_owned(),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or_default(),
+            result: match error {
+                Some(_) => Outcome::Failed,
+                None => Outcome::Ok,
+            },
+            error: error.map(|error| log::redact(&error)),
+            exit: None,
+            restarts: 0,
+            port: None,
+            container: None,
+            image: None,
+        }
+    }
+
+    /// The commit of a successful deploy.
+    pub fn deployed(&self) -> Option<Oid> {
+        match self.result {
+            Outcome::Ok | Outcome::Degraded => Oid::from_str(&self.oid).ok(),
+            Outcome::Failed => None,
+        }
+    }
+}
+
+/// The state file of `repo`, `configured` if given.
+pub(crate) fn path(repo: &Repository, configured: Option<&Path>) -> PathBuf {
+    match configured {
+        Some(path) => path.to_path_buf(),
+        None => repo.path().join(FILE),
+    }
+}
+
+/// Reads the state. A missing or unreadable file is no state, so a broken
+/// file only costs one redeploy.
+pub(crate) fn load(path: &Path) -> Option<State> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            warn!("ignoring state file {}: {}", path.display(), err);
+            return None;
+        }
+    };
+    match serde_json::from_str(&content) {
+        Ok(state) => Some(state),
+        Err(err) => {
+            warn!("ignoring state file {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Writes the state to a temporary file and renames it over the old one, so
+/// the file is never left half written.
+pub(crate) fn save(path: &Path, state: &State) -> Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let json = serde_json::to_string_pretty(state).map_err(std::io::Error::from)?;
+    fs::write(&temporary, json + "\n")?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
@@ -0,0 +1,38 @@
+use git2::{FetchOptions, Repository, SubmoduleUpdateOptions};
+
+use crate::error::{Error, Result};
+
+/// How many levels of nested submodules are updated.
+const MAX_DEPTH: usize = 2;
+
+/// Initializes missing submodules and checks out the commits recorded in
+/// the superproject, including submodules of submodules.
+pub(crate) fn update<'a>(
+    repo: &Repository,
+    fetch_options: &dyn Fn() -> FetchOptions<'a>,
+) -> Result<()> {
+    update_nested(repo, fetch_options, 1)
+}
+
+fn update_nested<'a>(
+    repo: &Repository,
+    fetch_options: &dyn Fn() -> FetchOptions<'a>,
+    depth: usize,
+) -> Result<()> {
+    for mut submodule in repo.submodules()? {
+        let path = submodule.path().display().to_string();
+        let mut options = SubmoduleUpdateOptions::new();
+        options.fetch(fetch_options());
+        submodule
+            .update(true, Some(&mut options))
+            .map_err(|err| Error::Submodule(path.clone(), err))?;
+
+        if depth < MAX_DEPTH {
+            let nested = submodule
+                .open()
+                .map_err(|err| Error::Submodule(path.clone(), err))?;
+            update_nested(&nested, fetch_options, depth + 1)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,218 @@
+use std::{io, str::FromStr};
+
+use crate::error::{Error, Result};
+
+/// The socket of the local syslog daemon.
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// The socket of journald's native protocol.
+#[cfg(target_os = "linux")]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Everything is logged as a daemon.
+#[cfg(unix)]
+const FACILITY: u8 = 3;
+
+/// Where `--log-target` sends the output of the command and localdeploy's
+/// own messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Target {
+    /// Files with `--log-dir`, otherwise localdeploy's own output.
+    File,
+    Syslog,
+    Journald,
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "file" => Ok(Target::File),
+            "syslog" => Ok(Target::Syslog),
+            "journald" => Ok(Target::Journald),
+            _ => Err(Error::InvalidArgument("log-target", s.to_owned())),
+        }
+    }
+}
+
+/// The syslog severities lines are sent with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Priority {
+    Warning = 4,
+    Info = 6,
+    Debug = 7,
+}
+
+/// What a line sent to journald is about, as fields of its own.
+#[derive(Debug, Default)]
+pub(crate) struct Fields<'a> {
+    /// stdout, stderr or pty for the command, localdeploy for its own.
+    pub stream: &'a str,
+    /// The commit deployed when the command started.
+    pub sha: Option<&'a str>,
+    /// The checkout of `--deploy` the command runs in.
+    pub checkout: Option<&'a str>,
+}
+
+/// A connection to syslog or journald, for one project.
+#[derive(Debug)]
+pub(crate) struct Sink {
+    target: Target,
+    /// Named after the checkout like the log file, the command's lines are
+    /// sent as `<project>` and localdeploy's as `localdeploy-<project>`.
+    project: String,
+    #[cfg(unix)]
+    socket: std::os::unix::net::UnixDatagram,
+}
+
+impl Sink {
+    /// Connects to the socket of `target`, fails if there is none.
+    #[cfg(unix)]
+    pub fn connect(target: Target, project: &str) -> io::Result<Self> {
+        let path = match target {
+            Target::Syslog => SYSLOG_SOCKET,
+            #[cfg(target_os = "linux")]
+            Target::Journald => JOURNALD_SOCKET,
+            #[cfg(not(target_os = "linux"))]
+            Target::Journald => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "journald needs linux",
+                ))
+            }
+            Target::File => return Err(io::ErrorKind::InvalidInput.into()),
+        };
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self {
+            target,
+            project: project.to_owned(),
+            socket,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect(_target: Target, _project: &str) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "syslog and journald need unix",
+        ))
+    }
+
+    /// Where the lines go, for messages.
+    pub fn name(&self) -> &'static str {
+        match self.target {
+            Target::Journald => "journald",
+            _ => "syslog",
+        }
+    }
+
+    /// Sends a line the command printed.
+    pub fn line(&self, priority: Priority, fields: &Fields, line: &[u8]) -> io::Result<()> {
+        self.send(&self.project, priority, fields, line)
+    }
+
+    /// Sends a message of localdeploy's own.
+    pub fn event(&self, priority: Priority, message: &str) -> io::Result<()> {
+        let identifier = format!("localdeploy-{}", self.project);
+        let fields = Fields {
+            stream: "localdeploy",
+            ..Fields::default()
+        };
+        self.send(&identifier, priority, &fields, message.as_bytes())
+    }
+
+    #[cfg(unix)]
+    fn send(
+        &self,
+        identifier: &str,
+        priority: Priority,
+        fields: &Fields,
+        line: &[u8],
+    ) -> io::Result<()> {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let datagram = match self.target {
+            Target::Journald => {
+                let mut datagram = Vec::new();
+                let priority = (priority as u8).to_string();
+                let facility = FACILITY.to_string();
+                let mut journal = vec![
+                    ("MESSAGE", line),
+                    ("PRIORITY", priority.as_bytes()),
+                    ("SYSLOG_FACILITY", facility.as_bytes()),
+                    ("SYSLOG_IDENTIFIER", identifier.as_bytes()),
+                    ("LOCALDEPLOY_PROJECT", self.project.as_bytes()),
+                    ("LOCALDEPLOY_STREAM", fields.stream.as_bytes()),
+                ];
+                if let Some(sha) = fields.sha {
+                    journal.push(("LOCALDEPLOY_SHA", sha.as_bytes()));
+                }
+                if let Some(checkout) = fields.checkout {
+                    journal.push(("LOCALDEPLOY_CHECKOUT", checkout.as_bytes()));
+                }
+                for (name, value) in journal {
+                    journal_field(&mut datagram, name, value);
+                }
+                datagram
+            }
+            _ => {
+                let mut datagram = format!(
+                    "<{}>{} {}[{}]: ",
+                    FACILITY * 8 + priority as u8,
+                    timestamp(),
+                    identifier,
+                    std::process::id()
+                )
+                .into_bytes();
+                datagram.extend_from_slice(line);
+                datagram
+            }
+        };
+        self.socket.send(&datagram).map(|_| ())
+    }
+
+    #[cfg(not(unix))]
+    fn send(&self, _: &str, _: Priority, _: &Fields, _: &[u8]) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Appends a field in journald's native protocol. Values with a newline
+/// are sent with their length in front.
+#[cfg(unix)]
+fn journal_field(datagram: &mut Vec<u8>, name: &str, value: &[u8]) {
+    datagram.extend_from_slice(name.as_bytes());
+    match value.contains(&b'\n') {
+        true => {
+            datagram.push(b'\n');
+            datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        }
+        false => datagram.push(b'='),
+    }
+    datagram.extend_from_slice(value);
+    datagram.push(b'\n');
+}
+
+/// The local time as syslog has it, like `Oct  4 09:05:01`.
+#[cfg(unix)]
+fn timestamp() -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut time = unsafe { std::mem::zeroed::<libc::tm>() };
+    if unsafe { libc::localtime_r(&now, &mut time) }.is_null() {
+        return String::new();
+    }
+    format!(
+        "{} {:>2} {:02}:{:02}:{:02}",
+        MONTHS[time.tm_mon.clamp(0, 11) as usize],
+        time.tm_mday,
+        time.tm_hour,
+        time.tm_min,
+        time.tm_sec
+    )
+}
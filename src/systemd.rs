@@ -0,0 +1,51 @@
+/// `--systemd-unit`: the unit runs the service, localdeploy only restarts
+/// it after a deploy.
+#[derive(Debug, Clone)]
+pub(crate) struct Unit {
+    pub name: String,
+    /// With `--systemd-user` the unit is one of the user manager.
+    pub user: bool,
+}
+
+/// What `systemctl is-active` says about a unit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum State {
+    Active,
+    /// Starting, or restarting before it is started again.
+    Activating,
+    /// Failed or stopped, with its state.
+    Down(String),
+}
+
+impl Unit {
+    /// `systemctl` for the system or the user manager with `args` for the
+    /// unit.
+    fn systemctl(&self, args: &[&str]) -> (String, Vec<String>) {
+        let mut command = Vec::new();
+        if self.user {
+            command.push("--user".to_owned());
+        }
+        command.extend(args.iter().map(|arg| (*arg).to_owned()));
+        command.push(self.name.clone());
+        ("systemctl".to_owned(), command)
+    }
+
+    /// Restarts the unit, or starts it if it isn't running.
+    pub fn restart(&self) -> (String, Vec<String>) {
+        self.systemctl(&["restart"])
+    }
+
+    /// Prints the state of the unit for `state`.
+    pub fn is_active(&self) -> (String, Vec<String>) {
+        self.systemctl(&["is-active"])
+    }
+}
+
+/// The state of the unit according to the output of `is_active`.
+pub(crate) fn state(output: &str) -> State {
+    match output.trim() {
+        "active" => State::Active,
+        "activating" | "reloading" | "deactivating" => State::Activating,
+        state => State::Down(state.to_owned()),
+    }
+}
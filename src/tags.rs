@@ -0,0 +1,93 @@
+use git2::{Oid, Repository};
+use glob::Pattern;
+use semver::{Prerelease, Version, VersionReq};
+
+use crate::error::Result;
+
+/// A tag and the commit it points to.
+#[derive(Debug, Clone)]
+pub(crate) struct Tag {
+    pub name: String,
+    pub commit: Oid,
+    /// Tagger time of annotated tags, commit time of lightweight ones.
+    pub time: i64,
+}
+
+/// All local tags, peeled to the commits they point to.
+pub(crate) fn list(repo: &Repository) -> Result<Vec<Tag>> {
+    let mut tags = Vec::new();
+    for reference in repo.references_glob("refs/tags/*")? {
+        let reference = reference?;
+        let name = match reference.shorthand() {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        // Tags of trees or blobs can't be deployed.
+        let commit = match reference.peel_to_commit() {
+            Ok(commit) => commit,
+            Err(_) => continue,
+        };
+        let time = match reference.target().map(|oid| repo.find_tag(oid)) {
+            Some(Ok(tag)) => match tag.tagger() {
+                Some(tagger) => tagger.when().seconds(),
+                None => commit.time().seconds(),
+            },
+            _ => commit.time().seconds(),
+        };
+        tags.push(Tag {
+            name,
+            commit: commit.id(),
+            time,
+        });
+    }
+    Ok(tags)
+}
+
+/// The newest tag matching `pattern`. Tags created at the same time are
+/// ordered by name.
+pub(crate) fn newest(repo: &Repository, pattern: &Pattern) -> Result<Option<Tag>> {
+    Ok(list(repo)?
+        .into_iter()
+        .filter(|tag| pattern.matches(&tag.name))
+        .max_by(|a, b| a.time.cmp(&b.time).then_with(|| a.name.cmp(&b.name))))
+}
+
+/// The tag with the highest semantic version matching `req`. A leading `v`
+/// is allowed, tags which are no semantic version are skipped.
+pub(crate) fn highest_semver(
+    repo: &Repository,
+    pattern: Option<&Pattern>,
+    req: &VersionReq,
+    include_prerelease: bool,
+) -> Result<Option<Tag>> {
+    let mut highest: Option<(Version, Tag)> = None;
+    for tag in list(repo)? {
+        if !pattern.is_none_or(|pattern| pattern.matches(&tag.name)) {
+            continue;
+        }
+        let version = match Version::parse(tag.name.trim_start_matches('v')) {
+            Ok(version) => version,
+            Err(err) => {
+                debug!("ignoring tag {}: {}", tag.name, err);
+                continue;
+            }
+        };
+        let matches = if version.pre.is_empty() {
+            req.matches(&version)
+        } else if include_prerelease {
+            // Requirements only match pre-releases of the exact versions
+            // they name, so match the release it leads up to instead.
+            let release = Version {
+                pre: Prerelease::EMPTY,
+                ..version.clone()
+            };
+            req.matches(&release)
+        } else {
+            false
+        };
+        if matches && highest.as_ref().is_none_or(|(max, _)| version > *max) {
+            highest = Some((version, tag));
+        }
+    }
+    Ok(highest.map(|(_, tag)| tag))
+}
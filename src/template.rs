@@ -0,0 +1,114 @@
+use crate::error::{Error, Result};
+
+/// The variables `{{name}}` in the command, the build command, the hooks
+/// and the health check can use.
+pub(crate) const VARIABLES: [&str; 6] = ["sha", "short_sha", "branch", "tag", "deploy_dir", "port"];
+
+/// The parts of a template, literal text and the names of variables.
+enum Part<'a> {
+    Text(&'a str),
+    Variable(&'a str),
+}
+
+fn parse(template: &str) -> std::result::Result<Vec<Part<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        parts.push(Part::Text(&rest[..start]));
+        let after = &rest[start + 2..];
+        // `{{{{` is a literal `{{`.
+        if let Some(after) = after.strip_prefix("{{") {
+            parts.push(Part::Text("{{"));
+            rest = after;
+            continue;
+        }
+        let end = after
+            .find("}}")
+            .ok_or_else(|| format!("{{{{ without }}}} in {}", template))?;
+        let name = after[..end].trim();
+        if !VARIABLES.contains(&name) {
+            return Err(format!(
+                "unknown variable {{{{{}}}}} in {}, known are {}",
+                name,
+                template,
+                VARIABLES.join(", ")
+            ));
+        }
+        parts.push(Part::Variable(name));
+        rest = &after[end + 2..];
+    }
+    parts.push(Part::Text(rest));
+    Ok(parts)
+}
+
+/// Checks the variables of the `template` given with `option`, so a typo
+/// fails at startup rather than at the next deploy.
+pub(crate) fn check(option: &'static str, template: &str) -> Result<()> {
+    parse(template)
+        .map(|_| ())
+        .map_err(|message| Error::InvalidArgument(option, message))
+}
+
+/// `template` with its variables replaced by what `value` gives for them,
+/// unset ones by nothing.
+pub(crate) fn render(template: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let parts = match parse(template) {
+        Ok(parts) => parts,
+        // Checked at startup.
+        Err(_) => return template.to_owned(),
+    };
+    parts
+        .into_iter()
+        .map(|part| match part {
+            Part::Text(text) => text.to_owned(),
+            Part::Variable(name) => value(name).unwrap_or_default(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, render};
+    use crate::error::Error;
+
+    fn rendered(template: &str) -> String {
+        render(template, |name| match name {
+            "sha" => Some("abc".to_owned()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(rendered("run {{sha}}"), "run abc");
+        assert_eq!(rendered("{{ sha }}-{{sha}}"), "abc-abc");
+        // Unset ones are empty.
+        assert_eq!(rendered("[{{tag}}]"), "[]");
+        assert_eq!(rendered("{ sha } }}"), "{ sha } }}");
+    }
+
+    #[test]
+    fn escaped() {
+        assert_eq!(rendered("{{{{sha}}"), "{{sha}}");
+        assert_eq!(rendered("{{{{{{sha}}"), "{{abc");
+    }
+
+    #[test]
+    fn invalid() {
+        for (template, message) in [
+            ("run {{sha", "{{ without }} in run {{sha"),
+            (
+                "run {{hash}}",
+                "unknown variable {{hash}} in run {{hash}}, known are sha, short_sha, branch, tag, deploy_dir, port",
+            ),
+        ] {
+            match check("build-command", template) {
+                Err(Error::InvalidArgument("build-command", found)) => assert_eq!(found, message),
+                checked => panic!("{:?}", checked),
+            }
+            // Left alone if it wasn't checked.
+            assert_eq!(rendered(template), template);
+        }
+        check("build-command", "run {{ short_sha }}").unwrap();
+    }
+}
@@ -0,0 +1,33 @@
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use crate::error::{Error, Result};
+
+/// Runs `work` on a worker thread and gives up waiting after `limit`. A hung
+/// connection can't be interrupted, so the worker is left behind and ends
+/// whenever the connection does.
+pub(crate) fn run<T, F>(what: &'static str, limit: Option<Duration>, work: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return work(),
+    };
+    let (sender, receiver) = mpsc::channel();
+    let worker = thread::spawn(move || {
+        let _ = sender.send(work());
+    });
+    match receiver.recv_timeout(limit) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(Error::Timeout(what, limit)),
+        Err(RecvTimeoutError::Disconnected) => match worker.join() {
+            Err(panic) => std::panic::resume_unwind(panic),
+            Ok(()) => unreachable!("the worker always sends a result"),
+        },
+    }
+}
@@ -0,0 +1,218 @@
+use std::{path::Path, process::Command, str::FromStr};
+
+use crate::error::{Error, Result};
+
+/// The user of `--run-as` the command runs as, with its groups.
+#[derive(Debug, Clone)]
+pub(crate) struct RunAs {
+    name: String,
+    uid: u32,
+    gid: u32,
+    /// The supplementary groups, those the user is a member of.
+    groups: Vec<u32>,
+    home: Option<String>,
+}
+
+impl FromStr for RunAs {
+    type Err = Error;
+
+    /// `USER` or `USER:GROUP`, by name or id. Without a group the user's
+    /// primary one is used. Switching to another user needs root, that is
+    /// checked here rather than at the first start of the command.
+    #[cfg(unix)]
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidArgument("run-as", format!("{}, {}", s, reason));
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (s, None),
+        };
+        if user.is_empty() || group == Some("") {
+            return Err(invalid("use USER or USER:GROUP"));
+        }
+        let (name, uid, primary, home) = match (passwd(user), user.parse::<u32>()) {
+            (Some(entry), _) => entry,
+            // An id without an entry still works, with a group given.
+            (None, Ok(uid)) if group.is_some() => (user.to_owned(), uid, 0, None),
+            (None, _) => return Err(invalid("no such user")),
+        };
+        let gid = match group {
+            Some(group) => match (group_id(group), group.parse::<u32>()) {
+                (Some(gid), _) | (None, Ok(gid)) => gid,
+                (None, Err(_)) => return Err(invalid("no such group")),
+            },
+            None => primary,
+        };
+        let run_as = Self {
+            groups: groups(&name, gid),
+            name,
+            uid,
+            gid,
+            home,
+        };
+        if !run_as.is_current() && unsafe { libc::geteuid() } != 0 {
+            return Err(invalid("running the command as another user needs root"));
+        }
+        Ok(run_as)
+    }
+
+    #[cfg(not(unix))]
+    fn from_str(s: &str) -> Result<Self> {
+        Err(Error::InvalidArgument(
+            "run-as",
+            format!("{}, running as another user needs unix", s),
+        ))
+    }
+}
+
+impl RunAs {
+    /// Makes `command` switch to the user before it is executed, with the
+    /// user's `HOME`, `USER` and `LOGNAME`.
+    #[cfg(unix)]
+    pub fn apply(&self, command: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        command.env("USER", &self.name).env("LOGNAME", &self.name);
+        if let Some(home) = &self.home {
+            command.env("HOME", home);
+        }
+        if self.is_current() {
+            return;
+        }
+        let (uid, gid, groups) = (self.uid, self.gid, self.groups.clone());
+        // Only async-signal-safe calls between fork and exec, the groups
+        // were looked up before. The groups go first, after setuid there is
+        // no permission left to change them.
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setgroups(groups.len() as _, groups.as_ptr() as *const _) != 0
+                    || libc::setgid(gid) != 0
+                    || libc::setuid(uid) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply(&self, _command: &mut Command) {}
+
+    /// Hands `path` over to the user, so the command's logs are its own.
+    #[cfg(unix)]
+    pub fn chown(&self, path: &Path) -> std::io::Result<()> {
+        std::os::unix::fs::chown(path, Some(self.uid), Some(self.gid))
+    }
+
+    #[cfg(not(unix))]
+    pub fn chown(&self, _path: &Path) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn is_current(&self) -> bool {
+        unsafe { libc::geteuid() == self.uid && libc::getegid() == self.gid }
+    }
+}
+
+/// The name, uid, gid and home directory of `user`, a name or a uid.
+#[cfg(unix)]
+pub(crate) fn passwd(user: &str) -> Option<(String, u32, u32, Option<String>)> {
+    use std::ffi::{CStr, CString};
+
+    let mut entry = unsafe { std::mem::zeroed::<libc::passwd>() };
+    let mut found = std::ptr::null_mut();
+    let mut buffer = vec![0; 16384];
+    let status = match user.parse::<u32>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            )
+        },
+        Err(_) => {
+            let name = CString::new(user).ok()?;
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut entry,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    &mut found,
+                )
+            }
+        }
+    };
+    if status != 0 || found.is_null() {
+        return None;
+    }
+    let text = |field: *const libc::c_char| {
+        Some(field).filter(|field| !field.is_null()).map(|field| {
+            unsafe { CStr::from_ptr(field) }
+                .to_string_lossy()
+                .into_owned()
+        })
+    };
+    Some((
+        text(entry.pw_name)?,
+        entry.pw_uid,
+        entry.pw_gid,
+        text(entry.pw_dir).filter(|home| !home.is_empty()),
+    ))
+}
+
+/// The gid of the group named `group`.
+#[cfg(unix)]
+fn group_id(group: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(group).ok()?;
+    let mut entry = unsafe { std::mem::zeroed::<libc::group>() };
+    let mut found = std::ptr::null_mut();
+    let mut buffer = vec![0; 16384];
+    let status = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    match status == 0 && !found.is_null() {
+        true => Some(entry.gr_gid),
+        false => None,
+    }
+}
+
+/// The groups `name` is a member of, including `gid`.
+#[cfg(unix)]
+fn groups(name: &str, gid: u32) -> Vec<u32> {
+    let name = match std::ffi::CString::new(name) {
+        Ok(name) => name,
+        Err(_) => return vec![gid],
+    };
+    let mut groups = vec![0u32; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let status = unsafe {
+            libc::getgrouplist(
+                name.as_ptr(),
+                gid as _,
+                groups.as_mut_ptr() as *mut _,
+                &mut count,
+            )
+        };
+        if status >= 0 {
+            groups.truncate(count.max(0) as usize);
+            return groups;
+        }
+        // Too small, the count says how many there are on most systems.
+        let needed = (count.max(0) as usize).max(groups.len() * 2);
+        if needed > 65536 {
+            return vec![gid];
+        }
+        groups.resize(needed, 0);
+    }
+}
@@ -0,0 +1,116 @@
+/// Splits a command line into words the way a POSIX shell does, without
+/// expanding anything. Runs of whitespace separate words, a backslash
+/// escapes the next character, single quotes keep everything up to the next
+/// one and double quotes everything but `\"`, `\\`, `\$` and `` \` ``.
+pub(crate) fn split(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // Quotes can make an empty word, so whitespace alone doesn't tell.
+    let mut in_word = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            '\\' => match chars.next() {
+                // A backslash before a newline continues the line.
+                Some('\n') => {}
+                Some(c) => {
+                    word.push(c);
+                    in_word = true;
+                }
+                None => return Err("trailing backslash"),
+            },
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated single quote"),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some('\n') => {}
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err("unterminated double quote"),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated double quote"),
+                    }
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split;
+
+    fn words(line: &str) -> Vec<String> {
+        split(line).unwrap()
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(words("echo 'a  b' \"c  d\""), ["echo", "a  b", "c  d"]);
+        assert_eq!(words("'it\"s' \"it's\""), ["it\"s", "it's"]);
+        assert_eq!(words("a'b'\"c\""), ["abc"]);
+        assert_eq!(words("'' \"\""), ["", ""]);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(words(r"a\ b c\\d \'"), ["a b", r"c\d", "'"]);
+        assert_eq!(words(r#""\"\\\$\`" "\n""#), [r#""\$`"#, r"\n"]);
+        assert_eq!(words("a \\\nb"), ["a", "b"]);
+        // Single quotes keep backslashes.
+        assert_eq!(words(r"'a\b'"), [r"a\b"]);
+    }
+
+    #[test]
+    fn empty() {
+        assert!(words("").is_empty());
+        assert!(words(" \t\n ").is_empty());
+    }
+
+    #[test]
+    fn path_with_spaces() {
+        assert_eq!(
+            words("\"/path with spaces/bin\" --flag"),
+            ["/path with spaces/bin", "--flag"]
+        );
+        assert_eq!(words(r"/path\ with\ spaces/bin"), ["/path with spaces/bin"]);
+    }
+
+    #[test]
+    fn unterminated() {
+        assert_eq!(split("echo 'a"), Err("unterminated single quote"));
+        assert_eq!(split("echo \"a"), Err("unterminated double quote"));
+        assert_eq!(split("echo \"a\\"), Err("unterminated double quote"));
+        assert_eq!(split("echo a\\"), Err("trailing backslash"));
+    }
+}
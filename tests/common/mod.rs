@@ -0,0 +1,193 @@
+//! Fixture repositories and a running localdeploy for the integration tests.
+
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use git2::{Repository, RepositoryInitOptions, Signature};
+
+/// A directory under the temporary one, removed with everything in it once
+/// the test is done.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "localdeploy-test-{}-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed),
+            name
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A repository on the branch `main`, with `files` committed to it.
+pub fn init(path: &Path, files: &[(&str, &str)]) -> Repository {
+    let mut options = RepositoryInitOptions::new();
+    options.initial_head("main");
+    let repo = Repository::init_opts(path, &options).unwrap();
+    commit(&repo, files, "init");
+    repo
+}
+
+/// Writes `files` to the working tree of `repo` and commits them on its
+/// branch.
+pub fn commit(repo: &Repository, files: &[(&str, &str)], message: &str) -> git2::Oid {
+    let workdir = repo.workdir().unwrap();
+    let mut index = repo.index().unwrap();
+    for (name, content) in files {
+        fs::write(workdir.join(name), content).unwrap();
+        index.add_path(Path::new(name)).unwrap();
+    }
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = Signature::now("test", "test@example.com").unwrap();
+    let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parent.iter().collect::<Vec<_>>(),
+    )
+    .unwrap()
+}
+
+/// A bare remote, a clone commits are pushed from and the checkout
+/// localdeploy deploys.
+pub struct Fixture {
+    pub dir: TempDir,
+    pub work: Repository,
+}
+
+impl Fixture {
+    pub fn new(files: &[(&str, &str)]) -> Self {
+        let dir = TempDir::new("fixture");
+        let work = init(&dir.path().join("work"), files);
+        let mut options = RepositoryInitOptions::new();
+        options.bare(true).initial_head("main");
+        Repository::init_opts(dir.path().join("remote.git"), &options).unwrap();
+        let fixture = Self { dir, work };
+        fixture.push();
+        Repository::clone(fixture.remote().to_str().unwrap(), fixture.checkout()).unwrap();
+        fixture
+    }
+
+    pub fn remote(&self) -> PathBuf {
+        self.dir.path().join("remote.git")
+    }
+
+    /// The checkout localdeploy deploys.
+    pub fn checkout(&self) -> PathBuf {
+        self.dir.path().join("checkout")
+    }
+
+    /// Commits `files` and pushes them to the remote.
+    pub fn push_commit(&self, files: &[(&str, &str)], message: &str) -> git2::Oid {
+        let oid = commit(&self.work, files, message);
+        self.push();
+        oid
+    }
+
+    fn push(&self) {
+        let mut remote = self
+            .work
+            .remote_anonymous(self.remote().to_str().unwrap())
+            .unwrap();
+        remote
+            .push(&["refs/heads/main:refs/heads/main"], None)
+            .unwrap();
+    }
+
+    /// The content of `name` in the checkout, empty while it doesn't exist.
+    pub fn read(&self, name: &str) -> String {
+        read(&self.checkout().join(name))
+    }
+}
+
+/// The content of `path`, empty while it doesn't exist.
+pub fn read(path: &Path) -> String {
+    fs::read_to_string(path).unwrap_or_default()
+}
+
+/// localdeploy running with `args`, stopped like by a service manager when
+/// dropped.
+pub struct Localdeploy(Child);
+
+impl Localdeploy {
+    pub fn start(args: &[&str]) -> Self {
+        Self::start_with_env(args, &[])
+    }
+
+    /// Started with `env` added to the environment.
+    pub fn start_with_env(args: &[&str], env: &[(&str, &str)]) -> Self {
+        let child = Command::new(env!("CARGO_BIN_EXE_localdeploy"))
+            .args(args)
+            .envs(env.iter().copied())
+            .process_group(0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        Self(child)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.0.id()
+    }
+}
+
+impl Drop for Localdeploy {
+    fn drop(&mut self) {
+        signal_group(self.0.id(), "TERM");
+        if !wait_for(|| matches!(self.0.try_wait(), Ok(Some(_)))) {
+            signal_group(self.0.id(), "KILL");
+            let _ = self.0.wait();
+        }
+    }
+}
+
+/// Sends `signal` to the process group `group`.
+fn signal_group(group: u32, signal: &str) {
+    let _ = Command::new("kill")
+        .arg(format!("-{}", signal))
+        .arg("--")
+        .arg(format!("-{}", group))
+        .status();
+}
+
+/// Whether `done` became true within 20 seconds.
+pub fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(20) {
+        if done() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
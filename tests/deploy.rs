@@ -0,0 +1,251 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{thread, time::Duration};
+
+use common::{wait_for, Fixture, Localdeploy};
+
+/// Whether the process `pid` is still running, a zombie has exited already.
+fn alive(pid: u32) -> bool {
+    let stat = common::read(format!("/proc/{}/stat", pid).as_ref());
+    !stat.is_empty() && !stat.contains(") Z ")
+}
+
+#[test]
+fn keeps_the_old_command_running_during_the_build() {
+    let fixture = Fixture::new(&[
+        ("app.txt", "v1\n"),
+        ("run.sh", "echo $$ > pid.txt\nexec sleep 60\n"),
+        (
+            "build.sh",
+            "cat app.txt > building\nsleep 2\ncat app.txt > built\n",
+        ),
+    ]);
+    let checkout = fixture.checkout();
+    let _localdeploy = Localdeploy::start(&[
+        "-p",
+        checkout.to_str().unwrap(),
+        "-c",
+        "sh run.sh",
+        "--build-command",
+        "sh build.sh",
+        "-i",
+        "1",
+    ]);
+    assert!(wait_for(|| fixture.read("built") == "v1\n"));
+    assert!(wait_for(|| !fixture.read("pid.txt").is_empty()));
+    let old = fixture.read("pid.txt").trim().parse().unwrap();
+
+    fixture.push_commit(&[("app.txt", "v2\n")], "v2");
+    assert!(wait_for(|| fixture.read("building") == "v2\n"));
+    // Alive is looked at first, so it was while the build hadn't finished.
+    loop {
+        let alive = alive(old);
+        if fixture.read("built") == "v2\n" {
+            break;
+        }
+        assert!(alive, "the old command was stopped during the build");
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(wait_for(
+        || fixture.read("pid.txt").trim() != old.to_string()
+    ));
+    assert!(wait_for(|| !alive(old)));
+}
+
+#[test]
+fn passes_the_deploy_variables() {
+    let fixture = Fixture::new(&[
+        ("app.txt", "v1\n"),
+        ("run.sh", "env > env.txt\nexec sleep 60\n"),
+        ("build.sh", "env > build-env.txt\n"),
+    ]);
+    let checkout = fixture.checkout();
+    let first = fixture.work.head().unwrap().target().unwrap().to_string();
+    let _localdeploy = Localdeploy::start(&[
+        "-p",
+        checkout.to_str().unwrap(),
+        "-c",
+        "sh run.sh",
+        "--build-command",
+        "sh build.sh",
+        "-i",
+        "1",
+    ]);
+    let var = |file: &str, name: &str| {
+        let prefix = format!("{}=", name);
+        fixture
+            .read(file)
+            .lines()
+            .find_map(|line| line.strip_prefix(&prefix).map(str::to_owned))
+    };
+    assert!(wait_for(|| var("env.txt", "LOCALDEPLOY_SHA").is_some()));
+    assert_eq!(var("env.txt", "LOCALDEPLOY_SHA").as_ref(), Some(&first));
+    assert_eq!(
+        var("env.txt", "LOCALDEPLOY_SHORT_SHA").as_deref(),
+        Some(&first[..7])
+    );
+    assert_eq!(
+        var("env.txt", "LOCALDEPLOY_BRANCH").as_deref(),
+        Some("main")
+    );
+    assert_eq!(
+        var("env.txt", "LOCALDEPLOY_TRIGGER").as_deref(),
+        Some("startup")
+    );
+    assert_eq!(var("env.txt", "LOCALDEPLOY_TAG"), None);
+    let time = var("env.txt", "LOCALDEPLOY_DEPLOY_TIME").unwrap();
+    assert!(humantime::parse_rfc3339(&time).is_ok(), "{}", time);
+
+    let second = fixture
+        .push_commit(&[("app.txt", "v2\n")], "v2")
+        .to_string();
+    assert!(wait_for(
+        || var("env.txt", "LOCALDEPLOY_SHA").as_ref() == Some(&second)
+    ));
+    // Set to what is on disk once updated, for the build as well.
+    for file in ["env.txt", "build-env.txt"] {
+        assert_eq!(
+            var(file, "LOCALDEPLOY_SHA").as_ref(),
+            Some(&second),
+            "{}",
+            file
+        );
+        assert_eq!(
+            var(file, "LOCALDEPLOY_PREVIOUS_SHA").as_ref(),
+            Some(&first),
+            "{}",
+            file
+        );
+        assert_eq!(
+            var(file, "LOCALDEPLOY_TRIGGER").as_deref(),
+            Some("interval"),
+            "{}",
+            file
+        );
+    }
+    assert_eq!(fixture.read("app.txt"), "v2\n");
+}
+
+#[test]
+fn releases_the_new_version_once_it_is_healthy() {
+    let fixture = Fixture::new(&[
+        ("app.txt", "good\n"),
+        ("run.sh", "pwd > ../../run.txt\nexec sleep 60\n"),
+        // What `current` points at while the new release is checked.
+        (
+            "check.sh",
+            "readlink ../../current >> ../../checked.txt\ngrep -q good app.txt\n",
+        ),
+    ]);
+    let checkout = fixture.checkout();
+    let deploy_dir = fixture.dir.path().join("deploy");
+    let _localdeploy = Localdeploy::start(&[
+        "-p",
+        checkout.to_str().unwrap(),
+        "-c",
+        "sh run.sh",
+        "--deploy-dir",
+        deploy_dir.to_str().unwrap(),
+        "--releases",
+        "3",
+        "--health-check-cmd",
+        "sh check.sh",
+        "--health-retries",
+        "0",
+        "-i",
+        "1",
+    ]);
+    let current = || {
+        std::fs::read_link(deploy_dir.join("current"))
+            .map(|link| link.display().to_string())
+            .unwrap_or_default()
+    };
+    let read = |name: &str| common::read(&deploy_dir.join(name));
+    assert!(wait_for(|| !current().is_empty()));
+    let first = current();
+    // Nothing was released while the first one was checked.
+    assert_eq!(read("checked.txt"), "");
+    assert!(wait_for(|| read("run.txt").trim().ends_with(&first)));
+
+    fixture.push_commit(&[("app.txt", "bad\n")], "bad");
+    assert!(wait_for(|| !read("checked.txt").is_empty()));
+    assert_eq!(read("checked.txt"), format!("{}\n", first));
+    // The bad one is removed and the command started again in the release
+    // `current` still points at.
+    let releases = || deploy_dir.join("releases").read_dir().unwrap().count();
+    assert!(wait_for(|| releases() == 1));
+    assert!(wait_for(|| read("run.txt").trim().ends_with(&first)));
+    assert_eq!(current(), first);
+}
+
+#[test]
+fn env_overrides_env_files_which_override_the_environment() {
+    let fixture = Fixture::new(&[
+        ("app.txt", "v1\n"),
+        ("run.sh", "env > env.txt\nexec sleep 60\n"),
+    ]);
+    let checkout = fixture.checkout();
+    let env_file = fixture.dir.path().join("app.env");
+    std::fs::write(&env_file, "FROM_FILE=file\nFROM_ENV=file\n").unwrap();
+    let _localdeploy = Localdeploy::start_with_env(
+        &[
+            "-p",
+            checkout.to_str().unwrap(),
+            "-c",
+            "sh run.sh",
+            "--env-file",
+            env_file.to_str().unwrap(),
+            "--env",
+            "FROM_ENV=option",
+        ],
+        &[
+            ("FROM_PROCESS", "process"),
+            ("FROM_FILE", "process"),
+            ("FROM_ENV", "process"),
+        ],
+    );
+    assert!(wait_for(|| fixture.read("env.txt").contains("FROM_ENV=")));
+    let env = fixture.read("env.txt");
+    for expected in ["FROM_PROCESS=process", "FROM_FILE=file", "FROM_ENV=option"] {
+        assert!(env.lines().any(|line| line == expected), "{}", expected);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn reaps_orphans_during_the_build() {
+    let fixture = Fixture::new(&[
+        ("app.txt", "v1\n"),
+        ("run.sh", "exec sleep 60\n"),
+        // The sleep is orphaned to localdeploy and exits right away.
+        (
+            "build.sh",
+            "(sleep 0 &)\nsleep 1\necho > orphaned\nsleep 5\necho > built\n",
+        ),
+    ]);
+    let checkout = fixture.checkout();
+    let localdeploy = Localdeploy::start(&[
+        "-p",
+        checkout.to_str().unwrap(),
+        "-c",
+        "sh run.sh",
+        "--build-command",
+        "sh build.sh",
+        "--init",
+    ]);
+    // The children of localdeploy which exited and weren't waited for.
+    let zombies = || {
+        std::fs::read_dir(format!("/proc/{}/task", localdeploy.id()))
+            .unwrap()
+            .map(|task| common::read(&task.unwrap().path().join("children")))
+            .collect::<String>()
+            .split_whitespace()
+            .filter(|pid| common::read(format!("/proc/{}/stat", pid).as_ref()).contains(") Z "))
+            .count()
+    };
+    assert!(wait_for(|| !fixture.read("orphaned").is_empty()));
+    assert!(wait_for(|| zombies() == 0));
+    assert_eq!(fixture.read("built"), "", "only reaped after the build");
+}
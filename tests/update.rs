@@ -0,0 +1,66 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::path::Path;
+
+use common::{read, wait_for, Fixture, Localdeploy, TempDir};
+
+/// Writes the deployed version for the test to see, then keeps running.
+const RUN: &str = "cat app.txt > out.txt\nexec sleep 60\n";
+
+#[test]
+fn fast_forwards_the_checkout() {
+    let fixture = Fixture::new(&[("app.txt", "v1\n"), ("run.sh", RUN)]);
+    let checkout = fixture.checkout();
+    let _localdeploy = Localdeploy::start(&[
+        "-p",
+        checkout.to_str().unwrap(),
+        "-c",
+        "sh run.sh",
+        "-i",
+        "1",
+    ]);
+    assert!(wait_for(|| fixture.read("out.txt") == "v1\n"));
+
+    fixture.push_commit(&[("app.txt", "v2\n")], "v2");
+    assert!(wait_for(|| fixture.read("app.txt") == "v2\n"));
+    // Restarted on the new version.
+    assert!(wait_for(|| fixture.read("out.txt") == "v2\n"));
+}
+
+/// A local path as the remote, cloned and fast-forwarded by localdeploy.
+fn deploys_from_local_remote(url: impl FnOnce(&Path) -> String) {
+    let dir = TempDir::new("local");
+    let origin = common::init(
+        &dir.path().join("origin"),
+        &[("app.txt", "v1\n"), ("run.sh", RUN)],
+    );
+    let checkout = dir.path().join("checkout");
+    let url = url(origin.workdir().unwrap());
+    let _localdeploy = Localdeploy::start(&[
+        "-n",
+        &url,
+        "-p",
+        checkout.to_str().unwrap(),
+        "-c",
+        "sh run.sh",
+        "-i",
+        "1",
+    ]);
+    assert!(wait_for(|| read(&checkout.join("out.txt")) == "v1\n"));
+
+    common::commit(&origin, &[("app.txt", "v2\n")], "v2");
+    assert!(wait_for(|| read(&checkout.join("app.txt")) == "v2\n"));
+    assert!(wait_for(|| read(&checkout.join("out.txt")) == "v2\n"));
+}
+
+#[test]
+fn deploys_from_local_path() {
+    deploys_from_local_remote(|origin| origin.to_str().unwrap().to_owned());
+}
+
+#[test]
+fn deploys_from_file_url() {
+    deploys_from_local_remote(|origin| format!("file://{}", origin.display()));
+}